@@ -0,0 +1,207 @@
+//! Keys of `api_key` authentication policies.
+//!
+//! A key is shown once, when it is created, as `ck_<key id>_<secret>`. Only a
+//! salted SHA-256 hash of the secret is stored, along with the key id to look
+//! it up by. Keys are stored in a JSON file or in a table of the namespace
+//! with the [`KEYS_TABLE_COLUMNS`], all of them TEXT.
+
+use std::{fs, io, path::Path, sync::Arc};
+
+use chrono::{SecondsFormat, Utc};
+use datafusion::arrow::{
+    array::{Array, ArrayRef, StringArray},
+    compute::cast,
+    datatypes::{DataType, Field, Schema},
+    error::ArrowError,
+    record_batch::RecordBatch,
+};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+const KEY_PREFIX: &str = "ck_";
+
+/// Columns of a table holding keys, in the order of [`keys_to_batch`].
+pub const KEYS_TABLE_COLUMNS: [&str; 6] = [
+    "key_id",
+    "principal",
+    "salt",
+    "hash",
+    "created_at",
+    "revoked_at",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ApiKey {
+    pub key_id: String,
+    /// Who requests with the key are made by.
+    pub principal: String,
+    pub salt: String,
+    /// SHA-256 of the salt followed by the secret.
+    pub hash: String,
+    /// RFC 3339 timestamps.
+    pub created_at: String,
+    pub revoked_at: Option<String>,
+}
+
+impl ApiKey {
+    /// Generate a key for `principal`. Returns what is stored of it with the
+    /// key to hand out.
+    pub fn generate(principal: &str) -> (Self, String) {
+        let key_id = hex::encode(random_bytes::<6>());
+        let secret = hex::encode(random_bytes::<32>());
+        let salt = random_bytes::<16>();
+
+        let api_key = ApiKey {
+            key_id: key_id.clone(),
+            principal: principal.to_string(),
+            salt: hex::encode(salt),
+            hash: hash(&salt, &secret),
+            created_at: now(),
+            revoked_at: None,
+        };
+
+        (api_key, format!("{KEY_PREFIX}{key_id}_{secret}"))
+    }
+
+    /// Whether `secret` is the secret of the key and the key wasn't revoked.
+    pub fn verify(&self, secret: &str) -> bool {
+        let Ok(salt) = hex::decode(&self.salt) else {
+            return false;
+        };
+
+        self.revoked_at.is_none() && constant_time_eq(&hash(&salt, secret), &self.hash)
+    }
+
+    pub fn revoke(&mut self) {
+        self.revoked_at.get_or_insert_with(now);
+    }
+}
+
+/// Split `key` into its key id and secret, if it looks like a key.
+pub fn parse_key(key: &str) -> Option<(&str, &str)> {
+    key.strip_prefix(KEY_PREFIX)?.split_once('_')
+}
+
+/// Read a keys file, without keys if it doesn't exist yet.
+pub fn read_keys_file(path: &Path) -> io::Result<Vec<ApiKey>> {
+    match fs::read(path) {
+        Ok(content) => Ok(serde_json::from_slice(&content)?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(vec![]),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn write_keys_file(path: &Path, keys: &[ApiKey]) -> io::Result<()> {
+    fs::write(path, serde_json::to_vec_pretty(keys)?)
+}
+
+pub fn keys_to_batch(keys: &[ApiKey]) -> Result<RecordBatch, ArrowError> {
+    let schema = Schema::new(
+        KEYS_TABLE_COLUMNS
+            .iter()
+            .map(|name| Field::new(*name, DataType::Utf8, *name == "revoked_at"))
+            .collect::<Vec<_>>(),
+    );
+    let column = |value: fn(&ApiKey) -> Option<&str>| -> ArrayRef {
+        Arc::new(keys.iter().map(value).collect::<StringArray>())
+    };
+
+    RecordBatch::try_new(
+        Arc::new(schema),
+        vec![
+            column(|k| Some(&k.key_id)),
+            column(|k| Some(&k.principal)),
+            column(|k| Some(&k.salt)),
+            column(|k| Some(&k.hash)),
+            column(|k| Some(&k.created_at)),
+            column(|k| k.revoked_at.as_deref()),
+        ],
+    )
+}
+
+/// Read keys from rows with the [`KEYS_TABLE_COLUMNS`], in any order.
+pub fn keys_from_batches(batches: &[RecordBatch]) -> Result<Vec<ApiKey>, ArrowError> {
+    let mut keys = vec![];
+
+    for batch in batches {
+        let columns = KEYS_TABLE_COLUMNS
+            .iter()
+            .map(|name| {
+                let index = batch.schema().index_of(name)?;
+                cast(batch.column(index), &DataType::Utf8)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let columns = columns
+            .iter()
+            .map(|c| c.as_any().downcast_ref::<StringArray>().unwrap())
+            .collect::<Vec<_>>();
+        let value = |column: usize, row: usize| {
+            Some(columns[column])
+                .filter(|c| c.is_valid(row))
+                .map(|c| c.value(row).to_string())
+        };
+
+        for row in 0..batch.num_rows() {
+            keys.push(ApiKey {
+                key_id: value(0, row).unwrap_or_default(),
+                principal: value(1, row).unwrap_or_default(),
+                salt: value(2, row).unwrap_or_default(),
+                hash: value(3, row).unwrap_or_default(),
+                created_at: value(4, row).unwrap_or_default(),
+                revoked_at: value(5, row),
+            });
+        }
+    }
+
+    Ok(keys)
+}
+
+fn hash(salt: &[u8], secret: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    hasher.update(secret.as_bytes());
+
+    hex::encode(hasher.finalize())
+}
+
+fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0; N];
+    rand::thread_rng().fill_bytes(&mut bytes);
+
+    bytes
+}
+
+fn now() -> String {
+    Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// Compare without giving away through timing how much of a guess was right.
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify() {
+        let (mut api_key, key) = ApiKey::generate("alice");
+        let (key_id, secret) = parse_key(&key).unwrap();
+
+        assert_eq!(key_id, api_key.key_id);
+        assert!(api_key.verify(secret));
+        assert!(!api_key.verify(&secret[1..]));
+
+        let keys = keys_from_batches(&[keys_to_batch(&[api_key.clone()]).unwrap()]).unwrap();
+        assert_eq!(keys, vec![api_key.clone()]);
+
+        api_key.revoke();
+        assert!(!api_key.verify(secret));
+    }
+}
@@ -1,16 +1,39 @@
-use std::sync::Arc;
+//! Evaluation of the expressions of authorization policies.
+//!
+//! An expression is evaluated against a single row describing the request,
+//! with the columns of [`AuthorizationExprContext::schema`]. Claims of the
+//! token of the request are read as `claims.<name>` and headers as
+//! `headers.<name>`, NULL if missing, and `client_ip << '10.0.0.0/8'` tells
+//! whether the client address is within a network.
+//!
+//! Row policies are evaluated for every row of their table instead, with the
+//! request fields replaced by their values for the request beforehand, see
+//! [`row_filter`]. So are masking policies, see [`masked_column`].
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    net::IpAddr,
+    ops::ControlFlow,
+    sync::Arc,
+};
 
 use datafusion::{
     arrow::{
-        array::BooleanArray,
-        datatypes::{DataType, Field, Schema},
+        array::{Array, ArrayRef, BooleanArray, StringArray},
+        datatypes::{DataType, Field, Schema, SchemaRef},
+        error::ArrowError,
         record_batch::RecordBatch,
     },
     common::DFSchema,
     config::ConfigOptions,
-    error::Result as DFResult,
-    logical_expr::{AggregateUDF, ScalarUDF, TableSource},
-    physical_expr::{create_physical_expr, execution_props::ExecutionProps},
+    error::{DataFusionError, Result as DFResult},
+    logical_expr::{create_udf, AggregateUDF, ExprSchemable, ScalarUDF, TableSource, Volatility},
+    optimizer::simplify_expressions::{ExprSimplifier, SimplifyContext},
+    physical_expr::{
+        create_physical_expr, execution_props::ExecutionProps, functions::make_scalar_function,
+        PhysicalExpr,
+    },
     physical_plan::ColumnarValue,
     scalar::ScalarValue,
     sql::{
@@ -18,21 +41,169 @@ use datafusion::{
         TableReference,
     },
 };
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use sqlparser::ast::{
+    visit_expressions_mut, BinaryOperator, Expr as SqlExpr, Function, FunctionArg, FunctionArgExpr,
+    Ident, ObjectName, Value as SqlValue,
+};
 
-use crate::AuthorizationPolicy;
+use crate::{AuthorizationPolicy, Catalog, Error, Mask, MaskingPolicy, PolicyKind, Table};
 
-#[derive(Debug, Default)]
+/// How the principal of a request was authenticated, named after the type of
+/// the authentication policy.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthMethod {
+    #[default]
+    Anonymous,
+    ApiKey,
+    Jwt,
+}
+
+impl fmt::Display for AuthMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthMethod::Anonymous => write!(f, "anonymous"),
+            AuthMethod::ApiKey => write!(f, "api_key"),
+            AuthMethod::Jwt => write!(f, "jwt"),
+        }
+    }
+}
+
+/// The request an authorization policy is evaluated for, also read from JSON
+/// fixtures to test policies with.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthorizationRequest {
+    /// None if anonymous.
+    pub principal: Option<String>,
+    pub auth_method: AuthMethod,
+    /// The verified claims of the token of the request, if any.
+    pub claims: Option<Map<String, Value>>,
+    pub client_ip: Option<IpAddr>,
+    /// By lowercase name, without headers carrying credentials.
+    #[serde(deserialize_with = "lowercase_keys")]
+    pub headers: BTreeMap<String, String>,
+    pub namespace: String,
+    pub handler: String,
+}
+
+fn lowercase_keys<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<BTreeMap<String, String>, D::Error> {
+    let map = BTreeMap::<String, String>::deserialize(deserializer)?;
+    Ok(map
+        .into_iter()
+        .map(|(k, v)| (k.to_lowercase(), v))
+        .collect())
+}
+
+impl AuthorizationRequest {
+    /// The value of field `name`, None if there's no such field.
+    fn field(&self, name: &str) -> Option<Option<String>> {
+        Some(match name {
+            "principal" => self.principal.clone(),
+            "auth_method" => Some(self.auth_method.to_string()),
+            "claims" => self
+                .claims
+                .as_ref()
+                .map(|c| serde_json::to_string(c).unwrap()),
+            "client_ip" => self.client_ip.map(|ip| ip.to_string()),
+            "headers" => Some(serde_json::to_string(&self.headers).unwrap()),
+            "namespace" => Some(self.namespace.clone()),
+            "handler" => Some(self.handler.clone()),
+            _ => return None,
+        })
+    }
+
+    fn claim(&self, name: &str) -> Option<String> {
+        json_text(self.claims.as_ref()?.get(name)?.clone())
+    }
+
+    fn to_batch(&self, schema: SchemaRef) -> Result<RecordBatch, ArrowError> {
+        let columns = schema
+            .fields()
+            .iter()
+            .map(|f| {
+                let value = self.field(f.name()).flatten();
+                Arc::new(StringArray::from(vec![value])) as ArrayRef
+            })
+            .collect();
+
+        RecordBatch::try_new(schema, columns)
+    }
+}
+
+/// The columns and functions available to authorization expressions.
+///
+/// `claims` and `headers` are JSON objects, which `claims.<name>` and
+/// `headers.<name>` are rewritten to look up in with `json_get`, and
+/// `a << b` is rewritten to `inet_contained_by(a, b)`.
+#[derive(Debug)]
 pub struct AuthorizationExprContext {
     config: ConfigOptions,
+    schema: SchemaRef,
+    functions: HashMap<String, Arc<ScalarUDF>>,
+}
+
+impl Default for AuthorizationExprContext {
+    fn default() -> Self {
+        let text = |name| Field::new(name, DataType::Utf8, true);
+        let schema = Schema::new(vec![
+            text("principal"),
+            text("auth_method"),
+            text("claims"),
+            text("client_ip"),
+            text("headers"),
+            text("namespace"),
+            text("handler"),
+        ]);
+        let functions = functions()
+            .into_iter()
+            .map(|f| (f.name.clone(), Arc::new(f)))
+            .collect();
+
+        Self {
+            config: ConfigOptions::default(),
+            schema: Arc::new(schema),
+            functions,
+        }
+    }
+}
+
+impl AuthorizationExprContext {
+    pub fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+
+    /// Plan `expr` to be evaluated against a request.
+    pub fn create_physical_expr(&self, expr: &SqlExpr) -> DFResult<Arc<dyn PhysicalExpr>> {
+        let df_schema = Arc::new(DFSchema::try_from(self.schema.as_ref().clone())?);
+        let rel_expr = SqlToRel::new(self).sql_to_expr(
+            rewrite_expr(expr),
+            &df_schema,
+            &mut PlannerContext::default(),
+        )?;
+        // Unlike queries, expressions aren't coerced by the analyzer.
+        let props = ExecutionProps::default();
+        let rel_expr = ExprSimplifier::new(SimplifyContext::new(&props))
+            .coerce(rel_expr, df_schema.clone())?;
+
+        create_physical_expr(&rel_expr, &df_schema, &self.schema, &props)
+    }
 }
 
 impl ContextProvider for AuthorizationExprContext {
-    fn get_table_provider(&self, _name: TableReference) -> DFResult<Arc<dyn TableSource>> {
-        todo!()
+    fn get_table_provider(&self, name: TableReference) -> DFResult<Arc<dyn TableSource>> {
+        Err(DataFusionError::Plan(format!(
+            "authorization expressions can't read table {name}"
+        )))
     }
 
-    fn get_function_meta(&self, _name: &str) -> Option<Arc<ScalarUDF>> {
-        None
+    fn get_function_meta(&self, name: &str) -> Option<Arc<ScalarUDF>> {
+        self.functions.get(name).cloned()
     }
 
     fn get_aggregate_meta(&self, _name: &str) -> Option<Arc<AggregateUDF>> {
@@ -48,45 +219,578 @@ impl ContextProvider for AuthorizationExprContext {
     }
 }
 
+/// The functions available to authorization expressions, which SQL sessions
+/// applying row and masking policies need as well.
+pub fn functions() -> Vec<ScalarUDF> {
+    vec![json_get_udf(), inet_contained_by_udf(), mask_hash_udf()]
+}
+
+/// The predicate rows of a table with `columns` must satisfy to be seen by
+/// `request` under the row `policies` of the table, None if it has none.
+///
+/// Like the policies of http handlers, rows must satisfy any of the
+/// permissive policies and all of the restrictive ones. Request fields are
+/// replaced by their values, so the predicate only refers to the columns,
+/// which fields of the same name give way to. Those fields can still be
+/// referred to as `request.<field>`.
+pub fn row_filter<'a>(
+    policies: impl IntoIterator<Item = &'a AuthorizationPolicy>,
+    columns: &[String],
+    request: &AuthorizationRequest,
+) -> Option<SqlExpr> {
+    let (permissive, restrictive): (Vec<_>, Vec<_>) = policies
+        .into_iter()
+        .partition(|p| p.kind == PolicyKind::Permissive);
+    if permissive.is_empty() && restrictive.is_empty() {
+        return None;
+    }
+
+    let bind = |policy: &AuthorizationPolicy| {
+        SqlExpr::Nested(Box::new(bind_request(&policy.expr, columns, request)))
+    };
+    let combine = |left: SqlExpr, op: BinaryOperator, right: SqlExpr| SqlExpr::BinaryOp {
+        left: Box::new(left),
+        op,
+        right: Box::new(right),
+    };
+
+    let permissive = permissive
+        .into_iter()
+        .map(bind)
+        .reduce(|a, b| combine(a, BinaryOperator::Or, b))
+        .unwrap_or(SqlExpr::Value(SqlValue::Boolean(false)));
+
+    Some(
+        restrictive
+            .into_iter()
+            .map(bind)
+            .fold(SqlExpr::Nested(Box::new(permissive)), |a, b| {
+                combine(a, BinaryOperator::And, b)
+            }),
+    )
+}
+
+/// The value of the column of masking `policy` of type `data_type` that
+/// `request` sees, among the `columns` of its table.
+pub fn masked_column(
+    policy: &MaskingPolicy,
+    data_type: &DataType,
+    columns: &[String],
+    request: &AuthorizationRequest,
+) -> SqlExpr {
+    let column = SqlExpr::Identifier(Ident::with_quote('"', &policy.column));
+    let as_text = || SqlExpr::Cast {
+        expr: Box::new(column.clone()),
+        data_type: sqlparser::ast::DataType::Text,
+    };
+    let call = |name: &str, args: Vec<SqlExpr>| {
+        SqlExpr::Function(Function {
+            name: ObjectName(vec![Ident::new(name)]),
+            args: args
+                .into_iter()
+                .map(|a| FunctionArg::Unnamed(FunctionArgExpr::Expr(a)))
+                .collect(),
+            over: None,
+            distinct: false,
+            special: false,
+        })
+    };
+
+    let masked = match &policy.mask {
+        Mask::Hash => call("mask_hash", vec![as_text()]),
+        Mask::Redact if data_type == &DataType::Utf8 => SqlExpr::Case {
+            operand: None,
+            conditions: vec![SqlExpr::IsNull(Box::new(column.clone()))],
+            results: vec![SqlExpr::Value(SqlValue::Null)],
+            else_result: Some(Box::new(SqlExpr::Value(SqlValue::SingleQuotedString(
+                "****".to_string(),
+            )))),
+        },
+        // A NULL of the type of the column.
+        Mask::Redact => call("nullif", vec![column.clone(), column.clone()]),
+        Mask::Truncate(length) => call(
+            "left",
+            vec![
+                as_text(),
+                SqlExpr::Value(SqlValue::Number(length.to_string(), false)),
+            ],
+        ),
+        Mask::Expr(expr) => SqlExpr::Nested(Box::new(bind_request(expr, columns, request))),
+    };
+
+    match &policy.unless {
+        // NULL masks the column just like FALSE.
+        Some(unless) => SqlExpr::Case {
+            operand: None,
+            conditions: vec![bind_request(unless, columns, request)],
+            results: vec![column],
+            else_result: Some(Box::new(masked)),
+        },
+        None => masked,
+    }
+}
+
+/// Replace the request fields in `expr` that aren't `columns` with their
+/// values for `request`.
+fn bind_request(expr: &SqlExpr, columns: &[String], request: &AuthorizationRequest) -> SqlExpr {
+    let literal = |value: Option<String>| {
+        SqlExpr::Value(value.map_or(SqlValue::Null, SqlValue::SingleQuotedString))
+    };
+    let normalize = |ident: &Ident| match ident.quote_style {
+        Some(_) => ident.value.clone(),
+        None => ident.value.to_lowercase(),
+    };
+
+    let mut expr = expr.clone();
+    let _ = visit_expressions_mut(&mut expr, |e| {
+        let value = match e {
+            SqlExpr::Identifier(ident) if !columns.contains(&normalize(ident)) => {
+                request.field(&normalize(ident)).map(literal)
+            }
+            SqlExpr::CompoundIdentifier(ids) if ids.len() == 2 => {
+                match ids[0].value.to_lowercase().as_str() {
+                    "request" => request.field(&normalize(&ids[1])).map(literal),
+                    "claims" => Some(literal(request.claim(&ids[1].value))),
+                    "headers" => Some(literal(
+                        request.headers.get(&ids[1].value.to_lowercase()).cloned(),
+                    )),
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+        if let Some(value) = value {
+            *e = value;
+        }
+        ControlFlow::<()>::Continue(())
+    });
+
+    rewrite_expr(&expr)
+}
+
+/// Rewrite what authorization expressions allow beyond what DataFusion
+/// plans to calls of the functions of [`AuthorizationExprContext`].
+fn rewrite_expr(expr: &SqlExpr) -> SqlExpr {
+    let call = |name: &str, args: Vec<SqlExpr>| {
+        SqlExpr::Function(Function {
+            name: ObjectName(vec![Ident::new(name)]),
+            args: args
+                .into_iter()
+                .map(|a| FunctionArg::Unnamed(FunctionArgExpr::Expr(a)))
+                .collect(),
+            over: None,
+            distinct: false,
+            special: false,
+        })
+    };
+
+    let mut expr = expr.clone();
+    let _ = visit_expressions_mut(&mut expr, |e| {
+        match e {
+            SqlExpr::CompoundIdentifier(ids) if ids.len() == 2 => {
+                let object = ids[0].value.to_lowercase();
+                let key = match object.as_str() {
+                    "claims" => ids[1].value.clone(),
+                    "headers" => ids[1].value.to_lowercase(),
+                    _ => return ControlFlow::<()>::Continue(()),
+                };
+                *e = call(
+                    "json_get",
+                    vec![
+                        SqlExpr::Identifier(Ident::new(object)),
+                        SqlExpr::Value(SqlValue::SingleQuotedString(key)),
+                    ],
+                );
+            }
+            SqlExpr::BinaryOp {
+                left,
+                op: BinaryOperator::PGBitwiseShiftLeft,
+                right,
+            } => {
+                let args = vec![left.as_ref().clone(), right.as_ref().clone()];
+                *e = call("inet_contained_by", args);
+            }
+            _ => {}
+        }
+        ControlFlow::Continue(())
+    });
+
+    expr
+}
+
+/// `json_get(object, key)`: the value of `key` in the JSON `object`, strings
+/// as they are and other values as JSON.
+fn json_get_udf() -> ScalarUDF {
+    let fun = make_scalar_function(|args: &[ArrayRef]| {
+        let objects = as_string_array(&args[0])?;
+        let keys = as_string_array(&args[1])?;
+        let values = objects
+            .iter()
+            .zip(keys)
+            .map(|(object, key)| {
+                let mut object = serde_json::from_str::<Map<String, Value>>(object?).ok()?;
+                json_text(object.remove(key?)?)
+            })
+            .collect::<StringArray>();
+
+        Ok(Arc::new(values) as ArrayRef)
+    });
+
+    create_udf(
+        "json_get",
+        vec![DataType::Utf8, DataType::Utf8],
+        Arc::new(DataType::Utf8),
+        Volatility::Immutable,
+        fun,
+    )
+}
+
+/// `inet_contained_by(address, network)`: whether `address` is within
+/// `network`, given as `<address>/<prefix length>`, or is that address.
+fn inet_contained_by_udf() -> ScalarUDF {
+    let fun = make_scalar_function(|args: &[ArrayRef]| {
+        let addresses = as_string_array(&args[0])?;
+        let networks = as_string_array(&args[1])?;
+        let contained = addresses
+            .iter()
+            .zip(networks)
+            .map(|(address, network)| inet_contained_by(address?, network?))
+            .collect::<BooleanArray>();
+
+        Ok(Arc::new(contained) as ArrayRef)
+    });
+
+    create_udf(
+        "inet_contained_by",
+        vec![DataType::Utf8, DataType::Utf8],
+        Arc::new(DataType::Boolean),
+        Volatility::Immutable,
+        fun,
+    )
+}
+
+/// `mask_hash(value)`: the hex SHA-256 digest of `value`.
+fn mask_hash_udf() -> ScalarUDF {
+    let fun = make_scalar_function(|args: &[ArrayRef]| {
+        let values = as_string_array(&args[0])?;
+        let digests = values
+            .iter()
+            .map(|value| value.map(|v| hex::encode(Sha256::digest(v))))
+            .collect::<StringArray>();
+
+        Ok(Arc::new(digests) as ArrayRef)
+    });
+
+    create_udf(
+        "mask_hash",
+        vec![DataType::Utf8],
+        Arc::new(DataType::Utf8),
+        Volatility::Immutable,
+        fun,
+    )
+}
+
+/// Strings as they are, other values as JSON.
+fn json_text(value: Value) -> Option<String> {
+    match value {
+        Value::Null => None,
+        Value::String(v) => Some(v),
+        v => Some(v.to_string()),
+    }
+}
+
+fn as_string_array(array: &ArrayRef) -> DFResult<&StringArray> {
+    array
+        .as_any()
+        .downcast_ref::<StringArray>()
+        .ok_or_else(|| DataFusionError::Internal("expected a string array".to_string()))
+}
+
+/// None if either isn't valid.
+fn inet_contained_by(address: &str, network: &str) -> Option<bool> {
+    let address = address.parse::<IpAddr>().ok()?;
+    let (network, prefix_len) = match network.split_once('/') {
+        Some((network, prefix_len)) => (network.parse().ok()?, Some(prefix_len.parse().ok()?)),
+        None => (network.parse().ok()?, None),
+    };
+    // IPv4 clients of a server listening on IPv6 have mapped addresses.
+    let address = match address {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(address, IpAddr::V4),
+        v4 => v4,
+    };
+
+    match (address, network) {
+        (IpAddr::V4(address), IpAddr::V4(network)) => {
+            let prefix_len: u32 = prefix_len.unwrap_or(32);
+            let mask = u32::MAX.checked_shl(32u32.checked_sub(prefix_len)?);
+            let mask = mask.unwrap_or(0);
+            Some(u32::from(address) & mask == u32::from(network) & mask)
+        }
+        (IpAddr::V6(address), IpAddr::V6(network)) => {
+            let prefix_len: u32 = prefix_len.unwrap_or(128);
+            let mask = u128::MAX.checked_shl(128u32.checked_sub(prefix_len)?);
+            let mask = mask.unwrap_or(0);
+            Some(u128::from(address) & mask == u128::from(network) & mask)
+        }
+        _ => Some(false),
+    }
+}
+
+/// What a policy made of a request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PolicyOutcome {
+    pub policy: String,
+    pub kind: PolicyKind,
+    pub allowed: bool,
+    /// Why the policy failed to evaluate, denying the request.
+    pub error: Option<String>,
+}
+
+/// Whether a request is allowed by the policies that apply to it, and why.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Decision {
+    pub allowed: bool,
+    /// The permissive policies that allowed the request, the restrictive
+    /// policies that denied it, or, if no permissive policy allowed it, the
+    /// permissive policies.
+    pub deciding_policies: Vec<String>,
+    /// Every policy in the order given.
+    pub outcomes: Vec<PolicyOutcome>,
+}
+
+impl Decision {
+    /// E.g. `denied by policy internal_only`.
+    pub fn reason(&self) -> String {
+        let decision = match self.allowed {
+            true => "allowed",
+            false => "denied",
+        };
+        match &self.deciding_policies[..] {
+            [] => format!("{decision}, no permissive policy applies"),
+            [policy] => format!("{decision} by policy {policy}"),
+            policies => format!("{decision} by policies {}", policies.join(", ")),
+        }
+    }
+}
+
+/// The reason followed by the outcome of every policy, e.g.
+/// `allowed by policy a (a: permissive, allow; b: restrictive, allow)`.
+impl fmt::Display for Decision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let outcomes = self
+            .outcomes
+            .iter()
+            .map(|o| {
+                let outcome = if o.allowed { "allow" } else { "deny" };
+                match &o.error {
+                    Some(error) => format!("{}: {}, {} ({error})", o.policy, o.kind, outcome),
+                    None => format!("{}: {}, {}", o.policy, o.kind, outcome),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        write!(f, "{} ({})", self.reason(), outcomes.join("; "))
+    }
+}
+
+/// Evaluates authorization policies, compiled once by [`AuthEval::new`].
 #[derive(Debug, Default)]
-pub struct AuthEval {}
+pub struct AuthEval {
+    context: AuthorizationExprContext,
+    /// By namespace and name, the error if the policy didn't compile.
+    compiled: HashMap<(String, String), std::result::Result<Arc<dyn PhysicalExpr>, String>>,
+}
 
 impl AuthEval {
-    pub fn eval_policy(&self, policy: &AuthorizationPolicy) -> bool {
-        let auth_context = AuthorizationExprContext::default();
-        let mut planner_context = PlannerContext::default();
-        let sql_to_rel = SqlToRel::new(&auth_context);
-        let placeholder_a = Field::new("placeholder_a", DataType::Boolean, true);
-        let schema = Arc::new(Schema::new(vec![placeholder_a]));
-        let df_schema = DFSchema::try_from((*schema).clone()).unwrap();
-        let placeholder_batch = RecordBatch::try_new(
-            schema.clone(),
-            vec![Arc::new(BooleanArray::from(vec![None]))],
-        )
-        .unwrap();
-
-        let rel_expr = sql_to_rel
-            .sql_to_expr(
-                policy.permissive_expr.clone(),
-                &df_schema,
-                &mut planner_context,
-            )
-            .unwrap();
-        let phys_expr =
-            create_physical_expr(&rel_expr, &df_schema, &schema, &ExecutionProps::default())
-                .unwrap();
-        let eval = phys_expr.evaluate(&placeholder_batch).unwrap();
-
-        match eval {
-            ColumnarValue::Scalar(ScalarValue::Boolean(Some(true))) => true,
-            ColumnarValue::Array(array_ref) => {
-                if let Some(array_ref) = array_ref.as_any().downcast_ref::<BooleanArray>() {
-                    array_ref.value(0)
-                } else {
-                    false
+    /// Compile the policies of http handlers and tables in `catalog`.
+    /// Policies that don't compile deny every request.
+    pub fn new(catalog: &Catalog) -> Self {
+        let mut auth_eval = AuthEval::default();
+
+        for ns in catalog.namespaces.values() {
+            for policy in ns.authorization_policies.values() {
+                if policy.table.is_some() {
+                    continue;
                 }
+                let compiled = auth_eval.compile_expr(&policy.expr);
+                auth_eval
+                    .compiled
+                    .insert((ns.name.clone(), policy.name.clone()), compiled);
             }
-            _ => false,
+        }
+
+        auth_eval
+    }
+
+    /// Plan the expression of `policy`, which must be boolean.
+    pub fn compile(&self, policy: &AuthorizationPolicy) -> Result<Arc<dyn PhysicalExpr>, Error> {
+        self.compile_expr(&policy.expr)
+            .map_err(|reason| Error::InvalidPolicy(policy.name.clone(), reason))
+    }
+
+    /// Plan the expression of row `policy` against the columns of `table`.
+    /// It must be boolean.
+    pub fn check_row_policy(
+        &self,
+        policy: &AuthorizationPolicy,
+        table: &Table,
+    ) -> Result<(), Error> {
+        self.check_table_expr(&policy.expr, table, true)
+            .map_err(|reason| Error::InvalidPolicy(policy.name.clone(), reason))
+    }
+
+    /// Plan the expressions of masking `policy` against the columns of
+    /// `table`. Its UNLESS expression must be boolean.
+    pub fn check_masking_policy(&self, policy: &MaskingPolicy, table: &Table) -> Result<(), Error> {
+        let invalid = |reason| Error::InvalidMaskingPolicy(policy.name.clone(), reason);
+
+        if let Mask::Expr(expr) = &policy.mask {
+            self.check_table_expr(expr, table, false).map_err(invalid)?;
+        }
+        if let Some(unless) = &policy.unless {
+            self.check_table_expr(unless, table, true)
+                .map_err(invalid)?;
+        }
+
+        Ok(())
+    }
+
+    /// Plan `expr` of a row or masking policy like a SQL session does,
+    /// against the columns of `table`, and check that it is boolean if
+    /// `boolean`.
+    fn check_table_expr(
+        &self,
+        expr: &SqlExpr,
+        table: &Table,
+        boolean: bool,
+    ) -> std::result::Result<(), String> {
+        let fields = table
+            .columns
+            .iter()
+            .map(|c| Ok(Field::new(&c.name, c.arrow_type()?, true)))
+            .collect::<Result<Vec<_>, Error>>()
+            .map_err(|e| e.to_string())?;
+        let df_schema =
+            Arc::new(DFSchema::try_from(Schema::new(fields)).map_err(|e| e.to_string())?);
+
+        // Request fields are replaced by their values, which any request
+        // without NULL fields stands for.
+        let request = AuthorizationRequest {
+            principal: Some(String::new()),
+            claims: Some(Map::new()),
+            client_ip: Some(IpAddr::from([0, 0, 0, 0])),
+            ..Default::default()
+        };
+        let columns = table
+            .columns
+            .iter()
+            .map(|c| c.name.clone())
+            .collect::<Vec<_>>();
+        let expr = bind_request(expr, &columns, &request);
+
+        let plan = || {
+            let rel_expr = SqlToRel::new(&self.context).sql_to_expr(
+                expr.clone(),
+                &df_schema,
+                &mut PlannerContext::default(),
+            )?;
+            let props = ExecutionProps::default();
+            ExprSimplifier::new(SimplifyContext::new(&props))
+                .coerce(rel_expr, df_schema.clone())?
+                .get_type(&df_schema)
+        };
+
+        match plan() {
+            Ok(DataType::Boolean) => Ok(()),
+            Ok(_) if !boolean => Ok(()),
+            Ok(data_type) => Err(format!("{expr} is {data_type}, not boolean")),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    fn compile_expr(&self, expr: &SqlExpr) -> std::result::Result<Arc<dyn PhysicalExpr>, String> {
+        let physical_expr = self
+            .context
+            .create_physical_expr(expr)
+            .map_err(|e| e.to_string())?;
+
+        match physical_expr.data_type(&self.context.schema()) {
+            Ok(DataType::Boolean) => Ok(physical_expr),
+            Ok(data_type) => Err(format!("{expr} is {data_type}, not boolean")),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    /// Decide on `request` by `policies`, like Postgres row security
+    /// policies: it must be allowed by any of the permissive policies and by
+    /// all of the restrictive ones, so without a permissive policy it is
+    /// denied. Policies that fail to evaluate deny it.
+    pub fn authorize<'a>(
+        &self,
+        policies: impl IntoIterator<Item = &'a AuthorizationPolicy>,
+        request: &AuthorizationRequest,
+    ) -> Decision {
+        let outcomes = policies
+            .into_iter()
+            .map(|policy| {
+                let allowed = self.eval_policy(policy, request);
+                PolicyOutcome {
+                    policy: policy.name.clone(),
+                    kind: policy.kind,
+                    allowed: allowed.as_ref().is_ok_and(|allowed| *allowed),
+                    error: allowed.err().map(|e| e.to_string()),
+                }
+            })
+            .collect::<Vec<_>>();
+        let policies = |kind: PolicyKind, allowed: bool| {
+            outcomes
+                .iter()
+                .filter(|o| o.kind == kind && o.allowed == allowed)
+                .map(|o| o.policy.clone())
+                .collect::<Vec<_>>()
+        };
+
+        let allowed_by = policies(PolicyKind::Permissive, true);
+        let denied_by = policies(PolicyKind::Restrictive, false);
+        let (allowed, deciding_policies) = match (allowed_by.is_empty(), denied_by.is_empty()) {
+            (_, false) => (false, denied_by),
+            (true, true) => (false, policies(PolicyKind::Permissive, false)),
+            (false, true) => (true, allowed_by),
+        };
+
+        Decision {
+            allowed,
+            deciding_policies,
+            outcomes,
+        }
+    }
+
+    /// Whether `policy` allows `request`, that is its expression evaluates
+    /// to TRUE rather than FALSE or NULL. Policies not compiled by
+    /// [`AuthEval::new`] are compiled first.
+    pub fn eval_policy(
+        &self,
+        policy: &AuthorizationPolicy,
+        request: &AuthorizationRequest,
+    ) -> Result<bool, Error> {
+        let key = (policy.namespace.clone(), policy.name.clone());
+        let expr = match self.compiled.get(&key) {
+            Some(Ok(expr)) => expr.clone(),
+            Some(Err(e)) => return Err(Error::InvalidPolicy(policy.name.clone(), e.clone())),
+            None => self.compile(policy)?,
+        };
+        let failed = |reason: String| Error::PolicyEvaluation(policy.name.clone(), reason);
+
+        let batch = request
+            .to_batch(self.context.schema())
+            .map_err(|e| failed(e.to_string()))?;
+        match expr.evaluate(&batch).map_err(|e| failed(e.to_string()))? {
+            ColumnarValue::Scalar(ScalarValue::Boolean(value)) => Ok(value == Some(true)),
+            ColumnarValue::Array(array) => match array.as_any().downcast_ref::<BooleanArray>() {
+                Some(array) if array.len() == 1 => Ok(array.is_valid(0) && array.value(0)),
+                _ => Err(failed(format!("evaluated to {array:?}"))),
+            },
+            ColumnarValue::Scalar(value) => Err(failed(format!("evaluated to {value}"))),
         }
     }
 }
@@ -100,23 +804,336 @@ mod tests {
         use super::AuthEval;
 
         let auth_eval = AuthEval::default();
+        let request = AuthorizationRequest::default();
+
+        let eval = |expr| auth_eval.eval_policy(&policy_for_expr(expr), &request);
 
         // Test always true policy.
-        assert!(auth_eval.eval_policy(&policy_for_expr("true")));
+        assert!(eval("true").unwrap());
 
         // Test always false policy.
-        assert!(!auth_eval.eval_policy(&policy_for_expr("false")));
+        assert!(!eval("false").unwrap());
 
         // Test a non-scalar policy.
-        assert!(auth_eval.eval_policy(&policy_for_expr("1 = 1")));
+        assert!(eval("1 = 1").unwrap());
+
+        // Test a policy that needs coercion.
+        assert!(eval("'1' = 1").unwrap());
+
+        // Test a NULL policy.
+        assert!(!eval("principal = 'bob'").unwrap());
+
+        // Test a policy that isn't boolean.
+        assert_eq!(
+            eval("1 + 1").unwrap_err().to_string(),
+            "invalid authorization policy policy name: 1 + 1 is Int64, not boolean"
+        );
+
+        // Test a policy that doesn't plan.
+        assert!(matches!(
+            eval("no_such_column"),
+            Err(Error::InvalidPolicy(..))
+        ));
+    }
+
+    #[test]
+    fn test_auth_eval_request() {
+        let auth_eval = AuthEval::default();
+        let mut request = AuthorizationRequest {
+            principal: Some("bob".to_string()),
+            auth_method: AuthMethod::Jwt,
+            claims: Some(serde_json::from_str(r#"{"role": "ingest", "level": 3}"#).unwrap()),
+            client_ip: Some("10.1.2.3".parse().unwrap()),
+            headers: BTreeMap::from([("x-tenant".to_string(), "acme".to_string())]),
+            namespace: "shop".to_string(),
+            handler: "ingest".to_string(),
+        };
+        let allows = |expr, request: &AuthorizationRequest| {
+            auth_eval
+                .eval_policy(&policy_for_expr(expr), request)
+                .unwrap()
+        };
+
+        let ingest = "claims.role = 'ingest' AND client_ip << '10.0.0.0/8'";
+        assert!(allows(ingest, &request));
+        assert!(allows(
+            "principal = 'bob' AND auth_method = 'jwt'",
+            &request
+        ));
+        assert!(allows("claims.level = '3'", &request));
+        assert!(allows(r#"headers."X-Tenant" = 'acme'"#, &request));
+        assert!(allows(
+            "namespace = 'shop' AND handler = 'ingest'",
+            &request
+        ));
+        // Missing claims are NULL.
+        assert!(!allows("claims.scope = 'admin'", &request));
+        assert!(allows("claims.scope IS NULL", &request));
+
+        request.client_ip = Some("192.168.0.1".parse().unwrap());
+        assert!(!allows(ingest, &request));
+        request.client_ip = Some("::ffff:10.0.0.1".parse().unwrap());
+        assert!(allows(ingest, &request));
+        request.client_ip = Some("2001:db8::1".parse().unwrap());
+        assert!(allows("client_ip << '2001:db8::/32'", &request));
+        assert!(!allows(ingest, &request));
+
+        request.claims = None;
+        assert!(!allows(ingest, &request));
+    }
+
+    #[test]
+    fn test_authorize() {
+        let auth_eval = AuthEval::default();
+        let request = AuthorizationRequest {
+            principal: Some("bob".to_string()),
+            ..Default::default()
+        };
+        let restrictive = |name: &str, expr| AuthorizationPolicy {
+            name: name.to_string(),
+            kind: PolicyKind::Restrictive,
+            ..policy_for_expr(expr)
+        };
+        let permissive = |name: &str, expr| AuthorizationPolicy {
+            name: name.to_string(),
+            ..policy_for_expr(expr)
+        };
+
+        let decision = auth_eval.authorize(
+            &[
+                permissive("admins", "principal = 'alice'"),
+                permissive("users", "principal IS NOT NULL"),
+                restrictive("not_blocked", "principal <> 'mallory'"),
+            ],
+            &request,
+        );
+        assert!(decision.allowed);
+        assert_eq!(decision.reason(), "allowed by policy users");
+        assert_eq!(
+            decision.to_string(),
+            "allowed by policy users (admins: permissive, deny; users: permissive, allow; \
+             not_blocked: restrictive, allow)"
+        );
+
+        let decision = auth_eval.authorize(
+            &[
+                permissive("users", "principal IS NOT NULL"),
+                restrictive("alice_only", "principal = 'alice'"),
+            ],
+            &request,
+        );
+        assert!(!decision.allowed);
+        assert_eq!(decision.reason(), "denied by policy alice_only");
+
+        let decision = auth_eval.authorize(
+            &[
+                permissive("admins", "principal = 'alice'"),
+                permissive("anonymous", "principal IS NULL"),
+            ],
+            &request,
+        );
+        assert!(!decision.allowed);
+        assert_eq!(decision.reason(), "denied by policies admins, anonymous");
+
+        let decision = auth_eval.authorize(&[restrictive("users", "true")], &request);
+        assert!(!decision.allowed);
+        assert_eq!(decision.reason(), "denied, no permissive policy applies");
+
+        // Policies that fail deny, with the reason.
+        let decision = auth_eval.authorize(
+            &[
+                permissive("users", "principal IS NOT NULL"),
+                restrictive("broken", "CAST(principal AS INT) = 1"),
+            ],
+            &request,
+        );
+        assert!(!decision.allowed);
+        assert_eq!(decision.reason(), "denied by policy broken");
+        assert!(decision.outcomes[1]
+            .error
+            .as_ref()
+            .unwrap()
+            .starts_with("evaluating authorization policy broken failed"));
+    }
+
+    #[test]
+    fn test_auth_eval_new() {
+        let mut catalog = Catalog::default();
+        let mut ns = crate::Namespace {
+            name: "a namespace".to_string(),
+            ..Default::default()
+        };
+        for (name, expr) in [("users", "principal IS NOT NULL"), ("broken", "principal")] {
+            let policy = AuthorizationPolicy {
+                name: name.to_string(),
+                ..policy_for_expr(expr)
+            };
+            ns.authorization_policies.insert(name.to_string(), policy);
+        }
+        catalog.namespaces.insert(ns.name.clone(), ns.clone());
+
+        let auth_eval = AuthEval::new(&catalog);
+        let request = AuthorizationRequest {
+            principal: Some("bob".to_string()),
+            ..Default::default()
+        };
+        assert!(auth_eval
+            .eval_policy(&ns.authorization_policies["users"], &request)
+            .unwrap());
+        assert_eq!(
+            auth_eval
+                .eval_policy(&ns.authorization_policies["broken"], &request)
+                .unwrap_err()
+                .to_string(),
+            "invalid authorization policy broken: principal is Utf8, not boolean"
+        );
+
+        // Catalogs reject such policies in the first place.
+        let edit = crate::edit::Edit::ReplaceAuthorizationPolicy(
+            ns.authorization_policies["broken"].clone(),
+        );
+        assert!(catalog.apply(&edit).is_err());
+    }
+
+    #[test]
+    fn test_row_filter() {
+        let request = AuthorizationRequest {
+            principal: Some("bob".to_string()),
+            claims: Some(serde_json::from_str(r#"{"tenant": "acme"}"#).unwrap()),
+            client_ip: Some("10.1.2.3".parse().unwrap()),
+            ..Default::default()
+        };
+        let columns = [
+            "owner".to_string(),
+            "tenant".to_string(),
+            "handler".to_string(),
+        ];
+        let restrictive = AuthorizationPolicy {
+            kind: PolicyKind::Restrictive,
+            ..policy_for_expr("tenant = claims.tenant AND handler = request.handler")
+        };
+        let policies = [
+            policy_for_expr("owner = principal"),
+            policy_for_expr("client_ip << '10.0.0.0/8' AND claims.admin = 'true'"),
+            restrictive.clone(),
+        ];
+
+        assert_eq!(
+            row_filter(&policies, &columns, &request)
+                .unwrap()
+                .to_string(),
+            "((owner = 'bob') OR (inet_contained_by('10.1.2.3', '10.0.0.0/8') \
+             AND NULL = 'true')) AND (tenant = 'acme' AND handler = '')"
+        );
+        assert_eq!(
+            row_filter(&[restrictive], &columns, &request)
+                .unwrap()
+                .to_string(),
+            "(false) AND (tenant = 'acme' AND handler = '')"
+        );
+        assert_eq!(row_filter(&[], &columns, &request), None);
+    }
+
+    #[test]
+    fn test_masked_column() {
+        let request = AuthorizationRequest {
+            principal: Some("bob".to_string()),
+            claims: Some(serde_json::from_str(r#"{"role": "analyst"}"#).unwrap()),
+            ..Default::default()
+        };
+        let columns = ["email".to_string(), "owner".to_string()];
+        let policy = |mask, unless: Option<&str>| MaskingPolicy {
+            namespace: "a namespace".to_string(),
+            name: "policy name".to_string(),
+            table: "a table".to_string(),
+            column: "email".to_string(),
+            mask,
+            unless: unless.map(parse_expr),
+        };
+        let masked = |policy: &MaskingPolicy, data_type| {
+            masked_column(policy, &data_type, &columns, &request).to_string()
+        };
+
+        assert_eq!(
+            masked(&policy(Mask::Hash, None), DataType::Utf8),
+            r#"mask_hash(CAST("email" AS TEXT))"#
+        );
+        assert_eq!(
+            masked(&policy(Mask::Redact, None), DataType::Utf8),
+            r#"CASE WHEN "email" IS NULL THEN NULL ELSE '****' END"#
+        );
+        assert_eq!(
+            masked(&policy(Mask::Redact, None), DataType::Int64),
+            r#"nullif("email", "email")"#
+        );
+        assert_eq!(
+            masked(&policy(Mask::Truncate(3), None), DataType::Utf8),
+            r#"left(CAST("email" AS TEXT), 3)"#
+        );
+        assert_eq!(
+            masked(
+                &policy(
+                    Mask::Expr(parse_expr("CASE WHEN owner = principal THEN email END")),
+                    Some("claims.role = 'admin'")
+                ),
+                DataType::Utf8
+            ),
+            r#"CASE WHEN 'analyst' = 'admin' THEN "email" ELSE (CASE WHEN owner = 'bob' THEN email END) END"#
+        );
+    }
+
+    #[test]
+    fn test_check_table_exprs() {
+        let auth_eval = AuthEval::default();
+        let column = |name: &str, data_type| crate::Column {
+            uid: 0,
+            name: name.to_string(),
+            data_type,
+        };
+        let table = Table {
+            namespace: "a namespace".to_string(),
+            uuid: uuid::Uuid::nil(),
+            name: "a table".to_string(),
+            columns: vec![
+                column("owner", sqlparser::ast::DataType::Text),
+                column("amount", sqlparser::ast::DataType::Integer(None)),
+            ],
+            partition_by: vec![],
+            retention_hours: None,
+            policies: vec![],
+        };
+
+        let row_policy = |expr| auth_eval.check_row_policy(&policy_for_expr(expr), &table);
+        row_policy("owner = principal AND amount < 100").unwrap();
+        row_policy("claims.role = 'admin' OR client_ip << '10.0.0.0/8'").unwrap();
+        row_policy("1 + 1").unwrap_err();
+        row_policy("amount").unwrap_err();
+        row_policy("missing = principal").unwrap_err();
+        row_policy("owner + 1 = 2").unwrap_err();
 
-        // Test a bad policy.
-        assert!(!auth_eval.eval_policy(&policy_for_expr("1 + 1")));
+        let masking_policy = |mask: Option<&str>, unless: Option<&str>| {
+            let policy = MaskingPolicy {
+                namespace: "a namespace".to_string(),
+                name: "policy name".to_string(),
+                table: "a table".to_string(),
+                column: "owner".to_string(),
+                mask: mask.map_or(Mask::Redact, |mask| Mask::Expr(parse_expr(mask))),
+                unless: unless.map(parse_expr),
+            };
+            auth_eval.check_masking_policy(&policy, &table)
+        };
+        masking_policy(Some("CASE WHEN owner = principal THEN owner END"), None).unwrap();
+        masking_policy(Some("amount * 2"), Some("claims.role = 'admin'")).unwrap();
+        masking_policy(Some("missing"), None).unwrap_err();
+        masking_policy(None, Some("amount")).unwrap_err();
+        masking_policy(None, Some("1 + 1")).unwrap_err();
     }
 
     fn policy_for_expr(expr: &str) -> AuthorizationPolicy {
         AuthorizationPolicy {
-            permissive_expr: parse_expr(expr),
+            kind: PolicyKind::Permissive,
+            expr: parse_expr(expr),
+            table: None,
             namespace: "a namespace".to_string(),
             name: "policy name".to_string(),
         }
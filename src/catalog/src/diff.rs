@@ -112,7 +112,10 @@ impl Diff {
                 namespace: a.name.clone(),
                 name: handler.name.clone(),
                 body: handler.body.clone(),
-                policy: handler.policy.clone(),
+                policies: handler.policies.clone(),
+                method: handler.method,
+                batch_window_ms: handler.batch_window_ms,
+                input: handler.input.clone(),
             }));
         }
 
@@ -127,7 +130,10 @@ impl Diff {
                 namespace: b.name.clone(),
                 name: handler.name.clone(),
                 body: handler.body.clone(),
-                policy: handler.policy.clone(),
+                policies: handler.policies.clone(),
+                method: handler.method,
+                batch_window_ms: handler.batch_window_ms,
+                input: handler.input.clone(),
             }));
         }
 
@@ -177,17 +183,32 @@ impl Diff {
             edits.push(Edit::ReplaceAuthorizationPolicy(AuthorizationPolicy {
                 namespace: a.name.clone(),
                 name: authorization_policy_name.name.clone(),
-                permissive_expr: authorization_policy_name.permissive_expr.clone(),
+                kind: authorization_policy_name.kind,
+                expr: authorization_policy_name.expr.clone(),
+                table: authorization_policy_name.table.clone(),
             }));
         }
 
+        // Masking policies, only those that changed so the diff shows how
+        // the masking of columns changes.
+        for policy in a.masking_policies.values() {
+            if !b.masking_policies.contains_key(&policy.name) {
+                edits.push(Edit::DropMaskingPolicy(policy.clone()));
+            }
+        }
+        for policy in b.masking_policies.values() {
+            if a.masking_policies.get(&policy.name) != Some(policy) {
+                edits.push(Edit::ReplaceMaskingPolicy(policy.clone()));
+            }
+        }
+
         Ok(edits)
     }
 
     fn diff_table(&self, a: &Table, b: &Table) -> Result<Vec<Edit>, DiffError> {
         assert_eq!(a.uuid, b.uuid, "table uuids must match");
 
-        let stmts = vec![];
+        let mut stmts = vec![];
         let mut alter_ops = Vec::new();
         // let mut table_name = ObjectName(vec![identifier(&a.name)]);
 
@@ -205,6 +226,24 @@ impl Diff {
             // );
         }
 
+        if a.partition_by != b.partition_by {
+            return Err(DiffError::DiffError(format!(
+                "changing the partitioning of table {}.{} from ({}) to ({}) is not supported",
+                a.namespace,
+                a.name,
+                a.partition_by.join(", "),
+                b.partition_by.join(", ")
+            )));
+        }
+
+        if a.retention_hours != b.retention_hours {
+            stmts.push(Edit::SetTableRetention(b.clone()));
+        }
+
+        if a.policies != b.policies {
+            stmts.push(Edit::SetTablePolicies(b.clone()));
+        }
+
         let a_column_ids = a.columns.iter().map(|v| v.uid).collect::<HashSet<_>>();
         let b_column_ids = b.columns.iter().map(|v| v.uid).collect::<HashSet<_>>();
 
@@ -282,7 +321,59 @@ fn identifier<S: Into<String>>(s: S) -> Ident {
 
 #[cfg(test)]
 mod tests {
+    use crate::{Mask, MaskingPolicy};
+
+    use super::*;
 
     #[test]
     fn test_diff() {}
+
+    #[test]
+    fn test_diff_masking_policies() {
+        let policy = |name: &str, column: &str, mask| MaskingPolicy {
+            namespace: "ns".to_string(),
+            name: name.to_string(),
+            table: "t".to_string(),
+            column: column.to_string(),
+            mask,
+            unless: None,
+        };
+        let catalog = |policies: &[MaskingPolicy]| {
+            let ns = Namespace {
+                name: "ns".to_string(),
+                masking_policies: policies
+                    .iter()
+                    .map(|p| (p.name.clone(), p.clone()))
+                    .collect(),
+                ..Default::default()
+            };
+            Catalog {
+                namespaces: [(ns.name.clone(), ns)].into(),
+            }
+        };
+        let a = catalog(&[
+            policy("email", "email", Mask::Hash),
+            policy("ip", "ip", Mask::Redact),
+            policy("name", "name", Mask::Redact),
+        ]);
+        let b = catalog(&[
+            policy("email", "email", Mask::Truncate(3)),
+            policy("ip", "ip", Mask::Redact),
+        ]);
+
+        let mut edits = Diff {}
+            .diff(&a, &b)
+            .unwrap()
+            .iter()
+            .map(|e| e.to_string())
+            .collect::<Vec<_>>();
+        edits.sort();
+        assert_eq!(
+            edits,
+            [
+                "ALTER TABLE ns.t ALTER COLUMN email SET MASKING POLICY email USING TRUNCATE(3)",
+                "ALTER TABLE ns.t ALTER COLUMN name DROP MASKING POLICY name",
+            ]
+        );
+    }
 }
@@ -1,6 +1,6 @@
 use std::fmt::Display;
 
-use crate::{AuthenticationPolicy, AuthorizationPolicy, HttpHandler, Table};
+use crate::{AuthenticationPolicy, AuthorizationPolicy, HttpHandler, MaskingPolicy, Table};
 
 #[derive(Debug)]
 pub enum Edit {
@@ -8,6 +8,8 @@ pub enum Edit {
 
     CreateTable(Table),
     DropTable(Table),
+    SetTableRetention(Table),
+    SetTablePolicies(Table),
 
     ReplaceHttpHandler(HttpHandler),
     DropHttpHandler(HttpHandler),
@@ -17,6 +19,9 @@ pub enum Edit {
 
     ReplaceAuthorizationPolicy(AuthorizationPolicy),
     DropAuthorizationPolicy(AuthorizationPolicy),
+
+    ReplaceMaskingPolicy(MaskingPolicy),
+    DropMaskingPolicy(MaskingPolicy),
 }
 
 impl Display for Edit {
@@ -26,6 +31,25 @@ impl Display for Edit {
 
             Edit::CreateTable(table) => write!(f, "CREATE {:?}", table),
             Edit::DropTable(table) => write!(f, "DROP {:?}", table),
+            Edit::SetTableRetention(table) => match table.retention_hours {
+                Some(hours) => write!(
+                    f,
+                    "ALTER TABLE {}.{} SET RETENTION {} HOURS",
+                    table.namespace, table.name, hours
+                ),
+                None => write!(
+                    f,
+                    "ALTER TABLE {}.{} UNSET RETENTION",
+                    table.namespace, table.name
+                ),
+            },
+            Edit::SetTablePolicies(table) => write!(
+                f,
+                "ALTER TABLE {}.{} SET POLICY ({})",
+                table.namespace,
+                table.name,
+                table.policies.join(", ")
+            ),
 
             handler @ Edit::ReplaceHttpHandler { .. } => write!(f, "REPLACE {:?}", handler),
             handler @ Edit::DropHttpHandler { .. } => write!(f, "DROP {:?}", handler),
@@ -35,6 +59,23 @@ impl Display for Edit {
 
             policy @ Edit::ReplaceAuthorizationPolicy(_) => write!(f, "REPLACE {:?}", policy),
             policy @ Edit::DropAuthorizationPolicy(_) => write!(f, "DROP {:?}", policy),
+
+            Edit::ReplaceMaskingPolicy(policy) => {
+                write!(
+                    f,
+                    "ALTER TABLE {}.{} ALTER COLUMN {} SET MASKING POLICY {} USING {}",
+                    policy.namespace, policy.table, policy.column, policy.name, policy.mask
+                )?;
+                match &policy.unless {
+                    Some(unless) => write!(f, " UNLESS {unless}"),
+                    None => Ok(()),
+                }
+            }
+            Edit::DropMaskingPolicy(policy) => write!(
+                f,
+                "ALTER TABLE {}.{} ALTER COLUMN {} DROP MASKING POLICY {}",
+                policy.namespace, policy.table, policy.column, policy.name
+            ),
         }
     }
 }
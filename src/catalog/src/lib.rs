@@ -1,9 +1,10 @@
 use std::collections::HashMap;
 
 use edit::Edit;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use thiserror::Error;
 
+pub mod api_key;
 pub mod auth;
 pub mod diff;
 pub mod edit;
@@ -12,6 +13,14 @@ pub mod edit;
 pub enum Error {
     #[error("invalid edit: {0}")]
     InvalidEdit(String),
+    #[error("unsupported type: {0}")]
+    UnsupportedType(String),
+    #[error("invalid authorization policy {0}: {1}")]
+    InvalidPolicy(String, String),
+    #[error("invalid masking policy {0}: {1}")]
+    InvalidMaskingPolicy(String, String),
+    #[error("evaluating authorization policy {0} failed: {1}")]
+    PolicyEvaluation(String, String),
 }
 
 type Result<T> = std::result::Result<T, Error>;
@@ -40,6 +49,13 @@ impl Catalog {
                     .tables
                     .insert(table.name.clone(), table.clone());
             }
+            Edit::SetTableRetention(table) | Edit::SetTablePolicies(table) => {
+                self.namespaces
+                    .get_mut(table.namespace.as_str())
+                    .unwrap()
+                    .tables
+                    .insert(table.name.clone(), table.clone());
+            }
             Edit::DropTable(table) => {
                 self.namespaces
                     .get_mut(table.namespace.as_str())
@@ -76,6 +92,14 @@ impl Catalog {
                     .remove(&policy.name);
             }
             Edit::ReplaceAuthorizationPolicy(policy) => {
+                // Row policies are planned against their table instead.
+                match &policy.table {
+                    Some(table) => auth::AuthEval::default()
+                        .check_row_policy(policy, self.table(&policy.namespace, table)?)?,
+                    None => {
+                        auth::AuthEval::default().compile(policy)?;
+                    }
+                }
                 self.namespaces
                     .get_mut(policy.namespace.as_str())
                     .unwrap()
@@ -89,10 +113,33 @@ impl Catalog {
                     .authorization_policies
                     .remove(&policy.name);
             }
+            Edit::ReplaceMaskingPolicy(policy) => {
+                auth::AuthEval::default()
+                    .check_masking_policy(policy, self.table(&policy.namespace, &policy.table)?)?;
+                self.namespaces
+                    .get_mut(policy.namespace.as_str())
+                    .unwrap()
+                    .masking_policies
+                    .insert(policy.name.clone(), policy.clone());
+            }
+            Edit::DropMaskingPolicy(policy) => {
+                self.namespaces
+                    .get_mut(policy.namespace.as_str())
+                    .unwrap()
+                    .masking_policies
+                    .remove(&policy.name);
+            }
         }
 
         Ok(())
     }
+
+    fn table(&self, namespace: &str, name: &str) -> Result<&Table> {
+        self.namespaces
+            .get(namespace)
+            .and_then(|ns| ns.tables.get(name))
+            .ok_or_else(|| Error::InvalidEdit(format!("table {namespace}.{name} not found")))
+    }
 }
 
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
@@ -102,6 +149,8 @@ pub struct Namespace {
     pub http_handlers: HashMap<String, HttpHandler>,
     pub authentication_policies: HashMap<String, AuthenticationPolicy>,
     pub authorization_policies: HashMap<String, AuthorizationPolicy>,
+    #[serde(default)]
+    pub masking_policies: HashMap<String, MaskingPolicy>,
 }
 
 impl Namespace {
@@ -120,6 +169,14 @@ impl Namespace {
     fn get_authorization_policy_by_name(&self, name: &str) -> Option<&AuthorizationPolicy> {
         self.authorization_policies.get(name)
     }
+
+    /// Whether table `name` keeps the keys of an `api_key` authentication
+    /// policy.
+    pub fn is_keys_table(&self, name: &str) -> bool {
+        self.authentication_policies.values().any(|policy| {
+            matches!(&policy.typ, AuthenticationPolicyType::ApiKey(ApiKeySource::Table(table)) if table == name)
+        })
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -128,6 +185,17 @@ pub struct Table {
     pub uuid: uuid::Uuid,
     pub name: String,
     pub columns: Vec<Column>,
+    /// Names of the columns the table is partitioned by.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub partition_by: Vec<String>,
+    /// Minimum time removed data files are kept before VACUUM may delete
+    /// them, when declared.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retention_hours: Option<u64>,
+    /// Authorization policies requests to handlers using the table must be
+    /// allowed by, on top of those of the handler.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub policies: Vec<String>,
 }
 
 impl Table {
@@ -143,17 +211,153 @@ pub struct Column {
     pub data_type: sqlparser::ast::DataType,
 }
 
+impl Column {
+    /// The type of the values of the column in queries.
+    pub fn arrow_type(&self) -> Result<datafusion::arrow::datatypes::DataType> {
+        arrow_type(&self.data_type).ok_or_else(|| {
+            Error::UnsupportedType(format!("{} of column {}", self.data_type, self.name))
+        })
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HttpHandler {
     pub namespace: String,
     pub name: String,
     pub body: String,
-    pub policy: String,
+    /// Authorization policies requests must be allowed by.
+    #[serde(alias = "policy", deserialize_with = "one_or_many")]
+    pub policies: Vec<String>,
+    /// Handlers that aren't POST are queries, whose rows are returned.
+    #[serde(default)]
+    pub method: HttpMethod,
+    /// Rows inserted by the handler are buffered for up to this long and
+    /// written together with those of other requests, when declared.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub batch_window_ms: Option<u64>,
+    /// Columns the JSON body of a request is parsed into, when declared.
+    /// The body is available as is in any case.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub input: Option<Vec<InputColumn>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InputColumn {
+    pub name: String,
+    pub data_type: sqlparser::ast::DataType,
+    pub nullable: bool,
+}
+
+impl InputColumn {
+    /// The type values of the column are parsed from JSON into.
+    pub fn arrow_type(&self) -> Result<datafusion::arrow::datatypes::DataType> {
+        arrow_type(&self.data_type).ok_or_else(|| {
+            Error::UnsupportedType(format!("{} of input column {}", self.data_type, self.name))
+        })
+    }
+}
+
+/// The Arrow type of values of SQL type `data_type`, None if unsupported.
+fn arrow_type(
+    data_type: &sqlparser::ast::DataType,
+) -> Option<datafusion::arrow::datatypes::DataType> {
+    use datafusion::arrow::datatypes::{DataType as Arrow, TimeUnit};
+    use sqlparser::ast::DataType as Sql;
+
+    match data_type {
+        Sql::Boolean => Some(Arrow::Boolean),
+        Sql::SmallInt(_) => Some(Arrow::Int16),
+        Sql::Int(_) | Sql::Integer(_) => Some(Arrow::Int32),
+        Sql::BigInt(_) => Some(Arrow::Int64),
+        Sql::Real => Some(Arrow::Float32),
+        Sql::Float(_) | Sql::Double | Sql::DoublePrecision => Some(Arrow::Float64),
+        Sql::Text | Sql::String | Sql::Varchar(_) => Some(Arrow::Utf8),
+        Sql::Date => Some(Arrow::Date32),
+        Sql::Timestamp(None, _) => Some(Arrow::Timestamp(TimeUnit::Microsecond, None)),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HttpMethod {
+    #[default]
+    Post,
+    Get,
+}
+
+impl std::fmt::Display for HttpMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HttpMethod::Post => write!(f, "POST"),
+            HttpMethod::Get => write!(f, "GET"),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum AuthenticationPolicyType {
     Anonymous(),
+    ApiKey(ApiKeySource),
+    Jwt(JwtPolicy),
+}
+
+/// Where the keys of an `api_key` authentication policy are kept.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ApiKeySource {
+    /// A JSON file, by its path.
+    File(String),
+    /// A table of the namespace of the policy, by its name.
+    Table(String),
+}
+
+/// Tokens a `jwt` authentication policy accepts.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JwtPolicy {
+    /// The `iss` claim tokens must have.
+    pub issuer: String,
+    /// A value the `aud` claim of tokens must have.
+    pub audience: String,
+    pub algorithms: Vec<JwtAlgorithm>,
+    pub keys: JwtKeySource,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum JwtAlgorithm {
+    HS256,
+    RS256,
+    ES256,
+}
+
+impl std::fmt::Display for JwtAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JwtAlgorithm::HS256 => write!(f, "HS256"),
+            JwtAlgorithm::RS256 => write!(f, "RS256"),
+            JwtAlgorithm::ES256 => write!(f, "ES256"),
+        }
+    }
+}
+
+impl std::str::FromStr for JwtAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_uppercase().as_str() {
+            "HS256" => Ok(JwtAlgorithm::HS256),
+            "RS256" => Ok(JwtAlgorithm::RS256),
+            "ES256" => Ok(JwtAlgorithm::ES256),
+            _ => Err(format!("unsupported JWT algorithm: {s}")),
+        }
+    }
+}
+
+/// Where the keys tokens are signed with are read from, by path.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum JwtKeySource {
+    /// A JSON Web Key Set, with `oct`, `RSA` or P-256 `EC` keys.
+    JwksFile(String),
+    /// A PEM encoded RSA or P-256 public key.
+    PemFile(String),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -163,9 +367,94 @@ pub struct AuthenticationPolicy {
     pub typ: AuthenticationPolicyType,
 }
 
+/// Requests are allowed if any of the permissive policies that apply and all
+/// of the restrictive ones allow them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PolicyKind {
+    #[default]
+    Permissive,
+    Restrictive,
+}
+
+impl std::fmt::Display for PolicyKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PolicyKind::Permissive => write!(f, "permissive"),
+            PolicyKind::Restrictive => write!(f, "restrictive"),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AuthorizationPolicy {
     pub namespace: String,
     pub name: String,
-    pub permissive_expr: sqlparser::ast::Expr,
+    #[serde(default)]
+    pub kind: PolicyKind,
+    #[serde(alias = "permissive_expr")]
+    pub expr: sqlparser::ast::Expr,
+    /// The table whose rows the policy restricts, if it is a row policy.
+    /// Row policies apply to SQL sessions of a principal rather than being
+    /// listed by http handlers and tables.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub table: Option<String>,
+}
+
+/// How a masking policy hides the values of its column.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Mask {
+    /// The hex SHA-256 digest of the value as text, so equal values can
+    /// still be grouped and joined on.
+    Hash,
+    /// `****` for text, NULL for other types.
+    Redact,
+    /// The first characters of the value as text.
+    Truncate(u64),
+    /// An expression of the columns of the table and the request, like the
+    /// expressions of row policies.
+    Expr(sqlparser::ast::Expr),
+}
+
+impl std::fmt::Display for Mask {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Mask::Hash => write!(f, "HASH"),
+            Mask::Redact => write!(f, "REDACT"),
+            Mask::Truncate(length) => write!(f, "TRUNCATE({length})"),
+            Mask::Expr(expr) => write!(f, "({expr})"),
+        }
+    }
+}
+
+/// Values of a column are masked when read by SQL sessions of a principal,
+/// unless the request of the session satisfies `unless`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MaskingPolicy {
+    pub namespace: String,
+    pub name: String,
+    pub table: String,
+    pub column: String,
+    pub mask: Mask,
+    /// An authorization expression, e.g. `claims.role = 'admin'`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unless: Option<sqlparser::ast::Expr>,
+}
+
+/// Read a list also from a single value, as it was stored before lists were
+/// allowed.
+fn one_or_many<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    })
 }
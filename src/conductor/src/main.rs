@@ -1,12 +1,24 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{path::PathBuf, sync::Arc, time::Duration};
 
 use anyhow::{anyhow, bail, Result};
 use arrow_cast::pretty;
+use catalog::{
+    api_key::{self, ApiKey},
+    auth::{AuthEval, AuthorizationRequest, Decision},
+    ApiKeySource, AuthenticationPolicyType, Catalog, Namespace,
+};
 use clap::{Parser, Subcommand, ValueHint};
+use datafusion::{
+    arrow::record_batch::RecordBatch,
+    datasource::TableProvider,
+    physical_plan::{memory::MemoryStream, SendableRecordBatchStream},
+    prelude::SessionContext,
+};
+use ensemble::EnsembleTable;
 use ensemble_x::storage::ObjectStore;
 use object_store::{aws::AmazonS3Builder, gcp::GoogleCloudStorageBuilder, prefix::PrefixStore};
 use rustyline::{self, error::ReadlineError};
-use score::Score;
+use score::{PolicyTest, Score};
 use sql::SqlSession;
 use url::Url;
 
@@ -23,9 +35,15 @@ enum Command {
     Diff(Diff),
     Apply(Apply),
     Sql(Sql),
+    RestoreTable(RestoreTable),
+    Gc(Gc),
+    Maintain(Maintain),
+    Keys(Keys),
+    Policy(Policy),
 }
 
-/// Compile a score definition into a catalog representation.
+/// Compile a score definition into a catalog representation and run its
+/// policy tests.
 #[derive(Parser, Debug)]
 struct Compile {
     #[clap(name = "PATH", value_hint = ValueHint::FilePath)]
@@ -60,6 +78,16 @@ struct Apply {
     /// Can be s3://, gs:// or just a local path.
     #[clap(long)]
     x_path: Option<String>,
+
+    /// Path to the database of the sqlite ensemble, created if it doesn't
+    /// exist.
+    #[clap(long, value_hint = ValueHint::FilePath)]
+    sqlite_path: Option<PathBuf>,
+
+    /// How many hours dropped tables are kept in the trash before they can be
+    /// purged by `conductor gc`.
+    #[clap(long, default_value_t = 7 * 24)]
+    trash_retention_hours: u64,
 }
 
 /// Start a SQL session.
@@ -68,16 +96,244 @@ struct Sql {
     #[clap(long, value_enum, default_value_t = Ensemble::EnsembleX)]
     ensemble: Ensemble,
 
+    /// Path to the ensemble-x data.
+    /// Can be s3://, gs://, memory:// or just a local path.
+    /// A memory:// ensemble starts out empty and is gone when the session
+    /// ends, see --score.
+    #[clap(long)]
+    x_path: Option<String>,
+
+    /// Path to the database of the sqlite ensemble, created if it doesn't
+    /// exist.
+    #[clap(long, value_hint = ValueHint::FilePath)]
+    sqlite_path: Option<PathBuf>,
+
+    /// Apply and commit this score before starting the session, e.g. to try
+    /// it out on a memory:// ensemble.
+    #[clap(long, value_hint = ValueHint::FilePath)]
+    score: Option<PathBuf>,
+
+    /// Query as this principal, restricted by the row policies of the tables.
+    /// Without it or --claims the session sees all rows.
+    #[clap(long)]
+    principal: Option<String>,
+
+    /// The claims of the principal as a JSON object, for row policies that
+    /// depend on them.
+    #[clap(long)]
+    claims: Option<String>,
+}
+
+/// Restore a dropped table that is still retained in the trash.
+#[derive(Parser, Debug)]
+struct RestoreTable {
+    #[clap(name = "UUID")]
+    uuid: uuid::Uuid,
+
+    #[clap(long, value_enum, default_value_t = Ensemble::EnsembleX)]
+    ensemble: Ensemble,
+
     /// Path to the ensemble-x data.
     /// Can be s3://, gs:// or just a local path.
     #[clap(long)]
     x_path: Option<String>,
 }
 
+/// Purge dropped tables whose retention period has expired.
+#[derive(Parser, Debug)]
+struct Gc {
+    #[clap(long, value_enum, default_value_t = Ensemble::EnsembleX)]
+    ensemble: Ensemble,
+
+    /// Purge the expired tables. By default, they are only listed.
+    #[clap(long)]
+    commit: bool,
+
+    /// Path to the ensemble-x data.
+    /// Can be s3://, gs:// or just a local path.
+    #[clap(long)]
+    x_path: Option<String>,
+}
+
+/// Compact and clean up the files of tables.
+#[derive(Parser, Debug)]
+struct Maintain {
+    #[command(subcommand)]
+    command: MaintainCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum MaintainCommand {
+    Optimize(Optimize),
+    Vacuum(Vacuum),
+}
+
+/// Rewrite the small files of tables into larger ones.
+#[derive(Parser, Debug)]
+struct Optimize {
+    /// Tables to optimize, as namespace.table. All tables if none are given.
+    #[clap(name = "TABLE")]
+    tables: Vec<String>,
+
+    #[clap(long, value_enum, default_value_t = Ensemble::EnsembleX)]
+    ensemble: Ensemble,
+
+    /// Path to the ensemble-x data.
+    /// Can be s3://, gs:// or just a local path.
+    #[clap(long)]
+    x_path: Option<String>,
+
+    /// Path to the database of the sqlite ensemble, created if it doesn't
+    /// exist.
+    #[clap(long, value_hint = ValueHint::FilePath)]
+    sqlite_path: Option<PathBuf>,
+}
+
+/// Delete files that are no longer part of tables.
+#[derive(Parser, Debug)]
+struct Vacuum {
+    /// Tables to vacuum, as namespace.table. All tables if none are given.
+    #[clap(name = "TABLE")]
+    tables: Vec<String>,
+
+    /// Keep removed files for this many hours. Defaults to the retention
+    /// declared for each table, and can't be shorter than it.
+    #[clap(long)]
+    retain_hours: Option<u64>,
+
+    /// Only list the files that would be deleted.
+    #[clap(long)]
+    dry_run: bool,
+
+    #[clap(long, value_enum, default_value_t = Ensemble::EnsembleX)]
+    ensemble: Ensemble,
+
+    /// Path to the ensemble-x data.
+    /// Can be s3://, gs:// or just a local path.
+    #[clap(long)]
+    x_path: Option<String>,
+
+    /// Path to the database of the sqlite ensemble, created if it doesn't
+    /// exist.
+    #[clap(long, value_hint = ValueHint::FilePath)]
+    sqlite_path: Option<PathBuf>,
+}
+
+/// Manage the keys of api_key authentication policies.
+#[derive(Parser, Debug)]
+struct Keys {
+    #[command(subcommand)]
+    command: KeysCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum KeysCommand {
+    Create(CreateKey),
+    Revoke(RevokeKey),
+    List(ListKeys),
+}
+
+/// Create a key and print it. It can't be shown again.
+#[derive(Parser, Debug)]
+struct CreateKey {
+    /// The policy to create the key for, as namespace.policy.
+    #[clap(name = "POLICY")]
+    policy: String,
+
+    /// Who requests with the key are made by.
+    #[clap(name = "PRINCIPAL")]
+    principal: String,
+
+    #[clap(long, value_enum, default_value_t = Ensemble::EnsembleX)]
+    ensemble: Ensemble,
+
+    /// Path to the ensemble-x data.
+    /// Can be s3://, gs:// or just a local path.
+    #[clap(long)]
+    x_path: Option<String>,
+
+    /// Path to the database of the sqlite ensemble, created if it doesn't
+    /// exist.
+    #[clap(long, value_hint = ValueHint::FilePath)]
+    sqlite_path: Option<PathBuf>,
+}
+
+/// Revoke a key, so that requests with it are rejected.
+#[derive(Parser, Debug)]
+struct RevokeKey {
+    /// The policy of the key, as namespace.policy.
+    #[clap(name = "POLICY")]
+    policy: String,
+
+    #[clap(name = "KEY_ID")]
+    key_id: String,
+
+    #[clap(long, value_enum, default_value_t = Ensemble::EnsembleX)]
+    ensemble: Ensemble,
+
+    /// Path to the ensemble-x data.
+    /// Can be s3://, gs:// or just a local path.
+    #[clap(long)]
+    x_path: Option<String>,
+
+    /// Path to the database of the sqlite ensemble, created if it doesn't
+    /// exist.
+    #[clap(long, value_hint = ValueHint::FilePath)]
+    sqlite_path: Option<PathBuf>,
+}
+
+/// List the keys of a policy, without their secrets.
+#[derive(Parser, Debug)]
+struct ListKeys {
+    /// The policy to list the keys of, as namespace.policy.
+    #[clap(name = "POLICY")]
+    policy: String,
+
+    #[clap(long, value_enum, default_value_t = Ensemble::EnsembleX)]
+    ensemble: Ensemble,
+
+    /// Path to the ensemble-x data.
+    /// Can be s3://, gs:// or just a local path.
+    #[clap(long)]
+    x_path: Option<String>,
+
+    /// Path to the database of the sqlite ensemble, created if it doesn't
+    /// exist.
+    #[clap(long, value_hint = ValueHint::FilePath)]
+    sqlite_path: Option<PathBuf>,
+}
+
 #[derive(Debug, Clone, clap::ValueEnum)]
 enum Ensemble {
     #[clap(name = "ensemble-x")]
     EnsembleX,
+    #[clap(name = "sqlite")]
+    Sqlite,
+}
+
+/// Test authorization policies without a server.
+#[derive(Parser, Debug)]
+struct Policy {
+    #[command(subcommand)]
+    command: PolicyCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum PolicyCommand {
+    Test(TestPolicy),
+}
+
+/// Decide on a request to an http handler of a score package like a server
+/// would, and print the policies that decided.
+#[derive(Parser, Debug)]
+struct TestPolicy {
+    #[clap(name = "PATH", value_hint = ValueHint::FilePath)]
+    score_path: PathBuf,
+
+    /// The request as a JSON object with the fields of authorization
+    /// expressions, e.g. `{"handler": "list", "claims": {"role": "admin"}}`.
+    #[clap(name = "REQUEST", value_hint = ValueHint::FilePath)]
+    request_path: PathBuf,
 }
 
 #[tokio::main]
@@ -89,9 +345,10 @@ async fn main() -> Result<()> {
     match global_args.command {
         Command::Compile(args) => {
             let score = Score::new(args.score_path);
-            let catalog = score.catalog()?;
+            let (catalog, policy_tests) = score.compile()?;
 
             println!("Catalog: {:#?}", catalog);
+            run_policy_tests(&catalog, &policy_tests).await?;
         }
         Command::Diff(args) => {
             let a_score = Score::new(args.a);
@@ -111,18 +368,123 @@ async fn main() -> Result<()> {
                 }
             }
         }
-        Command::Apply(args) => match args.ensemble {
+        Command::Apply(args) => {
+            let mut ensemble: Box<dyn ensemble::Ensemble> = match args.ensemble {
+                Ensemble::EnsembleX => {
+                    let store = configure_ensemble_x_storage(args.x_path.unwrap())?;
+                    let trash_retention = args
+                        .trash_retention_hours
+                        .checked_mul(60 * 60)
+                        .map(Duration::from_secs)
+                        .ok_or_else(|| {
+                            anyhow!(
+                                "trash retention of {} hours is too long",
+                                args.trash_retention_hours
+                            )
+                        })?;
+
+                    Box::new(
+                        ensemble_x::EnsembleX::new(store)
+                            .await?
+                            .with_trash_retention(trash_retention),
+                    )
+                }
+                Ensemble::Sqlite => open_ensemble(args.ensemble, None, args.sqlite_path).await?,
+            };
+            apply_ensemble(ensemble.as_mut(), args.score_path, args.commit).await?;
+        }
+        Command::Sql(args) => {
+            let mut ensemble = match (&args.ensemble, args.x_path.as_deref()) {
+                (Ensemble::EnsembleX, Some(x_path)) if is_memory_path(x_path) => {
+                    Box::new(ensemble_x::EnsembleX::new(ObjectStore::in_memory()).await?)
+                }
+                _ => open_ensemble(args.ensemble, args.x_path, args.sqlite_path).await?,
+            };
+            if let Some(score_path) = args.score {
+                apply_ensemble(ensemble.as_mut(), score_path, true).await?;
+            }
+            let request = match (args.principal, args.claims) {
+                (None, None) => None,
+                (principal, claims) => Some(AuthorizationRequest {
+                    principal,
+                    claims: claims
+                        .map(|c| serde_json::from_str(&c))
+                        .transpose()
+                        .map_err(|e| anyhow!("invalid claims: {e}"))?,
+                    ..Default::default()
+                }),
+            };
+            sql_session(ensemble.as_ref(), request).await?;
+        }
+        Command::RestoreTable(args) => match args.ensemble {
             Ensemble::EnsembleX => {
-                let workspace = args.score_path;
                 let data_path = args.x_path;
-                let commit = args.commit;
-                apply_ensemble_x(workspace, data_path, commit).await?;
+                restore_table_ensemble_x(data_path, args.uuid).await?;
             }
+            Ensemble::Sqlite => bail!("the sqlite ensemble has no trash to restore tables from"),
         },
-        Command::Sql(args) => match args.ensemble {
+        Command::Gc(args) => match args.ensemble {
             Ensemble::EnsembleX => {
                 let data_path = args.x_path;
-                sql_ensemble_x(data_path).await?;
+                let commit = args.commit;
+                gc_ensemble_x(data_path, commit).await?;
+            }
+            Ensemble::Sqlite => bail!("the sqlite ensemble has no trash to collect"),
+        },
+        Command::Maintain(args) => match args.command {
+            MaintainCommand::Optimize(args) => {
+                let ensemble = open_ensemble(args.ensemble, args.x_path, args.sqlite_path).await?;
+                optimize_tables(ensemble.as_ref(), args.tables).await?;
+            }
+            MaintainCommand::Vacuum(args) => {
+                let ensemble = open_ensemble(args.ensemble, args.x_path, args.sqlite_path).await?;
+                let retention = args
+                    .retain_hours
+                    .map(|hours| {
+                        hours
+                            .checked_mul(60 * 60)
+                            .map(Duration::from_secs)
+                            .ok_or_else(|| anyhow!("retention of {hours} hours is too long"))
+                    })
+                    .transpose()?;
+                vacuum_tables(ensemble.as_ref(), args.tables, retention, args.dry_run).await?;
+            }
+        },
+        Command::Keys(args) => match args.command {
+            KeysCommand::Create(args) => {
+                let ensemble = open_ensemble(args.ensemble, args.x_path, args.sqlite_path).await?;
+                create_key(ensemble.as_ref(), &args.policy, &args.principal).await?;
+            }
+            KeysCommand::Revoke(args) => {
+                let ensemble = open_ensemble(args.ensemble, args.x_path, args.sqlite_path).await?;
+                revoke_key(ensemble.as_ref(), &args.policy, &args.key_id).await?;
+            }
+            KeysCommand::List(args) => {
+                let ensemble = open_ensemble(args.ensemble, args.x_path, args.sqlite_path).await?;
+                list_keys(ensemble.as_ref(), &args.policy).await?;
+            }
+        },
+        Command::Policy(args) => match args.command {
+            PolicyCommand::Test(args) => {
+                let catalog = Score::new(args.score_path).catalog()?;
+                let request = std::fs::read_to_string(&args.request_path)?;
+                let mut request: AuthorizationRequest =
+                    serde_json::from_str(&request).map_err(|e| anyhow!("invalid request: {e}"))?;
+                if request.namespace.is_empty() {
+                    if let Some(ns) = catalog.namespaces.keys().next() {
+                        request.namespace = ns.clone();
+                    }
+                }
+
+                let decisions = decide(&catalog, &request).await?;
+                for (scope, decision) in &decisions {
+                    println!("{scope}: {decision}");
+                }
+                let (scope, decision) = final_decision(&decisions);
+                match decision.allowed {
+                    true => println!("allow"),
+                    false => println!("deny: {scope} {}", decision.reason()),
+                }
             }
         },
     }
@@ -130,18 +492,33 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
-async fn apply_ensemble_x(
+async fn open_ensemble(
+    ensemble: Ensemble,
+    x_path: Option<String>,
+    sqlite_path: Option<PathBuf>,
+) -> Result<Box<dyn ensemble::Ensemble>> {
+    match ensemble {
+        Ensemble::EnsembleX => {
+            let store = configure_ensemble_x_storage(x_path.unwrap())?;
+
+            Ok(Box::new(ensemble_x::EnsembleX::new(store).await?))
+        }
+        Ensemble::Sqlite => {
+            let path = sqlite_path.ok_or_else(|| anyhow!("--sqlite-path is required"))?;
+
+            Ok(Box::new(ensemble_sqlite::EnsembleSqlite::open(path)?))
+        }
+    }
+}
+
+async fn apply_ensemble(
+    ensemble: &mut dyn ensemble::Ensemble,
     score_path: PathBuf,
-    data_path: Option<String>,
     commit: bool,
 ) -> Result<()> {
-    use ensemble_x::EnsembleX;
-
     let score = Score::new(score_path);
     let catalog = score.catalog()?;
 
-    let store = configure_ensemble_x_storage(data_path.unwrap())?;
-    let mut ensemble = EnsembleX::new(store).await?;
     let from_catalog = ensemble.catalog()?;
     let diff = catalog::diff::Diff {};
     let edits = diff.diff(&from_catalog, &catalog)?;
@@ -163,12 +540,399 @@ async fn apply_ensemble_x(
     Ok(())
 }
 
-async fn sql_ensemble_x(data_path: Option<String>) -> Result<()> {
+async fn restore_table_ensemble_x(data_path: Option<String>, uuid: uuid::Uuid) -> Result<()> {
     use ensemble_x::EnsembleX;
 
+    let store = configure_ensemble_x_storage(data_path.unwrap())?;
+    let mut ensemble = EnsembleX::new(store).await?;
+
+    let table = ensemble.restore_table(&uuid).await?;
+    ensemble.commit().await?;
+
+    println!(
+        "Restored table {}.{} ({}).",
+        table.namespace, table.name, uuid
+    );
+
+    Ok(())
+}
+
+async fn gc_ensemble_x(data_path: Option<String>, commit: bool) -> Result<()> {
+    use ensemble_x::{trash, EnsembleX};
+
     let store = configure_ensemble_x_storage(data_path.unwrap())?;
     let ensemble = EnsembleX::new(store).await?;
+
+    let expired = if commit {
+        ensemble.gc().await?
+    } else {
+        let now = trash::unix_now();
+        ensemble
+            .trash()
+            .await?
+            .into_iter()
+            .filter(|entry| entry.is_expired(now))
+            .collect()
+    };
+
+    if expired.is_empty() {
+        println!("No expired tables.");
+        return Ok(());
+    }
+
+    for entry in expired.into_iter() {
+        println!(
+            "PURGE {}.{} ({});",
+            entry.table.namespace, entry.table.name, entry.table.uuid
+        );
+    }
+
+    Ok(())
+}
+
+async fn optimize_tables(ensemble: &dyn ensemble::Ensemble, tables: Vec<String>) -> Result<()> {
+    for (namespace, name) in maintained_tables(ensemble, tables)? {
+        let metrics = ensemble.table(&namespace, &name).await?.optimize().await?;
+
+        println!(
+            "OPTIMIZE {}.{}: {} files removed, {} files added;",
+            namespace, name, metrics.num_files_removed, metrics.num_files_added
+        );
+    }
+
+    Ok(())
+}
+
+async fn vacuum_tables(
+    ensemble: &dyn ensemble::Ensemble,
+    tables: Vec<String>,
+    retention: Option<Duration>,
+    dry_run: bool,
+) -> Result<()> {
+    for (namespace, name) in maintained_tables(ensemble, tables)? {
+        let files = ensemble
+            .table(&namespace, &name)
+            .await?
+            .vacuum(retention, dry_run)
+            .await?;
+
+        for file in &files {
+            println!("DELETE {}.{} {};", namespace, name, file);
+        }
+        println!(
+            "VACUUM {}.{}: {} files {};",
+            namespace,
+            name,
+            files.len(),
+            if dry_run { "to delete" } else { "deleted" }
+        );
+    }
+
+    Ok(())
+}
+
+/// Resolve namespace.table arguments, or all tables of the ensemble if there
+/// are none.
+fn maintained_tables(
+    ensemble: &dyn ensemble::Ensemble,
+    tables: Vec<String>,
+) -> Result<Vec<(String, String)>> {
+    let catalog = ensemble.catalog()?;
+
+    if tables.is_empty() {
+        let mut tables = catalog
+            .namespaces
+            .values()
+            .flat_map(|ns| ns.tables.values())
+            .map(|table| (table.namespace.clone(), table.name.clone()))
+            .collect::<Vec<_>>();
+        tables.sort();
+
+        return Ok(tables);
+    }
+
+    tables
+        .into_iter()
+        .map(|table| {
+            let (namespace, name) = table
+                .split_once('.')
+                .ok_or_else(|| anyhow!("Expected namespace.table, found: {}", table))?;
+            if !catalog
+                .namespaces
+                .get(namespace)
+                .is_some_and(|ns| ns.tables.contains_key(name))
+            {
+                bail!("Table not found: {}", table);
+            }
+
+            Ok((namespace.to_string(), name.to_string()))
+        })
+        .collect()
+}
+
+/// Where the keys of an api_key authentication policy are kept. Keys tables
+/// are left out of SQL sessions, so they are read and written directly.
+enum KeyStore {
+    File(PathBuf),
+    Table(Arc<dyn EnsembleTable>),
+}
+
+impl KeyStore {
+    /// The keys of `policy`, given as namespace.policy.
+    async fn new(ensemble: &dyn ensemble::Ensemble, policy: &str) -> Result<Self> {
+        let catalog = ensemble.catalog()?;
+        let (namespace, name) = policy
+            .split_once('.')
+            .ok_or_else(|| anyhow!("Expected namespace.policy, found: {}", policy))?;
+        let ns = catalog
+            .namespaces
+            .get(namespace)
+            .ok_or_else(|| anyhow!("Namespace not found: {}", namespace))?;
+        let policy = ns
+            .authentication_policies
+            .get(name)
+            .ok_or_else(|| anyhow!("Authentication policy not found: {}", policy))?;
+
+        match &policy.typ {
+            AuthenticationPolicyType::ApiKey(ApiKeySource::File(path)) => {
+                Ok(KeyStore::File(PathBuf::from(path)))
+            }
+            AuthenticationPolicyType::ApiKey(ApiKeySource::Table(table)) => {
+                if !ns.tables.contains_key(table) {
+                    bail!("Keys table not found: {}.{}", namespace, table);
+                }
+
+                Ok(KeyStore::Table(ensemble.table(namespace, table).await?))
+            }
+            _ => bail!(
+                "Not an api_key authentication policy: {}.{}",
+                namespace,
+                name
+            ),
+        }
+    }
+
+    async fn keys(&self) -> Result<Vec<ApiKey>> {
+        match self {
+            KeyStore::File(path) => Ok(api_key::read_keys_file(path)?),
+            KeyStore::Table(table) => read_keys_table(table.snapshot().await?).await,
+        }
+    }
+}
+
+async fn read_keys_table(table: Arc<dyn TableProvider>) -> Result<Vec<ApiKey>> {
+    let batches = SessionContext::new().read_table(table)?.collect().await?;
+
+    Ok(api_key::keys_from_batches(&batches)?)
+}
+
+/// `keys` as rows of keys table `table`.
+fn keys_table_rows(
+    table: &dyn EnsembleTable,
+    keys: &[ApiKey],
+) -> Result<SendableRecordBatchStream> {
+    let schema = table.schema();
+    let batch = api_key::keys_to_batch(keys)?;
+    let indices = schema
+        .fields()
+        .iter()
+        .map(|f| batch.schema().index_of(f.name()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let batch = RecordBatch::try_new(schema.clone(), batch.project(&indices)?.columns().to_vec())?;
+
+    Ok(Box::pin(MemoryStream::try_new(vec![batch], schema, None)?))
+}
+
+async fn create_key(
+    ensemble: &dyn ensemble::Ensemble,
+    policy: &str,
+    principal: &str,
+) -> Result<()> {
+    let store = KeyStore::new(ensemble, policy).await?;
+    let (api_key, key) = ApiKey::generate(principal);
+
+    match &store {
+        KeyStore::File(path) => {
+            let mut keys = api_key::read_keys_file(path)?;
+            keys.push(api_key.clone());
+            api_key::write_keys_file(path, &keys)?;
+        }
+        KeyStore::Table(table) => {
+            let rows = keys_table_rows(table.as_ref(), std::slice::from_ref(&api_key))?;
+            table.write(rows).await?;
+        }
+    }
+
+    println!("Created key {} for {}:", api_key.key_id, principal);
+    println!("{}", key);
+
+    Ok(())
+}
+
+async fn revoke_key(ensemble: &dyn ensemble::Ensemble, policy: &str, key_id: &str) -> Result<()> {
+    let revoke = |keys: &mut [ApiKey]| -> Result<()> {
+        let api_key = keys
+            .iter_mut()
+            .find(|k| k.key_id == key_id)
+            .ok_or_else(|| anyhow!("Key not found: {}", key_id))?;
+        if api_key.revoked_at.is_some() {
+            bail!("Key already revoked: {}", key_id);
+        }
+        api_key.revoke();
+
+        Ok(())
+    };
+
+    match KeyStore::new(ensemble, policy).await? {
+        KeyStore::File(path) => {
+            let mut keys = api_key::read_keys_file(&path)?;
+            revoke(&mut keys)?;
+            api_key::write_keys_file(&path, &keys)?;
+        }
+        KeyStore::Table(table) => {
+            // The keys are replaced as of the snapshot they were read from,
+            // keeping keys created in the meantime.
+            let snapshot = table.snapshot().await?;
+            let mut keys = read_keys_table(snapshot.clone()).await?;
+            revoke(&mut keys)?;
+            let rows = keys_table_rows(table.as_ref(), &keys)?;
+            table.overwrite(snapshot, rows, "REVOKE KEY").await?;
+        }
+    }
+
+    println!("Revoked key {}.", key_id);
+
+    Ok(())
+}
+
+async fn list_keys(ensemble: &dyn ensemble::Ensemble, policy: &str) -> Result<()> {
+    let store = KeyStore::new(ensemble, policy).await?;
+    let mut keys = store.keys().await?;
+    keys.sort_by(|a, b| a.created_at.cmp(&b.created_at));
+
+    // Everything but the salt and hash.
+    let batch = api_key::keys_to_batch(&keys)?.project(&[0, 1, 4, 5])?;
+    pretty::print_batches(&[batch])?;
+
+    Ok(())
+}
+
+/// The decisions on `request` by the policies of its http handler and of the
+/// tables the handler uses that have policies, in the order a server makes
+/// them.
+async fn decide(
+    catalog: &Catalog,
+    request: &AuthorizationRequest,
+) -> Result<Vec<(String, Decision)>> {
+    let namespace = catalog
+        .namespaces
+        .get(&request.namespace)
+        .ok_or_else(|| anyhow!("Namespace not found: {}", request.namespace))?;
+    let handler = namespace
+        .http_handlers
+        .get(&request.handler)
+        .ok_or_else(|| anyhow!("Http handler not found: {}", request.handler))?;
+    let auth_eval = AuthEval::new(catalog);
+    let decide = |ns: &Namespace, policies: &[String]| -> Result<Decision> {
+        let policies = policies
+            .iter()
+            .map(|name| {
+                ns.authorization_policies
+                    .get(name)
+                    .ok_or_else(|| anyhow!("Authorization policy not found: {}", name))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(auth_eval.authorize(policies, request))
+    };
+
+    let mut decisions = vec![(
+        format!("http handler {}", handler.name),
+        decide(namespace, &handler.policies)?,
+    )];
+
+    // The tables used are found like the server does, by planning the body
+    // in a session of an empty ensemble with the catalog.
+    let mut ensemble = ensemble_x::EnsembleX::new(ObjectStore::in_memory()).await?;
+    let edits = catalog::diff::Diff {}.diff(&Catalog::default(), catalog)?;
+    for edit in edits {
+        ensemble::Ensemble::apply(&mut ensemble, &edit).await?;
+    }
+    ensemble::Ensemble::commit(&mut ensemble).await?;
+    let session = SqlSession::new(&ensemble).await?;
+
+    for (table_ns, table_name) in session.tables_used(&handler.body)? {
+        let Some(ns) = catalog.namespaces.get(&table_ns) else {
+            continue;
+        };
+        let Some(table) = ns.tables.get(&table_name) else {
+            continue;
+        };
+        if !table.policies.is_empty() {
+            decisions.push((
+                format!("table {table_ns}.{table_name}"),
+                decide(ns, &table.policies)?,
+            ));
+        }
+    }
+
+    Ok(decisions)
+}
+
+/// The first decision denying the request, or the decision on its http
+/// handler if none does.
+fn final_decision(decisions: &[(String, Decision)]) -> &(String, Decision) {
+    decisions
+        .iter()
+        .find(|(_, decision)| !decision.allowed)
+        .unwrap_or(&decisions[0])
+}
+
+/// Run the `POLICY_TEST`s of a score package, failing if any of them does.
+async fn run_policy_tests(catalog: &Catalog, policy_tests: &[PolicyTest]) -> Result<()> {
+    let mut failed = 0;
+
+    for test in policy_tests {
+        let decisions = decide(catalog, &test.request).await?;
+        let (scope, decision) = final_decision(&decisions);
+
+        let passed = decision.allowed == test.allowed
+            && test
+                .deciding_policies
+                .as_ref()
+                .is_none_or(|policies| *policies == decision.deciding_policies);
+        if passed {
+            println!("policy test {} ... ok", test.name);
+            continue;
+        }
+
+        failed += 1;
+        let expected = match (test.allowed, &test.deciding_policies) {
+            (true, None) => "allow".to_string(),
+            (false, None) => "deny".to_string(),
+            (true, Some(policies)) => format!("allow by {}", policies.join(", ")),
+            (false, Some(policies)) => format!("deny by {}", policies.join(", ")),
+        };
+        println!(
+            "policy test {} ... FAILED: expected {expected}, but {scope} {decision}",
+            test.name
+        );
+    }
+
+    if failed > 0 {
+        bail!("{} of {} policy tests failed", failed, policy_tests.len());
+    }
+
+    Ok(())
+}
+
+async fn sql_session(
+    ensemble: &dyn ensemble::Ensemble,
+    request: Option<AuthorizationRequest>,
+) -> Result<()> {
     let mut session = SqlSession::new(ensemble).await?;
+    if let Some(request) = request {
+        session.authorize_as(request);
+    }
 
     let mut rl = rustyline::DefaultEditor::new()?;
     let rl_history_path = dirs::config_dir().unwrap().join(".conductor-sql-history");
@@ -194,8 +958,12 @@ async fn sql_ensemble_x(data_path: Option<String>) -> Result<()> {
 
                 rl.add_history_entry(&line)?;
                 rl.append_history(&rl_history_path).ok();
-                match session.execute(&line).await {
-                    Ok(result) => pretty::print_batches(&result)?,
+                match session.execute_all(&line).await {
+                    Ok(results) => {
+                        for result in results {
+                            pretty::print_batches(&result)?;
+                        }
+                    }
                     Err(err) => {
                         println!("Error: {}", err);
                         continue;
@@ -235,6 +1003,10 @@ fn parse_data_path(data_path: String) -> Result<Url> {
     ))
 }
 
+fn is_memory_path(data_path: &str) -> bool {
+    Url::parse(data_path).is_ok_and(|url| url.scheme() == "memory")
+}
+
 fn configure_object_storage(uri: &Url) -> Result<Box<object_store::DynObjectStore>> {
     match uri.scheme() {
         "file" => {
@@ -243,6 +1015,9 @@ fn configure_object_storage(uri: &Url) -> Result<Box<object_store::DynObjectStor
 
             Ok(Box::new(storage))
         }
+        // Each process would get its own empty store, `conductor sql` keeps
+        // one for the session instead.
+        "memory" => bail!("memory:// is only supported by `conductor sql`"),
         "gs" => {
             let gcs = GoogleCloudStorageBuilder::from_env()
                 .with_url(uri.as_str())
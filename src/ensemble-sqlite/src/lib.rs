@@ -0,0 +1,1237 @@
+//! An ensemble kept in a single SQLite database.
+//!
+//! Each table of the catalog is a SQLite table named `<namespace>.<table>`.
+//! The catalog itself is kept as JSON in `_conductor_catalog`, and the UUIDs
+//! of tables and the UIDs of their columns in `_conductor_tables` and
+//! `_conductor_columns`.
+//!
+//! The connection is used on blocking threads. Scans and deletes go through
+//! the rows of a table in pages of [`PAGE_SIZE`] rows, ordered by rowid.
+//!
+//! A snapshot of a table is the rows up to its largest rowid at the time, so
+//! an overwrite replaces those rows and keeps the ones appended since. It
+//! fails if some of them were deleted in the meantime. SQLite may reuse the
+//! largest rowid once its row is deleted, so a row deleted and one appended
+//! since a snapshot can go unnoticed.
+
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+use catalog::{edit::Edit, Catalog, Table};
+use datafusion::{
+    arrow::{
+        array::{Array, ArrayRef, BooleanArray, Float64Array, Int32Array, Int64Array, StringArray},
+        compute::cast,
+        datatypes::{DataType, Field, Schema, SchemaRef},
+        error::ArrowError,
+        record_batch::{RecordBatch, RecordBatchOptions},
+    },
+    common::DFSchema,
+    datasource::{streaming::PartitionStream, TableProvider},
+    error::DataFusionError,
+    execution::context::{SessionState, TaskContext},
+    logical_expr::TableType,
+    physical_expr::{create_physical_expr, execution_props::ExecutionProps},
+    physical_plan::{
+        stream::RecordBatchStreamAdapter, streaming::StreamingTableExec, ExecutionPlan,
+        SendableRecordBatchStream,
+    },
+    prelude::Expr,
+};
+use ensemble::{Ensemble, EnsembleTable, PreparedChange, TableChange, WriteMetrics};
+use futures::TryStreamExt;
+use rusqlite::{
+    params, params_from_iter, types::Value, Connection, OptionalExtension, Transaction,
+};
+use thiserror::Error;
+use tracing::trace;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("sqlite error: {0}")]
+    SqliteError(#[from] rusqlite::Error),
+    #[error("catalog error: {0}")]
+    CatalogError(#[from] catalog::Error),
+    #[error("datafusion error: {0}")]
+    DataFusionError(#[from] DataFusionError),
+    #[error("arrow error: {0}")]
+    ArrowError(#[from] ArrowError),
+    #[error("ensemble error: {0}")]
+    Error(String),
+}
+
+impl From<Error> for ensemble::Error {
+    fn from(e: Error) -> Self {
+        ensemble::Error::Backend(Box::new(e))
+    }
+}
+
+/// The number of rows scans and deletes read at a time.
+pub const PAGE_SIZE: usize = 8192;
+
+const METADATA_SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS _conductor_catalog (
+        id INTEGER PRIMARY KEY CHECK (id = 0),
+        catalog TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS _conductor_tables (
+        uuid TEXT PRIMARY KEY,
+        namespace TEXT NOT NULL,
+        name TEXT NOT NULL,
+        UNIQUE (namespace, name)
+    );
+    CREATE TABLE IF NOT EXISTS _conductor_columns (
+        table_uuid TEXT NOT NULL REFERENCES _conductor_tables (uuid) ON DELETE CASCADE,
+        uid INTEGER NOT NULL,
+        name TEXT NOT NULL,
+        PRIMARY KEY (table_uuid, uid)
+    );
+";
+
+pub struct EnsembleSqlite {
+    conn: Arc<Mutex<Connection>>,
+    catalog: Catalog,
+    pending_actions: Vec<Action>,
+}
+
+#[derive(Clone)]
+enum Action {
+    CreateTable(Table),
+    DropTable(Table),
+}
+
+#[derive(Clone)]
+pub struct SqliteTable {
+    conn: Arc<Mutex<Connection>>,
+    table_name: String,
+    schema: SchemaRef,
+}
+
+/// The rows of a [`SqliteTable`] at some point: those up to `max_rowid`, of
+/// which there were `num_rows`.
+#[derive(Clone)]
+pub struct SqliteSnapshot {
+    table: SqliteTable,
+    max_rowid: i64,
+    num_rows: i64,
+}
+
+/// Changes to [`SqliteTable`]s of a database, committed in a single
+/// transaction.
+pub struct PreparedSqlite {
+    conn: Arc<Mutex<Connection>>,
+    changes: Vec<SqliteChange>,
+}
+
+struct SqliteChange {
+    table: Arc<SqliteTable>,
+    /// The largest rowid and number of rows up to it an overwrite expects
+    /// the table to have, `None` for appends.
+    expected: Option<(i64, i64)>,
+    rows: Vec<RecordBatch>,
+    committed: Option<Committed>,
+}
+
+/// The rows a committed change removed and the rowids of those it inserted.
+type Committed = (Option<RecordBatch>, Vec<i64>);
+
+impl EnsembleSqlite {
+    /// Open the database at `path`, creating it if it doesn't exist.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Self::new(Connection::open(path)?)
+    }
+
+    /// A database kept in memory, for tests and local development.
+    pub fn open_in_memory() -> Result<Self, Error> {
+        Self::new(Connection::open_in_memory()?)
+    }
+
+    fn new(conn: Connection) -> Result<Self, Error> {
+        conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+        conn.execute_batch(METADATA_SCHEMA)?;
+
+        let catalog = match conn
+            .query_row("SELECT catalog FROM _conductor_catalog", [], |row| {
+                row.get::<_, String>(0)
+            })
+            .optional()?
+        {
+            Some(json) => serde_json::from_str(&json).map_err(|e| Error::Error(e.to_string()))?,
+            None => Catalog::default(),
+        };
+
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+            catalog,
+            pending_actions: vec![],
+        })
+    }
+
+    pub fn catalog(&self) -> Result<Catalog, Error> {
+        Ok(self.catalog.clone())
+    }
+
+    pub async fn table(&self, namespace: &str, name: &str) -> Result<Arc<SqliteTable>, Error> {
+        trace!(?namespace, ?name, "table");
+        let table = self
+            .catalog
+            .namespaces
+            .get(namespace)
+            .and_then(|ns| ns.tables.get(name))
+            .ok_or_else(|| Error::Error(format!("table not found: {namespace}.{name}")))?;
+
+        let fields = table
+            .columns
+            .iter()
+            .map(|c| Ok(Field::new(&c.name, column_type(&c.data_type)?.1, true)))
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        Ok(Arc::new(SqliteTable {
+            conn: self.conn.clone(),
+            table_name: table_name(table),
+            schema: Arc::new(Schema::new(fields)),
+        }))
+    }
+
+    pub async fn apply(&mut self, edit: &Edit) -> Result<(), Error> {
+        match edit {
+            Edit::CreateTable(table) => {
+                if !table.partition_by.is_empty() {
+                    return Err(Error::Error(format!(
+                        "partitioned table {}.{} is not supported by the sqlite ensemble",
+                        table.namespace, table.name
+                    )));
+                }
+                for column in &table.columns {
+                    column_type(&column.data_type)?;
+                }
+
+                self.catalog.apply(edit)?;
+                self.pending_actions
+                    .push(Action::CreateTable(table.clone()));
+            }
+            Edit::DropTable(table) => {
+                self.catalog.apply(edit)?;
+                self.pending_actions.push(Action::DropTable(table.clone()));
+            }
+            edit @ Edit::CreateNamespace { .. }
+            | edit @ Edit::SetTableRetention(_)
+            | edit @ Edit::SetTablePolicies(_)
+            | edit @ Edit::ReplaceHttpHandler(_)
+            | edit @ Edit::DropHttpHandler(_)
+            | edit @ Edit::ReplaceAuthenticationPolicy(_)
+            | edit @ Edit::DropAuthenticationPolicy(_)
+            | edit @ Edit::ReplaceAuthorizationPolicy(_)
+            | edit @ Edit::DropAuthorizationPolicy(_)
+            | edit @ Edit::ReplaceMaskingPolicy(_)
+            | edit @ Edit::DropMaskingPolicy(_) => self.catalog.apply(edit)?,
+        }
+
+        Ok(())
+    }
+
+    /// Carry out the pending actions and save the catalog in a single
+    /// transaction.
+    pub async fn commit(&mut self) -> Result<(), Error> {
+        let catalog_json =
+            serde_json::to_string(&self.catalog).map_err(|e| Error::Error(e.to_string()))?;
+
+        let actions = self.pending_actions.clone();
+        with_connection(&self.conn, move |conn| {
+            let tx = conn.transaction()?;
+
+            for action in &actions {
+                match action {
+                    Action::CreateTable(table) => create_table(&tx, table)?,
+                    Action::DropTable(table) => {
+                        tx.execute_batch(&format!("DROP TABLE {}", table_name(table)))?;
+                        tx.execute(
+                            "DELETE FROM _conductor_tables WHERE uuid = ?1",
+                            params![table.uuid.to_string()],
+                        )?;
+                    }
+                }
+            }
+
+            tx.execute(
+                "INSERT INTO _conductor_catalog (id, catalog) VALUES (0, ?1)
+                 ON CONFLICT (id) DO UPDATE SET catalog = excluded.catalog",
+                params![catalog_json],
+            )?;
+            Ok(tx.commit()?)
+        })
+        .await?;
+
+        self.pending_actions.clear();
+
+        Ok(())
+    }
+}
+
+/// Run `f` with `conn` locked, on a thread where blocking is fine.
+async fn with_connection<T, F>(conn: &Arc<Mutex<Connection>>, f: F) -> Result<T, Error>
+where
+    T: Send + 'static,
+    F: FnOnce(&mut Connection) -> Result<T, Error> + Send + 'static,
+{
+    let conn = conn.clone();
+    tokio::task::spawn_blocking(move || {
+        let mut conn = conn
+            .lock()
+            .map_err(|_| Error::Error("sqlite connection poisoned".to_string()))?;
+        f(&mut conn)
+    })
+    .await
+    .map_err(|e| Error::Error(e.to_string()))?
+}
+
+fn create_table(tx: &Transaction, table: &Table) -> Result<(), Error> {
+    let columns = table
+        .columns
+        .iter()
+        .map(|c| {
+            Ok(format!(
+                "{} {}",
+                quote(&c.name),
+                column_type(&c.data_type)?.0
+            ))
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+    tx.execute_batch(&format!(
+        "CREATE TABLE {} ({})",
+        table_name(table),
+        columns.join(", ")
+    ))?;
+
+    tx.execute(
+        "INSERT INTO _conductor_tables (uuid, namespace, name) VALUES (?1, ?2, ?3)",
+        params![table.uuid.to_string(), table.namespace, table.name],
+    )?;
+    for column in &table.columns {
+        tx.execute(
+            "INSERT INTO _conductor_columns (table_uuid, uid, name) VALUES (?1, ?2, ?3)",
+            params![table.uuid.to_string(), column.uid, column.name],
+        )?;
+    }
+
+    Ok(())
+}
+
+/// The quoted name of the SQLite table keeping the rows of `table`.
+fn table_name(table: &Table) -> String {
+    quote(&format!("{}.{}", table.namespace, table.name))
+}
+
+fn quote(identifier: &str) -> String {
+    format!("\"{}\"", identifier.replace('"', "\"\""))
+}
+
+/// The SQLite column type and the Arrow data type of a column type.
+fn column_type(data_type: &sqlparser::ast::DataType) -> Result<(&'static str, DataType), Error> {
+    use sqlparser::ast::DataType as SqlDataType;
+
+    match data_type {
+        SqlDataType::Integer(_) | SqlDataType::Int(_) => Ok(("INTEGER", DataType::Int32)),
+        SqlDataType::BigInt(_) => Ok(("INTEGER", DataType::Int64)),
+        SqlDataType::Boolean => Ok(("INTEGER", DataType::Boolean)),
+        SqlDataType::Double | SqlDataType::Float(_) => Ok(("REAL", DataType::Float64)),
+        SqlDataType::Text | SqlDataType::Varchar(_) | SqlDataType::String => {
+            Ok(("TEXT", DataType::Utf8))
+        }
+        data_type => Err(Error::Error(format!(
+            "column type {data_type} is not supported by the sqlite ensemble"
+        ))),
+    }
+}
+
+impl SqliteTable {
+    /// The rowids and the columns of `schema` of the rows of the table
+    /// matching `condition`.
+    fn select(
+        &self,
+        conn: &Connection,
+        schema: &SchemaRef,
+        condition: &str,
+        params: impl rusqlite::Params,
+    ) -> Result<(Vec<i64>, RecordBatch), Error> {
+        let columns = std::iter::once("rowid".to_string())
+            .chain(schema.fields().iter().map(|f| quote(f.name())))
+            .collect::<Vec<_>>();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM {} {condition}",
+            columns.join(", "),
+            self.table_name
+        ))?;
+
+        let mut rowids = vec![];
+        let mut values = vec![vec![]; schema.fields().len()];
+        let mut rows = stmt.query(params)?;
+        while let Some(row) = rows.next()? {
+            rowids.push(row.get(0)?);
+            for (i, column) in values.iter_mut().enumerate() {
+                column.push(row.get::<_, Value>(i + 1)?);
+            }
+        }
+
+        let arrays = schema
+            .fields()
+            .iter()
+            .zip(values)
+            .map(|(field, values)| to_array(field.data_type(), values))
+            .collect::<Result<Vec<_>, Error>>()?;
+        // Scans without columns still need the number of rows.
+        let options = RecordBatchOptions::new().with_row_count(Some(rowids.len()));
+        let batch = RecordBatch::try_new_with_options(schema.clone(), arrays, &options)?;
+
+        Ok((rowids, batch))
+    }
+
+    /// The rowids and the columns of `schema` of the next [`PAGE_SIZE`] rows
+    /// after rowid `after`, up to rowid `until`.
+    fn read_page(
+        &self,
+        conn: &Connection,
+        schema: &SchemaRef,
+        after: i64,
+        until: i64,
+    ) -> Result<(Vec<i64>, RecordBatch), Error> {
+        self.select(
+            conn,
+            schema,
+            "WHERE rowid > ?1 AND rowid <= ?2 ORDER BY rowid LIMIT ?3",
+            params![after, until, PAGE_SIZE as i64],
+        )
+    }
+
+    /// Delete the rows of a snapshot, those up to `max_rowid`, unless some
+    /// of the `num_rows` it had were deleted since.
+    fn delete_snapshot(
+        &self,
+        tx: &Transaction,
+        (max_rowid, num_rows): (i64, i64),
+    ) -> Result<(), Error> {
+        let remaining = tx.query_row(
+            &format!("SELECT count(*) FROM {} WHERE rowid <= ?1", self.table_name),
+            params![max_rowid],
+            |row| row.get::<_, i64>(0),
+        )?;
+        if remaining != num_rows {
+            return Err(Error::Error(format!(
+                "table {} was changed concurrently",
+                self.table_name
+            )));
+        }
+        tx.execute(
+            &format!("DELETE FROM {} WHERE rowid <= ?1", self.table_name),
+            params![max_rowid],
+        )?;
+
+        Ok(())
+    }
+
+    /// Insert the rows of `batches`, returning their rowids.
+    fn insert(&self, tx: &Transaction, batches: &[RecordBatch]) -> Result<Vec<i64>, Error> {
+        let columns = self
+            .schema
+            .fields()
+            .iter()
+            .map(|f| quote(f.name()))
+            .collect::<Vec<_>>();
+        let placeholders = vec!["?"; columns.len()];
+        let mut stmt = tx.prepare(&format!(
+            "INSERT INTO {} ({}) VALUES ({})",
+            self.table_name,
+            columns.join(", "),
+            placeholders.join(", ")
+        ))?;
+
+        let mut rowids = vec![];
+        for batch in batches {
+            let arrays = batch
+                .columns()
+                .iter()
+                .zip(self.schema.fields())
+                .map(|(array, field)| Ok(cast(array, field.data_type())?))
+                .collect::<Result<Vec<_>, Error>>()?;
+
+            for row in 0..batch.num_rows() {
+                let values = arrays
+                    .iter()
+                    .map(|array| to_value(array, row))
+                    .collect::<Result<Vec<_>, Error>>()?;
+                stmt.execute(params_from_iter(values))?;
+                rowids.push(tx.last_insert_rowid());
+            }
+        }
+
+        Ok(rowids)
+    }
+
+    pub async fn write(&self, input: SendableRecordBatchStream) -> Result<WriteMetrics, Error> {
+        let batches = input.try_collect::<Vec<_>>().await?;
+        let num_rows = batches.iter().map(|b| b.num_rows() as u64).sum();
+
+        let table = self.clone();
+        with_connection(&self.conn, move |conn| {
+            let tx = conn.transaction()?;
+            table.insert(&tx, &batches)?;
+            Ok(tx.commit()?)
+        })
+        .await?;
+
+        Ok(WriteMetrics {
+            num_rows,
+            ..Default::default()
+        })
+    }
+
+    pub async fn snapshot(&self) -> Result<SqliteSnapshot, Error> {
+        let table = self.clone();
+        let (max_rowid, num_rows) = with_connection(&self.conn, move |conn| {
+            Ok(conn.query_row(
+                &format!("SELECT max(rowid), count(*) FROM {}", table.table_name),
+                [],
+                |row| Ok((row.get::<_, Option<i64>>(0)?, row.get::<_, i64>(1)?)),
+            )?)
+        })
+        .await?;
+
+        Ok(SqliteSnapshot {
+            table: self.clone(),
+            max_rowid: max_rowid.unwrap_or(i64::MIN),
+            num_rows,
+        })
+    }
+
+    /// Replace the rows of `snapshot` with `input`, unless some of them were
+    /// deleted since it was taken. Rows appended since are kept.
+    pub async fn overwrite(
+        &self,
+        snapshot: &SqliteSnapshot,
+        input: SendableRecordBatchStream,
+    ) -> Result<(), Error> {
+        let batches = input.try_collect::<Vec<_>>().await?;
+
+        let table = self.clone();
+        let expected = (snapshot.max_rowid, snapshot.num_rows);
+        with_connection(&self.conn, move |conn| {
+            let tx = conn.transaction()?;
+            table.delete_snapshot(&tx, expected)?;
+            table.insert(&tx, &batches)?;
+            Ok(tx.commit()?)
+        })
+        .await
+    }
+
+    pub fn prepare(self: &Arc<Self>, change: TableChange) -> Result<PreparedSqlite, Error> {
+        let (expected, rows) = match change {
+            TableChange::Append(rows) => (None, rows),
+            TableChange::Overwrite { snapshot, rows, .. } => {
+                let snapshot = snapshot
+                    .as_any()
+                    .downcast_ref::<SqliteSnapshot>()
+                    .ok_or_else(|| Error::Error("snapshot is not a sqlite snapshot".to_string()))?;
+                (Some((snapshot.max_rowid, snapshot.num_rows)), rows)
+            }
+        };
+
+        Ok(PreparedSqlite {
+            conn: self.conn.clone(),
+            changes: vec![SqliteChange {
+                table: self.clone(),
+                expected,
+                rows,
+                committed: None,
+            }],
+        })
+    }
+
+    pub async fn delete(&self, predicate: Option<Expr>) -> Result<usize, Error> {
+        let table = self.clone();
+        let Some(predicate) = predicate else {
+            return with_connection(&self.conn, move |conn| {
+                Ok(conn.execute(&format!("DELETE FROM {}", table.table_name), [])?)
+            })
+            .await;
+        };
+
+        // Predicates are DataFusion expressions, so evaluate them on the rows
+        // of the table a page at a time, and delete the matching rows by
+        // rowid in a single transaction.
+        let predicate = create_physical_expr(
+            &predicate,
+            &DFSchema::try_from(self.schema.as_ref().clone())?,
+            &self.schema,
+            &ExecutionProps::new(),
+        )?;
+        with_connection(&self.conn, move |conn| {
+            let tx = conn.transaction()?;
+            let mut stmt = tx.prepare(&format!(
+                "DELETE FROM {} WHERE rowid = ?1",
+                table.table_name
+            ))?;
+
+            let mut num_deleted_rows = 0;
+            let mut after = i64::MIN;
+            loop {
+                let (rowids, rows) = table.read_page(&tx, &table.schema, after, i64::MAX)?;
+                let Some(last) = rowids.last() else {
+                    break;
+                };
+                after = *last;
+
+                let matches = predicate.evaluate(&rows)?.into_array(rows.num_rows());
+                let matches = matches
+                    .as_any()
+                    .downcast_ref::<BooleanArray>()
+                    .ok_or_else(|| Error::Error("DELETE predicate is not boolean".to_string()))?;
+                for (rowid, matches) in rowids.iter().zip(matches) {
+                    if matches == Some(true) {
+                        stmt.execute(params![rowid])?;
+                        num_deleted_rows += 1;
+                    }
+                }
+            }
+
+            drop(stmt);
+            tx.commit()?;
+
+            Ok(num_deleted_rows)
+        })
+        .await
+    }
+}
+
+impl PreparedSqlite {
+    /// Take over the changes of `other` if it changes tables of the same
+    /// database.
+    fn absorb(&mut self, other: &mut PreparedSqlite) -> bool {
+        if !Arc::ptr_eq(&self.conn, &other.conn) {
+            return false;
+        }
+        self.changes.append(&mut other.changes);
+
+        true
+    }
+
+    pub async fn commit(&mut self) -> Result<(), Error> {
+        let changes = std::mem::take(&mut self.changes);
+        let (changes, committed) = with_connection(&self.conn, move |conn| {
+            let committed = commit_changes(conn, &changes);
+            Ok((changes, committed))
+        })
+        .await?;
+        self.changes = changes;
+
+        for (change, committed) in self.changes.iter_mut().zip(committed?) {
+            change.committed = Some(committed);
+        }
+
+        Ok(())
+    }
+
+    /// Delete the rows the changes inserted and put back those they removed.
+    /// Rows written by others since are kept.
+    pub async fn revert(&mut self) -> Result<(), Error> {
+        let changes = std::mem::take(&mut self.changes);
+        let (changes, reverted) = with_connection(&self.conn, move |conn| {
+            let reverted = revert_changes(conn, &changes);
+            Ok((changes, reverted))
+        })
+        .await?;
+        self.changes = changes;
+        reverted?;
+
+        for change in &mut self.changes {
+            change.committed = None;
+        }
+
+        Ok(())
+    }
+}
+
+/// Make `changes` in a single transaction. Returns, for each change, the rows
+/// it removed and the rowids of those it inserted.
+fn commit_changes(
+    conn: &mut Connection,
+    changes: &[SqliteChange],
+) -> Result<Vec<Committed>, Error> {
+    let tx = conn.transaction()?;
+
+    let mut committed = vec![];
+    for change in changes {
+        let removed = match change.expected {
+            Some(expected @ (max_rowid, _)) => {
+                // Kept to put them back on revert.
+                let (_, removed) = change.table.select(
+                    &tx,
+                    &change.table.schema,
+                    "WHERE rowid <= ?1 ORDER BY rowid",
+                    params![max_rowid],
+                )?;
+                change.table.delete_snapshot(&tx, expected)?;
+                Some(removed)
+            }
+            None => None,
+        };
+        let inserted = change.table.insert(&tx, &change.rows)?;
+        committed.push((removed, inserted));
+    }
+    tx.commit()?;
+
+    Ok(committed)
+}
+
+fn revert_changes(conn: &mut Connection, changes: &[SqliteChange]) -> Result<(), Error> {
+    let tx = conn.transaction()?;
+
+    for change in changes.iter().rev() {
+        let Some((removed, inserted)) = &change.committed else {
+            continue;
+        };
+
+        let mut stmt = tx.prepare(&format!(
+            "DELETE FROM {} WHERE rowid = ?1",
+            change.table.table_name
+        ))?;
+        for rowid in inserted {
+            stmt.execute(params![rowid])?;
+        }
+        if let Some(removed) = removed {
+            change.table.insert(&tx, std::slice::from_ref(removed))?;
+        }
+    }
+    tx.commit()?;
+
+    Ok(())
+}
+
+fn to_array(data_type: &DataType, values: Vec<Value>) -> Result<ArrayRef, Error> {
+    let integers = || {
+        values.iter().map(|v| match v {
+            Value::Integer(i) => Some(*i),
+            _ => None,
+        })
+    };
+
+    let array: ArrayRef = match data_type {
+        DataType::Int32 => Arc::new(
+            integers()
+                .map(|i| i.map(i32::try_from).transpose())
+                .collect::<Result<Int32Array, _>>()
+                .map_err(|e| Error::Error(e.to_string()))?,
+        ),
+        DataType::Int64 => Arc::new(integers().collect::<Int64Array>()),
+        DataType::Boolean => Arc::new(
+            integers()
+                .map(|i| i.map(|i| i != 0))
+                .collect::<BooleanArray>(),
+        ),
+        DataType::Float64 => Arc::new(
+            values
+                .iter()
+                .map(|v| match v {
+                    Value::Real(f) => Some(*f),
+                    Value::Integer(i) => Some(*i as f64),
+                    _ => None,
+                })
+                .collect::<Float64Array>(),
+        ),
+        DataType::Utf8 => Arc::new(
+            values
+                .iter()
+                .map(|v| match v {
+                    Value::Text(s) => Some(s.as_str()),
+                    _ => None,
+                })
+                .collect::<StringArray>(),
+        ),
+        data_type => return Err(Error::Error(format!("unsupported data type: {data_type}"))),
+    };
+
+    Ok(array)
+}
+
+/// The value at `row` of `array`, which has one of the types returned by
+/// [`column_type`].
+fn to_value(array: &ArrayRef, row: usize) -> Result<Value, Error> {
+    if array.is_null(row) {
+        return Ok(Value::Null);
+    }
+
+    let any = array.as_any();
+    let value = if let Some(array) = any.downcast_ref::<Int32Array>() {
+        Value::Integer(array.value(row).into())
+    } else if let Some(array) = any.downcast_ref::<Int64Array>() {
+        Value::Integer(array.value(row))
+    } else if let Some(array) = any.downcast_ref::<BooleanArray>() {
+        Value::Integer(array.value(row).into())
+    } else if let Some(array) = any.downcast_ref::<Float64Array>() {
+        Value::Real(array.value(row))
+    } else if let Some(array) = any.downcast_ref::<StringArray>() {
+        Value::Text(array.value(row).to_string())
+    } else {
+        return Err(Error::Error(format!(
+            "unsupported data type: {}",
+            array.data_type()
+        )));
+    };
+
+    Ok(value)
+}
+
+#[async_trait]
+impl Ensemble for EnsembleSqlite {
+    fn catalog(&self) -> Result<Catalog, ensemble::Error> {
+        Ok(EnsembleSqlite::catalog(self)?)
+    }
+
+    async fn apply(&mut self, edit: &Edit) -> Result<(), ensemble::Error> {
+        Ok(EnsembleSqlite::apply(self, edit).await?)
+    }
+
+    async fn commit(&mut self) -> Result<(), ensemble::Error> {
+        Ok(EnsembleSqlite::commit(self).await?)
+    }
+
+    async fn table(
+        &self,
+        namespace: &str,
+        name: &str,
+    ) -> Result<Arc<dyn EnsembleTable>, ensemble::Error> {
+        Ok(EnsembleSqlite::table(self, namespace, name).await?)
+    }
+}
+
+#[async_trait]
+impl EnsembleTable for SqliteTable {
+    async fn write(
+        &self,
+        input: SendableRecordBatchStream,
+    ) -> Result<WriteMetrics, ensemble::Error> {
+        Ok(SqliteTable::write(self, input).await?)
+    }
+
+    async fn delete(&self, predicate: Option<Expr>) -> Result<usize, ensemble::Error> {
+        Ok(SqliteTable::delete(self, predicate).await?)
+    }
+
+    async fn snapshot(&self) -> Result<Arc<dyn TableProvider>, ensemble::Error> {
+        Ok(Arc::new(SqliteTable::snapshot(self).await?))
+    }
+
+    async fn overwrite(
+        &self,
+        snapshot: Arc<dyn TableProvider>,
+        input: SendableRecordBatchStream,
+        _operation: &str,
+    ) -> Result<(), ensemble::Error> {
+        let snapshot = snapshot
+            .as_any()
+            .downcast_ref::<SqliteSnapshot>()
+            .ok_or_else(|| Error::Error("snapshot is not a sqlite snapshot".to_string()))?;
+
+        Ok(SqliteTable::overwrite(self, snapshot, input).await?)
+    }
+
+    async fn prepare(
+        self: Arc<Self>,
+        change: TableChange,
+    ) -> Result<Box<dyn PreparedChange>, ensemble::Error> {
+        Ok(Box::new(SqliteTable::prepare(&self, change)?))
+    }
+}
+
+#[async_trait]
+impl PreparedChange for PreparedSqlite {
+    fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+        self
+    }
+
+    fn absorb(&mut self, other: &mut dyn PreparedChange) -> bool {
+        match other.as_any_mut().downcast_mut::<PreparedSqlite>() {
+            Some(other) => PreparedSqlite::absorb(self, other),
+            None => false,
+        }
+    }
+
+    async fn commit(&mut self) -> Result<(), ensemble::Error> {
+        Ok(PreparedSqlite::commit(self).await?)
+    }
+
+    async fn revert(&mut self) -> Result<(), ensemble::Error> {
+        Ok(PreparedSqlite::revert(self).await?)
+    }
+}
+
+#[async_trait]
+impl TableProvider for SqliteTable {
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+
+    fn table_type(&self) -> TableType {
+        TableType::Base
+    }
+
+    async fn scan(
+        &self,
+        _state: &SessionState,
+        projection: Option<&Vec<usize>>,
+        _filters: &[Expr],
+        _limit: Option<usize>,
+    ) -> datafusion::error::Result<Arc<dyn ExecutionPlan>> {
+        let schema = match projection {
+            Some(projection) => Arc::new(self.schema.project(projection)?),
+            None => self.schema.clone(),
+        };
+        let scan = SqliteScan {
+            table: self.clone(),
+            schema: schema.clone(),
+            until: i64::MAX,
+        };
+
+        Ok(Arc::new(StreamingTableExec::try_new(
+            schema,
+            vec![Arc::new(scan)],
+            None,
+            false,
+        )?))
+    }
+}
+
+/// The columns of `schema` of the rows of a [`SqliteTable`] up to rowid
+/// `until`, read a page at a time. Rows written during a scan may or may not
+/// be seen.
+struct SqliteScan {
+    table: SqliteTable,
+    schema: SchemaRef,
+    until: i64,
+}
+
+impl PartitionStream for SqliteScan {
+    fn schema(&self) -> &SchemaRef {
+        &self.schema
+    }
+
+    fn execute(&self, _ctx: Arc<TaskContext>) -> SendableRecordBatchStream {
+        let table = self.table.clone();
+        let schema = self.schema.clone();
+        let until = self.until;
+        let pages = futures::stream::try_unfold(Some(i64::MIN), move |after| {
+            let table = table.clone();
+            let schema = schema.clone();
+            async move {
+                let Some(after) = after else {
+                    return Ok(None);
+                };
+                let conn = table.conn.clone();
+                let (rowids, rows) = with_connection(&conn, move |conn| {
+                    table.read_page(conn, &schema, after, until)
+                })
+                .await
+                .map_err(|e| DataFusionError::External(Box::new(e)))?;
+
+                // A page that isn't full is the last one.
+                Ok(rowids
+                    .last()
+                    .map(|last| (rows, (rowids.len() == PAGE_SIZE).then_some(*last))))
+            }
+        });
+
+        Box::pin(RecordBatchStreamAdapter::new(self.schema.clone(), pages))
+    }
+}
+
+#[async_trait]
+impl TableProvider for SqliteSnapshot {
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.table.schema()
+    }
+
+    fn table_type(&self) -> TableType {
+        TableType::Base
+    }
+
+    async fn scan(
+        &self,
+        _state: &SessionState,
+        projection: Option<&Vec<usize>>,
+        _filters: &[Expr],
+        _limit: Option<usize>,
+    ) -> datafusion::error::Result<Arc<dyn ExecutionPlan>> {
+        let schema = match projection {
+            Some(projection) => Arc::new(self.table.schema.project(projection)?),
+            None => self.table.schema.clone(),
+        };
+        let scan = SqliteScan {
+            table: self.table.clone(),
+            schema: schema.clone(),
+            until: self.max_rowid,
+        };
+
+        Ok(Arc::new(StreamingTableExec::try_new(
+            schema,
+            vec![Arc::new(scan)],
+            None,
+            false,
+        )?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use catalog::Column;
+    use datafusion::{
+        physical_plan::memory::MemoryStream,
+        prelude::{col, lit, SessionContext},
+    };
+    use sqlparser::ast::DataType as SqlDataType;
+
+    use super::*;
+
+    async fn ensemble(tables: &[&str]) -> EnsembleSqlite {
+        let mut ensemble = EnsembleSqlite::open_in_memory().unwrap();
+        ensemble
+            .apply(&Edit::CreateNamespace {
+                name: "ns".to_string(),
+            })
+            .await
+            .unwrap();
+        for name in tables {
+            let table = Table {
+                namespace: "ns".to_string(),
+                uuid: uuid::Uuid::new_v4(),
+                name: name.to_string(),
+                columns: vec![Column {
+                    uid: 1,
+                    name: "id".to_string(),
+                    data_type: SqlDataType::Integer(None),
+                }],
+                partition_by: vec![],
+                retention_hours: None,
+                policies: vec![],
+            };
+            ensemble.apply(&Edit::CreateTable(table)).await.unwrap();
+        }
+        ensemble.commit().await.unwrap();
+
+        ensemble
+    }
+
+    fn rows(table: &SqliteTable, ids: Vec<i32>) -> RecordBatch {
+        RecordBatch::try_new(table.schema(), vec![Arc::new(Int32Array::from(ids))]).unwrap()
+    }
+
+    async fn write(table: &SqliteTable, ids: Vec<i32>) {
+        let input = MemoryStream::try_new(vec![rows(table, ids)], table.schema(), None).unwrap();
+        table.write(Box::pin(input)).await.unwrap();
+    }
+
+    /// The ids of the rows of `snapshot`, sorted.
+    async fn snapshot_ids(snapshot: SqliteSnapshot) -> Vec<i32> {
+        let batches = SessionContext::new()
+            .read_table(Arc::new(snapshot))
+            .unwrap()
+            .collect()
+            .await
+            .unwrap();
+        let mut ids = batches
+            .iter()
+            .flat_map(|b| {
+                let ids = b.column(0).as_any().downcast_ref::<Int32Array>();
+                ids.unwrap().values().to_vec()
+            })
+            .collect::<Vec<_>>();
+        ids.sort();
+
+        ids
+    }
+
+    /// The ids of the rows of `table`, sorted.
+    async fn ids(table: &SqliteTable) -> Vec<i32> {
+        snapshot_ids(table.snapshot().await.unwrap()).await
+    }
+
+    #[tokio::test]
+    async fn test_commit_all() {
+        let ensemble = ensemble(&["t", "u"]).await;
+        let t = ensemble.table("ns", "t").await.unwrap();
+        let u = ensemble.table("ns", "u").await.unwrap();
+        write(&t, vec![1]).await;
+        write(&u, vec![1]).await;
+
+        let prepare = |u_snapshot: SqliteSnapshot| {
+            let changes = vec![
+                EnsembleTable::prepare(t.clone(), TableChange::Append(vec![rows(&t, vec![2])])),
+                EnsembleTable::prepare(
+                    u.clone(),
+                    TableChange::Overwrite {
+                        snapshot: Arc::new(u_snapshot),
+                        rows: vec![rows(&u, vec![9])],
+                        operation: "DELETE".to_string(),
+                    },
+                ),
+            ];
+            futures::future::try_join_all(changes)
+        };
+
+        // The second change fails, so the first one isn't made either.
+        let changes = prepare(u.snapshot().await.unwrap()).await.unwrap();
+        write(&u, vec![3]).await;
+        u.delete(Some(col("id").eq(lit(1)))).await.unwrap();
+        ensemble::commit_all(changes).await.unwrap_err();
+        assert_eq!(ids(&t).await, [1]);
+        assert_eq!(ids(&u).await, [3]);
+
+        // Rows appended since the snapshot are kept.
+        let changes = prepare(u.snapshot().await.unwrap()).await.unwrap();
+        write(&u, vec![4]).await;
+        ensemble::commit_all(changes).await.unwrap();
+        assert_eq!(ids(&t).await, [1, 2]);
+        assert_eq!(ids(&u).await, [4, 9]);
+    }
+
+    #[tokio::test]
+    async fn test_revert() {
+        let ensemble = ensemble(&["t"]).await;
+        let t = ensemble.table("ns", "t").await.unwrap();
+        write(&t, vec![1, 2]).await;
+
+        let snapshot = t.snapshot().await.unwrap();
+        let mut change = SqliteTable::prepare(
+            &t,
+            TableChange::Overwrite {
+                snapshot: Arc::new(snapshot),
+                rows: vec![rows(&t, vec![2, 5])],
+                operation: "UPDATE".to_string(),
+            },
+        )
+        .unwrap();
+        change.commit().await.unwrap();
+        write(&t, vec![7]).await;
+        assert_eq!(ids(&t).await, [2, 5, 7]);
+
+        // Rows written since the change was committed are kept.
+        change.revert().await.unwrap();
+        assert_eq!(ids(&t).await, [1, 2, 7]);
+    }
+
+    #[tokio::test]
+    async fn test_scan() {
+        let ensemble = ensemble(&["t"]).await;
+        let t = ensemble.table("ns", "t").await.unwrap();
+        let num_rows = PAGE_SIZE as i32 * 2 + 1;
+        write(&t, (0..num_rows).collect()).await;
+
+        let ctx = SessionContext::new();
+        ctx.register_table("t", t.clone()).unwrap();
+        let batches = ctx
+            .sql("SELECT count(*), sum(CAST(id AS BIGINT)) FROM t")
+            .await
+            .unwrap()
+            .collect()
+            .await
+            .unwrap();
+        let value = |column: usize| {
+            batches[0]
+                .column(column)
+                .as_any()
+                .downcast_ref::<Int64Array>()
+                .unwrap()
+                .value(0)
+        };
+        assert_eq!(value(0), num_rows as i64);
+        assert_eq!(value(1), (0..num_rows as i64).sum::<i64>());
+
+        // Every page is a batch.
+        let plan = t.scan(&ctx.state(), None, &[], None).await.unwrap();
+        let batches = datafusion::physical_plan::collect(plan, ctx.task_ctx())
+            .await
+            .unwrap();
+        assert_eq!(
+            batches.iter().map(|b| b.num_rows()).collect::<Vec<_>>(),
+            [PAGE_SIZE, PAGE_SIZE, 1]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_delete() {
+        let ensemble = ensemble(&["t"]).await;
+        let t = ensemble.table("ns", "t").await.unwrap();
+        let num_rows = PAGE_SIZE as i32 + 10;
+        write(&t, (0..num_rows).collect()).await;
+
+        let predicate = col("id").gt_eq(lit(5)).and(col("id").lt(lit(num_rows - 5)));
+        assert_eq!(t.delete(Some(predicate)).await.unwrap(), PAGE_SIZE);
+        assert_eq!(
+            ids(&t).await,
+            [
+                0,
+                1,
+                2,
+                3,
+                4,
+                num_rows - 5,
+                num_rows - 4,
+                num_rows - 3,
+                num_rows - 2,
+                num_rows - 1
+            ]
+        );
+
+        assert_eq!(t.delete(None).await.unwrap(), 10);
+        assert_eq!(ids(&t).await, [] as [i32; 0]);
+    }
+
+    #[tokio::test]
+    async fn test_overwrite() {
+        let ensemble = ensemble(&["t"]).await;
+        let t = ensemble.table("ns", "t").await.unwrap();
+        write(&t, vec![1, 2]).await;
+
+        let overwrite = |snapshot, ids| {
+            let input = MemoryStream::try_new(vec![rows(&t, ids)], t.schema(), None).unwrap();
+            let t = t.clone();
+            async move { t.overwrite(&snapshot, Box::pin(input)).await }
+        };
+
+        let snapshot = t.snapshot().await.unwrap();
+        overwrite(snapshot, vec![3]).await.unwrap();
+        assert_eq!(ids(&t).await, [3]);
+
+        // Rows appended since the snapshot aren't part of it, and are kept.
+        let snapshot = t.snapshot().await.unwrap();
+        write(&t, vec![4]).await;
+        assert_eq!(snapshot_ids(snapshot.clone()).await, [3]);
+        overwrite(snapshot, vec![5]).await.unwrap();
+        assert_eq!(ids(&t).await, [4, 5]);
+
+        // Rows deleted since the snapshot make it fail.
+        let snapshot = t.snapshot().await.unwrap();
+        t.delete(Some(col("id").eq(lit(4)))).await.unwrap();
+        overwrite(snapshot, vec![6]).await.unwrap_err();
+        assert_eq!(ids(&t).await, [5]);
+    }
+
+    #[test]
+    fn test_to_value() {
+        let array: ArrayRef = Arc::new(StringArray::from(vec![Some("a"), None]));
+        assert_eq!(to_value(&array, 0).unwrap(), Value::Text("a".to_string()));
+        assert_eq!(to_value(&array, 1).unwrap(), Value::Null);
+
+        let array: ArrayRef = Arc::new(datafusion::arrow::array::Date32Array::from(vec![1]));
+        to_value(&array, 0).unwrap_err();
+    }
+}
@@ -1,23 +1,39 @@
-use std::sync::Arc;
+use std::{
+    collections::HashSet,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
 use async_trait::async_trait;
-use catalog::{edit::Edit, Catalog};
+use catalog::{edit::Edit, Catalog, Table};
+use chrono::{DateTime, Utc};
 use datafusion::{
-    arrow::{datatypes::SchemaRef, record_batch::RecordBatch},
+    arrow::{compute::cast, datatypes::SchemaRef, record_batch::RecordBatch},
     datasource::TableProvider,
     error::DataFusionError,
     execution::context::SessionState,
     logical_expr::{TableProviderFilterPushDown, TableType},
-    physical_plan::ExecutionPlan,
+    physical_plan::{memory::MemoryStream, ExecutionPlan},
     physical_plan::{RecordBatchStream, SendableRecordBatchStream, Statistics},
     prelude::Expr,
 };
 use deltalake::writer::DeltaWriter;
 use deltalake::{
-    operations::create::CreateBuilder, storage::DeltaObjectStore, writer::RecordBatchWriter,
-    DeltaTable, DeltaTableBuilder, SchemaDataType, SchemaField,
+    action::{Action as DeltaAction, Add, DeltaOperation, Remove, SaveMode},
+    operations::{
+        create::CreateBuilder, delete::DeleteBuilder, optimize::OptimizeBuilder, transaction,
+        vacuum::VacuumBuilder,
+    },
+    storage::DeltaObjectStore,
+    table_state::DeltaTableState,
+    writer::RecordBatchWriter,
+    ApplyLogError, DeltaTable, DeltaTableBuilder, DeltaTableError, SchemaDataType, SchemaField,
+};
+use ensemble::{
+    Ensemble, EnsembleTable, OptimizeMetrics, PreparedChange, TableChange, TableCommit,
+    WriteMetrics,
 };
-use futures::{Stream, StreamExt};
+use futures::{Stream, StreamExt, TryStreamExt};
 use object_store::{path::Path, prefix::PrefixStore, ObjectStore as ObjectStoreTrait};
 use serde_json::json;
 use thiserror::Error;
@@ -25,9 +41,13 @@ use tokio::sync::Mutex;
 use tracing::trace;
 use url::Url;
 
+use crate::location::{located_part, LocatedSnapshot};
 use crate::storage::ObjectStore;
+use crate::trash::{TrashEntry, DEFAULT_TRASH_RETENTION};
 
+mod location;
 pub mod storage;
+pub mod trash;
 
 #[derive(Debug, Error)]
 pub enum Error {
@@ -39,29 +59,99 @@ pub enum Error {
     DeltaTable(#[from] deltalake::DeltaTableError),
     #[error("catalog error: {0}")]
     CatalogError(#[from] catalog::Error),
+    #[error("datafusion error: {0}")]
+    DataFusionError(#[from] DataFusionError),
     #[error("ensemble error: {0}")]
     Error(String),
 }
 
+impl From<Error> for ensemble::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::DataFusionError(e) => ensemble::Error::DataFusionError(e),
+            e => ensemble::Error::Backend(Box::new(e)),
+        }
+    }
+}
+
 const METADATA_TABLE_UUID: &str = "orchestack.table-uuid";
 const METADATA_COLUMN_UID: &str = "orchestack.column-uid";
+const METADATA_OPERATION: &str = "orchestack.operation";
 
 pub struct EnsembleX {
     storage: ObjectStore,
     catalog: Catalog,
     pending_actions: Vec<Action>,
+    trash_retention: Duration,
+    write_options: WriteOptions,
 }
 
+/// A Delta table of the ensemble.
+///
+/// Readers work on the snapshot of the table current when they start, and
+/// are never blocked by writers. Writers commit one at a time and then
+/// publish the new version as the current snapshot.
 pub struct TableX {
-    inner: Mutex<DeltaTable>,
+    current: RwLock<TableSnapshot>,
+    writer: Mutex<()>,
+    retention: Duration,
+    write_options: WriteOptions,
+}
+
+/// How [`TableX::write`] splits its input into data files.
+#[derive(Debug, Clone)]
+pub struct WriteOptions {
+    /// Buffered rows are written out to new data files once they take up
+    /// this many bytes in memory, which bounds the memory a write uses. The
+    /// Parquet files are usually smaller than the Arrow data.
+    pub target_file_size: usize,
+    /// Also write out buffered rows once this long has passed since the last
+    /// files were written, checked as batches arrive. Either way, the files
+    /// of a write are committed together at its end.
+    pub flush_interval: Option<Duration>,
 }
+
+impl Default for WriteOptions {
+    fn default() -> Self {
+        Self {
+            target_file_size: 128 * 1024 * 1024,
+            flush_interval: None,
+        }
+    }
+}
+
+/// A version of a table, with the schema and statistics the planner asks
+/// for computed once.
+#[derive(Clone)]
+struct TableSnapshot {
+    table: Arc<DeltaTable>,
+    schema: SchemaRef,
+    statistics: Option<Statistics>,
+}
+
+impl TableSnapshot {
+    fn new(table: DeltaTable) -> Result<Self, Error> {
+        Ok(Self {
+            schema: table.get_state().arrow_schema()?,
+            statistics: table.statistics(),
+            table: Arc::new(table),
+        })
+    }
+}
+
+#[allow(clippy::enum_variant_names)]
 enum Action {
-    CreateTable(CreateBuilder),
-    DropTable { namespace: String, name: String },
+    CreateTable(Box<CreateBuilder>),
+    DropTable(Table),
+    RestoreTable(TrashEntry),
 }
 
 const CATALOG_PATH: &str = "_conductor_catalog.json";
 
+/// Removed data files are kept for a week, unless the table declares a
+/// retention.
+pub const DEFAULT_VACUUM_RETENTION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
 impl EnsembleX {
     pub async fn new(storage: ObjectStore) -> Result<Self, Error> {
         let catalog = match storage.get(&Path::parse(CATALOG_PATH).unwrap()).await {
@@ -75,24 +165,70 @@ impl EnsembleX {
             storage: storage.clone(),
             catalog,
             pending_actions: vec![],
+            trash_retention: DEFAULT_TRASH_RETENTION,
+            write_options: WriteOptions::default(),
         })
     }
 
+    /// How long dropped tables are kept in the trash before [`Self::gc`] can
+    /// purge them.
+    pub fn with_trash_retention(mut self, retention: Duration) -> Self {
+        self.trash_retention = retention;
+        self
+    }
+
+    /// How writes to the tables of the ensemble split their input into data
+    /// files.
+    pub fn with_write_options(mut self, write_options: WriteOptions) -> Self {
+        self.write_options = write_options;
+        self
+    }
+
     pub fn catalog(&self) -> Result<Catalog, Error> {
         Ok(self.catalog.clone())
     }
 
+    /// Changes whenever the catalog of the ensemble in `storage` is saved, so
+    /// that copies of it can tell when they are out of date. `None` if no
+    /// catalog was saved yet.
+    pub async fn catalog_tag(storage: &ObjectStore) -> Result<Option<String>, Error> {
+        match storage.head(&Path::parse(CATALOG_PATH).unwrap()).await {
+            Ok(meta) => Ok(Some(format!(
+                "{}-{}",
+                meta.last_modified.timestamp_nanos_opt().unwrap_or_default(),
+                meta.size
+            ))),
+            Err(object_store::Error::NotFound { .. }) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
     pub async fn table(&self, namespace: &str, name: &str) -> Result<Arc<TableX>, Error> {
         trace!(?namespace, ?name, "table");
         let (store, location) = self.store_for_table(namespace, name);
+        let retention = self
+            .catalog
+            .namespaces
+            .get(namespace)
+            .and_then(|ns| ns.tables.get(name))
+            .and_then(|table| table.retention_hours)
+            .map_or(Ok(DEFAULT_VACUUM_RETENTION), |hours| {
+                hours
+                    .checked_mul(60 * 60)
+                    .map(Duration::from_secs)
+                    .ok_or_else(|| Error::Error(format!("retention of {hours} hours is too long")))
+            })?;
+
+        let table = DeltaTableBuilder::from_uri(location.clone())
+            .with_storage_backend(store, location)
+            .load()
+            .await?;
 
         Ok(Arc::new(TableX {
-            inner: Mutex::new(
-                DeltaTableBuilder::from_uri(location.clone())
-                    .with_storage_backend(store, location)
-                    .load()
-                    .await?,
-            ),
+            current: RwLock::new(TableSnapshot::new(table)?),
+            writer: Mutex::new(()),
+            retention,
+            write_options: self.write_options.clone(),
         }))
     }
 
@@ -118,29 +254,31 @@ impl EnsembleX {
                 let create_builder = CreateBuilder::new()
                     .with_table_name(table.name.clone())
                     .with_columns(delta_columns)
+                    .with_partition_columns(table.partition_by.clone())
                     .with_metadata(table_metadata)
                     .with_object_store(delta_storage);
 
                 self.catalog.apply(edit)?;
 
                 self.pending_actions
-                    .push(Action::CreateTable(create_builder));
+                    .push(Action::CreateTable(Box::new(create_builder)));
             }
             Edit::DropTable(table) => {
                 self.catalog.apply(edit)?;
 
-                self.pending_actions.push(Action::DropTable {
-                    namespace: table.namespace.clone(),
-                    name: table.name.clone(),
-                });
+                self.pending_actions.push(Action::DropTable(table.clone()));
             }
             edit @ Edit::CreateNamespace { .. }
+            | edit @ Edit::SetTableRetention(_)
+            | edit @ Edit::SetTablePolicies(_)
             | edit @ Edit::ReplaceHttpHandler(_)
             | edit @ Edit::DropHttpHandler(_)
             | edit @ Edit::ReplaceAuthenticationPolicy(_)
             | edit @ Edit::DropAuthenticationPolicy(_)
             | edit @ Edit::ReplaceAuthorizationPolicy(_)
-            | edit @ Edit::DropAuthorizationPolicy(_) => self.catalog.apply(edit)?,
+            | edit @ Edit::DropAuthorizationPolicy(_)
+            | edit @ Edit::ReplaceMaskingPolicy(_)
+            | edit @ Edit::DropMaskingPolicy(_) => self.catalog.apply(edit)?,
         }
 
         Ok(())
@@ -152,22 +290,38 @@ impl EnsembleX {
         for action in actions.into_iter() {
             match action {
                 Action::CreateTable(create_builder) => {
-                    create_builder.await?;
+                    (*create_builder).await?;
                 }
-                Action::DropTable { namespace, name } => {
-                    let prefix = self.store_for_namespace(&namespace);
-                    let mut lst = prefix
-                        .list(Some(&object_store::path::Path::parse(&name).unwrap()))
+                Action::DropTable(table) => {
+                    let table_path = table_path(&table.namespace, &table.name);
+
+                    // A table re-created with the same UUID and dropped again
+                    // replaces the previous trash entry.
+                    self.purge_trash_entry(&table.uuid).await?;
+                    self.move_objects(&table_path, &trash::data_prefix(&table.uuid))
+                        .await?;
+
+                    let entry = TrashEntry::new(table, self.trash_retention);
+                    let entry_json_bytes =
+                        serde_json::to_vec(&entry).map_err(|e| Error::Error(e.to_string()))?;
+                    self.storage
+                        .put(
+                            &trash::entry_path(&entry.table.uuid),
+                            entry_json_bytes.into(),
+                        )
                         .await?;
-                    while let Some(e) = lst.next().await {
-                        let meta = e?;
 
-                        prefix.delete(&meta.location).await?;
-                    }
+                    self.storage.remove_empty_prefixes(&table_path).await?;
+                }
+                Action::RestoreTable(entry) => {
+                    let table = &entry.table;
 
-                    // TODO: Cleanup prefixes (folders on local filesystem).
-                    //  These aren't present in the list call above, but they
-                    //  do prevent folder deletion on the filesystem.
+                    self.move_objects(
+                        &trash::data_prefix(&table.uuid),
+                        &table_path(&table.namespace, &table.name),
+                    )
+                    .await?;
+                    self.purge_trash_entry(&table.uuid).await?;
                 }
             }
         }
@@ -185,11 +339,138 @@ impl EnsembleX {
         Ok(())
     }
 
-    fn store_for_namespace(&self, namespace: &str) -> Arc<PrefixStore<storage::ObjectStore>> {
-        Arc::new(PrefixStore::new(
-            self.storage.clone(),
-            object_store::path::Path::parse(namespace).unwrap(),
-        ))
+    /// List the tables in the trash, including expired ones which haven't
+    /// been purged yet.
+    pub async fn trash(&self) -> Result<Vec<TrashEntry>, Error> {
+        let listing = self
+            .storage
+            .list_with_delimiter(Some(&trash::trash_path()))
+            .await?;
+
+        let mut entries = vec![];
+        for prefix in listing.common_prefixes {
+            let Some(uuid) = prefix
+                .filename()
+                .and_then(|n| uuid::Uuid::parse_str(n).ok())
+            else {
+                continue;
+            };
+            let entry_bytes = match self.storage.get(&trash::entry_path(&uuid)).await {
+                Ok(get_result) => get_result.bytes().await?,
+                // Leftovers of an interrupted drop or purge.
+                Err(object_store::Error::NotFound { .. }) => continue,
+                Err(e) => return Err(e.into()),
+            };
+
+            entries.push(
+                serde_json::from_slice(&entry_bytes).map_err(|e| Error::Error(e.to_string()))?,
+            );
+        }
+
+        Ok(entries)
+    }
+
+    /// Bring back a dropped table which is still retained in the trash. The
+    /// table is restored under its original namespace and name on commit.
+    pub async fn restore_table(&mut self, uuid: &uuid::Uuid) -> Result<Table, Error> {
+        let entry: TrashEntry = match self.storage.get(&trash::entry_path(uuid)).await {
+            Ok(get_result) => serde_json::from_slice(&get_result.bytes().await?)
+                .map_err(|e| Error::Error(e.to_string()))?,
+            Err(object_store::Error::NotFound { .. }) => {
+                return Err(Error::Error(format!("table {} is not in the trash", uuid)))
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        if entry.is_expired(trash::unix_now()) {
+            return Err(Error::Error(format!(
+                "table {} has expired and can no longer be restored",
+                uuid
+            )));
+        }
+
+        let table = entry.table.clone();
+        let namespace = self
+            .catalog
+            .namespaces
+            .get(&table.namespace)
+            .ok_or_else(|| {
+                Error::Error(format!(
+                    "namespace {} of table {} does not exist",
+                    table.namespace, uuid
+                ))
+            })?;
+        if namespace.tables.contains_key(&table.name) {
+            return Err(Error::Error(format!(
+                "table {}.{} already exists",
+                table.namespace, table.name
+            )));
+        }
+        if namespace.tables.values().any(|t| t.uuid == table.uuid) {
+            return Err(Error::Error(format!("table {} already exists", uuid)));
+        }
+
+        self.catalog.apply(&Edit::CreateTable(table.clone()))?;
+        self.pending_actions.push(Action::RestoreTable(entry));
+
+        Ok(table)
+    }
+
+    /// Purge dropped tables whose retention period has expired and clean up
+    /// empty prefixes left behind. Returns the purged entries.
+    pub async fn gc(&self) -> Result<Vec<TrashEntry>, Error> {
+        let now = trash::unix_now();
+
+        let mut purged = vec![];
+        for entry in self.trash().await? {
+            if !entry.is_expired(now) {
+                continue;
+            }
+
+            self.purge_trash_entry(&entry.table.uuid).await?;
+            purged.push(entry);
+        }
+
+        self.storage.remove_empty_prefixes(&Path::default()).await?;
+
+        Ok(purged)
+    }
+
+    async fn purge_trash_entry(&self, uuid: &uuid::Uuid) -> Result<(), Error> {
+        let entry_prefix = trash::entry_prefix(uuid);
+        let objects = self
+            .storage
+            .list(Some(&entry_prefix))
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?;
+        for meta in objects {
+            self.storage.delete(&meta.location).await?;
+        }
+
+        self.storage.remove_empty_prefixes(&entry_prefix).await?;
+
+        Ok(())
+    }
+
+    /// Move every object under the `from` prefix to the `to` prefix.
+    async fn move_objects(&self, from: &Path, to: &Path) -> Result<(), Error> {
+        let objects = self
+            .storage
+            .list(Some(from))
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?;
+        for meta in objects {
+            let Some(parts) = meta.location.prefix_match(from) else {
+                continue;
+            };
+            let location = parts.fold(to.clone(), |path, part| path.child(part));
+
+            self.storage.rename(&meta.location, &location).await?;
+        }
+
+        Ok(())
     }
 
     fn store_for_table(
@@ -207,15 +488,19 @@ impl EnsembleX {
 
         let store = Arc::new(PrefixStore::new(
             self.storage.clone(),
-            object_store::path::Path::parse(namespace)
-                .unwrap()
-                .child(object_store::path::PathPart::parse(name).unwrap()),
+            table_path(namespace, name),
         ));
 
         (store, location)
     }
 }
 
+fn table_path(namespace: &str, name: &str) -> Path {
+    Path::parse(namespace)
+        .unwrap()
+        .child(object_store::path::PathPart::parse(name).unwrap())
+}
+
 fn map_type(dt: &sqlparser::ast::DataType) -> SchemaDataType {
     match dt {
         sqlparser::ast::DataType::Integer(_) => SchemaDataType::primitive("integer".to_string()),
@@ -225,18 +510,584 @@ fn map_type(dt: &sqlparser::ast::DataType) -> SchemaDataType {
 }
 
 impl TableX {
-    pub async fn write(&self, input: SendableRecordBatchStream) -> Result<(), Error> {
-        let mut table = self.inner.lock().await;
-        let mut writer = RecordBatchWriter::for_table(&table)?;
+    fn current(&self) -> TableSnapshot {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Make `table` the current snapshot. Only called by writers, holding
+    /// the writer lock.
+    fn publish(&self, table: DeltaTable) -> Result<(), Error> {
+        *self.current.write().unwrap() = TableSnapshot::new(table)?;
+
+        Ok(())
+    }
+
+    /// Append the rows of `input` in a single commit, rolling over to new
+    /// data files as set by the [`WriteOptions`] of the ensemble.
+    pub async fn write(&self, input: SendableRecordBatchStream) -> Result<WriteMetrics, Error> {
+        let _writer = self.writer.lock().await;
+        let mut table = copy_table(&self.current().table);
+        let (num_rows, adds) = self.write_files(&table, input).await?;
+
+        let metrics = WriteMetrics {
+            num_rows,
+            num_files: adds.len() as u64,
+            num_bytes: adds.iter().map(|add| add.size as u64).sum(),
+        };
+        if adds.is_empty() {
+            return Ok(metrics);
+        }
+
+        let actions = adds.into_iter().map(DeltaAction::add).collect::<Vec<_>>();
+        transaction::commit(
+            table.object_store().as_ref(),
+            &actions,
+            DeltaOperation::Write {
+                mode: SaveMode::Append,
+                partition_by: None,
+                predicate: None,
+            },
+            table.get_state(),
+            None,
+        )
+        .await?;
+        table.update().await?;
+        self.publish(table)?;
+
+        Ok(metrics)
+    }
+
+    /// Write the rows of `input` to new data files of `table`, without
+    /// committing them. Returns the number of rows with the files.
+    async fn write_files(
+        &self,
+        table: &DeltaTable,
+        input: SendableRecordBatchStream,
+    ) -> Result<(u64, Vec<Add>), Error> {
+        let mut writer = RecordBatchWriter::for_table(table)?;
         let mut schema_adapter = SchemaAdapterStream::new(input, writer.arrow_schema());
 
+        let mut num_rows = 0;
+        let mut adds = vec![];
+        let mut buffered_bytes = 0;
+        let mut last_flush = Instant::now();
         while let Some(batch) = schema_adapter.next().await {
-            writer.write(batch.unwrap()).await?;
+            let batch = batch?;
+            num_rows += batch.num_rows() as u64;
+            buffered_bytes += batch.get_array_memory_size();
+            writer.write(batch).await?;
+
+            let flush_due = self
+                .write_options
+                .flush_interval
+                .is_some_and(|interval| last_flush.elapsed() >= interval);
+            if buffered_bytes >= self.write_options.target_file_size || flush_due {
+                adds.extend(writer.flush().await?);
+                buffered_bytes = 0;
+                last_flush = Instant::now();
+            }
+        }
+        adds.extend(writer.flush().await?);
+
+        Ok((num_rows, adds))
+    }
+
+    /// Write the data files of `change` to be committed later, e.g. with
+    /// changes to other tables.
+    pub async fn prepare(self: &Arc<Self>, change: TableChange) -> Result<PreparedX, Error> {
+        let current = self.snapshot();
+        let stream = |rows: Vec<RecordBatch>| -> Result<SendableRecordBatchStream, Error> {
+            let schema = match rows.first() {
+                Some(batch) => batch.schema(),
+                None => current.get_state().arrow_schema()?,
+            };
+            Ok(Box::pin(MemoryStream::try_new(rows, schema, None)?))
+        };
+
+        let (snapshot, rows, operation) = match change {
+            TableChange::Append(rows) => (None, rows, None),
+            TableChange::Overwrite {
+                snapshot,
+                rows,
+                operation,
+            } => {
+                let snapshot = copy_table(delta_table(&snapshot)?);
+                (Some(snapshot), rows, Some(operation))
+            }
+        };
+
+        let (_, adds) = self
+            .write_files(snapshot.as_ref().unwrap_or(&current), stream(rows)?)
+            .await?;
+        let removes = snapshot
+            .as_ref()
+            .map(|snapshot| snapshot.get_state().files().to_vec())
+            .unwrap_or_default();
+
+        Ok(PreparedX {
+            table: self.clone(),
+            snapshot,
+            operation,
+            adds,
+            removes,
+        })
+    }
+
+    /// Load the commits made since the current snapshot, e.g. by other
+    /// processes.
+    pub async fn update(&self) -> Result<(), Error> {
+        // The log is read without holding up writers.
+        let current = self.snapshot();
+        let mut table = copy_table(&current);
+        table.update().await?;
+        if table.version() == current.version() {
+            return Ok(());
+        }
+        let updated = TableSnapshot::new(table)?;
+
+        // Writers may have published a later version in the meantime.
+        let _writer = self.writer.lock().await;
+        if updated.table.version() > self.snapshot().version() {
+            *self.current.write().unwrap() = updated;
         }
-        writer.flush_and_commit(&mut table).await?;
 
         Ok(())
     }
+
+    /// The table as it is now, unaffected by later writes.
+    pub fn snapshot(&self) -> Arc<DeltaTable> {
+        self.current().table
+    }
+
+    /// The table as it was at `version`.
+    pub async fn load_version(&self, version: i64) -> Result<DeltaTable, Error> {
+        let mut table = copy_table(&self.snapshot());
+        table.load_version(version).await?;
+
+        Ok(table)
+    }
+
+    /// The table at the latest version committed at or before `datetime`.
+    pub async fn load_with_datetime(&self, datetime: DateTime<Utc>) -> Result<DeltaTable, Error> {
+        let mut table = copy_table(&self.snapshot());
+        table.load_with_datetime(datetime).await?;
+
+        // Times before the first commit resolve to the first commit. Commit
+        // times are compared in seconds, like the lookup does.
+        let committed_at = DeltaTableState::from_commit(&table, table.version())
+            .await
+            .map_err(DeltaTableError::from)?
+            .commit_infos()
+            .iter()
+            .find_map(|info| info.timestamp);
+        if committed_at.is_some_and(|committed_at| committed_at / 1000 > datetime.timestamp()) {
+            return Err(Error::Error(format!(
+                "table has no version at or before {datetime}"
+            )));
+        }
+
+        Ok(table)
+    }
+
+    /// Commits of the table, newest first. Commits whose log entries were
+    /// cleaned up are not listed.
+    pub async fn history(&self, limit: Option<usize>) -> Result<Vec<TableCommit>, Error> {
+        let mut table = copy_table(&self.snapshot());
+        table.update().await?;
+
+        let mut commits = vec![];
+        for version in (0..=table.version()).rev() {
+            if limit.is_some_and(|limit| commits.len() >= limit) {
+                break;
+            }
+
+            let state = match DeltaTableState::from_commit(&table, version).await {
+                Ok(state) => state,
+                Err(ApplyLogError::EndOfLog) => break,
+                Err(e) => return Err(DeltaTableError::from(e).into()),
+            };
+            commits.extend(state.commit_infos().iter().map(|info| {
+                TableCommit {
+                    version,
+                    timestamp: info.timestamp,
+                    operation: info.operation.clone(),
+                    operation_parameters: info
+                        .operation_parameters
+                        .as_ref()
+                        .and_then(|parameters| serde_json::to_string(parameters).ok()),
+                    conductor_operation: info
+                        .info
+                        .get(METADATA_OPERATION)
+                        .and_then(|operation| operation.as_str())
+                        .map(String::from),
+                }
+            }));
+        }
+
+        Ok(commits)
+    }
+
+    /// Rewrite small data files into larger ones.
+    pub async fn optimize(&self) -> Result<OptimizeMetrics, Error> {
+        let _writer = self.writer.lock().await;
+        let table = self.snapshot();
+
+        let (new_table, metrics) =
+            OptimizeBuilder::new(table.object_store(), table.state.clone()).await?;
+        self.publish(new_table)?;
+
+        Ok(OptimizeMetrics {
+            num_files_added: metrics.num_files_added,
+            num_files_removed: metrics.num_files_removed,
+        })
+    }
+
+    /// Delete data files that were removed from the table longer than
+    /// `retention` ago, by default the retention of the table. Returns the
+    /// deleted files, or the files that would be deleted if `dry_run`.
+    pub async fn vacuum(
+        &self,
+        retention: Option<Duration>,
+        dry_run: bool,
+    ) -> Result<Vec<String>, Error> {
+        let retention = retention.unwrap_or(self.retention);
+        if retention < self.retention {
+            return Err(Error::Error(format!(
+                "retention of {} hours is shorter than the table retention of {} hours",
+                retention.as_secs() / 3600,
+                self.retention.as_secs() / 3600
+            )));
+        }
+
+        let mut table = copy_table(&self.snapshot());
+        table.update().await?;
+
+        let (_, metrics) = VacuumBuilder::new(table.object_store(), table.state.clone())
+            .with_retention_period(
+                chrono::Duration::from_std(retention).map_err(|e| Error::Error(e.to_string()))?,
+            )
+            // The table retention is enforced above, rather than the one in
+            // the Delta table configuration.
+            .with_enforce_retention_duration(false)
+            .with_dry_run(dry_run)
+            .await?;
+
+        Ok(metrics.files_deleted)
+    }
+
+    /// Delete the rows matching `predicate`, or all rows if there is none.
+    /// Returns the number of deleted rows.
+    pub async fn delete(&self, predicate: Option<Expr>) -> Result<usize, Error> {
+        let _writer = self.writer.lock().await;
+        let table = self.snapshot();
+
+        let mut builder = DeleteBuilder::new(table.object_store(), table.state.clone());
+        if let Some(predicate) = predicate {
+            builder = builder.with_predicate(predicate);
+        }
+        let (new_table, metrics) = builder.await?;
+
+        let num_deleted_rows = match metrics.num_deleted_rows {
+            Some(num_deleted_rows) => num_deleted_rows,
+            // Whole files were removed without being scanned, so count their
+            // rows from the file statistics instead.
+            None => {
+                let remaining = new_table
+                    .get_state()
+                    .files()
+                    .iter()
+                    .map(|add| add.path.as_str())
+                    .collect::<HashSet<_>>();
+                table
+                    .get_state()
+                    .files()
+                    .iter()
+                    .filter(|add| !remaining.contains(add.path.as_str()))
+                    .map(num_records)
+                    .sum()
+            }
+        };
+
+        self.publish(new_table)?;
+
+        Ok(num_deleted_rows)
+    }
+
+    /// Replace the rows of `snapshot` with `input` in a single commit. Rows
+    /// written after the snapshot was taken are kept. `operation` is recorded
+    /// in the commit info.
+    pub async fn overwrite(
+        &self,
+        snapshot: &DeltaTable,
+        input: SendableRecordBatchStream,
+        operation: &str,
+    ) -> Result<(), Error> {
+        let _writer = self.writer.lock().await;
+        let (_, adds) = self.write_files(snapshot, input).await?;
+
+        self.commit_overwrite(snapshot, &adds, snapshot.get_state().files(), operation)
+            .await
+    }
+
+    /// Commit adding the files `adds` and removing the files `removes` of
+    /// `snapshot`, then publish the new version. Only called by writers,
+    /// holding the writer lock.
+    async fn commit_overwrite(
+        &self,
+        snapshot: &DeltaTable,
+        adds: &[Add],
+        removes: &[Add],
+        operation: &str,
+    ) -> Result<(), Error> {
+        let deletion_timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as i64)
+            .unwrap_or_default();
+
+        let mut actions = adds
+            .iter()
+            .cloned()
+            .map(DeltaAction::add)
+            .collect::<Vec<_>>();
+        actions.extend(removes.iter().map(|add| {
+            DeltaAction::remove(Remove {
+                path: add.path.clone(),
+                deletion_timestamp: Some(deletion_timestamp),
+                data_change: true,
+                extended_file_metadata: Some(true),
+                partition_values: Some(add.partition_values.clone()),
+                size: Some(add.size),
+                tags: None,
+            })
+        }));
+
+        if actions.is_empty() {
+            return Ok(());
+        }
+
+        let operation_metadata = [(METADATA_OPERATION.to_string(), json!(operation))];
+        transaction::commit(
+            snapshot.object_store().as_ref(),
+            &actions,
+            DeltaOperation::Write {
+                mode: SaveMode::Overwrite,
+                partition_by: None,
+                predicate: None,
+            },
+            snapshot.get_state(),
+            Some(operation_metadata.into_iter().collect()),
+        )
+        .await?;
+
+        let mut table = copy_table(&self.snapshot());
+        table.update().await?;
+        self.publish(table)
+    }
+}
+
+/// A change to a [`TableX`] whose data files are written, but not committed.
+pub struct PreparedX {
+    table: Arc<TableX>,
+    /// The snapshot an overwrite replaces the rows of, `None` for appends.
+    snapshot: Option<DeltaTable>,
+    operation: Option<String>,
+    adds: Vec<Add>,
+    removes: Vec<Add>,
+}
+
+impl PreparedX {
+    pub async fn commit(&self) -> Result<(), Error> {
+        let _writer = self.table.writer.lock().await;
+
+        match (&self.snapshot, &self.operation) {
+            (Some(snapshot), Some(operation)) => {
+                self.table
+                    .commit_overwrite(snapshot, &self.adds, &self.removes, operation)
+                    .await
+            }
+            _ => {
+                if self.adds.is_empty() {
+                    return Ok(());
+                }
+
+                let mut table = copy_table(&self.table.snapshot());
+                let actions = self
+                    .adds
+                    .iter()
+                    .cloned()
+                    .map(DeltaAction::add)
+                    .collect::<Vec<_>>();
+                transaction::commit(
+                    table.object_store().as_ref(),
+                    &actions,
+                    DeltaOperation::Write {
+                        mode: SaveMode::Append,
+                        partition_by: None,
+                        predicate: None,
+                    },
+                    table.get_state(),
+                    None,
+                )
+                .await?;
+                table.update().await?;
+                self.table.publish(table)
+            }
+        }
+    }
+
+    /// Commit removing the files the change added and adding back those it
+    /// removed.
+    pub async fn revert(&self) -> Result<(), Error> {
+        let _writer = self.table.writer.lock().await;
+        let mut current = copy_table(&self.table.snapshot());
+        current.update().await?;
+
+        self.table
+            .commit_overwrite(&current, &self.removes, &self.adds, "REVERT")
+            .await
+    }
+}
+
+/// A copy of `table` that can be updated or committed to on its own.
+fn copy_table(table: &DeltaTable) -> DeltaTable {
+    let mut copy = DeltaTable::new(table.object_store(), Default::default());
+    copy.state = table.state.clone();
+
+    copy
+}
+
+/// The delta table of a snapshot taken with [`TableX::snapshot`].
+fn delta_table(snapshot: &Arc<dyn TableProvider>) -> Result<&DeltaTable, Error> {
+    snapshot
+        .as_any()
+        .downcast_ref::<DeltaTable>()
+        .ok_or_else(|| Error::Error("snapshot is not a delta table".to_string()))
+}
+
+fn num_records(add: &Add) -> usize {
+    add.get_stats()
+        .ok()
+        .flatten()
+        .map(|stats| stats.num_records as usize)
+        .unwrap_or_default()
+}
+
+#[async_trait]
+impl Ensemble for EnsembleX {
+    fn catalog(&self) -> Result<Catalog, ensemble::Error> {
+        Ok(EnsembleX::catalog(self)?)
+    }
+
+    async fn apply(&mut self, edit: &Edit) -> Result<(), ensemble::Error> {
+        Ok(EnsembleX::apply(self, edit).await?)
+    }
+
+    async fn commit(&mut self) -> Result<(), ensemble::Error> {
+        Ok(EnsembleX::commit(self).await?)
+    }
+
+    async fn table(
+        &self,
+        namespace: &str,
+        name: &str,
+    ) -> Result<Arc<dyn EnsembleTable>, ensemble::Error> {
+        Ok(EnsembleX::table(self, namespace, name).await?)
+    }
+}
+
+#[async_trait]
+impl EnsembleTable for TableX {
+    async fn write(
+        &self,
+        input: SendableRecordBatchStream,
+    ) -> Result<WriteMetrics, ensemble::Error> {
+        Ok(TableX::write(self, input).await?)
+    }
+
+    async fn delete(&self, predicate: Option<Expr>) -> Result<usize, ensemble::Error> {
+        Ok(TableX::delete(self, predicate).await?)
+    }
+
+    async fn snapshot(&self) -> Result<Arc<dyn TableProvider>, ensemble::Error> {
+        Ok(TableX::snapshot(self))
+    }
+
+    async fn overwrite(
+        &self,
+        snapshot: Arc<dyn TableProvider>,
+        input: SendableRecordBatchStream,
+        operation: &str,
+    ) -> Result<(), ensemble::Error> {
+        Ok(TableX::overwrite(self, delta_table(&snapshot)?, input, operation).await?)
+    }
+
+    async fn located_snapshot(
+        &self,
+        snapshot: Arc<dyn TableProvider>,
+    ) -> Result<Option<Arc<dyn TableProvider>>, ensemble::Error> {
+        let snapshot = copy_table(delta_table(&snapshot)?);
+
+        Ok(Some(Arc::new(LocatedSnapshot::new(snapshot))))
+    }
+
+    async fn snapshot_part(
+        &self,
+        snapshot: Arc<dyn TableProvider>,
+        locations: &[String],
+    ) -> Result<Arc<dyn TableProvider>, ensemble::Error> {
+        let part = located_part(delta_table(&snapshot)?, locations).map_err(Error::from)?;
+
+        Ok(Arc::new(part))
+    }
+
+    async fn prepare(
+        self: Arc<Self>,
+        change: TableChange,
+    ) -> Result<Box<dyn PreparedChange>, ensemble::Error> {
+        Ok(Box::new(TableX::prepare(&self, change).await?))
+    }
+
+    async fn load_version(&self, version: i64) -> Result<Arc<dyn TableProvider>, ensemble::Error> {
+        Ok(Arc::new(TableX::load_version(self, version).await?))
+    }
+
+    async fn load_with_datetime(
+        &self,
+        datetime: DateTime<Utc>,
+    ) -> Result<Arc<dyn TableProvider>, ensemble::Error> {
+        Ok(Arc::new(TableX::load_with_datetime(self, datetime).await?))
+    }
+
+    async fn history(&self, limit: Option<usize>) -> Result<Vec<TableCommit>, ensemble::Error> {
+        Ok(TableX::history(self, limit).await?)
+    }
+
+    async fn optimize(&self) -> Result<OptimizeMetrics, ensemble::Error> {
+        Ok(TableX::optimize(self).await?)
+    }
+
+    async fn vacuum(
+        &self,
+        retention: Option<Duration>,
+        dry_run: bool,
+    ) -> Result<Vec<String>, ensemble::Error> {
+        Ok(TableX::vacuum(self, retention, dry_run).await?)
+    }
+}
+
+#[async_trait]
+impl PreparedChange for PreparedX {
+    fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+        self
+    }
+
+    async fn commit(&mut self) -> Result<(), ensemble::Error> {
+        Ok(PreparedX::commit(self).await?)
+    }
+
+    async fn revert(&mut self) -> Result<(), ensemble::Error> {
+        Ok(PreparedX::revert(self).await?)
+    }
 }
 
 #[async_trait]
@@ -246,10 +1097,7 @@ impl TableProvider for TableX {
     }
 
     fn schema(&self) -> SchemaRef {
-        futures::executor::block_on(async {
-            let table = self.inner.lock().await;
-            table.get_state().arrow_schema().unwrap()
-        })
+        self.current().schema
     }
 
     fn table_type(&self) -> TableType {
@@ -267,7 +1115,7 @@ impl TableProvider for TableX {
         // The datasource should return *at least* this number of rows if available.
         limit: Option<usize>,
     ) -> datafusion::error::Result<Arc<dyn ExecutionPlan>> {
-        let table = self.inner.lock().await;
+        let table = self.snapshot();
         table.scan(state, projection, filters, limit).await
     }
 
@@ -275,19 +1123,12 @@ impl TableProvider for TableX {
         &self,
         filter: &Expr,
     ) -> datafusion::error::Result<TableProviderFilterPushDown> {
-        futures::executor::block_on(async {
-            let table = self.inner.lock().await;
-
-            #[allow(deprecated)]
-            table.supports_filter_pushdown(filter)
-        })
+        #[allow(deprecated)]
+        self.snapshot().supports_filter_pushdown(filter)
     }
 
     fn statistics(&self) -> Option<Statistics> {
-        futures::executor::block_on(async {
-            let table = self.inner.lock().await;
-            table.statistics()
-        })
+        self.current().statistics
     }
 }
 
@@ -307,6 +1148,11 @@ impl SchemaAdapterStream {
 
         for field in self.schema.fields() {
             match schema.index_of(field.name()) {
+                // Partition columns are dictionary encoded when read, so
+                // values read from the table come back in a different type.
+                Ok(field_ix) if schema.field(field_ix).data_type() != field.data_type() => {
+                    columns.push(cast(batch.column(field_ix), field.data_type())?)
+                }
                 Ok(field_ix) => columns.push(batch.column(field_ix).clone()),
                 Err(_) => {
                     columns.push(datafusion::arrow::array::new_null_array(
@@ -339,3 +1185,257 @@ impl Stream for SchemaAdapterStream {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use catalog::{edit::Edit, Column, Table};
+    use datafusion::{
+        arrow::{array::Int32Array, record_batch::RecordBatch},
+        datasource::TableProvider,
+        physical_plan::memory::MemoryStream,
+        prelude::SessionContext,
+    };
+    use ensemble::TableChange;
+    use object_store::local::LocalFileSystem;
+    use sqlparser::ast::DataType;
+
+    use crate::{storage::ObjectStore, trash, EnsembleX, TableX, WriteOptions};
+
+    /// An ensemble in `storage` with namespace `ns` and its `tables`, each
+    /// with an integer column `id`.
+    async fn ensemble(storage: ObjectStore, tables: &[&str]) -> EnsembleX {
+        let mut ensemble = EnsembleX::new(storage).await.unwrap();
+        ensemble
+            .apply(&Edit::CreateNamespace {
+                name: "ns".to_string(),
+            })
+            .await
+            .unwrap();
+        for name in tables {
+            let table = Table {
+                namespace: "ns".to_string(),
+                uuid: uuid::Uuid::new_v4(),
+                name: name.to_string(),
+                columns: vec![Column {
+                    uid: 1,
+                    name: "id".to_string(),
+                    data_type: DataType::Integer(None),
+                }],
+                partition_by: vec![],
+                retention_hours: None,
+                policies: vec![],
+            };
+            ensemble.apply(&Edit::CreateTable(table)).await.unwrap();
+        }
+        ensemble.commit().await.unwrap();
+
+        ensemble
+    }
+
+    fn rows(table: &TableX, ids: Vec<i32>) -> RecordBatch {
+        RecordBatch::try_new(table.schema(), vec![Arc::new(Int32Array::from(ids))]).unwrap()
+    }
+
+    async fn write(table: &TableX, ids: Vec<i32>) {
+        let input = MemoryStream::try_new(vec![rows(table, ids)], table.schema(), None).unwrap();
+        table.write(Box::pin(input)).await.unwrap();
+    }
+
+    /// The ids of the rows of `table`, sorted.
+    async fn ids(table: Arc<dyn TableProvider>) -> Vec<i32> {
+        let batches = SessionContext::new()
+            .read_table(table)
+            .unwrap()
+            .collect()
+            .await
+            .unwrap();
+        let mut ids = batches
+            .iter()
+            .flat_map(|b| {
+                let ids = b.column(0).as_any().downcast_ref::<Int32Array>().unwrap();
+                ids.values().to_vec()
+            })
+            .collect::<Vec<_>>();
+        ids.sort();
+
+        ids
+    }
+
+    #[tokio::test]
+    async fn test_write_rolls_over_files() {
+        let ensemble = ensemble(ObjectStore::in_memory(), &["t"])
+            .await
+            .with_write_options(WriteOptions {
+                target_file_size: 1,
+                flush_interval: None,
+            });
+
+        let table = ensemble.table("ns", "t").await.unwrap();
+        let batch = rows(&table, vec![1, 2]);
+        let input = MemoryStream::try_new(
+            vec![batch.clone(), batch.clone(), batch],
+            table.schema(),
+            None,
+        )
+        .unwrap();
+
+        let metrics = table.write(Box::pin(input)).await.unwrap();
+        assert_eq!(metrics.num_rows, 6);
+        assert_eq!(metrics.num_files, 3);
+
+        let snapshot = table.snapshot();
+        assert_eq!(snapshot.version(), 1);
+        assert_eq!(snapshot.get_files().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_commit_all() {
+        let ensemble = ensemble(ObjectStore::in_memory(), &["t", "u"]).await;
+        let t = ensemble.table("ns", "t").await.unwrap();
+        let u = ensemble.table("ns", "u").await.unwrap();
+        write(&t, vec![1]).await;
+        write(&u, vec![1]).await;
+
+        let t_change = t
+            .prepare(TableChange::Append(vec![rows(&t, vec![2])]))
+            .await
+            .unwrap();
+        let u_change = u
+            .prepare(TableChange::Overwrite {
+                snapshot: u.snapshot(),
+                rows: vec![rows(&u, vec![9])],
+                operation: "DELETE".to_string(),
+            })
+            .await
+            .unwrap();
+
+        // The rows the overwrite replaces are deleted in the meantime, so it
+        // conflicts and the append to the first table is reverted.
+        u.delete(None).await.unwrap();
+        write(&t, vec![3]).await;
+        ensemble::commit_all(vec![Box::new(t_change), Box::new(u_change)])
+            .await
+            .unwrap_err();
+        assert_eq!(ids(t.snapshot()).await, [1, 3]);
+        assert_eq!(ids(u.snapshot()).await, Vec::<i32>::new());
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_isolation() {
+        let ensemble = ensemble(ObjectStore::in_memory(), &["t"]).await;
+        let table = ensemble.table("ns", "t").await.unwrap();
+        write(&table, vec![1]).await;
+
+        // A reader keeps the version it started with while a writer commits.
+        let snapshot = table.snapshot();
+        let (_, read) = tokio::join!(write(&table, vec![2]), ids(snapshot.clone()));
+        assert_eq!(read, [1]);
+        assert_eq!(ids(snapshot).await, [1]);
+        assert_eq!(ids(table.snapshot()).await, [1, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_update() {
+        let storage = ObjectStore::in_memory();
+        let table = ensemble(storage.clone(), &["t"])
+            .await
+            .table("ns", "t")
+            .await
+            .unwrap();
+        write(&table, vec![1]).await;
+
+        // Another process commits to the same table.
+        let other = EnsembleX::new(storage).await.unwrap();
+        write(&other.table("ns", "t").await.unwrap(), vec![2]).await;
+        assert_eq!(ids(table.snapshot()).await, [1]);
+
+        table.update().await.unwrap();
+        assert_eq!(table.snapshot().version(), 2);
+        assert_eq!(ids(table.snapshot()).await, [1, 2]);
+
+        // Commits after an update build on the commits it picked up.
+        write(&table, vec![3]).await;
+        assert_eq!(ids(table.snapshot()).await, [1, 2, 3]);
+    }
+
+    /// Drop table `name` of namespace `ns`, returning it.
+    async fn drop_table(ensemble: &mut EnsembleX, name: &str) -> Table {
+        let table = ensemble.catalog().unwrap().namespaces["ns"].tables[name].clone();
+        ensemble
+            .apply(&Edit::DropTable(table.clone()))
+            .await
+            .unwrap();
+        ensemble.commit().await.unwrap();
+
+        table
+    }
+
+    #[tokio::test]
+    async fn test_restore_table() {
+        let mut ensemble = ensemble(ObjectStore::in_memory(), &["t"]).await;
+        write(&ensemble.table("ns", "t").await.unwrap(), vec![1, 2]).await;
+
+        let dropped = drop_table(&mut ensemble, "t").await;
+        let trash = ensemble.trash().await.unwrap();
+        assert_eq!(
+            trash.iter().map(|e| e.table.uuid).collect::<Vec<_>>(),
+            [dropped.uuid]
+        );
+        assert!(!trash[0].is_expired(trash::unix_now()));
+
+        // A table of the same name has to be dropped before restoring.
+        let mut recreated = dropped.clone();
+        recreated.uuid = uuid::Uuid::new_v4();
+        ensemble.apply(&Edit::CreateTable(recreated)).await.unwrap();
+        ensemble.commit().await.unwrap();
+        ensemble.restore_table(&dropped.uuid).await.unwrap_err();
+        drop_table(&mut ensemble, "t").await;
+
+        ensemble.restore_table(&dropped.uuid).await.unwrap();
+        ensemble.commit().await.unwrap();
+        let t = ensemble.table("ns", "t").await.unwrap();
+        assert_eq!(ids(t.snapshot()).await, [1, 2]);
+        assert_eq!(ensemble.trash().await.unwrap().len(), 1);
+        ensemble.restore_table(&dropped.uuid).await.unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn test_gc() {
+        let dir = std::env::temp_dir().join(format!("ensemble-x-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        let storage = ObjectStore::new(
+            Arc::new(LocalFileSystem::new_with_prefix(&dir).unwrap()),
+            url::Url::from_directory_path(&dir).unwrap(),
+            false,
+        );
+
+        let mut ensemble = ensemble(storage, &["t", "u"])
+            .await
+            .with_trash_retention(Duration::ZERO);
+        write(&ensemble.table("ns", "t").await.unwrap(), vec![1]).await;
+        let expired = drop_table(&mut ensemble, "t").await;
+        let mut ensemble = ensemble.with_trash_retention(trash::DEFAULT_TRASH_RETENTION);
+        let retained = drop_table(&mut ensemble, "u").await;
+        assert!(!dir.join("ns").join("t").exists());
+
+        // Only expired tables are purged, along with their directories.
+        let purged = ensemble.gc().await.unwrap();
+        assert_eq!(
+            purged.iter().map(|e| e.table.uuid).collect::<Vec<_>>(),
+            [expired.uuid]
+        );
+        let trash_dir = dir.join("_conductor_trash");
+        assert!(!trash_dir.join(expired.uuid.to_string()).exists());
+        assert!(trash_dir.join(retained.uuid.to_string()).exists());
+        ensemble.restore_table(&expired.uuid).await.unwrap_err();
+        ensemble.restore_table(&retained.uuid).await.unwrap();
+        ensemble.commit().await.unwrap();
+
+        assert!(ensemble.gc().await.unwrap().is_empty());
+        assert!(!trash_dir.exists());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
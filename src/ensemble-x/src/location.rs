@@ -0,0 +1,222 @@
+//! Where the rows of a table are stored, so that UPDATE and MERGE only
+//! rewrite the data files with rows they change.
+
+use std::{collections::HashSet, sync::Arc};
+
+use async_trait::async_trait;
+use datafusion::{
+    arrow::{
+        array::{ArrayRef, StringArray},
+        compute::cast,
+        datatypes::{DataType, Field, Schema, SchemaRef},
+        record_batch::{RecordBatch, RecordBatchOptions},
+    },
+    datasource::{streaming::PartitionStream, TableProvider},
+    error::{DataFusionError, Result as DFResult},
+    execution::context::{SessionState, TaskContext},
+    logical_expr::TableType,
+    physical_plan::{
+        stream::RecordBatchStreamAdapter, streaming::StreamingTableExec, ExecutionPlan,
+        SendableRecordBatchStream,
+    },
+    prelude::Expr,
+};
+use deltalake::{
+    action::{Action as DeltaAction, Add, MetaData, Protocol},
+    table_state::DeltaTableState,
+    DeltaTable, DeltaTableError,
+};
+use ensemble::LOCATION_COLUMN;
+use futures::{Stream, StreamExt, TryStreamExt};
+
+/// A snapshot of a table with the path of the data file of each row in
+/// [`LOCATION_COLUMN`].
+pub(crate) struct LocatedSnapshot {
+    table: Arc<DeltaTable>,
+    schema: SchemaRef,
+}
+
+impl LocatedSnapshot {
+    pub(crate) fn new(table: DeltaTable) -> Self {
+        let mut fields = TableProvider::schema(&table).fields().to_vec();
+        fields.push(Arc::new(Field::new(LOCATION_COLUMN, DataType::Utf8, false)));
+
+        Self {
+            table: Arc::new(table),
+            schema: Arc::new(Schema::new(fields)),
+        }
+    }
+}
+
+#[async_trait]
+impl TableProvider for LocatedSnapshot {
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+
+    fn table_type(&self) -> TableType {
+        TableType::Base
+    }
+
+    async fn scan(
+        &self,
+        state: &SessionState,
+        projection: Option<&Vec<usize>>,
+        _filters: &[Expr],
+        _limit: Option<usize>,
+    ) -> DFResult<Arc<dyn ExecutionPlan>> {
+        let projection = projection
+            .cloned()
+            .unwrap_or_else(|| (0..self.schema.fields().len()).collect());
+        let schema = Arc::new(self.schema.project(&projection)?);
+        let scan = LocatedScan {
+            table: self.table.clone(),
+            // The location column comes after those of the table.
+            location_index: self.schema.fields().len() - 1,
+            state: state.clone(),
+            projection,
+            schema: schema.clone(),
+        };
+
+        Ok(Arc::new(StreamingTableExec::try_new(
+            schema,
+            vec![Arc::new(scan)],
+            None,
+            false,
+        )?))
+    }
+}
+
+/// The `projection` of the rows of a [`LocatedSnapshot`], read a data file at
+/// a time.
+#[derive(Clone)]
+struct LocatedScan {
+    table: Arc<DeltaTable>,
+    location_index: usize,
+    state: SessionState,
+    projection: Vec<usize>,
+    schema: SchemaRef,
+}
+
+impl PartitionStream for LocatedScan {
+    fn schema(&self) -> &SchemaRef {
+        &self.schema
+    }
+
+    fn execute(&self, ctx: Arc<TaskContext>) -> SendableRecordBatchStream {
+        let scan = Arc::new(self.clone());
+        let files = self.table.get_state().files().clone();
+        let batches = futures::stream::iter(files)
+            .then(move |add| scan.clone().file_batches(add, ctx.clone()))
+            .try_flatten();
+
+        Box::pin(RecordBatchStreamAdapter::new(self.schema.clone(), batches))
+    }
+}
+
+impl LocatedScan {
+    /// The rows of data file `add`.
+    async fn file_batches(
+        self: Arc<Self>,
+        add: Add,
+        ctx: Arc<TaskContext>,
+    ) -> DFResult<impl Stream<Item = DFResult<RecordBatch>>> {
+        let part =
+            table_part(&self.table, [&add]).map_err(|e| DataFusionError::External(Box::new(e)))?;
+        let mut file_projection = self
+            .projection
+            .iter()
+            .copied()
+            .filter(|i| *i != self.location_index)
+            .collect::<Vec<_>>();
+        // Data files can't be read without any columns, so one is read for
+        // the row count and left out again by `with_location`.
+        if file_projection.is_empty() {
+            file_projection.push(0);
+        }
+        let plan = part
+            .scan(&self.state, Some(&file_projection), &[], None)
+            .await?;
+        let streams = (0..plan.output_partitioning().partition_count())
+            .map(|i| plan.execute(i, ctx.clone()))
+            .collect::<DFResult<Vec<_>>>()?;
+
+        Ok(futures::stream::iter(streams)
+            .flatten()
+            .map(move |batch| self.with_location(batch?, &add.path)))
+    }
+
+    /// The columns of `batch`, read from the data file at `path`, with the
+    /// location column inserted where the projection has it.
+    fn with_location(&self, batch: RecordBatch, path: &str) -> DFResult<RecordBatch> {
+        let mut file_columns = batch.columns().iter();
+        let columns = self
+            .projection
+            .iter()
+            .zip(self.schema.fields())
+            .map(|(i, field)| match *i == self.location_index {
+                true => Ok(Arc::new(StringArray::from(vec![path; batch.num_rows()])) as ArrayRef),
+                false => {
+                    let column = file_columns.next().ok_or_else(|| {
+                        DataFusionError::Internal("missing column of data file".to_string())
+                    })?;
+                    Ok(cast(column, field.data_type())?)
+                }
+            })
+            .collect::<DFResult<Vec<_>>>()?;
+
+        Ok(RecordBatch::try_new_with_options(
+            self.schema.clone(),
+            columns,
+            &RecordBatchOptions::new().with_row_count(Some(batch.num_rows())),
+        )?)
+    }
+}
+
+/// The files of `table` at `locations`, as a table of their own at the same
+/// version.
+pub(crate) fn located_part(
+    table: &DeltaTable,
+    locations: &[String],
+) -> Result<DeltaTable, DeltaTableError> {
+    let locations = locations.iter().map(String::as_str).collect::<HashSet<_>>();
+    let files = table.get_state().files().iter();
+
+    table_part(
+        table,
+        files.filter(|add| locations.contains(add.path.as_str())),
+    )
+}
+
+/// `table` with only the data files `files`. Commits made with it as the
+/// snapshot only conflict with changes to those files.
+fn table_part<'a>(
+    table: &DeltaTable,
+    files: impl IntoIterator<Item = &'a Add>,
+) -> Result<DeltaTable, DeltaTableError> {
+    let state = table.get_state();
+    let mut actions = vec![
+        DeltaAction::protocol(Protocol {
+            min_reader_version: state.min_reader_version(),
+            min_writer_version: state.min_writer_version(),
+        }),
+        DeltaAction::metaData(MetaData::try_from(table.get_metadata()?.clone())?),
+    ];
+    // Paths are decoded once more when the actions are applied.
+    actions.extend(files.into_iter().map(|add| {
+        DeltaAction::add(Add {
+            path: add.path.replace('%', "%25"),
+            ..add.clone()
+        })
+    }));
+
+    let mut part = DeltaTable::new(table.object_store(), Default::default());
+    part.state = DeltaTableState::from_actions(actions, table.version())
+        .map_err(|e| DeltaTableError::Generic(e.to_string()))?;
+
+    Ok(part)
+}
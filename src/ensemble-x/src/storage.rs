@@ -1,4 +1,4 @@
-use std::{ops::Range, sync::Arc};
+use std::{io, ops::Range, path::PathBuf, sync::Arc};
 
 use async_trait::async_trait;
 use bytes::Bytes;
@@ -26,9 +26,70 @@ impl ObjectStore {
         }
     }
 
+    /// A store kept in memory, for tests and local development. Clones share
+    /// the same data, which is gone once the last of them is dropped.
+    pub fn in_memory() -> Self {
+        Self::new(
+            Arc::new(object_store::memory::InMemory::new()),
+            url::Url::parse("memory:///").unwrap(),
+            false,
+        )
+    }
+
     pub fn location(&self) -> &url::Url {
         &self.location
     }
+
+    /// Filesystem path of `path` when the store is backed by a local
+    /// directory.
+    fn local_path(&self, path: &Path) -> Option<PathBuf> {
+        if self.location.scheme() != "file" {
+            return None;
+        }
+
+        let mut local_path = self.location.to_file_path().ok()?;
+        local_path.extend(path.parts().map(|part| part.as_ref().to_string()));
+
+        Some(local_path)
+    }
+
+    /// Remove empty directories under `prefix`, including `prefix` itself
+    /// unless it is the root of the store. Object stores have no real
+    /// directories, so this is a no-op for anything but the local filesystem.
+    pub async fn remove_empty_prefixes(&self, prefix: &Path) -> io::Result<()> {
+        let Some(local_path) = self.local_path(prefix) else {
+            return Ok(());
+        };
+        let remove_self = prefix.parts().next().is_some();
+
+        // Walking the directories blocks, so it runs on a blocking thread.
+        tokio::task::spawn_blocking(move || {
+            if local_path.is_dir() {
+                remove_empty_dirs(&local_path, remove_self)?;
+            }
+
+            Ok(())
+        })
+        .await
+        .map_err(io::Error::other)?
+    }
+}
+
+fn remove_empty_dirs(dir: &std::path::Path, remove_self: bool) -> io::Result<bool> {
+    let mut empty = true;
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() && remove_empty_dirs(&entry.path(), true)? {
+            continue;
+        }
+        empty = false;
+    }
+
+    if empty && remove_self {
+        std::fs::remove_dir(dir)?;
+    }
+
+    Ok(empty)
 }
 
 #[async_trait]
@@ -98,6 +159,19 @@ impl object_store::ObjectStore for ObjectStore {
         self.inner.copy(from, to).await
     }
 
+    async fn rename(&self, from: &Path, to: &Path) -> object_store::Result<()> {
+        // The local filesystem store doesn't create missing parent directories
+        // on rename.
+        if let Some(parent) = self.local_path(to).as_deref().and_then(|p| p.parent()) {
+            std::fs::create_dir_all(parent).map_err(|e| object_store::Error::Generic {
+                store: "LocalFileSystem",
+                source: Box::new(e),
+            })?;
+        }
+
+        self.inner.rename(from, to).await
+    }
+
     async fn copy_if_not_exists(&self, from: &Path, to: &Path) -> object_store::Result<()> {
         if self.unsafe_rename {
             return self.inner.copy(from, to).await;
@@ -0,0 +1,64 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use catalog::Table;
+use object_store::path::Path;
+use serde::{Deserialize, Serialize};
+
+/// Dropped tables are kept for a week unless configured otherwise.
+pub const DEFAULT_TRASH_RETENTION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+const TRASH_PATH: &str = "_conductor_trash";
+const TRASH_ENTRY_FILE: &str = "entry.json";
+const TRASH_DATA_PREFIX: &str = "table";
+
+/// A dropped table waiting in the trash.
+///
+/// Objects of a dropped table are moved to `_conductor_trash/<uuid>/table/`,
+/// and the entry is stored next to them in `_conductor_trash/<uuid>/entry.json`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrashEntry {
+    pub table: Table,
+    /// Seconds since the unix epoch.
+    pub dropped_at: u64,
+    /// Seconds since the unix epoch.
+    pub expires_at: u64,
+}
+
+impl TrashEntry {
+    pub(crate) fn new(table: Table, retention: Duration) -> Self {
+        let dropped_at = unix_now();
+
+        Self {
+            table,
+            dropped_at,
+            expires_at: dropped_at.saturating_add(retention.as_secs()),
+        }
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        now >= self.expires_at
+    }
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+pub(crate) fn trash_path() -> Path {
+    Path::from(TRASH_PATH)
+}
+
+pub(crate) fn entry_prefix(uuid: &uuid::Uuid) -> Path {
+    trash_path().child(uuid.to_string())
+}
+
+pub(crate) fn entry_path(uuid: &uuid::Uuid) -> Path {
+    entry_prefix(uuid).child(TRASH_ENTRY_FILE)
+}
+
+pub(crate) fn data_prefix(uuid: &uuid::Uuid) -> Path {
+    entry_prefix(uuid).child(TRASH_DATA_PREFIX)
+}
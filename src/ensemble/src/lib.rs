@@ -0,0 +1,333 @@
+//! The interface conductor, the SQL session and ostinator use to reach the
+//! data of an ensemble, so that storage backends can be plugged in.
+
+use std::{any::Any, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use catalog::{edit::Edit, Catalog};
+use chrono::{DateTime, Utc};
+use datafusion::{
+    arrow::record_batch::RecordBatch, datasource::TableProvider, error::DataFusionError,
+    physical_plan::SendableRecordBatchStream, prelude::Expr,
+};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("{0} is not supported by this ensemble")]
+    Unsupported(String),
+    #[error("catalog error: {0}")]
+    CatalogError(#[from] catalog::Error),
+    #[error("datafusion error: {0}")]
+    DataFusionError(#[from] DataFusionError),
+    #[error("{0}")]
+    Backend(Box<dyn std::error::Error + Send + Sync>),
+}
+
+/// Column of [`EnsembleTable::located_snapshot`] telling where each row is
+/// stored.
+pub const LOCATION_COLUMN: &str = "__ensemble_location";
+
+fn unsupported<T>(operation: &str) -> Result<T, Error> {
+    Err(Error::Unsupported(operation.to_string()))
+}
+
+/// Where the catalog and the data of its tables are kept.
+///
+/// Edits are applied to the catalog right away, and to the data on
+/// [`Ensemble::commit`].
+#[async_trait]
+pub trait Ensemble: Send + Sync {
+    fn catalog(&self) -> Result<Catalog, Error>;
+
+    async fn apply(&mut self, edit: &Edit) -> Result<(), Error>;
+
+    async fn commit(&mut self) -> Result<(), Error>;
+
+    async fn table(&self, namespace: &str, name: &str) -> Result<Arc<dyn EnsembleTable>, Error>;
+}
+
+/// A table of an ensemble. Backends implement what they can beyond
+/// appending rows, the rest fails with [`Error::Unsupported`].
+#[async_trait]
+pub trait EnsembleTable: TableProvider {
+    /// Append the rows of `input`.
+    async fn write(&self, input: SendableRecordBatchStream) -> Result<WriteMetrics, Error>;
+
+    /// Delete the rows matching `predicate`, or all rows if there is none.
+    /// Returns the number of deleted rows.
+    async fn delete(&self, _predicate: Option<Expr>) -> Result<usize, Error> {
+        unsupported("DELETE")
+    }
+
+    /// The table as it is now, unaffected by later writes.
+    async fn snapshot(&self) -> Result<Arc<dyn TableProvider>, Error> {
+        unsupported("snapshot")
+    }
+
+    /// Replace the rows of `snapshot`, taken with [`EnsembleTable::snapshot`],
+    /// with `input`. `operation` names the statement doing it.
+    async fn overwrite(
+        &self,
+        _snapshot: Arc<dyn TableProvider>,
+        _input: SendableRecordBatchStream,
+        _operation: &str,
+    ) -> Result<(), Error> {
+        unsupported("overwrite")
+    }
+
+    /// `snapshot` with a [`LOCATION_COLUMN`] telling where each row is
+    /// stored, e.g. its data file, so that an overwrite can be limited to the
+    /// locations of the rows it changes with [`EnsembleTable::snapshot_part`].
+    /// None if the backend doesn't keep rows apart.
+    async fn located_snapshot(
+        &self,
+        _snapshot: Arc<dyn TableProvider>,
+    ) -> Result<Option<Arc<dyn TableProvider>>, Error> {
+        Ok(None)
+    }
+
+    /// The rows of `snapshot` stored at `locations`, taken from
+    /// [`EnsembleTable::located_snapshot`], as a snapshot of its own.
+    async fn snapshot_part(
+        &self,
+        _snapshot: Arc<dyn TableProvider>,
+        _locations: &[String],
+    ) -> Result<Arc<dyn TableProvider>, Error> {
+        unsupported("snapshot parts")
+    }
+
+    /// Get `change` ready to be committed together with changes to other
+    /// tables, e.g. by writing its data files. Nothing is visible before
+    /// [`PreparedChange::commit`].
+    async fn prepare(
+        self: Arc<Self>,
+        _change: TableChange,
+    ) -> Result<Box<dyn PreparedChange>, Error> {
+        unsupported("transactions")
+    }
+
+    /// The table as it was at `version`.
+    async fn load_version(&self, _version: i64) -> Result<Arc<dyn TableProvider>, Error> {
+        unsupported("VERSION AS OF")
+    }
+
+    /// The table as it was at `datetime`.
+    async fn load_with_datetime(
+        &self,
+        _datetime: DateTime<Utc>,
+    ) -> Result<Arc<dyn TableProvider>, Error> {
+        unsupported("TIMESTAMP AS OF")
+    }
+
+    /// Commits of the table, newest first.
+    async fn history(&self, _limit: Option<usize>) -> Result<Vec<TableCommit>, Error> {
+        unsupported("DESCRIBE HISTORY")
+    }
+
+    /// Rewrite small data files into larger ones.
+    async fn optimize(&self) -> Result<OptimizeMetrics, Error> {
+        unsupported("OPTIMIZE")
+    }
+
+    /// Delete data files that were removed from the table longer than
+    /// `retention` ago, by default the retention of the table. Returns the
+    /// deleted files, or the files that would be deleted if `dry_run`.
+    async fn vacuum(
+        &self,
+        _retention: Option<Duration>,
+        _dry_run: bool,
+    ) -> Result<Vec<String>, Error> {
+        unsupported("VACUUM")
+    }
+}
+
+/// A change a transaction made to a table.
+pub enum TableChange {
+    /// Append `rows`.
+    Append(Vec<RecordBatch>),
+    /// Replace the rows of `snapshot`, taken with [`EnsembleTable::snapshot`],
+    /// with `rows`. `operation` names the statements doing it.
+    Overwrite {
+        snapshot: Arc<dyn TableProvider>,
+        rows: Vec<RecordBatch>,
+        operation: String,
+    },
+}
+
+/// A [`TableChange`] ready to be committed.
+#[async_trait]
+pub trait PreparedChange: Send {
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+
+    /// Take over `other`, to commit it together with this change in a
+    /// single transaction of the backend. Returns false if the backend can't,
+    /// e.g. because `other` changes a table of another backend.
+    fn absorb(&mut self, _other: &mut dyn PreparedChange) -> bool {
+        false
+    }
+
+    async fn commit(&mut self) -> Result<(), Error>;
+
+    /// Undo the change once committed, because a change to another table of
+    /// the same transaction failed to commit. Changes committed by others in
+    /// the meantime are kept.
+    async fn revert(&mut self) -> Result<(), Error>;
+}
+
+/// Commit `changes`, all or nothing. Changes a backend can commit in a
+/// single transaction are committed that way. The others are committed in
+/// order, and when one fails those committed before it are reverted, so
+/// readers may see them in the meantime.
+pub async fn commit_all(changes: Vec<Box<dyn PreparedChange>>) -> Result<(), Error> {
+    let mut combined: Vec<Box<dyn PreparedChange>> = vec![];
+    for mut change in changes {
+        if !combined.iter_mut().any(|c| c.absorb(change.as_mut())) {
+            combined.push(change);
+        }
+    }
+
+    for i in 0..combined.len() {
+        let Err(e) = combined[i].commit().await else {
+            continue;
+        };
+
+        for committed in combined[..i].iter_mut().rev() {
+            if let Err(revert_error) = committed.revert().await {
+                return Err(Error::Backend(
+                    format!("{e}, then reverting the committed changes failed: {revert_error}")
+                        .into(),
+                ));
+            }
+        }
+        return Err(e);
+    }
+
+    Ok(())
+}
+
+/// A commit in the history of a table.
+#[derive(Debug, Clone)]
+pub struct TableCommit {
+    pub version: i64,
+    /// Milliseconds since the unix epoch.
+    pub timestamp: Option<i64>,
+    pub operation: Option<String>,
+    /// Parameters of the operation, as JSON.
+    pub operation_parameters: Option<String>,
+    /// The statement that made the commit, for commits made by a SQL session.
+    pub conductor_operation: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct WriteMetrics {
+    pub num_rows: u64,
+    /// Data files added, for backends that keep rows in files.
+    pub num_files: u64,
+    pub num_bytes: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct OptimizeMetrics {
+    pub num_files_added: u64,
+    pub num_files_removed: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// Records what happens to the changes of a test, by name.
+    type Log = Arc<Mutex<Vec<String>>>;
+
+    struct TestChange {
+        names: Vec<&'static str>,
+        /// Changes with the same group are committed together.
+        group: Option<u32>,
+        fail: bool,
+        log: Log,
+    }
+
+    #[async_trait]
+    impl PreparedChange for TestChange {
+        fn as_any_mut(&mut self) -> &mut dyn Any {
+            self
+        }
+
+        fn absorb(&mut self, other: &mut dyn PreparedChange) -> bool {
+            match other.as_any_mut().downcast_mut::<TestChange>() {
+                Some(other) if self.group.is_some() && other.group == self.group => {
+                    self.names.append(&mut other.names);
+                    self.fail |= other.fail;
+                    true
+                }
+                _ => false,
+            }
+        }
+
+        async fn commit(&mut self) -> Result<(), Error> {
+            let names = self.names.join("+");
+            if self.fail {
+                return Err(Error::Backend(format!("{names} failed").into()));
+            }
+            self.log.lock().unwrap().push(format!("commit {names}"));
+            Ok(())
+        }
+
+        async fn revert(&mut self) -> Result<(), Error> {
+            let names = self.names.join("+");
+            self.log.lock().unwrap().push(format!("revert {names}"));
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_commit_all() {
+        let log = Log::default();
+        let change = |name, group, fail| {
+            Box::new(TestChange {
+                names: vec![name],
+                group,
+                fail,
+                log: log.clone(),
+            }) as Box<dyn PreparedChange>
+        };
+
+        commit_all(vec![
+            change("a", None, false),
+            change("b", Some(1), false),
+            change("c", Some(1), false),
+        ])
+        .await
+        .unwrap();
+        assert_eq!(*log.lock().unwrap(), ["commit a", "commit b+c"]);
+
+        // Changes committed before the failing one are reverted.
+        log.lock().unwrap().clear();
+        let e = commit_all(vec![
+            change("a", None, false),
+            change("b", None, false),
+            change("c", None, true),
+            change("d", None, false),
+        ])
+        .await
+        .unwrap_err();
+        assert_eq!(e.to_string(), "c failed");
+        assert_eq!(
+            *log.lock().unwrap(),
+            ["commit a", "commit b", "revert b", "revert a"]
+        );
+
+        // Changes committed together fail together.
+        log.lock().unwrap().clear();
+        commit_all(vec![
+            change("a", Some(1), false),
+            change("b", Some(1), true),
+        ])
+        .await
+        .unwrap_err();
+        assert!(log.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,173 @@
+//! Authentication of requests by the authentication policies of their
+//! namespace.
+//!
+//! A request presenting a key, as `Authorization: Bearer <key>` or
+//! `X-Api-Key: <key>`, is made by the principal of the key if it belongs to
+//! one of the `api_key` policies, and is rejected otherwise. A bearer token
+//! that isn't a key is verified by the `jwt` policy of its issuer, and the
+//! request is made by its subject. A request without credentials is
+//! anonymous, which is only allowed by an `anonymous` policy.
+
+use std::{path::Path, sync::Arc};
+
+use axum::http::{header::AUTHORIZATION, HeaderMap, HeaderName};
+use catalog::{
+    api_key::{self, ApiKey},
+    auth::AuthMethod,
+    ApiKeySource, AuthenticationPolicyType, Namespace,
+};
+use datafusion::prelude::{col, lit, SessionContext};
+use ensemble::Ensemble;
+
+use crate::{
+    cache::CachedEnsemble,
+    error::Error,
+    jwt::{self, Claims},
+};
+
+pub(crate) const API_KEY_HEADER: HeaderName = HeaderName::from_static("x-api-key");
+
+/// Who a request is made by.
+#[derive(Debug, Default)]
+pub(crate) struct Identity {
+    /// None if anonymous.
+    pub(crate) principal: Option<String>,
+    pub(crate) method: AuthMethod,
+    /// The claims of the token the request was authenticated with.
+    pub(crate) claims: Option<Claims>,
+}
+
+pub(crate) async fn authenticate(
+    ensemble: &CachedEnsemble,
+    namespace: &Namespace,
+    headers: &HeaderMap,
+) -> Result<Identity, Error> {
+    let Some(credentials) = presented_credentials(headers) else {
+        let anonymous_access_allowed = namespace
+            .authentication_policies
+            .values()
+            .any(|p| matches!(p.typ, AuthenticationPolicyType::Anonymous()));
+
+        return match anonymous_access_allowed {
+            true => Ok(Identity::default()),
+            false => Err(Error::Unauthenticated(
+                "the namespace doesn't allow anonymous access".to_string(),
+            )),
+        };
+    };
+    let key = match credentials {
+        Credentials::Bearer(token) if api_key::parse_key(token).is_none() => {
+            return authenticate_token(ensemble, namespace, token)
+        }
+        Credentials::Bearer(key) | Credentials::ApiKey(key) => key,
+    };
+
+    let invalid_key = || Error::Unauthenticated("invalid API key".to_string());
+    let (key_id, secret) = api_key::parse_key(key).ok_or_else(invalid_key)?;
+
+    for policy in namespace.authentication_policies.values() {
+        let AuthenticationPolicyType::ApiKey(source) = &policy.typ else {
+            continue;
+        };
+
+        let keys = load_keys(ensemble, &namespace.name, source, key_id).await?;
+        if let Some(api_key) = keys.iter().find(|k| k.key_id == key_id) {
+            return match api_key.verify(secret) {
+                true => Ok(Identity {
+                    principal: Some(api_key.principal.clone()),
+                    method: AuthMethod::ApiKey,
+                    claims: None,
+                }),
+                false => Err(invalid_key()),
+            };
+        }
+    }
+
+    Err(invalid_key())
+}
+
+/// Verify `token` with the `jwt` policy of its issuer.
+fn authenticate_token(
+    ensemble: &CachedEnsemble,
+    namespace: &Namespace,
+    token: &str,
+) -> Result<Identity, Error> {
+    let invalid_token = |e: jwt::JwtError| Error::Unauthenticated(format!("invalid token: {e}"));
+
+    let issuer = jwt::issuer(token).ok_or_else(|| invalid_token(jwt::JwtError::Malformed))?;
+    let (name, policy) = namespace
+        .authentication_policies
+        .values()
+        .find_map(|p| match &p.typ {
+            AuthenticationPolicyType::Jwt(jwt) if jwt.issuer == issuer => Some((&p.name, jwt)),
+            _ => None,
+        })
+        .ok_or_else(|| invalid_token(jwt::JwtError::WrongIssuer(Some(issuer))))?;
+
+    let keys = ensemble
+        .jwt_keys
+        .get(&(namespace.name.clone(), name.clone()))
+        .ok_or_else(|| Error::Internal(format!("keys of policy {name} not loaded")))?
+        .as_ref()
+        .map_err(|e| Error::Internal(e.clone()))?;
+    let claims = jwt::verify(token, policy, keys).map_err(invalid_token)?;
+
+    Ok(Identity {
+        principal: claims
+            .get("sub")
+            .and_then(|sub| sub.as_str())
+            .map(str::to_string),
+        method: AuthMethod::Jwt,
+        claims: Some(claims),
+    })
+}
+
+enum Credentials<'a> {
+    Bearer(&'a str),
+    ApiKey(&'a str),
+}
+
+fn presented_credentials(headers: &HeaderMap) -> Option<Credentials<'_>> {
+    let bearer = headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split_once(' '))
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+        .map(|(_, token)| Credentials::Bearer(token.trim()));
+
+    bearer.or_else(|| {
+        headers
+            .get(API_KEY_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .map(|key| Credentials::ApiKey(key.trim()))
+    })
+}
+
+/// The keys of `source` with id `key_id`, checked for changes on every
+/// request so that revoked keys stop working as soon as the server sees the
+/// change.
+async fn load_keys(
+    ensemble: &CachedEnsemble,
+    namespace: &str,
+    source: &ApiKeySource,
+    key_id: &str,
+) -> Result<Arc<Vec<ApiKey>>, Error> {
+    match source {
+        ApiKeySource::File(path) => ensemble
+            .file_keys(Path::new(path))
+            .await
+            .map_err(|e| Error::Internal(format!("reading keys file {path} failed: {e}"))),
+        ApiKeySource::Table(name) => {
+            let snapshot = ensemble.table(namespace, name).await?.snapshot().await?;
+            let batches = SessionContext::new()
+                .read_table(snapshot)?
+                .filter(col("key_id").eq(lit(key_id)))?
+                .collect()
+                .await?;
+
+            api_key::keys_from_batches(&batches)
+                .map(Arc::new)
+                .map_err(|e| Error::Internal(format!("reading keys table {name} failed: {e}")))
+        }
+    }
+}
@@ -0,0 +1,276 @@
+//! Micro-batching of the rows inserted by http handlers with a batch window.
+//!
+//! Rows are buffered per table. A buffer is written in a single commit once
+//! the earliest window of the requests in it has passed, or once it holds
+//! `max_bytes` of rows, whichever comes first. Requests are answered only
+//! after the commit that contains their rows.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use datafusion::{arrow::record_batch::RecordBatch, physical_plan::memory::MemoryStream};
+use tokio::{
+    sync::oneshot,
+    time::{sleep_until, Instant},
+};
+use tracing::{info, warn};
+
+use ensemble::Ensemble;
+
+use crate::AppState;
+
+/// Namespace and name of a table.
+type TableKey = (String, String);
+
+pub(crate) struct Batcher {
+    max_bytes: usize,
+    buffers: Mutex<HashMap<TableKey, Buffer>>,
+    next_buffer_id: Mutex<u64>,
+}
+
+struct Buffer {
+    /// Tells the buffer apart from those created for the same table after
+    /// it was flushed.
+    id: u64,
+    deadline: Instant,
+    batches: Vec<RecordBatch>,
+    num_bytes: usize,
+    acks: Vec<oneshot::Sender<Result<(), String>>>,
+}
+
+impl Batcher {
+    pub(crate) fn new(max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            buffers: Mutex::new(HashMap::new()),
+            next_buffer_id: Mutex::new(0),
+        }
+    }
+
+    /// Buffer `batches` to be inserted into `table` within `window`, and
+    /// wait until they are committed.
+    pub(crate) async fn insert(
+        &self,
+        state: &Arc<AppState>,
+        table: TableKey,
+        batches: Vec<RecordBatch>,
+        window: Duration,
+    ) -> Result<()> {
+        let (ack, acked) = oneshot::channel();
+        let deadline = Instant::now() + window;
+
+        let (full, scheduled) = {
+            let mut buffers = self.buffers.lock().unwrap();
+            let mut scheduled = None;
+            let buffer = buffers.entry(table.clone()).or_insert_with(|| {
+                let id = self.next_buffer_id();
+                scheduled = Some((id, deadline));
+                Buffer {
+                    id,
+                    deadline,
+                    batches: vec![],
+                    num_bytes: 0,
+                    acks: vec![],
+                }
+            });
+
+            // A request with a shorter window than the ones already buffered
+            // brings the flush forward.
+            if deadline < buffer.deadline {
+                buffer.deadline = deadline;
+                scheduled = Some((buffer.id, deadline));
+            }
+            buffer.num_bytes += batches
+                .iter()
+                .map(|b| b.get_array_memory_size())
+                .sum::<usize>();
+            buffer.batches.extend(batches);
+            buffer.acks.push(ack);
+
+            let full = (buffer.num_bytes >= self.max_bytes).then_some(buffer.id);
+            (full, scheduled)
+        };
+
+        // Flushes run on their own, so that they complete even if the
+        // request that started them goes away.
+        let flush = match (full, scheduled) {
+            (Some(id), _) => Some((id, Instant::now())),
+            (None, scheduled) => scheduled,
+        };
+        if let Some((id, deadline)) = flush {
+            let state = state.clone();
+            tokio::spawn(async move {
+                sleep_until(deadline).await;
+                state.batcher.flush(&state, &table, id).await;
+            });
+        }
+
+        acked
+            .await
+            .map_err(|_| anyhow!("batch was dropped before it was written"))?
+            .map_err(|e| anyhow!(e))
+    }
+
+    /// Write the buffer of `table`, unless it was already written.
+    async fn flush(&self, state: &AppState, table: &TableKey, id: u64) {
+        let buffer = {
+            let mut buffers = self.buffers.lock().unwrap();
+            match buffers.get(table) {
+                Some(buffer) if buffer.id == id => buffers.remove(table).unwrap(),
+                _ => return,
+            }
+        };
+
+        self.write(state, table, buffer).await;
+    }
+
+    /// Write all buffers, e.g. on shutdown.
+    pub(crate) async fn flush_all(&self, state: &AppState) {
+        let buffers = std::mem::take(&mut *self.buffers.lock().unwrap());
+
+        for (table, buffer) in buffers {
+            self.write(state, &table, buffer).await;
+        }
+    }
+
+    async fn write(&self, state: &AppState, table: &TableKey, buffer: Buffer) {
+        let num_requests = buffer.acks.len();
+        let result = write_batches(state, table, buffer.batches).await;
+
+        match &result {
+            Ok(()) => info!(?table, num_requests, "batch written"),
+            Err(e) => warn!(?table, num_requests, error = %e, "batch failed"),
+        }
+        for ack in buffer.acks {
+            // The request may have gone away in the meantime.
+            let _ = ack.send(result.as_ref().map(|_| ()).map_err(|e| e.to_string()));
+        }
+    }
+
+    fn next_buffer_id(&self) -> u64 {
+        let mut next_buffer_id = self.next_buffer_id.lock().unwrap();
+        *next_buffer_id += 1;
+        *next_buffer_id
+    }
+}
+
+async fn write_batches(
+    state: &AppState,
+    table: &TableKey,
+    batches: Vec<RecordBatch>,
+) -> Result<()> {
+    let Some(schema) = batches.first().map(|b| b.schema()) else {
+        return Ok(());
+    };
+
+    let ensemble_table = state.ensemble().table(&table.0, &table.1).await?;
+    let input = MemoryStream::try_new(batches, schema, None)?;
+    ensemble_table.write(Box::pin(input)).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use datafusion::arrow::{array::Int32Array, record_batch::RecordBatch};
+    use ensemble::Ensemble;
+    use tokio::time::timeout;
+
+    use super::TableKey;
+    use crate::{
+        tests::{ids, test_state},
+        AppState,
+    };
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    fn table(name: &str) -> TableKey {
+        ("ns".to_string(), name.to_string())
+    }
+
+    fn rows(ids: Vec<i32>) -> Vec<RecordBatch> {
+        let ids = Arc::new(Int32Array::from(ids));
+        vec![RecordBatch::try_from_iter([("id", ids as _)]).unwrap()]
+    }
+
+    /// Insert `ids` into `ns.t` within `window`.
+    async fn insert(state: Arc<AppState>, ids: Vec<i32>, window: Duration) -> anyhow::Result<()> {
+        let insert = state.batcher.insert(&state, table("t"), rows(ids), window);
+        timeout(TIMEOUT, insert).await?
+    }
+
+    /// The ids in `ns.t`, sorted, and the number of commits to it.
+    async fn table_state(state: &AppState) -> (Vec<i32>, usize) {
+        let table = state.ensemble().table("ns", "t").await.unwrap();
+
+        (
+            ids(table.snapshot().await.unwrap()).await,
+            table.history(None).await.unwrap().len(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_flush_by_window() {
+        let state = test_state(usize::MAX).await;
+
+        let first = tokio::spawn(insert(state.clone(), vec![1], Duration::from_secs(5)));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(table_state(&state).await, (vec![], 1));
+
+        // The shorter window of the second request brings the flush forward,
+        // and both requests are answered by the same commit.
+        insert(state.clone(), vec![2], Duration::from_millis(100))
+            .await
+            .unwrap();
+        timeout(Duration::from_secs(1), first)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(table_state(&state).await, (vec![1, 2], 2));
+    }
+
+    #[tokio::test]
+    async fn test_flush_by_size() {
+        let num_bytes = rows(vec![1])[0].get_array_memory_size();
+        let state = test_state(2 * num_bytes).await;
+        let window = Duration::from_secs(3600);
+
+        let first = tokio::spawn(insert(state.clone(), vec![1], window));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(table_state(&state).await, (vec![], 1));
+
+        // The second request fills the buffer, which is written right away.
+        insert(state.clone(), vec![2], window).await.unwrap();
+        first.await.unwrap().unwrap();
+        assert_eq!(table_state(&state).await, (vec![1, 2], 2));
+    }
+
+    #[tokio::test]
+    async fn test_failed_write() {
+        let state = test_state(usize::MAX).await;
+        let window = Duration::from_millis(100);
+
+        // Every request waiting on a failed write fails.
+        let insert = |ids| {
+            state
+                .batcher
+                .insert(&state, table("missing"), rows(ids), window)
+        };
+        let (first, second) = timeout(TIMEOUT, async {
+            tokio::join!(insert(vec![1]), insert(vec![2]))
+        })
+        .await
+        .unwrap();
+        assert!(first.unwrap_err().to_string().contains("not found"));
+        assert!(second.unwrap_err().to_string().contains("not found"));
+
+        insert(vec![3]).await.unwrap_err();
+    }
+}
@@ -0,0 +1,250 @@
+//! The catalog and table handles shared by all requests.
+//!
+//! Loading them per request costs a catalog read and a table log replay each
+//! time. Instead they are loaded once and refreshed in the background: the
+//! whole cache is reloaded when `conductor apply` saves a new catalog, and
+//! otherwise the tables catch up with commits made by other processes.
+
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use catalog::{
+    api_key::{self, ApiKey},
+    auth::AuthEval,
+    edit::Edit,
+    AuthenticationPolicyType, Catalog,
+};
+use ensemble::{Ensemble, EnsembleTable};
+use ensemble_x::{storage::ObjectStore, EnsembleX, TableX};
+
+use crate::jwt::JwtKeys;
+
+pub(crate) struct CachedEnsemble {
+    /// Tag of the catalog the cache was loaded from.
+    catalog_tag: Option<String>,
+    pub(crate) catalog: Catalog,
+    /// The authorization policies of the catalog, compiled.
+    pub(crate) auth_eval: AuthEval,
+    /// The keys of the `jwt` authentication policies, by namespace and
+    /// policy, or why they couldn't be loaded.
+    pub(crate) jwt_keys: HashMap<(String, String), Result<JwtKeys, String>>,
+    /// The keys files of `api_key` authentication policies read so far, by
+    /// path.
+    key_files: Mutex<HashMap<PathBuf, KeysFile>>,
+    tables: HashMap<(String, String), Arc<TableX>>,
+}
+
+struct KeysFile {
+    /// Modification time and length of the file when it was read, None if it
+    /// didn't exist.
+    version: Option<(SystemTime, u64)>,
+    keys: Arc<Vec<ApiKey>>,
+}
+
+impl CachedEnsemble {
+    pub(crate) async fn load(storage: &ObjectStore) -> Result<Self> {
+        // Read before the catalog, so that a catalog saved in between is
+        // picked up by the next refresh.
+        let catalog_tag = EnsembleX::catalog_tag(storage).await?;
+        let ensemble = EnsembleX::new(storage.clone()).await?;
+        let catalog = ensemble.catalog()?;
+
+        let mut tables = HashMap::new();
+        let mut jwt_keys = HashMap::new();
+        for (ns_name, ns) in &catalog.namespaces {
+            for table_name in ns.tables.keys() {
+                let table = ensemble.table(ns_name, table_name).await?;
+                tables.insert((ns_name.clone(), table_name.clone()), table);
+            }
+            for policy in ns.authentication_policies.values() {
+                let AuthenticationPolicyType::Jwt(jwt) = &policy.typ else {
+                    continue;
+                };
+                let keys = JwtKeys::load(&jwt.keys).await.map_err(|e| e.to_string());
+                jwt_keys.insert((ns_name.clone(), policy.name.clone()), keys);
+            }
+        }
+
+        Ok(Self {
+            catalog_tag,
+            auth_eval: AuthEval::new(&catalog),
+            jwt_keys,
+            key_files: Mutex::default(),
+            catalog,
+            tables,
+        })
+    }
+
+    /// The keys in keys file `path`. The file is read again once it changes,
+    /// so that revoked keys stop working as soon as the server sees it.
+    pub(crate) async fn file_keys(&self, path: &Path) -> io::Result<Arc<Vec<ApiKey>>> {
+        let version = match tokio::fs::metadata(path).await {
+            Ok(metadata) => Some((metadata.modified()?, metadata.len())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        if let Some(file) = self.key_files.lock().unwrap().get(path) {
+            if file.version == version {
+                return Ok(file.keys.clone());
+            }
+        }
+
+        let keys = match version {
+            Some(_) => {
+                let path = path.to_path_buf();
+                let keys = tokio::task::spawn_blocking(move || api_key::read_keys_file(&path));
+                Arc::new(keys.await??)
+            }
+            None => Arc::default(),
+        };
+        self.key_files.lock().unwrap().insert(
+            path.to_path_buf(),
+            KeysFile {
+                version,
+                keys: keys.clone(),
+            },
+        );
+
+        Ok(keys)
+    }
+
+    /// Whether the catalog in `storage` was saved since the cache was loaded.
+    pub(crate) async fn is_stale(&self, storage: &ObjectStore) -> Result<bool> {
+        Ok(EnsembleX::catalog_tag(storage).await? != self.catalog_tag)
+    }
+
+    /// Load the commits made to the tables since they were cached.
+    pub(crate) async fn update_tables(&self) -> Result<()> {
+        for table in self.tables.values() {
+            table.update().await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Ensemble for CachedEnsemble {
+    fn catalog(&self) -> Result<Catalog, ensemble::Error> {
+        Ok(self.catalog.clone())
+    }
+
+    async fn apply(&mut self, _edit: &Edit) -> Result<(), ensemble::Error> {
+        Err(ensemble::Error::Unsupported(
+            "editing the catalog of a cached ensemble".to_string(),
+        ))
+    }
+
+    async fn commit(&mut self) -> Result<(), ensemble::Error> {
+        Err(ensemble::Error::Unsupported(
+            "editing the catalog of a cached ensemble".to_string(),
+        ))
+    }
+
+    async fn table(
+        &self,
+        namespace: &str,
+        name: &str,
+    ) -> Result<Arc<dyn EnsembleTable>, ensemble::Error> {
+        match self.tables.get(&(namespace.to_string(), name.to_string())) {
+            Some(table) => Ok(table.clone()),
+            None => Err(ensemble::Error::Backend(
+                format!("table {namespace}.{name} not found").into(),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use datafusion::datasource::TableProvider;
+    use datafusion::{
+        arrow::array::Int32Array, arrow::record_batch::RecordBatch,
+        physical_plan::memory::MemoryStream,
+    };
+    use ensemble::Ensemble;
+    use ensemble_x::EnsembleX;
+
+    use catalog::api_key::{self, ApiKey};
+
+    use crate::tests::{create_tables, ids, test_state};
+
+    #[tokio::test]
+    async fn test_reload() {
+        let state = test_state(usize::MAX).await;
+        let ensemble = state.ensemble();
+        assert!(!ensemble.is_stale(&state.object_store).await.unwrap());
+
+        // Another process applies a new catalog.
+        create_tables(&state.object_store, &["u"]).await;
+        assert!(ensemble.is_stale(&state.object_store).await.unwrap());
+
+        state.refresh().await.unwrap();
+        let reloaded = state.ensemble();
+        assert!(!reloaded.is_stale(&state.object_store).await.unwrap());
+        reloaded.table("ns", "u").await.unwrap();
+        // Requests already running keep the ensemble they started with.
+        assert!(ensemble.table("ns", "u").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_update_tables() {
+        let state = test_state(usize::MAX).await;
+        let ensemble = state.ensemble();
+        let table = ensemble.table("ns", "t").await.unwrap();
+        let before = table.snapshot().await.unwrap();
+
+        // Another process writes to the table.
+        let other = EnsembleX::new(state.object_store.clone())
+            .await
+            .unwrap()
+            .table("ns", "t")
+            .await
+            .unwrap();
+        let rows =
+            RecordBatch::try_new(other.schema(), vec![Arc::new(Int32Array::from(vec![1, 2]))])
+                .unwrap();
+        let input = MemoryStream::try_new(vec![rows], other.schema(), None).unwrap();
+        other.write(Box::pin(input)).await.unwrap();
+
+        // The tables catch up without reloading the ensemble.
+        state.refresh().await.unwrap();
+        assert!(Arc::ptr_eq(&ensemble, &state.ensemble()));
+        assert_eq!(ids(table.snapshot().await.unwrap()).await, [1, 2]);
+        assert_eq!(ids(before).await, Vec::<i32>::new());
+    }
+
+    #[tokio::test]
+    async fn test_file_keys() {
+        let state = test_state(usize::MAX).await;
+        let ensemble = state.ensemble();
+        let path = std::env::temp_dir().join(format!("keys-{}.json", uuid::Uuid::new_v4()));
+        assert!(ensemble.file_keys(&path).await.unwrap().is_empty());
+
+        let (mut key, _) = ApiKey::generate("alice");
+        api_key::write_keys_file(&path, &[key.clone()]).unwrap();
+        let keys = ensemble.file_keys(&path).await.unwrap();
+        assert_eq!(keys.len(), 1);
+        // Read once until the file changes.
+        assert!(Arc::ptr_eq(
+            &keys,
+            &ensemble.file_keys(&path).await.unwrap()
+        ));
+
+        key.revoke();
+        api_key::write_keys_file(&path, &[key]).unwrap();
+        let keys = ensemble.file_keys(&path).await.unwrap();
+        assert!(keys[0].revoked_at.is_some());
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
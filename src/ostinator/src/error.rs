@@ -0,0 +1,139 @@
+//! Errors of http handlers and how they are answered.
+//!
+//! Every error is answered with a status code and a JSON body such as
+//! `{"error": {"code": "handler_not_found", "message": "...", "request_id": "..."}}`.
+//! Details of internal errors are only logged, the body just says what kind
+//! of error it was.
+
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use catalog::HttpMethod;
+use datafusion::{arrow::error::ArrowError, error::DataFusionError};
+use serde_json::json;
+
+/// Header carrying the id of a request, taken from the request if it has one.
+pub(crate) const REQUEST_ID_HEADER: &str = "x-request-id";
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum Error {
+    #[error("namespace {0} not found")]
+    NamespaceNotFound(String),
+    #[error("http handler {0} not found")]
+    HandlerNotFound(String),
+    #[error("authorization policy {0} not found")]
+    PolicyNotFound(String),
+    #[error("http handler {0} only accepts {1} requests")]
+    MethodNotAllowed(String, HttpMethod),
+    #[error("none of the formats in {0} can be returned")]
+    NotAcceptable(String),
+    #[error("unauthenticated: {0}")]
+    Unauthenticated(String),
+    /// Why, e.g. `to http handler h denied by policy p`.
+    #[error("access {0}")]
+    Forbidden(String),
+    #[error("bad input: {0}")]
+    BadInput(String),
+    #[error("sql error: {0}")]
+    Sql(sql::Error),
+    #[error("ensemble error: {0}")]
+    Ensemble(#[from] ensemble::Error),
+    #[error("internal error: {0}")]
+    Internal(String),
+}
+
+impl Error {
+    pub(crate) fn status(&self) -> StatusCode {
+        match self {
+            Error::NamespaceNotFound(_) | Error::HandlerNotFound(_) => StatusCode::NOT_FOUND,
+            Error::MethodNotAllowed(..) => StatusCode::METHOD_NOT_ALLOWED,
+            Error::NotAcceptable(_) => StatusCode::NOT_ACCEPTABLE,
+            Error::Unauthenticated(_) => StatusCode::UNAUTHORIZED,
+            Error::Forbidden(_) => StatusCode::FORBIDDEN,
+            Error::BadInput(_) => StatusCode::BAD_REQUEST,
+            // A handler referring to a policy that doesn't exist is a broken
+            // catalog, not a bad request.
+            Error::PolicyNotFound(_) | Error::Sql(_) | Error::Ensemble(_) | Error::Internal(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+
+    pub(crate) fn code(&self) -> &'static str {
+        match self {
+            Error::NamespaceNotFound(_) => "namespace_not_found",
+            Error::HandlerNotFound(_) => "handler_not_found",
+            Error::PolicyNotFound(_) => "policy_not_found",
+            Error::MethodNotAllowed(..) => "method_not_allowed",
+            Error::NotAcceptable(_) => "not_acceptable",
+            Error::Unauthenticated(_) => "unauthenticated",
+            Error::Forbidden(_) => "forbidden",
+            Error::BadInput(_) => "bad_input",
+            Error::Sql(_) => "sql_error",
+            Error::Ensemble(_) => "storage_error",
+            Error::Internal(_) => "internal_error",
+        }
+    }
+}
+
+impl From<sql::Error> for Error {
+    /// Values of the input that can't be converted to the columns they are
+    /// inserted into are the client's fault, anything else is the handler's.
+    fn from(e: sql::Error) -> Self {
+        fn root_arrow_error(e: &DataFusionError) -> Option<&ArrowError> {
+            match e.find_root() {
+                DataFusionError::ArrowError(e) => Some(e),
+                _ => None,
+            }
+        }
+        // The rows are read from the input only once they are written, so
+        // such errors can also come back from the ensemble.
+        let arrow_error = match &e {
+            sql::Error::ArrowError(e) => Some(e),
+            sql::Error::DFError(e)
+            | sql::Error::EnsembleError(ensemble::Error::DataFusionError(e)) => root_arrow_error(e),
+            _ => None,
+        };
+
+        match arrow_error {
+            Some(
+                ArrowError::CastError(_) | ArrowError::ParseError(_) | ArrowError::JsonError(_),
+            ) => Error::BadInput(e.to_string()),
+            _ => Error::Sql(e),
+        }
+    }
+}
+
+impl From<DataFusionError> for Error {
+    fn from(e: DataFusionError) -> Self {
+        Error::from(sql::Error::DFError(e))
+    }
+}
+
+/// An error answered to the request with the given id.
+pub(crate) struct ErrorResponse {
+    pub(crate) error: Error,
+    pub(crate) request_id: String,
+}
+
+impl IntoResponse for ErrorResponse {
+    fn into_response(self) -> Response {
+        let status = self.error.status();
+        let message = if status.is_server_error() {
+            "internal error, see the server logs for this request id".to_string()
+        } else {
+            self.error.to_string()
+        };
+        let body = json!({
+            "error": {
+                "code": self.error.code(),
+                "message": message,
+                "request_id": self.request_id,
+            }
+        });
+
+        (status, [(REQUEST_ID_HEADER, self.request_id)], Json(body)).into_response()
+    }
+}
@@ -1,10 +1,15 @@
+//! The `temporary.input` table of http handlers, holding the body of the
+//! request.
+
 use std::{any::Any, sync::Arc};
 
 use async_trait::async_trait;
+use catalog::InputColumn;
 use datafusion::{
     arrow::{
-        array::StringArray,
+        array::{ArrayRef, DictionaryArray, Int32Array, StringArray},
         datatypes::{DataType, Field, Schema, SchemaRef},
+        json::ReaderBuilder,
         record_batch::RecordBatch,
     },
     datasource::TableProvider,
@@ -13,19 +18,90 @@ use datafusion::{
     physical_plan::{memory::MemoryExec, ExecutionPlan},
     prelude::Expr,
 };
+use serde_json::Value;
+
+use crate::error::Error;
 
 pub(crate) struct HttpHandlerInput {
     schema: SchemaRef,
-    body: String,
+    batches: Vec<RecordBatch>,
 }
 
 impl HttpHandlerInput {
+    /// The whole body as a single row with a `body` column, for handlers
+    /// that don't declare their input.
     pub(crate) fn new(body: String) -> Self {
+        let schema = Arc::new(Schema::new(vec![Field::new("body", DataType::Utf8, true)]));
+        let body_array = StringArray::from(vec![body]);
+        let batch = RecordBatch::try_new(schema.clone(), vec![Arc::new(body_array)]).unwrap();
+
         Self {
-            schema: Arc::new(Schema::new(vec![Field::new("body", DataType::Utf8, true)])),
-            body,
+            schema,
+            batches: vec![batch],
         }
     }
+
+    /// A row per JSON object in `body`, which is either a single object, an
+    /// array of objects or newline-delimited objects. Fields that aren't
+    /// input columns are ignored. Each row also has the whole body in a
+    /// `body` column, dictionary encoded so that the rows share it.
+    pub(crate) fn parse(body: &str, columns: &[InputColumn]) -> Result<Self, Error> {
+        let bad_input = |e: &dyn std::fmt::Display| Error::BadInput(e.to_string());
+
+        let fields = columns
+            .iter()
+            .map(|c| Ok(Field::new(&c.name, c.arrow_type()?, c.nullable)))
+            .collect::<Result<Vec<_>, catalog::Error>>()
+            .map_err(|e| Error::Internal(e.to_string()))?;
+        let input_schema = Arc::new(Schema::new(fields.clone()));
+
+        let rows = if body.trim_start().starts_with('[') {
+            serde_json::from_str::<Vec<Value>>(body).map_err(|e| bad_input(&e))?
+        } else {
+            serde_json::Deserializer::from_str(body)
+                .into_iter::<Value>()
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| bad_input(&e))?
+        };
+        if let Some(row) = rows.iter().find(|row| !row.is_object()) {
+            return Err(Error::BadInput(format!(
+                "expected a JSON object, got {row}"
+            )));
+        }
+
+        let schema = Arc::new(Schema::new(
+            [Field::new(
+                "body",
+                DataType::Dictionary(Box::new(DataType::Int32), Box::new(DataType::Utf8)),
+                true,
+            )]
+            .into_iter()
+            .chain(fields)
+            .collect::<Vec<_>>(),
+        ));
+        let mut decoder = ReaderBuilder::new(input_schema)
+            .build_decoder()
+            .map_err(|e| Error::Internal(e.to_string()))?;
+        let body_value: ArrayRef = Arc::new(StringArray::from(vec![body]));
+        let mut batches = vec![];
+        for chunk in rows.chunks(1024) {
+            decoder.serialize(chunk).map_err(|e| bad_input(&e))?;
+            if let Some(batch) = decoder.flush().map_err(|e| bad_input(&e))? {
+                let keys = Int32Array::from(vec![0; batch.num_rows()]);
+                let body_array = DictionaryArray::new(keys, body_value.clone());
+                let columns = [Arc::new(body_array) as ArrayRef]
+                    .into_iter()
+                    .chain(batch.columns().iter().cloned())
+                    .collect();
+                batches.push(
+                    RecordBatch::try_new(schema.clone(), columns)
+                        .map_err(|e| Error::Internal(e.to_string()))?,
+                );
+            }
+        }
+
+        Ok(Self { schema, batches })
+    }
 }
 
 #[async_trait]
@@ -47,7 +123,7 @@ impl TableProvider for HttpHandlerInput {
     async fn scan(
         &self,
         _state: &SessionState,
-        _projection: Option<&Vec<usize>>,
+        projection: Option<&Vec<usize>>,
         _filters: &[Expr],
         // limit can be used to reduce the amount scanned
         // from the datasource as a performance optimization.
@@ -55,13 +131,100 @@ impl TableProvider for HttpHandlerInput {
         // The datasource should return *at least* this number of rows if available.
         _limit: Option<usize>,
     ) -> datafusion::error::Result<Arc<dyn ExecutionPlan>> {
-        let body_array = StringArray::from(vec![self.body.clone()]);
-        let batch = RecordBatch::try_new(self.schema.clone(), vec![Arc::new(body_array)]).unwrap();
-
         Ok(Arc::new(MemoryExec::try_new(
-            &[vec![batch]],
+            std::slice::from_ref(&self.batches),
             self.schema.clone(),
-            None,
+            projection.cloned(),
         )?))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use datafusion::{
+        arrow::{array::Array, compute::cast, datatypes::Int32Type},
+        sql::sqlparser::ast::DataType as SqlDataType,
+    };
+
+    use super::*;
+
+    fn columns() -> Vec<InputColumn> {
+        vec![
+            InputColumn {
+                name: "id".to_string(),
+                data_type: SqlDataType::Integer(None),
+                nullable: false,
+            },
+            InputColumn {
+                name: "name".to_string(),
+                data_type: SqlDataType::Text,
+                nullable: true,
+            },
+        ]
+    }
+
+    fn num_rows(input: &HttpHandlerInput) -> usize {
+        input.batches.iter().map(|b| b.num_rows()).sum()
+    }
+
+    fn body_array(batch: &RecordBatch) -> &DictionaryArray<Int32Type> {
+        batch.column(0).as_any().downcast_ref().unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        let object = HttpHandlerInput::parse(r#"{"id": 1, "name": "a"}"#, &columns()).unwrap();
+        assert_eq!(num_rows(&object), 1);
+
+        let body = r#"[{"id": 1}, {"id": 2}]"#;
+        let array = HttpHandlerInput::parse(body, &columns()).unwrap();
+        assert_eq!(num_rows(&array), 2);
+        // The body is kept next to the columns it is parsed into.
+        let names = array.schema.fields().iter().map(|f| f.name().as_str());
+        assert_eq!(names.collect::<Vec<_>>(), ["body", "id", "name"]);
+        let bodies = cast(array.batches[0].column(0), &DataType::Utf8).unwrap();
+        let bodies = bodies.as_any().downcast_ref::<StringArray>().unwrap();
+        assert_eq!(bodies.iter().collect::<Vec<_>>(), [Some(body), Some(body)]);
+
+        let ndjson = "{\"id\": 1}\n{\"id\": 2}\n{\"id\": 3}\n";
+        let ndjson = HttpHandlerInput::parse(ndjson, &columns()).unwrap();
+        assert_eq!(num_rows(&ndjson), 3);
+
+        for body in [r#"{"id": "x"}"#, r#"{"name": "a"}"#, "[1]", "{"] {
+            assert!(matches!(
+                HttpHandlerInput::parse(body, &columns()),
+                Err(Error::BadInput(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_parse_many_rows() {
+        let body = (0..100_000)
+            .map(|id| format!("{{\"id\": {id}, \"name\": \"n\"}}\n"))
+            .collect::<String>();
+        let input = HttpHandlerInput::parse(&body, &columns()).unwrap();
+        assert_eq!(num_rows(&input), 100_000);
+
+        // The rows share a single copy of the body.
+        let body_data = |batch| {
+            let values = body_array(batch).values();
+            let values = values.as_any().downcast_ref::<StringArray>().unwrap();
+            values.value_data().as_ptr()
+        };
+        assert!(input
+            .batches
+            .iter()
+            .all(|b| body_data(b) == body_data(&input.batches[0])));
+        let row_size = input
+            .batches
+            .iter()
+            .map(|b| {
+                let columns = b.columns()[1..].iter();
+                body_array(b).keys().get_array_memory_size()
+                    + columns.map(|c| c.get_array_memory_size()).sum::<usize>()
+            })
+            .sum::<usize>();
+        assert!(row_size < body.len(), "{row_size} bytes for the rows");
+    }
+}
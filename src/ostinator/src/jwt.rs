@@ -0,0 +1,510 @@
+//! Validation of JSON Web Tokens by `jwt` authentication policies, with keys
+//! read from local files rather than fetched from the identity provider.
+//! The files are read when the catalog is loaded.
+
+use std::time::SystemTime;
+
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
+use catalog::{JwtAlgorithm, JwtKeySource, JwtPolicy};
+use ring::{hmac, signature};
+use serde_json::{Map, Value};
+use tokio::fs;
+
+/// Clock skew tolerated when checking `exp` and `nbf`, in seconds.
+const LEEWAY_SECS: u64 = 60;
+
+pub(crate) type Claims = Map<String, Value>;
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum JwtError {
+    #[error("malformed token")]
+    Malformed,
+    #[error("algorithm {0} is not allowed")]
+    AlgorithmNotAllowed(String),
+    #[error("no key to verify the token with")]
+    KeyNotFound,
+    #[error("invalid signature")]
+    InvalidSignature,
+    #[error("the token has expired")]
+    Expired,
+    #[error("the token is not valid yet")]
+    NotYetValid,
+    #[error("the token was issued by {0:?}")]
+    WrongIssuer(Option<String>),
+    #[error("the token is not meant for this audience")]
+    WrongAudience,
+    #[error("invalid keys: {0}")]
+    Keys(String),
+}
+
+#[derive(Debug)]
+enum Key {
+    Hmac(Vec<u8>),
+    Rsa {
+        n: Vec<u8>,
+        e: Vec<u8>,
+    },
+    /// An uncompressed P-256 point.
+    EcP256(Vec<u8>),
+}
+
+impl Key {
+    fn verify(&self, algorithm: JwtAlgorithm, message: &[u8], sig: &[u8]) -> bool {
+        match (self, algorithm) {
+            (Key::Hmac(secret), JwtAlgorithm::HS256) => {
+                hmac::verify(&hmac::Key::new(hmac::HMAC_SHA256, secret), message, sig).is_ok()
+            }
+            (Key::Rsa { n, e }, JwtAlgorithm::RS256) => signature::RsaPublicKeyComponents { n, e }
+                .verify(&signature::RSA_PKCS1_2048_8192_SHA256, message, sig)
+                .is_ok(),
+            (Key::EcP256(point), JwtAlgorithm::ES256) => {
+                signature::UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_FIXED, point)
+                    .verify(message, sig)
+                    .is_ok()
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The keys of a policy, with their key ids if they have one.
+#[derive(Debug)]
+pub(crate) struct JwtKeys(Vec<(Option<String>, Key)>);
+
+impl JwtKeys {
+    pub(crate) async fn load(source: &JwtKeySource) -> Result<Self, JwtError> {
+        let (JwtKeySource::JwksFile(path) | JwtKeySource::PemFile(path)) = source;
+        let keys = fs::read_to_string(path)
+            .await
+            .map_err(|e| JwtError::Keys(format!("reading {path}: {e}")))?;
+
+        match source {
+            JwtKeySource::JwksFile(_) => Self::from_jwks(&keys),
+            JwtKeySource::PemFile(_) => Self::from_pem(&keys),
+        }
+    }
+
+    fn from_jwks(jwks: &str) -> Result<Self, JwtError> {
+        let jwks: Value = serde_json::from_str(jwks).map_err(|e| JwtError::Keys(e.to_string()))?;
+        let jwks = jwks
+            .get("keys")
+            .and_then(Value::as_array)
+            .ok_or_else(|| JwtError::Keys("a JWKS must have a keys array".to_string()))?;
+
+        let mut keys = vec![];
+        for jwk in jwks {
+            let field = |name: &str| -> Result<Vec<u8>, JwtError> {
+                jwk.get(name)
+                    .and_then(Value::as_str)
+                    .and_then(|v| URL_SAFE_NO_PAD.decode(v).ok())
+                    .ok_or_else(|| JwtError::Keys(format!("invalid or missing {name} of a key")))
+            };
+            let key = match jwk.get("kty").and_then(Value::as_str) {
+                Some("oct") => Key::Hmac(field("k")?),
+                Some("RSA") => Key::Rsa {
+                    n: strip_leading_zeros(field("n")?),
+                    e: strip_leading_zeros(field("e")?),
+                },
+                Some("EC") if jwk.get("crv").and_then(Value::as_str) == Some("P-256") => {
+                    Key::EcP256([vec![0x04], field("x")?, field("y")?].concat())
+                }
+                // Keys of other types can't verify the allowed algorithms.
+                _ => continue,
+            };
+            let kid = jwk.get("kid").and_then(Value::as_str).map(str::to_string);
+
+            keys.push((kid, key));
+        }
+
+        Ok(JwtKeys(keys))
+    }
+
+    fn from_pem(pem: &str) -> Result<Self, JwtError> {
+        let invalid = || JwtError::Keys("invalid PEM public key".to_string());
+
+        let mut lines = pem.lines().map(str::trim).filter(|l| !l.is_empty());
+        let label = lines
+            .next()
+            .and_then(|l| l.strip_prefix("-----BEGIN "))
+            .and_then(|l| l.strip_suffix("-----"))
+            .ok_or_else(invalid)?
+            .to_string();
+        let body = lines
+            .take_while(|l| !l.starts_with("-----END "))
+            .collect::<String>();
+        let der = STANDARD.decode(body).map_err(|_| invalid())?;
+
+        let key = match label.as_str() {
+            "RSA PUBLIC KEY" => rsa_key(&der).ok_or_else(invalid)?,
+            "PUBLIC KEY" => {
+                // SubjectPublicKeyInfo: the algorithm, then the key itself.
+                let (spki, _) = der_read(&der, 0x30).ok_or_else(invalid)?;
+                let (algorithm, rest) = der_read(spki, 0x30).ok_or_else(invalid)?;
+                let (bits, _) = der_read(rest, 0x03).ok_or_else(invalid)?;
+                let public_key = bits.get(1..).ok_or_else(invalid)?;
+
+                if contains(algorithm, RSA_ENCRYPTION_OID) {
+                    rsa_key(public_key).ok_or_else(invalid)?
+                } else if contains(algorithm, EC_PUBLIC_KEY_OID) && contains(algorithm, P256_OID) {
+                    Key::EcP256(public_key.to_vec())
+                } else {
+                    return Err(JwtError::Keys(
+                        "only RSA and P-256 public keys are supported".to_string(),
+                    ));
+                }
+            }
+            _ => return Err(invalid()),
+        };
+
+        Ok(JwtKeys(vec![(None, key)]))
+    }
+}
+
+/// The `iss` claim of `token`, before it is verified, to pick the policy to
+/// verify it with.
+pub(crate) fn issuer(token: &str) -> Option<String> {
+    let (_, claims, _) = decode(token).ok()?;
+
+    claims.get("iss")?.as_str().map(str::to_string)
+}
+
+/// The claims of `token` if it is valid for `policy`.
+pub(crate) fn verify(token: &str, policy: &JwtPolicy, keys: &JwtKeys) -> Result<Claims, JwtError> {
+    let (header, claims, sig) = decode(token)?;
+
+    let alg = header
+        .get("alg")
+        .and_then(Value::as_str)
+        .ok_or(JwtError::Malformed)?;
+    let algorithm = alg
+        .parse::<JwtAlgorithm>()
+        .ok()
+        .filter(|a| policy.algorithms.contains(a))
+        .ok_or_else(|| JwtError::AlgorithmNotAllowed(alg.to_string()))?;
+
+    let kid = header.get("kid").and_then(Value::as_str);
+    let mut candidates = keys
+        .0
+        .iter()
+        .filter(|(key_kid, _)| kid.is_none() || key_kid.is_none() || key_kid.as_deref() == kid)
+        .peekable();
+    if candidates.peek().is_none() {
+        return Err(JwtError::KeyNotFound);
+    }
+    let message = &token[..token.rfind('.').unwrap()];
+    if !candidates.any(|(_, key)| key.verify(algorithm, message.as_bytes(), &sig)) {
+        return Err(JwtError::InvalidSignature);
+    }
+
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    match claims.get("exp").and_then(Value::as_u64) {
+        Some(exp) if exp.saturating_add(LEEWAY_SECS) > now => {}
+        _ => return Err(JwtError::Expired),
+    }
+    if let Some(nbf) = claims.get("nbf").and_then(Value::as_u64) {
+        if nbf > now + LEEWAY_SECS {
+            return Err(JwtError::NotYetValid);
+        }
+    }
+
+    let iss = claims.get("iss").and_then(Value::as_str);
+    if iss != Some(policy.issuer.as_str()) {
+        return Err(JwtError::WrongIssuer(iss.map(str::to_string)));
+    }
+    let audience_matches = match claims.get("aud") {
+        Some(Value::String(aud)) => *aud == policy.audience,
+        Some(Value::Array(auds)) => auds.iter().any(|aud| aud == policy.audience.as_str()),
+        _ => false,
+    };
+    if !audience_matches {
+        return Err(JwtError::WrongAudience);
+    }
+
+    Ok(claims)
+}
+
+/// The header, claims and signature of `token`.
+fn decode(token: &str) -> Result<(Claims, Claims, Vec<u8>), JwtError> {
+    let mut parts = token.split('.');
+    let (Some(header), Some(claims), Some(sig), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(JwtError::Malformed);
+    };
+
+    let json = |part: &str| -> Result<Claims, JwtError> {
+        let bytes = URL_SAFE_NO_PAD
+            .decode(part)
+            .map_err(|_| JwtError::Malformed)?;
+        serde_json::from_slice(&bytes).map_err(|_| JwtError::Malformed)
+    };
+    let sig = URL_SAFE_NO_PAD
+        .decode(sig)
+        .map_err(|_| JwtError::Malformed)?;
+
+    Ok((json(header)?, json(claims)?, sig))
+}
+
+const RSA_ENCRYPTION_OID: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01];
+const EC_PUBLIC_KEY_OID: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+const P256_OID: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+
+/// The modulus and exponent of a DER encoded PKCS #1 RSAPublicKey.
+fn rsa_key(der: &[u8]) -> Option<Key> {
+    let (sequence, _) = der_read(der, 0x30)?;
+    let (n, rest) = der_read(sequence, 0x02)?;
+    let (e, _) = der_read(rest, 0x02)?;
+
+    Some(Key::Rsa {
+        n: strip_leading_zeros(n.to_vec()),
+        e: strip_leading_zeros(e.to_vec()),
+    })
+}
+
+/// The contents of the DER value with `tag` at the start of `input`, and what
+/// follows it.
+fn der_read(input: &[u8], tag: u8) -> Option<(&[u8], &[u8])> {
+    let (&actual_tag, rest) = input.split_first()?;
+    if actual_tag != tag {
+        return None;
+    }
+
+    let (&first, rest) = rest.split_first()?;
+    let (len, rest) = if first < 0x80 {
+        (first as usize, rest)
+    } else {
+        let num_bytes = (first & 0x7f) as usize;
+        if num_bytes == 0 || num_bytes > 4 || rest.len() < num_bytes {
+            return None;
+        }
+        let len = rest[..num_bytes]
+            .iter()
+            .fold(0, |len, b| (len << 8) | *b as usize);
+        (len, &rest[num_bytes..])
+    };
+
+    (rest.len() >= len).then(|| rest.split_at(len))
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+fn strip_leading_zeros(mut bytes: Vec<u8>) -> Vec<u8> {
+    let zeros = bytes.iter().take_while(|b| **b == 0).count();
+    bytes.drain(..zeros.min(bytes.len().saturating_sub(1)));
+
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use ring::{
+        rand::SystemRandom,
+        signature::{
+            EcdsaKeyPair, KeyPair, RsaKeyPair, ECDSA_P256_SHA256_FIXED_SIGNING, RSA_PKCS1_SHA256,
+        },
+    };
+    use serde_json::json;
+
+    use super::*;
+
+    fn policy(algorithms: Vec<JwtAlgorithm>) -> JwtPolicy {
+        JwtPolicy {
+            issuer: "https://idp.example.com/".to_string(),
+            audience: "conductor".to_string(),
+            algorithms,
+            keys: JwtKeySource::JwksFile("unused".to_string()),
+        }
+    }
+
+    fn encode(header: Value, claims: Value, sign: impl Fn(&[u8]) -> Vec<u8>) -> String {
+        let message = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(header.to_string()),
+            URL_SAFE_NO_PAD.encode(claims.to_string())
+        );
+        let sig = URL_SAFE_NO_PAD.encode(sign(message.as_bytes()));
+
+        format!("{message}.{sig}")
+    }
+
+    fn claims(exp_from_now: i64) -> Value {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+
+        json!({
+            "iss": "https://idp.example.com/",
+            "aud": ["other", "conductor"],
+            "sub": "alice",
+            "exp": now + exp_from_now,
+        })
+    }
+
+    #[test]
+    fn test_hs256() {
+        let secret = b"a secret of the identity provider";
+        let keys = JwtKeys::from_jwks(
+            &json!({"keys": [{"kty": "oct", "kid": "1", "k": URL_SAFE_NO_PAD.encode(secret)}]})
+                .to_string(),
+        )
+        .unwrap();
+        let sign = |message: &[u8]| {
+            let key = hmac::Key::new(hmac::HMAC_SHA256, secret);
+            hmac::sign(&key, message).as_ref().to_vec()
+        };
+        let header = json!({"alg": "HS256", "kid": "1"});
+
+        let token = encode(header.clone(), claims(600), sign);
+        let claims_ = verify(&token, &policy(vec![JwtAlgorithm::HS256]), &keys).unwrap();
+        assert_eq!(claims_["sub"], "alice");
+        assert_eq!(issuer(&token).as_deref(), Some("https://idp.example.com/"));
+
+        assert!(matches!(
+            verify(&token, &policy(vec![JwtAlgorithm::RS256]), &keys),
+            Err(JwtError::AlgorithmNotAllowed(_))
+        ));
+        assert!(matches!(
+            verify(
+                &format!("{token}x"),
+                &policy(vec![JwtAlgorithm::HS256]),
+                &keys
+            ),
+            Err(JwtError::InvalidSignature | JwtError::Malformed)
+        ));
+
+        let expired = encode(header.clone(), claims(-600), sign);
+        assert!(matches!(
+            verify(&expired, &policy(vec![JwtAlgorithm::HS256]), &keys),
+            Err(JwtError::Expired)
+        ));
+
+        // Far future expiry times don't overflow.
+        let mut far_future = claims(600);
+        far_future["exp"] = json!(u64::MAX);
+        let token = encode(header.clone(), far_future, sign);
+        assert!(verify(&token, &policy(vec![JwtAlgorithm::HS256]), &keys).is_ok());
+
+        let mut other_audience = claims(600);
+        other_audience["aud"] = json!("someone-else");
+        let token = encode(header, other_audience, sign);
+        assert!(matches!(
+            verify(&token, &policy(vec![JwtAlgorithm::HS256]), &keys),
+            Err(JwtError::WrongAudience)
+        ));
+    }
+
+    #[test]
+    fn test_es256() {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+        let key_pair =
+            EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref()).unwrap();
+        let point = key_pair.public_key().as_ref();
+        let keys = JwtKeys::from_jwks(
+            &json!({"keys": [{
+                "kty": "EC",
+                "crv": "P-256",
+                "x": URL_SAFE_NO_PAD.encode(&point[1..33]),
+                "y": URL_SAFE_NO_PAD.encode(&point[33..]),
+            }]})
+            .to_string(),
+        )
+        .unwrap();
+
+        let token = encode(json!({"alg": "ES256"}), claims(600), |message| {
+            key_pair.sign(&rng, message).unwrap().as_ref().to_vec()
+        });
+        assert!(verify(&token, &policy(vec![JwtAlgorithm::ES256]), &keys).is_ok());
+
+        // The same key as a SubjectPublicKeyInfo.
+        let spki_prefix = [
+            &[0x30, 0x59, 0x30, 0x13, 0x06, 0x07][..],
+            EC_PUBLIC_KEY_OID,
+            &[0x06, 0x08],
+            P256_OID,
+            &[0x03, 0x42, 0x00],
+        ]
+        .concat();
+        let pem = format!(
+            "-----BEGIN PUBLIC KEY-----\n{}\n-----END PUBLIC KEY-----\n",
+            STANDARD.encode([spki_prefix, point.to_vec()].concat())
+        );
+        let keys = JwtKeys::from_pem(&pem).unwrap();
+        assert!(verify(&token, &policy(vec![JwtAlgorithm::ES256]), &keys).is_ok());
+    }
+
+    #[test]
+    fn test_rs256() {
+        let pem_der = |pem: &str| {
+            let body = pem.lines().filter(|l| !l.starts_with("-----"));
+            STANDARD.decode(body.collect::<String>()).unwrap()
+        };
+        let private_key = pem_der(include_str!(
+            "../../../tests/fixtures/jwt/rsa_private_key.pem"
+        ));
+        let key_pair = RsaKeyPair::from_pkcs8(&private_key).unwrap();
+        let public_key = key_pair.public_key();
+
+        let rng = SystemRandom::new();
+        let token = encode(
+            json!({"alg": "RS256", "kid": "rsa"}),
+            claims(600),
+            |message| {
+                let mut sig = vec![0; key_pair.public_modulus_len()];
+                key_pair
+                    .sign(&RSA_PKCS1_SHA256, &rng, message, &mut sig)
+                    .unwrap();
+                sig
+            },
+        );
+        let rs256 = policy(vec![JwtAlgorithm::RS256]);
+
+        let keys = JwtKeys::from_jwks(
+            &json!({"keys": [{
+                "kty": "RSA",
+                "kid": "rsa",
+                "n": URL_SAFE_NO_PAD.encode(public_key.modulus().big_endian_without_leading_zero()),
+                "e": URL_SAFE_NO_PAD.encode(public_key.exponent().big_endian_without_leading_zero()),
+            }]})
+            .to_string(),
+        )
+        .unwrap();
+        assert_eq!(verify(&token, &rs256, &keys).unwrap()["sub"], "alice");
+        assert!(matches!(
+            verify(&token, &policy(vec![JwtAlgorithm::ES256]), &keys),
+            Err(JwtError::AlgorithmNotAllowed(_))
+        ));
+
+        // The same key as a SubjectPublicKeyInfo and as a PKCS #1
+        // RSAPublicKey.
+        let keys = JwtKeys::from_pem(include_str!(
+            "../../../tests/fixtures/jwt/rsa_public_key.pem"
+        ))
+        .unwrap();
+        assert!(verify(&token, &rs256, &keys).is_ok());
+        let pem = format!(
+            "-----BEGIN RSA PUBLIC KEY-----\n{}\n-----END RSA PUBLIC KEY-----\n",
+            STANDARD.encode(public_key.as_ref())
+        );
+        let keys = JwtKeys::from_pem(&pem).unwrap();
+        assert!(verify(&token, &rs256, &keys).is_ok());
+
+        let (message, sig) = token.rsplit_once('.').unwrap();
+        let mut sig = URL_SAFE_NO_PAD.decode(sig).unwrap();
+        sig[0] ^= 1;
+        let tampered = format!("{message}.{}", URL_SAFE_NO_PAD.encode(sig));
+        assert!(matches!(
+            verify(&tampered, &rs256, &keys),
+            Err(JwtError::InvalidSignature)
+        ));
+    }
+}
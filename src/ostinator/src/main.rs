@@ -1,16 +1,18 @@
 use std::net::SocketAddr;
 use std::path::PathBuf;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
 use anyhow::{bail, Context, Result};
-use axum::extract::{Path, State};
-use axum::http::StatusCode;
-use axum::response::IntoResponse;
+use axum::body::Bytes;
+use axum::extract::{ConnectInfo, Path, State};
+use axum::http::{header::CONTENT_TYPE, HeaderMap, HeaderValue, Method, StatusCode, Uri};
+use axum::response::{IntoResponse, Response};
 use axum::routing::post;
 use axum::Router;
 use axum_macros::debug_handler;
-use catalog::auth::AuthEval;
-use catalog::AuthenticationPolicyType;
+use catalog::auth::{AuthEval, AuthorizationRequest};
+use catalog::{HttpMethod, Namespace};
 use clap::Parser;
 
 use datafusion::catalog::schema::{MemorySchemaProvider, SchemaProvider};
@@ -19,12 +21,30 @@ use ensemble_x::storage::ObjectStore;
 use object_store::aws::AmazonS3Builder;
 use object_store::gcp::GoogleCloudStorageBuilder;
 use object_store::prefix::PrefixStore;
-use tracing::{info, trace};
+use tracing::{error, info, info_span, warn, Instrument};
 use url::Url;
+use uuid::Uuid;
 
+use crate::authentication::authenticate;
+use crate::batcher::Batcher;
+use crate::cache::CachedEnsemble;
+use crate::error::{Error, ErrorResponse, REQUEST_ID_HEADER};
 use crate::http_handler_input::HttpHandlerInput;
+use crate::request::RequestContext;
+use crate::result_format::ResultFormat;
 
+mod authentication;
+mod batcher;
+mod cache;
+mod error;
 mod http_handler_input;
+mod jwt;
+mod request;
+mod result_format;
+
+/// Header explaining how a request was authorized, with
+/// `--explain-authorization`.
+const AUTHORIZATION_EXPLAIN_HEADER: &str = "x-authorization-explain";
 
 #[derive(Debug, Parser)]
 struct Args {
@@ -35,6 +55,28 @@ struct Args {
     /// Can be s3://, gs:// or just a local path.
     #[clap(long)]
     x_path: Option<String>,
+
+    /// Rows buffered for a table by http handlers with a batch window are
+    /// written once they take up this many bytes, even if the window hasn't
+    /// passed yet.
+    #[clap(long, default_value_t = 8 * 1024 * 1024)]
+    batch_max_bytes: usize,
+
+    /// How often to check for changes made by `conductor apply` and by
+    /// writers in other processes.
+    #[clap(long, default_value_t = 2)]
+    reload_interval_secs: u64,
+
+    /// Take the client address of requests from the last address of the
+    /// `X-Forwarded-For` header, appended by a proxy in front of the server.
+    #[clap(long)]
+    trust_forwarded_for: bool,
+
+    /// Answer requests with how they were authorized in the
+    /// `X-Authorization-Explain` header. It tells clients about the
+    /// policies, so only meant for debugging them.
+    #[clap(long)]
+    explain_authorization: bool,
 }
 
 #[tokio::main]
@@ -42,101 +84,326 @@ async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
     let args = Args::parse();
 
-    let state = Arc::new(AppState::new(args.x_path.unwrap())?);
+    let state = Arc::new(
+        AppState::new(
+            args.x_path.unwrap(),
+            Batcher::new(args.batch_max_bytes),
+            args.trust_forwarded_for,
+            args.explain_authorization,
+        )
+        .await?,
+    );
+
+    let refreshed_state = state.clone();
+    let reload_interval = Duration::from_secs(args.reload_interval_secs);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(reload_interval);
+        loop {
+            interval.tick().await;
+            if let Err(e) = refreshed_state.refresh().await {
+                warn!(error = %e, "refreshing the ensemble failed");
+            }
+        }
+    });
 
     let app = Router::new()
-        .route("/ns/:ns/handler/:handler", post(http_handler))
-        .with_state(state);
+        .route(
+            "/ns/:ns/handler/:handler",
+            post(http_handler).get(http_handler),
+        )
+        .with_state(state.clone());
     let addr = args.addr;
 
     info!(?addr, "server listening");
     axum::Server::bind(&addr)
-        .serve(app.into_make_service())
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(async {
+            tokio::signal::ctrl_c().await.ok();
+            info!("shutting down");
+        })
         .await
-        .with_context(|| format!("failed to bind to {}", addr))
+        .with_context(|| format!("failed to bind to {}", addr))?;
+
+    // Requests still waiting for their batch were answered before the server
+    // stopped, but write whatever is left anyway.
+    state.batcher.flush_all(&state).await;
+
+    Ok(())
 }
 
 #[debug_handler]
 async fn http_handler(
     State(state): State<Arc<AppState>>,
     Path((ns_name, handler_name)): Path<(String, String)>,
-    body: String,
-) -> impl IntoResponse {
-    // Let's load the catalog.
-    let ensemble = state.ensemble_x().await.unwrap();
-    let catalog = ensemble.catalog().unwrap();
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let request_id = headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string())
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+    let span = info_span!(
+        "http_handler",
+        %request_id,
+        namespace = %ns_name,
+        handler = %handler_name
+    );
+
+    let mut request = RequestContext::new(method, &uri, headers, peer, state.trust_forwarded_for);
+    let result = run_http_handler(&state, &ns_name, &handler_name, &mut request, body)
+        .instrument(span.clone())
+        .await;
+
+    let mut response = match result {
+        Ok(response) => ([(REQUEST_ID_HEADER, request_id)], response).into_response(),
+        Err(error) => {
+            span.in_scope(|| {
+                let status = error.status().as_u16();
+                if error.status().is_server_error() {
+                    error!(status, code = error.code(), %error, "request failed");
+                } else {
+                    info!(status, code = error.code(), %error, "request rejected");
+                }
+            });
+            ErrorResponse { error, request_id }.into_response()
+        }
+    };
 
-    let anonymous_access_allowed = catalog
-        .namespaces
-        .get(&ns_name)
-        .unwrap()
-        .authentication_policies
-        .values()
-        .any(|p| matches!(p.typ, AuthenticationPolicyType::Anonymous()));
-    if !anonymous_access_allowed {
-        trace!("anonymous access not allowed");
-        return (StatusCode::UNAUTHORIZED, ());
+    if state.explain_authorization && !request.authorization.is_empty() {
+        if let Ok(explanation) = HeaderValue::from_str(&request.authorization.join("; ")) {
+            response
+                .headers_mut()
+                .insert(AUTHORIZATION_EXPLAIN_HEADER, explanation);
+        }
     }
 
-    let handler = catalog
-        .namespaces
-        .get(&ns_name)
-        .unwrap()
-        .http_handlers
-        .get(&handler_name)
-        .unwrap();
+    response
+}
 
-    let policy = catalog
+async fn run_http_handler(
+    state: &Arc<AppState>,
+    ns_name: &str,
+    handler_name: &str,
+    request: &mut RequestContext,
+    body: Bytes,
+) -> Result<Response, Error> {
+    let body = String::from_utf8(body.to_vec())
+        .map_err(|_| Error::BadInput("the body is not valid UTF-8".to_string()))?;
+
+    let ensemble = state.ensemble();
+    let namespace = ensemble
+        .catalog
         .namespaces
-        .get(&ns_name)
-        .unwrap()
-        .authorization_policies
-        .get(&handler.policy)
-        .unwrap();
+        .get(ns_name)
+        .ok_or_else(|| Error::NamespaceNotFound(ns_name.to_string()))?;
+
+    let identity = authenticate(&ensemble, namespace, &request.headers).await?;
+    request.principal = identity.principal;
+    request.auth_method = identity.method;
+    request.claims = identity.claims;
 
-    let auth_eval = AuthEval::default();
-    if !auth_eval.eval_policy(policy) {
-        return (StatusCode::UNAUTHORIZED, ());
+    let handler = namespace
+        .http_handlers
+        .get(handler_name)
+        .ok_or_else(|| Error::HandlerNotFound(handler_name.to_string()))?;
+    let request_method = match request.method {
+        Method::GET => Some(HttpMethod::Get),
+        Method::POST => Some(HttpMethod::Post),
+        _ => None,
+    };
+    if request_method != Some(handler.method) {
+        return Err(Error::MethodNotAllowed(
+            handler_name.to_string(),
+            handler.method,
+        ));
     }
+    // Rejected before running the handler, which may not be cheap.
+    let format = match handler.method {
+        HttpMethod::Get => Some(ResultFormat::from_headers(&request.headers)?),
+        HttpMethod::Post => None,
+    };
+
+    let authorization_request = request.authorization_request(ns_name, handler_name);
+    authorize(
+        &ensemble.auth_eval,
+        namespace,
+        &format!("http handler {handler_name}"),
+        &handler.policies,
+        &authorization_request,
+        request,
+    )?;
 
     info!(?handler, "http handler");
 
     // TODO: Load into session only objects that are needed by the http handler.
-    let mut session = sql::SqlSession::new(ensemble).await.unwrap();
+    let mut session = sql::SqlSession::new(ensemble.as_ref()).await?;
+    session.authorize_as(authorization_request.clone());
+
+    for (table_ns, table_name) in session.tables_used(&handler.body)? {
+        let Some(table_namespace) = ensemble.catalog.namespaces.get(&table_ns) else {
+            continue;
+        };
+        let Some(table) = table_namespace.tables.get(&table_name) else {
+            continue;
+        };
+        authorize(
+            &ensemble.auth_eval,
+            table_namespace,
+            &format!("table {table_ns}.{table_name}"),
+            &table.policies,
+            &authorization_request,
+            request,
+        )?;
+    }
 
     let schema = MemorySchemaProvider::new();
 
-    let input = HttpHandlerInput::new(body);
-    schema
-        .register_table("input".to_string(), Arc::new(input))
-        .unwrap();
+    let input = match &handler.input {
+        Some(columns) => HttpHandlerInput::parse(&body, columns)?,
+        None => HttpHandlerInput::new(body),
+    };
+    schema.register_table("input".to_string(), Arc::new(input))?;
+    schema.register_table("request".to_string(), Arc::new(request.request_table()?))?;
+    schema.register_table(
+        "request_params".to_string(),
+        Arc::new(request.params_table()?),
+    )?;
+    schema.register_table(
+        "request_claims".to_string(),
+        Arc::new(request.claims_table()?),
+    )?;
 
     session
         .state
         .catalog_list()
         .catalog("conductor")
-        .unwrap()
-        .register_schema("temporary", Arc::new(schema))
-        .unwrap();
+        .ok_or_else(|| Error::Internal("catalog conductor not found".to_string()))?
+        .register_schema("temporary", Arc::new(schema))?;
+
+    if let Some(batch_window_ms) = handler.batch_window_ms {
+        let (table, rows) = session.rows_to_insert(&handler.body).await?;
+        let window = Duration::from_millis(batch_window_ms);
+
+        state
+            .batcher
+            .insert(state, table, rows, window)
+            .await
+            .map_err(|e| Error::Internal(format!("batched insert failed: {e}")))?;
+
+        return Ok(StatusCode::OK.into_response());
+    }
+
+    if let Some(format) = format {
+        let (schema, rows) = session.query(&handler.body).await?;
+        let body = format
+            .write(&schema, &rows)
+            .map_err(|e| Error::Internal(format!("serializing the rows failed: {e}")))?;
+
+        return Ok(([(CONTENT_TYPE, format.content_type())], body).into_response());
+    }
 
-    session.execute(handler.body.as_str()).await.unwrap();
+    session.execute(handler.body.as_str()).await?;
 
-    (StatusCode::OK, ())
+    Ok(StatusCode::OK.into_response())
+}
+
+/// Decide on the request by the `policies` of `scope` in `namespace`, an
+/// http handler or a table, and note the decision in `request`. Tables
+/// without policies don't restrict access.
+fn authorize(
+    auth_eval: &AuthEval,
+    namespace: &Namespace,
+    scope: &str,
+    policies: &[String],
+    authorization_request: &AuthorizationRequest,
+    request: &mut RequestContext,
+) -> Result<(), Error> {
+    if policies.is_empty() {
+        return Ok(());
+    }
+    let policies = policies
+        .iter()
+        .map(|name| {
+            namespace
+                .authorization_policies
+                .get(name)
+                .ok_or_else(|| Error::PolicyNotFound(name.clone()))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let decision = auth_eval.authorize(policies, authorization_request);
+    for outcome in &decision.outcomes {
+        if let Some(error) = &outcome.error {
+            warn!(
+                scope,
+                policy = outcome.policy,
+                error,
+                "authorization policy failed, denying"
+            );
+        }
+    }
+    info!(scope, decision = %decision.reason(), "authorization");
+    request.authorization.push(format!("{scope}: {decision}"));
+
+    match decision.allowed {
+        true => Ok(()),
+        false => Err(Error::Forbidden(format!(
+            "to {scope} {}",
+            decision.reason()
+        ))),
+    }
 }
 
 struct AppState {
     object_store: ObjectStore,
+    batcher: Batcher,
+    ensemble: RwLock<Arc<CachedEnsemble>>,
+    trust_forwarded_for: bool,
+    explain_authorization: bool,
 }
 
 impl AppState {
-    fn new(data_path: String) -> Result<Self> {
+    async fn new(
+        data_path: String,
+        batcher: Batcher,
+        trust_forwarded_for: bool,
+        explain_authorization: bool,
+    ) -> Result<Self> {
         let object_store = configure_ensemble_x_storage(data_path)?;
+        let ensemble = CachedEnsemble::load(&object_store).await?;
+
+        Ok(Self {
+            object_store,
+            batcher,
+            ensemble: RwLock::new(Arc::new(ensemble)),
+            trust_forwarded_for,
+            explain_authorization,
+        })
+    }
 
-        Ok(Self { object_store })
+    fn ensemble(&self) -> Arc<CachedEnsemble> {
+        self.ensemble.read().unwrap().clone()
     }
 
-    async fn ensemble_x(&self) -> Result<ensemble_x::EnsembleX> {
-        Ok(ensemble_x::EnsembleX::new(self.object_store.clone()).await?)
+    /// Reload the ensemble if its catalog was changed, otherwise bring its
+    /// tables up to date. Requests already running keep what they started
+    /// with.
+    async fn refresh(&self) -> Result<()> {
+        let ensemble = self.ensemble();
+
+        if ensemble.is_stale(&self.object_store).await? {
+            let reloaded = CachedEnsemble::load(&self.object_store).await?;
+            *self.ensemble.write().unwrap() = Arc::new(reloaded);
+            info!("catalog reloaded");
+        } else {
+            ensemble.update_tables().await?;
+        }
+
+        Ok(())
     }
 }
 
@@ -166,6 +433,9 @@ fn configure_object_storage(uri: &Url) -> Result<Box<object_store::DynObjectStor
 
             Ok(Box::new(storage))
         }
+        // The store would be empty and only visible to this process, with
+        // no way to apply a score to it.
+        "memory" => bail!("memory:// is not supported by the server"),
         "gs" => {
             let gcs = GoogleCloudStorageBuilder::from_env()
                 .with_url(uri.as_str())
@@ -196,3 +466,84 @@ fn configure_ensemble_x_storage(data_path: String) -> Result<ObjectStore> {
         true,
     ))
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, RwLock};
+
+    use catalog::{edit::Edit, Column, Table};
+    use datafusion::{
+        arrow::array::Int32Array, datasource::TableProvider, prelude::SessionContext,
+    };
+    use ensemble::Ensemble;
+    use ensemble_x::{storage::ObjectStore, EnsembleX};
+    use sqlparser::ast::DataType;
+
+    use crate::{batcher::Batcher, cache::CachedEnsemble, AppState};
+
+    /// Create namespace `ns` and its `tables`, each with an integer column
+    /// `id`, in `storage`.
+    pub(crate) async fn create_tables(storage: &ObjectStore, tables: &[&str]) {
+        let mut ensemble = EnsembleX::new(storage.clone()).await.unwrap();
+        if !ensemble.catalog().unwrap().namespaces.contains_key("ns") {
+            let namespace = Edit::CreateNamespace {
+                name: "ns".to_string(),
+            };
+            Ensemble::apply(&mut ensemble, &namespace).await.unwrap();
+        }
+        for name in tables {
+            let table = Table {
+                namespace: "ns".to_string(),
+                uuid: uuid::Uuid::new_v4(),
+                name: name.to_string(),
+                columns: vec![Column {
+                    uid: 1,
+                    name: "id".to_string(),
+                    data_type: DataType::Integer(None),
+                }],
+                partition_by: vec![],
+                retention_hours: None,
+                policies: vec![],
+            };
+            Ensemble::apply(&mut ensemble, &Edit::CreateTable(table))
+                .await
+                .unwrap();
+        }
+        Ensemble::commit(&mut ensemble).await.unwrap();
+    }
+
+    /// The state of a server on an in-memory ensemble with table `ns.t`.
+    pub(crate) async fn test_state(max_batch_bytes: usize) -> Arc<AppState> {
+        let object_store = ObjectStore::in_memory();
+        create_tables(&object_store, &["t"]).await;
+        let ensemble = CachedEnsemble::load(&object_store).await.unwrap();
+
+        Arc::new(AppState {
+            object_store,
+            batcher: Batcher::new(max_batch_bytes),
+            ensemble: RwLock::new(Arc::new(ensemble)),
+            trust_forwarded_for: false,
+            explain_authorization: false,
+        })
+    }
+
+    /// The ids of the rows of `table`, sorted.
+    pub(crate) async fn ids(table: Arc<dyn TableProvider>) -> Vec<i32> {
+        let batches = SessionContext::new()
+            .read_table(table)
+            .unwrap()
+            .collect()
+            .await
+            .unwrap();
+        let mut ids = batches
+            .iter()
+            .flat_map(|b| {
+                let ids = b.column(0).as_any().downcast_ref::<Int32Array>().unwrap();
+                ids.values().to_vec()
+            })
+            .collect::<Vec<_>>();
+        ids.sort();
+
+        ids
+    }
+}
@@ -0,0 +1,287 @@
+//! The context of a request, available to handler SQL as the
+//! `temporary.request` table with a single row, the
+//! `temporary.request_params` table with a row per query parameter, and the
+//! `temporary.request_claims` table with a row per claim of the token the
+//! request was authenticated with.
+
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::http::{header, HeaderMap, Method, Uri};
+use catalog::auth::{AuthMethod, AuthorizationRequest};
+use datafusion::{
+    arrow::{
+        array::{ArrayRef, StringArray, TimestampMicrosecondArray},
+        datatypes::{DataType, Field, Schema, TimeUnit},
+        record_batch::RecordBatch,
+    },
+    datasource::MemTable,
+    error::Result,
+};
+use serde_json::Value;
+
+use crate::{authentication::API_KEY_HEADER, jwt::Claims};
+
+/// Headers that carry credentials, left out of the `headers` column.
+const REDACTED_HEADERS: [header::HeaderName; 4] = [
+    header::AUTHORIZATION,
+    header::COOKIE,
+    header::PROXY_AUTHORIZATION,
+    API_KEY_HEADER,
+];
+
+pub(crate) struct RequestContext {
+    pub(crate) method: Method,
+    pub(crate) path: String,
+    pub(crate) query: Option<String>,
+    pub(crate) params: Vec<(String, String)>,
+    pub(crate) headers: HeaderMap,
+    pub(crate) ip_address: IpAddr,
+    /// Microseconds since the epoch.
+    pub(crate) received_at: i64,
+    /// Name of the authenticated principal, if not anonymous.
+    pub(crate) principal: Option<String>,
+    pub(crate) auth_method: AuthMethod,
+    /// Verified claims of the token of the request.
+    pub(crate) claims: Option<Claims>,
+    /// How the request was authorized, by each http handler or table whose
+    /// policies were checked.
+    pub(crate) authorization: Vec<String>,
+}
+
+impl RequestContext {
+    /// The client address is that of the peer, or the last address of the
+    /// `X-Forwarded-For` header if `trust_forwarded_for`, i.e. when behind a
+    /// proxy. That is the one the proxy appended, the ones before it are
+    /// whatever the client sent.
+    pub(crate) fn new(
+        method: Method,
+        uri: &Uri,
+        headers: HeaderMap,
+        peer: SocketAddr,
+        trust_forwarded_for: bool,
+    ) -> Self {
+        let params = uri
+            .query()
+            .map(|q| {
+                url::form_urlencoded::parse(q.as_bytes())
+                    .into_owned()
+                    .collect()
+            })
+            .unwrap_or_default();
+        let forwarded_for = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .next_back()
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.rsplit(',').next())
+            .and_then(|ip| ip.trim().parse().ok());
+        let ip_address = match forwarded_for {
+            Some(ip) if trust_forwarded_for => ip,
+            _ => peer.ip(),
+        };
+        let received_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as i64;
+
+        Self {
+            method,
+            path: uri.path().to_string(),
+            query: uri.query().map(|q| q.to_string()),
+            params,
+            headers,
+            ip_address,
+            received_at,
+            principal: None,
+            auth_method: AuthMethod::Anonymous,
+            claims: None,
+            authorization: vec![],
+        }
+    }
+
+    fn header(&self, name: header::HeaderName) -> Option<&str> {
+        self.headers.get(name).and_then(|v| v.to_str().ok())
+    }
+
+    /// The headers by name, values of repeated headers joined by commas.
+    fn headers(&self) -> BTreeMap<String, String> {
+        let mut headers = BTreeMap::<String, String>::new();
+        for (name, value) in &self.headers {
+            if REDACTED_HEADERS.contains(name) {
+                continue;
+            }
+            let value = String::from_utf8_lossy(value.as_bytes());
+            headers
+                .entry(name.to_string())
+                .and_modify(|v| {
+                    v.push_str(", ");
+                    v.push_str(&value);
+                })
+                .or_insert_with(|| value.into_owned());
+        }
+
+        headers
+    }
+
+    /// What authorization policies of the handler are evaluated against.
+    pub(crate) fn authorization_request(
+        &self,
+        namespace: &str,
+        handler: &str,
+    ) -> AuthorizationRequest {
+        AuthorizationRequest {
+            principal: self.principal.clone(),
+            auth_method: self.auth_method,
+            claims: self.claims.clone(),
+            client_ip: Some(self.ip_address),
+            headers: self.headers(),
+            namespace: namespace.to_string(),
+            handler: handler.to_string(),
+        }
+    }
+
+    pub(crate) fn request_table(&self) -> Result<MemTable> {
+        let text = |name| Field::new(name, DataType::Utf8, true);
+        let schema = Arc::new(Schema::new(vec![
+            text("method"),
+            text("path"),
+            text("query"),
+            text("ip_address"),
+            text("host"),
+            text("user_agent"),
+            text("referer"),
+            text("content_type"),
+            text("headers"),
+            Field::new(
+                "received_at",
+                DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into())),
+                false,
+            ),
+            text("principal"),
+            text("claims"),
+        ]));
+
+        let string = |value: Option<&str>| Arc::new(StringArray::from(vec![value])) as ArrayRef;
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                string(Some(self.method.as_str())),
+                string(Some(&self.path)),
+                string(self.query.as_deref()),
+                string(Some(&self.ip_address.to_string())),
+                string(self.header(header::HOST)),
+                string(self.header(header::USER_AGENT)),
+                string(self.header(header::REFERER)),
+                string(self.header(header::CONTENT_TYPE)),
+                string(Some(&serde_json::to_string(&self.headers()).unwrap())),
+                Arc::new(
+                    TimestampMicrosecondArray::from(vec![self.received_at]).with_timezone("UTC"),
+                ),
+                string(self.principal.as_deref()),
+                string(
+                    self.claims
+                        .as_ref()
+                        .map(|c| serde_json::to_string(c).unwrap())
+                        .as_deref(),
+                ),
+            ],
+        )?;
+
+        MemTable::try_new(schema, vec![vec![batch]])
+    }
+
+    pub(crate) fn params_table(&self) -> Result<MemTable> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("name", DataType::Utf8, false),
+            Field::new("value", DataType::Utf8, false),
+        ]));
+        let names = StringArray::from_iter_values(self.params.iter().map(|(n, _)| n));
+        let values = StringArray::from_iter_values(self.params.iter().map(|(_, v)| v));
+        let batch = RecordBatch::try_new(schema.clone(), vec![Arc::new(names), Arc::new(values)])?;
+
+        MemTable::try_new(schema, vec![vec![batch]])
+    }
+
+    /// String claims as they are, others as JSON.
+    pub(crate) fn claims_table(&self) -> Result<MemTable> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("name", DataType::Utf8, false),
+            Field::new("value", DataType::Utf8, false),
+        ]));
+        let claims = self.claims.iter().flatten().collect::<Vec<_>>();
+        let names = StringArray::from_iter_values(claims.iter().map(|(n, _)| n));
+        let values = StringArray::from_iter_values(claims.iter().map(|(_, v)| match v {
+            Value::String(v) => v.clone(),
+            v => v.to_string(),
+        }));
+        let batch = RecordBatch::try_new(schema.clone(), vec![Arc::new(names), Arc::new(values)])?;
+
+        MemTable::try_new(schema, vec![vec![batch]])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    #[test]
+    fn test_client_address() {
+        let peer: SocketAddr = "10.0.0.1:4000".parse().unwrap();
+        let uri: Uri = "/ns/h?a=1".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.append(
+            "x-forwarded-for",
+            HeaderValue::from_static("1.1.1.1, 2.2.2.2"),
+        );
+        headers.append("x-forwarded-for", HeaderValue::from_static("3.3.3.3"));
+        let ip_address = |headers: &HeaderMap, trust_forwarded_for| {
+            RequestContext::new(
+                Method::GET,
+                &uri,
+                headers.clone(),
+                peer,
+                trust_forwarded_for,
+            )
+            .ip_address
+            .to_string()
+        };
+
+        assert_eq!(ip_address(&headers, false), "10.0.0.1");
+        assert_eq!(ip_address(&headers, true), "3.3.3.3");
+
+        headers.remove("x-forwarded-for");
+        headers.insert(
+            "x-forwarded-for",
+            HeaderValue::from_static("1.1.1.1, 2.2.2.2"),
+        );
+        assert_eq!(ip_address(&headers, true), "2.2.2.2");
+
+        headers.insert("x-forwarded-for", HeaderValue::from_static("garbage"));
+        assert_eq!(ip_address(&headers, true), "10.0.0.1");
+    }
+
+    #[test]
+    fn test_redacted_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, HeaderValue::from_static("Bearer t"));
+        headers.insert(header::COOKIE, HeaderValue::from_static("session=s"));
+        headers.insert("x-api-key", HeaderValue::from_static("key"));
+        headers.insert(header::USER_AGENT, HeaderValue::from_static("curl"));
+        let request = RequestContext::new(
+            Method::GET,
+            &"/ns/h".parse().unwrap(),
+            headers,
+            "10.0.0.1:4000".parse().unwrap(),
+            false,
+        );
+
+        assert_eq!(request.headers().keys().collect::<Vec<_>>(), ["user-agent"]);
+    }
+}
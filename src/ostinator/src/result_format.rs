@@ -0,0 +1,155 @@
+//! Serialization of the rows returned by GET handlers, in the format asked
+//! for by the `Accept` header of the request.
+
+use axum::http::{header::ACCEPT, HeaderMap};
+use datafusion::arrow::{
+    csv, datatypes::SchemaRef, error::ArrowError, ipc::writer::StreamWriter, json,
+    record_batch::RecordBatch,
+};
+
+use crate::error::Error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ResultFormat {
+    /// A JSON array of objects, one per row.
+    Json,
+    /// One JSON object per row and line.
+    NdJson,
+    /// CSV with a header line.
+    Csv,
+    /// An Arrow IPC stream.
+    ArrowIpc,
+}
+
+impl ResultFormat {
+    /// The acceptable format the request prefers, by quality and then in the
+    /// order it lists them, JSON if the request doesn't say.
+    pub(crate) fn from_headers(headers: &HeaderMap) -> Result<Self, Error> {
+        let Some(accept) = headers.get(ACCEPT) else {
+            return Ok(ResultFormat::Json);
+        };
+        let invalid = || Error::BadInput("invalid Accept header".to_string());
+        let accept = accept.to_str().map_err(|_| invalid())?;
+
+        let mut media_ranges = vec![];
+        for media_range in accept.split(',') {
+            let mut parts = media_range.split(';').map(str::trim);
+            let media_type = parts.next().unwrap_or_default().to_lowercase();
+            let q = match parts.find_map(|p| p.strip_prefix("q=")) {
+                Some(q) => q.parse::<f32>().map_err(|_| invalid())?,
+                None => 1.0,
+            };
+            if q > 0.0 {
+                media_ranges.push((media_type, q));
+            }
+        }
+        // The sort is stable, so ranges of equal quality keep their order.
+        media_ranges.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+
+        for (media_type, _) in media_ranges {
+            let format = match media_type.as_str() {
+                "application/json" | "application/*" | "*/*" => ResultFormat::Json,
+                "application/x-ndjson" | "application/jsonl" => ResultFormat::NdJson,
+                "text/csv" | "text/*" => ResultFormat::Csv,
+                "application/vnd.apache.arrow.stream" => ResultFormat::ArrowIpc,
+                _ => continue,
+            };
+            return Ok(format);
+        }
+
+        Err(Error::NotAcceptable(accept.to_string()))
+    }
+
+    pub(crate) fn content_type(&self) -> &'static str {
+        match self {
+            ResultFormat::Json => "application/json",
+            ResultFormat::NdJson => "application/x-ndjson",
+            ResultFormat::Csv => "text/csv",
+            ResultFormat::ArrowIpc => "application/vnd.apache.arrow.stream",
+        }
+    }
+
+    pub(crate) fn write(
+        &self,
+        schema: &SchemaRef,
+        batches: &[RecordBatch],
+    ) -> Result<Vec<u8>, ArrowError> {
+        let mut buf = vec![];
+
+        match self {
+            ResultFormat::Json => {
+                // The writer writes nothing at all without rows.
+                if batches.iter().all(|b| b.num_rows() == 0) {
+                    buf.extend_from_slice(b"[]");
+                } else {
+                    let mut writer = json::ArrayWriter::new(&mut buf);
+                    writer.write_batches(batches)?;
+                    writer.finish()?;
+                }
+            }
+            ResultFormat::NdJson => {
+                let mut writer = json::LineDelimitedWriter::new(&mut buf);
+                writer.write_batches(batches)?;
+                writer.finish()?;
+            }
+            ResultFormat::Csv => {
+                let mut writer = csv::Writer::new(&mut buf);
+                // The header is written along with the first batch, so write
+                // an empty one for it in case there are no rows.
+                writer.write(&RecordBatch::new_empty(schema.clone()))?;
+                for batch in batches {
+                    writer.write(batch)?;
+                }
+            }
+            ResultFormat::ArrowIpc => {
+                let mut writer = StreamWriter::try_new(&mut buf, schema)?;
+                for batch in batches {
+                    writer.write(batch)?;
+                }
+                writer.finish()?;
+            }
+        }
+
+        Ok(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    fn format(accept: &str) -> Option<ResultFormat> {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, HeaderValue::from_str(accept).unwrap());
+        ResultFormat::from_headers(&headers).ok()
+    }
+
+    #[test]
+    fn test_from_headers() {
+        assert_eq!(
+            ResultFormat::from_headers(&HeaderMap::new()).ok(),
+            Some(ResultFormat::Json)
+        );
+        assert_eq!(format("text/csv"), Some(ResultFormat::Csv));
+        assert_eq!(
+            format("application/x-ndjson;q=0, text/html, */*;q=0.1"),
+            Some(ResultFormat::Json)
+        );
+        assert_eq!(
+            format("application/vnd.apache.arrow.stream"),
+            Some(ResultFormat::ArrowIpc)
+        );
+        assert_eq!(
+            format("text/csv;q=0.1, application/json"),
+            Some(ResultFormat::Json)
+        );
+        assert_eq!(
+            format("text/csv;q=0.5, application/x-ndjson;q=0.5"),
+            Some(ResultFormat::Csv)
+        );
+        assert_eq!(format("text/html"), None);
+        assert_eq!(format("text/csv;q=x"), None);
+    }
+}
@@ -1,28 +1,31 @@
 use std::collections::HashSet;
 
 use catalog::{
-    AuthenticationPolicy, AuthenticationPolicyType, AuthorizationPolicy, Catalog, Column,
-    HttpHandler, Namespace, Table,
+    api_key, auth::AuthEval, ApiKeySource, AuthenticationPolicy, AuthenticationPolicyType,
+    AuthorizationPolicy, Catalog, Column, HttpHandler, HttpMethod, InputColumn, Mask,
+    MaskingPolicy, Namespace, Table,
 };
 
 use crate::parser::Statement;
-use crate::{Result, ScoreError, ScorePkg};
+use crate::{PolicyTest, Result, ScoreError, ScorePkg};
 
 pub struct ScoreCompiler {}
 
 impl ScoreCompiler {
-    pub(crate) fn compile(&self, pkg: ScorePkg) -> Result<Catalog> {
+    pub(crate) fn compile(&self, pkg: ScorePkg) -> Result<(Catalog, Vec<PolicyTest>)> {
         let mut catalog = Catalog::default();
+        let mut policy_tests = vec![];
 
         if !pkg.files.is_empty() {
-            let ns = self.compile_pkg(pkg)?;
+            let ns;
+            (ns, policy_tests) = self.compile_pkg(pkg)?;
             catalog.namespaces.insert(ns.name.clone(), ns);
         }
 
-        Ok(catalog)
+        Ok((catalog, policy_tests))
     }
 
-    fn compile_pkg(&self, pkg: ScorePkg) -> Result<Namespace> {
+    fn compile_pkg(&self, pkg: ScorePkg) -> Result<(Namespace, Vec<PolicyTest>)> {
         // Parse namespace declaration from any file. Then we will just check
         // that all other files have the same namespace.
         let namespace_name = match pkg.files[0].statements.front().unwrap() {
@@ -37,6 +40,7 @@ impl ScoreCompiler {
 
         let mut table_names = HashSet::new();
         let mut table_uuids = HashSet::new();
+        let mut policy_tests = Vec::<PolicyTest>::new();
 
         for file in &pkg.files {
             let mut stmt_iter = file.statements.iter();
@@ -62,6 +66,9 @@ impl ScoreCompiler {
                             uuid: Into::into(table_decl.uuid),
                             name: table_decl.name.clone(),
                             columns: Default::default(),
+                            partition_by: Default::default(),
+                            retention_hours: table_decl.retention_hours,
+                            policies: table_decl.policies.clone(),
                         };
 
                         let mut column_names = HashSet::new();
@@ -87,6 +94,33 @@ impl ScoreCompiler {
                             });
                         }
 
+                        for col in &table_decl.partition_by {
+                            if !column_names.contains(&col.value)
+                                || table.partition_by.contains(&col.value)
+                            {
+                                return Err(ScoreError::CompileError {
+                                    error: format!(
+                                        "invalid partition column {} of table {}",
+                                        col.value, table_decl.name
+                                    ),
+                                    path: file.path.clone(),
+                                });
+                            }
+
+                            table.partition_by.push(col.value.clone());
+                        }
+                        if !table.columns.is_empty()
+                            && table.partition_by.len() == table.columns.len()
+                        {
+                            return Err(ScoreError::CompileError {
+                                error: format!(
+                                    "table {} must have a column that is not a partition column",
+                                    table_decl.name
+                                ),
+                                path: file.path.clone(),
+                            });
+                        }
+
                         if table_names.contains(&table_decl.name)
                             || table_uuids.contains(&table_decl.uuid)
                         {
@@ -115,15 +149,83 @@ impl ScoreCompiler {
                             });
                         }
 
+                        let mut body = vec![];
+                        for stmt in &handler_decl.body {
+                            match stmt {
+                                sql::parser::Statement::Statement(
+                                    sqlparser::ast::Statement::StartTransaction { .. }
+                                    | sqlparser::ast::Statement::Commit { .. }
+                                    | sqlparser::ast::Statement::Rollback { .. },
+                                )
+                                | sql::parser::Statement::DescribeHistory(_)
+                                | sql::parser::Statement::Optimize(_)
+                                | sql::parser::Statement::Vacuum { .. } => {
+                                    return Err(ScoreError::CompileError {
+                                        error: format!(
+                                            "unsupported statement in http handler: {}",
+                                            handler_decl.name
+                                        ),
+                                        path: file.path.clone(),
+                                    })
+                                }
+                                sql::parser::Statement::Statement(st) => body.push(st),
+                            }
+                        }
+                        // Only inserted rows can be buffered and written
+                        // together.
+                        if handler_decl.batch_window.is_some()
+                            && !matches!(body[..], [sqlparser::ast::Statement::Insert { .. }])
+                        {
+                            return Err(ScoreError::CompileError {
+                                error: format!(
+                                    "http handler {} has a BATCH WINDOW but its body is not a single INSERT",
+                                    handler_decl.name
+                                ),
+                                path: file.path.clone(),
+                            });
+                        }
+
+                        // Only queries have rows to return.
+                        if handler_decl.method == HttpMethod::Get
+                            && !matches!(body[..], [sqlparser::ast::Statement::Query(_)])
+                        {
+                            return Err(ScoreError::CompileError {
+                                error: format!(
+                                    "http handler {} has METHOD GET but its body is not a single query",
+                                    handler_decl.name
+                                ),
+                                path: file.path.clone(),
+                            });
+                        }
+
+                        let input = match &handler_decl.input {
+                            Some(columns) => {
+                                Some(compile_input(&handler_decl.name, columns).map_err(
+                                    |error| ScoreError::CompileError {
+                                        error,
+                                        path: file.path.clone(),
+                                    },
+                                )?)
+                            }
+                            None => None,
+                        };
+
                         ns.http_handlers.insert(
                             handler_decl.name.clone(),
                             HttpHandler {
                                 namespace: ns.name.clone(),
                                 name: handler_decl.name.clone(),
-                                policy: handler_decl.policy.clone(),
-                                body: match &handler_decl.body {
-                                    sql::parser::Statement::Statement(st) => st.to_string(),
-                                },
+                                policies: handler_decl.policies.clone(),
+                                method: handler_decl.method,
+                                input,
+                                batch_window_ms: handler_decl
+                                    .batch_window
+                                    .map(|window| window.as_millis() as u64),
+                                body: body
+                                    .iter()
+                                    .map(|st| st.to_string())
+                                    .collect::<Vec<_>>()
+                                    .join("; "),
                             },
                         );
                     }
@@ -140,9 +242,27 @@ impl ScoreCompiler {
                             AuthenticationPolicy {
                                 namespace: ns.name.clone(),
                                 name: policy_decl.name.clone(),
-                                typ: match policy_decl.typ.as_str() {
-                                    "anonymous" => AuthenticationPolicyType::Anonymous(),
-                                    _ => unimplemented!(),
+                                typ: match (
+                                    policy_decl.typ.as_str(),
+                                    &policy_decl.keys,
+                                    &policy_decl.jwt,
+                                ) {
+                                    ("anonymous", _, _) => AuthenticationPolicyType::Anonymous(),
+                                    ("api_key", Some(keys), _) => {
+                                        AuthenticationPolicyType::ApiKey(keys.clone())
+                                    }
+                                    ("jwt", _, Some(jwt)) => {
+                                        AuthenticationPolicyType::Jwt(jwt.clone())
+                                    }
+                                    (typ, _, _) => {
+                                        return Err(ScoreError::CompileError {
+                                            error: format!(
+                                                "unknown type {} of authentication policy {}",
+                                                typ, policy_decl.name
+                                            ),
+                                            path: file.path.clone(),
+                                        })
+                                    }
                                 },
                             },
                         );
@@ -160,14 +280,252 @@ impl ScoreCompiler {
                             AuthorizationPolicy {
                                 namespace: ns.name.clone(),
                                 name: policy_decl.name.clone(),
-                                permissive_expr: policy_decl.permissive_expr.clone(),
+                                kind: policy_decl.kind,
+                                table: policy_decl.table.clone(),
+                                expr: policy_decl.expr.clone(),
                             },
                         );
                     }
+                    Statement::MaskingPolicyDecl(policy_decl) => {
+                        if ns.masking_policies.contains_key(&policy_decl.name) {
+                            return Err(ScoreError::CompileError {
+                                error: "conflicting masking policy declaration".into(),
+                                path: file.path.clone(),
+                            });
+                        }
+
+                        ns.masking_policies.insert(
+                            policy_decl.name.clone(),
+                            MaskingPolicy {
+                                namespace: ns.name.clone(),
+                                name: policy_decl.name.clone(),
+                                table: policy_decl.table.clone(),
+                                column: policy_decl.column.clone(),
+                                mask: policy_decl.mask.clone(),
+                                unless: policy_decl.unless.clone(),
+                            },
+                        );
+                    }
+                    Statement::PolicyTestDecl(test_decl) => {
+                        if policy_tests.iter().any(|t| t.name == test_decl.name) {
+                            return Err(ScoreError::CompileError {
+                                error: "conflicting policy test declaration".into(),
+                                path: file.path.clone(),
+                            });
+                        }
+
+                        let mut request = test_decl.request.clone();
+                        if request.namespace.is_empty() {
+                            request.namespace = ns.name.clone();
+                        }
+                        policy_tests.push(PolicyTest {
+                            name: test_decl.name.clone(),
+                            request,
+                            allowed: test_decl.allowed,
+                            deciding_policies: test_decl.deciding_policies.clone(),
+                        });
+                    }
+                }
+            }
+        }
+
+        check_keys_tables(&ns).map_err(ScoreError::Error)?;
+        check_policies(&ns).map_err(ScoreError::Error)?;
+        check_masking_policies(&ns).map_err(ScoreError::Error)?;
+        check_policy_tests(&ns, &policy_tests).map_err(ScoreError::Error)?;
+
+        Ok((ns, policy_tests))
+    }
+}
+
+/// Check that the keys tables of `api_key` authentication policies have the
+/// columns keys are stored in.
+fn check_keys_tables(ns: &Namespace) -> std::result::Result<(), String> {
+    for policy in ns.authentication_policies.values() {
+        let AuthenticationPolicyType::ApiKey(ApiKeySource::Table(table_name)) = &policy.typ else {
+            continue;
+        };
+        let table = ns.tables.get(table_name).ok_or_else(|| {
+            format!(
+                "keys table {} of authentication policy {} not found",
+                table_name, policy.name
+            )
+        })?;
+
+        let mut columns = table
+            .columns
+            .iter()
+            .map(|c| (c.name.as_str(), &c.data_type))
+            .collect::<Vec<_>>();
+        columns.sort();
+        let mut expected = api_key::KEYS_TABLE_COLUMNS
+            .iter()
+            .map(|name| (*name, &sqlparser::ast::DataType::Text))
+            .collect::<Vec<_>>();
+        expected.sort();
+        if columns != expected {
+            return Err(format!(
+                "keys table {} of authentication policy {} must have the TEXT columns {}",
+                table_name,
+                policy.name,
+                api_key::KEYS_TABLE_COLUMNS.join(", ")
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Check that the authorization policies of http handlers and tables are
+/// declared, that the tables of row policies exist, and that the expressions
+/// of policies compile to booleans, those of row policies against the columns
+/// of their table.
+fn check_policies(ns: &Namespace) -> std::result::Result<(), String> {
+    let handlers = ns
+        .http_handlers
+        .values()
+        .map(|h| ("http handler", &h.name, &h.policies));
+    let tables = ns.tables.values().map(|t| ("table", &t.name, &t.policies));
+
+    for (entity, name, policies) in handlers.chain(tables) {
+        for policy in policies {
+            match ns.authorization_policies.get(policy) {
+                None => {
+                    return Err(format!(
+                        "authorization policy {policy} of {entity} {name} not found"
+                    ))
                 }
+                Some(p) if p.table.is_some() => {
+                    return Err(format!(
+                        "authorization policy {policy} of {entity} {name} is a row policy"
+                    ))
+                }
+                Some(_) => {}
             }
         }
+    }
 
-        Ok(ns)
+    let auth_eval = AuthEval::default();
+    for policy in ns.authorization_policies.values() {
+        if policy.table.is_none() {
+            auth_eval.compile(policy).map_err(|e| e.to_string())?;
+        }
+        if let Some(table) = &policy.table {
+            let table = ns
+                .tables
+                .get(table)
+                .ok_or_else(|| format!("table {table} of row policy {} not found", policy.name))?;
+            auth_eval
+                .check_row_policy(policy, table)
+                .map_err(|e| e.to_string())?;
+        }
     }
+
+    Ok(())
+}
+
+/// Check that masking policies mask existing columns, each by one policy,
+/// truncate only text, and that their expressions compile against the
+/// columns of their table, to booleans for UNLESS.
+fn check_masking_policies(ns: &Namespace) -> std::result::Result<(), String> {
+    let auth_eval = AuthEval::default();
+    let mut masked = HashSet::new();
+
+    for policy in ns.masking_policies.values() {
+        let (table, column) = ns
+            .tables
+            .get(&policy.table)
+            .and_then(|t| Some((t, t.columns.iter().find(|c| c.name == policy.column)?)))
+            .ok_or_else(|| {
+                format!(
+                    "column {}.{} of masking policy {} not found",
+                    policy.table, policy.column, policy.name
+                )
+            })?;
+
+        if !masked.insert((&policy.table, &policy.column)) {
+            return Err(format!(
+                "column {}.{} has more than one masking policy",
+                policy.table, policy.column
+            ));
+        }
+
+        if matches!(policy.mask, Mask::Truncate(_))
+            && column.data_type != sqlparser::ast::DataType::Text
+        {
+            return Err(format!(
+                "masking policy {} truncates column {}.{}, which is not TEXT",
+                policy.name, policy.table, policy.column
+            ));
+        }
+
+        auth_eval
+            .check_masking_policy(policy, table)
+            .map_err(|e| e.to_string())?;
+    }
+
+    Ok(())
+}
+
+/// Check that policy tests request http handlers of the namespace and expect
+/// declared policies to decide.
+fn check_policy_tests(ns: &Namespace, tests: &[PolicyTest]) -> std::result::Result<(), String> {
+    for test in tests {
+        if test.request.namespace != ns.name {
+            return Err(format!(
+                "namespace {} of policy test {} is not {}",
+                test.request.namespace, test.name, ns.name
+            ));
+        }
+        if !ns.http_handlers.contains_key(&test.request.handler) {
+            return Err(format!(
+                "http handler {} of policy test {} not found",
+                test.request.handler, test.name
+            ));
+        }
+        for policy in test.deciding_policies.iter().flatten() {
+            if !ns.authorization_policies.contains_key(policy) {
+                return Err(format!(
+                    "authorization policy {policy} of policy test {} not found",
+                    test.name
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The input columns declared by http handler `handler`.
+fn compile_input(
+    handler: &str,
+    columns: &[sqlparser::ast::ColumnDef],
+) -> std::result::Result<Vec<InputColumn>, String> {
+    // The body as is comes with the input columns.
+    let mut names = HashSet::from(["body".to_string()]);
+    let mut input = vec![];
+
+    for column in columns {
+        if !names.insert(column.name.value.clone()) {
+            return Err(format!(
+                "conflicting input column {} of http handler {handler}",
+                column.name
+            ));
+        }
+
+        let input_column = InputColumn {
+            name: column.name.value.clone(),
+            data_type: column.data_type.clone(),
+            nullable: !column
+                .options
+                .iter()
+                .any(|o| matches!(o.option, sqlparser::ast::ColumnOption::NotNull)),
+        };
+        input_column
+            .arrow_type()
+            .map_err(|e| format!("http handler {handler}: {e}"))?;
+        input.push(input_column);
+    }
+
+    Ok(input)
 }
@@ -3,7 +3,7 @@ use std::{collections::VecDeque, fs, io, path::PathBuf};
 pub mod compiler;
 pub mod parser;
 
-use catalog::Catalog;
+use catalog::{auth::AuthorizationRequest, Catalog};
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -33,6 +33,11 @@ impl Score {
 
     /// Parse the score file and return a catalog.
     pub fn catalog(&self) -> Result<Catalog> {
+        Ok(self.compile()?.0)
+    }
+
+    /// Parse the score file and return a catalog and the policy tests.
+    pub fn compile(&self) -> Result<(Catalog, Vec<PolicyTest>)> {
         let score_files = fs::read_dir(&self.path)?
             .map(|entry| -> Result<_> {
                 let entry = entry?;
@@ -66,12 +71,20 @@ impl Score {
 
         // Compile the statements into a catalog.
         let compiler = compiler::ScoreCompiler {};
-        let catalog = compiler.compile(pkg)?;
-
-        Ok(catalog)
+        compiler.compile(pkg)
     }
 }
 
+/// A `POLICY_TEST`: the decision expected on a request to an http handler.
+#[derive(Debug, Clone)]
+pub struct PolicyTest {
+    pub name: String,
+    pub request: AuthorizationRequest,
+    pub allowed: bool,
+    /// The policies expected to decide, if given.
+    pub deciding_policies: Option<Vec<String>>,
+}
+
 struct ScorePkg {
     #[allow(dead_code)]
     path: PathBuf,
@@ -1,23 +1,29 @@
-use std::collections::VecDeque;
+use std::{collections::VecDeque, time::Duration};
 
+use catalog::{
+    auth::AuthorizationRequest, ApiKeySource, HttpMethod, JwtKeySource, JwtPolicy, Mask, PolicyKind,
+};
 use sql::parser::SqlParser;
 use sqlparser::{
     ast::{DollarQuotedString, Ident, TableConstraint, Value},
     dialect::GenericDialect,
     keywords::Keyword,
-    parser::Parser,
+    parser::{IsOptional, Parser},
     tokenizer::{self, Token, TokenWithLocation, Tokenizer},
 };
 
 use crate::{Result, ScoreError};
 
 #[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
 pub enum Statement {
     NamespaceDecl(String),
     TableDecl(TableDecl),
     HttpHandlerDecl(HttpHandlerDecl),
     AuthenticationPolicyDecl(AuthenticationPolicyDecl),
     AuthorizationPolicyDecl(AuthorizationPolicyDecl),
+    MaskingPolicyDecl(MaskingPolicyDecl),
+    PolicyTestDecl(PolicyTestDecl),
 }
 
 #[derive(Debug)]
@@ -26,6 +32,9 @@ pub struct TableDecl {
     pub uuid: uuid::Uuid,
     pub columns: Vec<ColumnDef>,
     // pub constraints: Vec<TableConstraint>,
+    pub partition_by: Vec<Ident>,
+    pub retention_hours: Option<u64>,
+    pub policies: Vec<String>,
 }
 
 #[derive(Debug)]
@@ -37,20 +46,50 @@ pub struct ColumnDef {
 #[derive(Debug)]
 pub struct HttpHandlerDecl {
     pub name: String,
-    pub policy: String,
-    pub body: sql::parser::Statement,
+    pub policies: Vec<String>,
+    pub method: HttpMethod,
+    pub input: Option<Vec<sqlparser::ast::ColumnDef>>,
+    pub batch_window: Option<Duration>,
+    /// The statements of the body, run in order in a transaction of their
+    /// own, see [`sql::SqlSession::execute_all`].
+    pub body: Vec<sql::parser::Statement>,
 }
 
 #[derive(Debug)]
 pub struct AuthenticationPolicyDecl {
     pub name: String,
     pub typ: String,
+    /// Where the keys of an `api_key` policy are kept.
+    pub keys: Option<ApiKeySource>,
+    /// The tokens a `jwt` policy accepts.
+    pub jwt: Option<JwtPolicy>,
 }
 
 #[derive(Debug)]
 pub struct AuthorizationPolicyDecl {
     pub name: String,
-    pub permissive_expr: sqlparser::ast::Expr,
+    pub kind: PolicyKind,
+    /// The table whose rows a row policy applies to.
+    pub table: Option<String>,
+    pub expr: sqlparser::ast::Expr,
+}
+
+#[derive(Debug)]
+pub struct MaskingPolicyDecl {
+    pub name: String,
+    pub table: String,
+    pub column: String,
+    pub mask: Mask,
+    pub unless: Option<sqlparser::ast::Expr>,
+}
+
+#[derive(Debug)]
+pub struct PolicyTestDecl {
+    pub name: String,
+    pub request: AuthorizationRequest,
+    pub allowed: bool,
+    /// The policies expected to decide, if given.
+    pub deciding_policies: Option<Vec<String>>,
 }
 
 pub struct ScoreParser<'a> {
@@ -117,6 +156,8 @@ impl<'a> ScoreParser<'a> {
             "HTTP_HANDLER",
             "AUTHENTICATION_POLICY",
             "AUTHORIZATION_POLICY",
+            "MASKING_POLICY",
+            "POLICY_TEST",
         ];
 
         if let Token::Word(w) = self.peek_token().token {
@@ -137,6 +178,14 @@ impl<'a> ScoreParser<'a> {
                     self.parser.next_token();
                     return self.parse_authorization_policy_decl();
                 }
+                "MASKING_POLICY" => {
+                    self.parser.next_token();
+                    return self.parse_masking_policy_decl();
+                }
+                "POLICY_TEST" => {
+                    self.parser.next_token();
+                    return self.parse_policy_test_decl();
+                }
                 _ => {}
             }
         }
@@ -148,15 +197,71 @@ impl<'a> ScoreParser<'a> {
         let name = self.parser.parse_identifier()?;
         let uuid = self.parse_table_uuid()?;
         let (columns, _constraints) = self.parse_columns()?;
+        let partition_by = self.parse_table_partition_by()?;
+        let retention_hours = self.parse_table_retention()?;
+        let policies = match self.peek_token().token {
+            Token::Word(w) if w.value.to_lowercase() == "policy" => self.parse_policies()?,
+            _ => vec![],
+        };
 
         Ok(Statement::TableDecl(TableDecl {
             name: name.value,
             uuid,
             columns,
             // constraints,
+            partition_by,
+            retention_hours,
+            policies,
         }))
     }
 
+    /// `POLICY name, ...`
+    fn parse_policies(&mut self) -> Result<Vec<String>> {
+        self.expect_word("POLICY")?;
+
+        Ok(self
+            .parser
+            .parse_comma_separated(Parser::parse_identifier)?
+            .into_iter()
+            .map(|policy| policy.value)
+            .collect())
+    }
+
+    fn parse_table_partition_by(&mut self) -> Result<Vec<Ident>> {
+        if !self
+            .parser
+            .parse_keywords(&[Keyword::PARTITION, Keyword::BY])
+        {
+            return Ok(vec![]);
+        }
+
+        Ok(self
+            .parser
+            .parse_parenthesized_column_list(IsOptional::Mandatory, false)?)
+    }
+
+    fn parse_table_retention(&mut self) -> Result<Option<u64>> {
+        match self.parser.peek_token().token {
+            Token::Word(w) if w.value.to_lowercase() == "retention" => {
+                self.parser.next_token();
+                let hours = match self.parser.parse_value()? {
+                    Value::Number(num, _) => num
+                        .parse::<u64>()
+                        .map_err(|e| ScoreError::Error(e.to_string()))?,
+                    _ => return self.expected("literal number", self.peek_token()),
+                };
+                self.parser.expect_token(&Token::Word(tokenizer::Word {
+                    value: "HOURS".to_string(),
+                    quote_style: None,
+                    keyword: Keyword::NoKeyword,
+                }))?;
+
+                Ok(Some(hours))
+            }
+            _ => Ok(None),
+        }
+    }
+
     fn parse_table_uuid(&mut self) -> Result<uuid::Uuid> {
         match self.parser.peek_token().token {
             Token::Word(w) => match w.value.to_lowercase().as_str() {
@@ -266,13 +371,10 @@ impl<'a> ScoreParser<'a> {
 
     fn parse_http_handler_decl(&mut self) -> Result<Statement> {
         let name = self.parser.parse_identifier()?;
-
-        self.parser.expect_token(&Token::Word(tokenizer::Word {
-            value: "POLICY".to_string(),
-            quote_style: None,
-            keyword: Keyword::NoKeyword,
-        }))?;
-        let policy = self.parser.parse_identifier()?;
+        let policies = self.parse_policies()?;
+        let method = self.parse_http_handler_method()?;
+        let input = self.parse_http_handler_input()?;
+        let batch_window = self.parse_http_handler_batch_window()?;
 
         self.parser.expect_keyword(Keyword::AS)?;
         let body = match self.peek_token().token {
@@ -280,61 +382,365 @@ impl<'a> ScoreParser<'a> {
                 self.parser.next_token();
 
                 let mut sql_stmt_parser = SqlParser::new(&value)?;
-                sql_stmt_parser.parse_sql()?.into_iter().next().unwrap()
+                let stmts = Vec::from(sql_stmt_parser.parse_sql()?);
+                if stmts.is_empty() {
+                    return self.expected("statements in the body", self.peek_token());
+                }
+                stmts
             }
             _ => return self.expected("dollar quoted string", self.peek_token()),
         };
 
         let handler_decl = HttpHandlerDecl {
             name: name.value,
-            policy: policy.value,
+            policies,
+            method,
+            input,
+            batch_window,
             body,
         };
         Ok(Statement::HttpHandlerDecl(handler_decl))
     }
 
+    fn parse_http_handler_method(&mut self) -> Result<HttpMethod> {
+        match self.parser.peek_token().token {
+            Token::Word(w) if w.value.to_lowercase() == "method" => {
+                self.parser.next_token();
+
+                let twl = self.peek_token();
+                match self
+                    .parser
+                    .parse_identifier()?
+                    .value
+                    .to_uppercase()
+                    .as_str()
+                {
+                    "GET" => Ok(HttpMethod::Get),
+                    "POST" => Ok(HttpMethod::Post),
+                    _ => self.expected("GET or POST", twl),
+                }
+            }
+            _ => Ok(HttpMethod::Post),
+        }
+    }
+
+    /// `INPUT (name type [NOT NULL], ...)`
+    fn parse_http_handler_input(&mut self) -> Result<Option<Vec<sqlparser::ast::ColumnDef>>> {
+        match self.parser.peek_token().token {
+            Token::Word(w) if w.value.to_lowercase() == "input" => {
+                self.parser.next_token();
+                self.parser.expect_token(&Token::LParen)?;
+                let columns = self
+                    .parser
+                    .parse_comma_separated(Parser::parse_column_def)?;
+                self.parser.expect_token(&Token::RParen)?;
+
+                Ok(Some(columns))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn parse_http_handler_batch_window(&mut self) -> Result<Option<Duration>> {
+        match self.parser.peek_token().token {
+            Token::Word(w) if w.value.to_lowercase() == "batch" => {
+                self.parser.next_token();
+                self.parser.expect_keyword(Keyword::WINDOW)?;
+
+                Ok(Some(self.parse_duration()?))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// A duration such as `500ms`, `1s` or `2m`.
+    fn parse_duration(&mut self) -> Result<Duration> {
+        let twl = self.peek_token();
+        let amount = match self.parser.parse_value()? {
+            Value::Number(num, _) => num
+                .parse::<u64>()
+                .map_err(|e| ScoreError::Error(e.to_string()))?,
+            _ => return self.expected("duration such as 500ms, 1s or 2m", twl),
+        };
+
+        match self.parser.next_token().token {
+            Token::Word(w) => match w.value.to_lowercase().as_str() {
+                "ms" => Ok(Duration::from_millis(amount)),
+                "s" => Ok(Duration::from_secs(amount)),
+                "m" => match amount.checked_mul(60) {
+                    Some(secs) => Ok(Duration::from_secs(secs)),
+                    None => Err(ScoreError::Error(format!("duration {amount}m is too long"))),
+                },
+                _ => self.expected("duration unit ms, s or m", twl),
+            },
+            _ => self.expected("duration unit ms, s or m", twl),
+        }
+    }
+
     fn peek_token(&self) -> TokenWithLocation {
         self.parser.peek_token()
     }
 
+    /// `name TYPE = anonymous`,
+    /// `name TYPE = api_key KEYS_FILE 'path' | KEYS_TABLE table` or
+    /// `name TYPE = jwt ISSUER 'iss' AUDIENCE 'aud' ALGORITHMS (alg, ...)
+    ///  JWKS_FILE 'path' | PEM_FILE 'path'`
     fn parse_authentication_policy_decl(&mut self) -> Result<Statement> {
         let name = self.parser.parse_identifier()?;
 
         self.parser.expect_keyword(Keyword::TYPE)?;
         self.parser.expect_token(&Token::Eq)?;
-        self.parser.expect_token(&Token::Word(tokenizer::Word {
-            value: "anonymous".to_string(),
-            quote_style: None,
-            keyword: Keyword::NoKeyword,
-        }))?;
+        let typ = self.parser.parse_identifier()?.value.to_lowercase();
+
+        let keys = if typ == "api_key" {
+            let twl = self.peek_token();
+            match self
+                .parser
+                .parse_identifier()?
+                .value
+                .to_uppercase()
+                .as_str()
+            {
+                "KEYS_FILE" => Some(ApiKeySource::File(self.parser.parse_literal_string()?)),
+                "KEYS_TABLE" => Some(ApiKeySource::Table(self.parser.parse_identifier()?.value)),
+                _ => return self.expected("KEYS_FILE or KEYS_TABLE", twl),
+            }
+        } else {
+            None
+        };
+        let jwt = if typ == "jwt" {
+            Some(self.parse_jwt_policy()?)
+        } else {
+            None
+        };
 
         Ok(Statement::AuthenticationPolicyDecl(
             AuthenticationPolicyDecl {
                 name: name.value,
-                typ: "anonymous".to_string(),
+                typ,
+                keys,
+                jwt,
             },
         ))
     }
 
+    fn parse_jwt_policy(&mut self) -> Result<JwtPolicy> {
+        self.expect_word("ISSUER")?;
+        let issuer = self.parser.parse_literal_string()?;
+        self.expect_word("AUDIENCE")?;
+        let audience = self.parser.parse_literal_string()?;
+
+        self.expect_word("ALGORITHMS")?;
+        self.parser.expect_token(&Token::LParen)?;
+        let mut algorithms = vec![];
+        loop {
+            let twl = self.peek_token();
+            match self.parser.parse_identifier()?.value.parse() {
+                Ok(algorithm) => algorithms.push(algorithm),
+                Err(_) => return self.expected("HS256, RS256 or ES256", twl),
+            }
+            if !self.parser.consume_token(&Token::Comma) {
+                break;
+            }
+        }
+        self.parser.expect_token(&Token::RParen)?;
+
+        let twl = self.peek_token();
+        let keys = match self
+            .parser
+            .parse_identifier()?
+            .value
+            .to_uppercase()
+            .as_str()
+        {
+            "JWKS_FILE" => JwtKeySource::JwksFile(self.parser.parse_literal_string()?),
+            "PEM_FILE" => JwtKeySource::PemFile(self.parser.parse_literal_string()?),
+            _ => return self.expected("JWKS_FILE or PEM_FILE", twl),
+        };
+
+        Ok(JwtPolicy {
+            issuer,
+            audience,
+            algorithms,
+            keys,
+        })
+    }
+
+    /// Consume the unquoted word `expected`, in any case.
+    fn expect_word(&mut self, expected: &str) -> Result<()> {
+        match self.peek_token().token {
+            Token::Word(w) if w.quote_style.is_none() && w.value.eq_ignore_ascii_case(expected) => {
+                self.parser.next_token();
+                Ok(())
+            }
+            _ => self.expected(expected, self.peek_token()),
+        }
+    }
+
+    /// `name permissive_expr = <expr>` or `name restrictive_expr = <expr>`,
+    /// or a row policy
+    /// `name ON TABLE table [AS PERMISSIVE | RESTRICTIVE] USING <expr>`
     fn parse_authorization_policy_decl(&mut self) -> Result<Statement> {
         let name = self.parser.parse_identifier()?;
 
-        self.parser.expect_token(&Token::Word(tokenizer::Word {
-            value: "permissive_expr".to_string(),
-            quote_style: None,
-            keyword: Keyword::NoKeyword,
-        }))?;
+        if self.parser.parse_keyword(Keyword::ON) {
+            self.parser.expect_keyword(Keyword::TABLE)?;
+            let table = self.parser.parse_identifier()?;
+            let kind = match self.parser.parse_keyword(Keyword::AS) {
+                true => {
+                    let twl = self.peek_token();
+                    match self
+                        .parser
+                        .parse_identifier()?
+                        .value
+                        .to_lowercase()
+                        .as_str()
+                    {
+                        "permissive" => PolicyKind::Permissive,
+                        "restrictive" => PolicyKind::Restrictive,
+                        _ => return self.expected("PERMISSIVE or RESTRICTIVE", twl),
+                    }
+                }
+                false => PolicyKind::Permissive,
+            };
+            self.parser.expect_keyword(Keyword::USING)?;
+            let expr = self.parser.parse_expr()?;
+
+            return Ok(Statement::AuthorizationPolicyDecl(
+                AuthorizationPolicyDecl {
+                    name: name.value,
+                    kind,
+                    table: Some(table.value),
+                    expr,
+                },
+            ));
+        }
+
+        let twl = self.peek_token();
+        let kind = match self
+            .parser
+            .parse_identifier()?
+            .value
+            .to_lowercase()
+            .as_str()
+        {
+            "permissive_expr" => PolicyKind::Permissive,
+            "restrictive_expr" => PolicyKind::Restrictive,
+            _ => return self.expected("permissive_expr or restrictive_expr", twl),
+        };
         self.parser.expect_token(&Token::Eq)?;
-        let permissive_expr = self.parser.parse_expr()?;
+        let expr = self.parser.parse_expr()?;
 
         Ok(Statement::AuthorizationPolicyDecl(
             AuthorizationPolicyDecl {
                 name: name.value,
-                permissive_expr,
+                kind,
+                table: None,
+                expr,
             },
         ))
     }
 
+    /// `name ON COLUMN table.column USING <mask> [UNLESS <expr>]`, the mask
+    /// being one of `HASH`, `REDACT`, `TRUNCATE(<length>)` or `(<expr>)`.
+    fn parse_masking_policy_decl(&mut self) -> Result<Statement> {
+        let name = self.parser.parse_identifier()?;
+        self.parser.expect_keyword(Keyword::ON)?;
+        self.parser.expect_keyword(Keyword::COLUMN)?;
+        let table = self.parser.parse_identifier()?.value;
+        self.parser.expect_token(&Token::Period)?;
+        let column = self.parser.parse_identifier()?.value;
+        self.parser.expect_keyword(Keyword::USING)?;
+
+        let twl = self.peek_token();
+        let mask = match &twl.token {
+            Token::LParen => {
+                self.parser.next_token();
+                let expr = self.parser.parse_expr()?;
+                self.parser.expect_token(&Token::RParen)?;
+                Mask::Expr(expr)
+            }
+            Token::Word(w) => {
+                self.parser.next_token();
+                match w.value.to_lowercase().as_str() {
+                    "hash" => Mask::Hash,
+                    "redact" => Mask::Redact,
+                    "truncate" => {
+                        self.parser.expect_token(&Token::LParen)?;
+                        let length = self.parser.parse_literal_uint()?;
+                        self.parser.expect_token(&Token::RParen)?;
+                        Mask::Truncate(length)
+                    }
+                    _ => return self.expected("HASH, REDACT, TRUNCATE or (<expr>)", twl),
+                }
+            }
+            _ => return self.expected("HASH, REDACT, TRUNCATE or (<expr>)", twl),
+        };
+
+        let unless = match self.peek_token().token {
+            Token::Word(w) if w.value.eq_ignore_ascii_case("unless") => {
+                self.parser.next_token();
+                Some(self.parser.parse_expr()?)
+            }
+            _ => None,
+        };
+
+        Ok(Statement::MaskingPolicyDecl(MaskingPolicyDecl {
+            name: name.value,
+            table,
+            column,
+            mask,
+            unless,
+        }))
+    }
+
+    /// `name REQUEST $$<request as JSON>$$ EXPECT ALLOW | DENY [BY policy, ...]`
+    fn parse_policy_test_decl(&mut self) -> Result<Statement> {
+        let name = self.parser.parse_identifier()?;
+
+        self.expect_word("REQUEST")?;
+        let request = match self.peek_token().token {
+            Token::DollarQuotedString(DollarQuotedString { value, .. }) => {
+                self.parser.next_token();
+                serde_json::from_str(&value).map_err(|e| {
+                    ScoreError::Error(format!("invalid request of policy test {name}: {e}"))
+                })?
+            }
+            _ => return self.expected("dollar quoted string", self.peek_token()),
+        };
+
+        self.expect_word("EXPECT")?;
+        let twl = self.peek_token();
+        let allowed = match self
+            .parser
+            .parse_identifier()?
+            .value
+            .to_lowercase()
+            .as_str()
+        {
+            "allow" => true,
+            "deny" => false,
+            _ => return self.expected("ALLOW or DENY", twl),
+        };
+        let deciding_policies = match self.parser.parse_keyword(Keyword::BY) {
+            true => Some(
+                self.parser
+                    .parse_comma_separated(Parser::parse_identifier)?
+                    .into_iter()
+                    .map(|policy| policy.value)
+                    .collect(),
+            ),
+            false => None,
+        };
+
+        Ok(Statement::PolicyTestDecl(PolicyTestDecl {
+            name: name.value,
+            request,
+            allowed,
+            deciding_policies,
+        }))
+    }
+
     fn expected<T>(&self, expected: &str, found: TokenWithLocation) -> Result<T> {
         Err(ScoreError::Error(format!(
             "Expected {expected}, found: {found} at Line: {}, Column {}",
@@ -345,6 +751,8 @@ impl<'a> ScoreParser<'a> {
 
 #[cfg(test)]
 mod tests {
+    use catalog::JwtAlgorithm;
+
     use super::*;
 
     #[test]
@@ -371,4 +779,301 @@ mod tests {
         let stmts = ScoreParser::new(sql).unwrap().parse().unwrap();
         println!("{:?}", stmts);
     }
+
+    /// The statement `decl` declares in a namespace of its own.
+    fn parse(decl: &str) -> Result<Statement> {
+        let sql = format!("NAMESPACE ns; {decl}");
+        let mut stmts = ScoreParser::new(&sql)?.parse()?;
+        assert_eq!(stmts.len(), 2);
+
+        Ok(stmts.pop_back().unwrap())
+    }
+
+    fn table(clauses: &str) -> Result<TableDecl> {
+        let decl = format!(
+            "TABLE t UUID '9B972E4A-D412-48CD-9290-7BD2A192966B' \
+             (id INTEGER UID 1, age INTEGER UID 2) {clauses}"
+        );
+        match parse(&decl)? {
+            Statement::TableDecl(table) => Ok(table),
+            stmt => panic!("unexpected statement: {:?}", stmt),
+        }
+    }
+
+    fn http_handler(clauses: &str) -> Result<HttpHandlerDecl> {
+        match parse(&format!("HTTP_HANDLER h {clauses}"))? {
+            Statement::HttpHandlerDecl(handler) => Ok(handler),
+            stmt => panic!("unexpected statement: {:?}", stmt),
+        }
+    }
+
+    fn authentication_policy(clauses: &str) -> Result<AuthenticationPolicyDecl> {
+        match parse(&format!("AUTHENTICATION_POLICY p TYPE = {clauses}"))? {
+            Statement::AuthenticationPolicyDecl(policy) => Ok(policy),
+            stmt => panic!("unexpected statement: {:?}", stmt),
+        }
+    }
+
+    fn authorization_policy(clauses: &str) -> Result<AuthorizationPolicyDecl> {
+        match parse(&format!("AUTHORIZATION_POLICY p {clauses}"))? {
+            Statement::AuthorizationPolicyDecl(policy) => Ok(policy),
+            stmt => panic!("unexpected statement: {:?}", stmt),
+        }
+    }
+
+    fn masking_policy(clauses: &str) -> Result<MaskingPolicyDecl> {
+        match parse(&format!("MASKING_POLICY p ON COLUMN t.name {clauses}"))? {
+            Statement::MaskingPolicyDecl(policy) => Ok(policy),
+            stmt => panic!("unexpected statement: {:?}", stmt),
+        }
+    }
+
+    fn policy_test(clauses: &str) -> Result<PolicyTestDecl> {
+        match parse(&format!("POLICY_TEST t {clauses}"))? {
+            Statement::PolicyTestDecl(test) => Ok(test),
+            stmt => panic!("unexpected statement: {:?}", stmt),
+        }
+    }
+
+    #[test]
+    fn test_table_partition_by() {
+        assert_eq!(table("").unwrap().partition_by, vec![]);
+        assert_eq!(
+            table("PARTITION BY (age)").unwrap().partition_by,
+            vec![Ident::new("age")]
+        );
+
+        assert!(table("PARTITION BY age").is_err());
+        assert!(table("PARTITION BY ()").is_err());
+    }
+
+    #[test]
+    fn test_table_retention() {
+        assert_eq!(table("").unwrap().retention_hours, None);
+        assert_eq!(
+            table("RETENTION 24 HOURS").unwrap().retention_hours,
+            Some(24)
+        );
+        assert_eq!(
+            table("PARTITION BY (age) RETENTION 1 HOURS")
+                .unwrap()
+                .retention_hours,
+            Some(1)
+        );
+
+        assert!(table("RETENTION 99999999999999999999 HOURS").is_err());
+        assert!(table("RETENTION 24 DAYS").is_err());
+        assert!(table("RETENTION 24").is_err());
+    }
+
+    #[test]
+    fn test_table_policy() {
+        assert!(table("").unwrap().policies.is_empty());
+        assert_eq!(
+            table("RETENTION 24 HOURS POLICY tenant_only, internal_only")
+                .unwrap()
+                .policies,
+            vec!["tenant_only", "internal_only"]
+        );
+
+        assert!(table("POLICY").is_err());
+        assert!(table("POLICY tenant_only RETENTION 24 HOURS").is_err());
+    }
+
+    #[test]
+    fn test_http_handler_policy() {
+        let handler = http_handler("POLICY allow_all, internal_only AS $$SELECT 1$$").unwrap();
+        assert_eq!(handler.policies, vec!["allow_all", "internal_only"]);
+
+        assert!(http_handler("AS $$SELECT 1$$").is_err());
+        assert!(http_handler("POLICY AS $$SELECT 1$$").is_err());
+    }
+
+    #[test]
+    fn test_http_handler_method() {
+        let handler = http_handler("POLICY p AS $$SELECT 1$$").unwrap();
+        assert_eq!(handler.method, HttpMethod::Post);
+        let handler = http_handler("POLICY p METHOD get AS $$SELECT 1$$").unwrap();
+        assert_eq!(handler.method, HttpMethod::Get);
+
+        assert!(http_handler("POLICY p METHOD PUT AS $$SELECT 1$$").is_err());
+    }
+
+    #[test]
+    fn test_http_handler_input() {
+        let handler = http_handler(
+            "POLICY p INPUT (id INTEGER NOT NULL, name TEXT) \
+             AS $$INSERT INTO t SELECT id, name FROM temporary.input$$",
+        )
+        .unwrap();
+        let input = handler.input.unwrap();
+        assert_eq!(input.len(), 2);
+        assert_eq!(input[0].name, Ident::new("id"));
+        assert_eq!(input[1].data_type, sqlparser::ast::DataType::Text);
+
+        assert!(http_handler("POLICY p INPUT id INTEGER AS $$SELECT 1$$").is_err());
+        assert!(http_handler("POLICY p INPUT () AS $$SELECT 1$$").is_err());
+    }
+
+    #[test]
+    fn test_http_handler_batch_window() {
+        let batch_window = |window: &str| {
+            http_handler(&format!(
+                "POLICY p BATCH WINDOW {window} AS $$INSERT INTO t VALUES (1)$$"
+            ))
+            .map(|handler| handler.batch_window)
+        };
+        assert_eq!(
+            batch_window("500ms").unwrap(),
+            Some(Duration::from_millis(500))
+        );
+        assert_eq!(batch_window("2 s").unwrap(), Some(Duration::from_secs(2)));
+        assert_eq!(batch_window("3m").unwrap(), Some(Duration::from_secs(180)));
+        let handler = http_handler("POLICY p METHOD POST AS $$SELECT 1$$").unwrap();
+        assert_eq!(handler.batch_window, None);
+
+        assert!(batch_window("5h").is_err());
+        assert!(batch_window("500").is_err());
+        assert!(batch_window("fast").is_err());
+        assert!(batch_window("99999999999999999999ms").is_err());
+        assert!(batch_window("999999999999999999m").is_err());
+    }
+
+    #[test]
+    fn test_http_handler_body() {
+        let handler = http_handler(
+            "POLICY p AS $$
+                INSERT INTO foo SELECT * FROM bar;
+                DELETE FROM bar;
+            $$",
+        )
+        .unwrap();
+        assert_eq!(handler.body.len(), 2);
+
+        assert!(http_handler("POLICY p AS $$$$").is_err());
+        assert!(http_handler("POLICY p AS 'SELECT 1'").is_err());
+    }
+
+    #[test]
+    fn test_api_key_policy() {
+        let policy = authentication_policy("api_key KEYS_FILE '/etc/conductor/keys.json'").unwrap();
+        assert_eq!(
+            policy.keys,
+            Some(ApiKeySource::File("/etc/conductor/keys.json".to_string()))
+        );
+        let policy = authentication_policy("API_KEY keys_table api_keys").unwrap();
+        assert_eq!(policy.typ, "api_key");
+        assert_eq!(
+            policy.keys,
+            Some(ApiKeySource::Table("api_keys".to_string()))
+        );
+        assert_eq!(authentication_policy("anonymous").unwrap().keys, None);
+
+        assert!(authentication_policy("api_key KEYS_DIR '/etc/conductor'").is_err());
+        assert!(authentication_policy("api_key").is_err());
+    }
+
+    #[test]
+    fn test_jwt_policy() {
+        let jwt = |options: &str| {
+            authentication_policy(&format!(
+                "jwt ISSUER 'https://idp.example.com/' AUDIENCE 'conductor' {options}"
+            ))
+        };
+        let policy = jwt("ALGORITHMS (RS256, es256) JWKS_FILE '/etc/conductor/jwks.json'").unwrap();
+        let policy = policy.jwt.unwrap();
+        assert_eq!(policy.issuer, "https://idp.example.com/");
+        assert_eq!(policy.audience, "conductor");
+        assert_eq!(
+            policy.algorithms,
+            vec![JwtAlgorithm::RS256, JwtAlgorithm::ES256]
+        );
+        assert_eq!(
+            policy.keys,
+            JwtKeySource::JwksFile("/etc/conductor/jwks.json".to_string())
+        );
+        let policy = jwt("ALGORITHMS (HS256) PEM_FILE '/etc/conductor/key.pem'").unwrap();
+        assert_eq!(
+            policy.jwt.unwrap().keys,
+            JwtKeySource::PemFile("/etc/conductor/key.pem".to_string())
+        );
+
+        assert!(jwt("ALGORITHMS (none) JWKS_FILE '/etc/conductor/jwks.json'").is_err());
+        assert!(jwt("ALGORITHMS () JWKS_FILE '/etc/conductor/jwks.json'").is_err());
+        assert!(jwt("ALGORITHMS (RS256) JWKS_URL 'https://idp.example.com/jwks'").is_err());
+        assert!(jwt("JWKS_FILE '/etc/conductor/jwks.json'").is_err());
+        assert!(authentication_policy("jwt AUDIENCE 'conductor'").is_err());
+    }
+
+    #[test]
+    fn test_authorization_policy() {
+        let policy = authorization_policy("permissive_expr = true").unwrap();
+        assert_eq!(policy.kind, PolicyKind::Permissive);
+        assert_eq!(policy.table, None);
+        let policy = authorization_policy("restrictive_expr = client_ip << '10.0.0.0/8'").unwrap();
+        assert_eq!(policy.kind, PolicyKind::Restrictive);
+
+        assert!(authorization_policy("lenient_expr = true").is_err());
+        assert!(authorization_policy("permissive_expr true").is_err());
+    }
+
+    #[test]
+    fn test_row_policy() {
+        let policy = authorization_policy("ON TABLE bar USING owner = principal").unwrap();
+        assert_eq!(policy.table.as_deref(), Some("bar"));
+        assert_eq!(policy.kind, PolicyKind::Permissive);
+        assert_eq!(policy.expr.to_string(), "owner = principal");
+        let policy =
+            authorization_policy("ON TABLE bar AS RESTRICTIVE USING NOT archived").unwrap();
+        assert_eq!(policy.kind, PolicyKind::Restrictive);
+
+        assert!(authorization_policy("ON TABLE bar AS LENIENT USING true").is_err());
+        assert!(authorization_policy("ON bar USING true").is_err());
+        assert!(authorization_policy("ON TABLE bar owner = principal").is_err());
+    }
+
+    #[test]
+    fn test_masking_policy() {
+        let policy = masking_policy("USING HASH UNLESS claims.role = 'admin'").unwrap();
+        assert_eq!(
+            (policy.table.as_str(), policy.column.as_str()),
+            ("t", "name")
+        );
+        assert_eq!(policy.mask, Mask::Hash);
+        assert_eq!(policy.unless.unwrap().to_string(), "claims.role = 'admin'");
+        assert_eq!(masking_policy("USING redact").unwrap().mask, Mask::Redact);
+        let policy = masking_policy("USING TRUNCATE(2)").unwrap();
+        assert_eq!(policy.mask, Mask::Truncate(2));
+        assert_eq!(policy.unless, None);
+        let policy = masking_policy("USING (age / 10 * 10)").unwrap();
+        assert_eq!(policy.mask.to_string(), "(age / 10 * 10)");
+
+        assert!(masking_policy("USING SCRAMBLE").is_err());
+        assert!(masking_policy("USING TRUNCATE(-1)").is_err());
+        assert!(masking_policy("USING TRUNCATE").is_err());
+        assert!(parse("MASKING_POLICY p ON COLUMN name USING HASH").is_err());
+    }
+
+    #[test]
+    fn test_policy_test() {
+        let test = policy_test(
+            r#"REQUEST $${"handler": "list", "client_ip": "192.168.0.1", "headers": {"X-Tenant": "acme"}}$$
+            EXPECT DENY BY internal_only"#,
+        )
+        .unwrap();
+        assert_eq!(test.request.handler, "list");
+        assert_eq!(test.request.headers["x-tenant"], "acme");
+        assert!(!test.allowed);
+        assert_eq!(
+            test.deciding_policies,
+            Some(vec!["internal_only".to_string()])
+        );
+        let test = policy_test(r#"REQUEST $${"handler": "list"}$$ EXPECT ALLOW"#).unwrap();
+        assert!(test.allowed);
+        assert_eq!(test.deciding_policies, None);
+
+        assert!(policy_test(r#"REQUEST $${"handler": "list"}$$ EXPECT MAYBE"#).is_err());
+        assert!(policy_test(r#"REQUEST $${"handler": }$$ EXPECT ALLOW"#).is_err());
+        assert!(policy_test(r#"REQUEST '{"handler": "list"}' EXPECT ALLOW"#).is_err());
+    }
 }
@@ -0,0 +1,882 @@
+//! UPDATE and MERGE INTO for ensemble tables.
+//!
+//! Ensemble tables can only delete rows, so UPDATE and MERGE are carried out
+//! copy-on-write: a query computes the new contents of the target table from
+//! a snapshot of it, and the result replaces that snapshot in a single
+//! commit. For tables that tell where their rows are stored, only the part of
+//! the snapshot stored where the changed rows are is replaced, e.g. the data
+//! files holding them.
+
+use std::{collections::HashMap, sync::Arc};
+
+use datafusion::{
+    arrow::{
+        array::{Int64Array, StringArray, UInt64Array},
+        datatypes::{DataType, Field, Schema},
+        record_batch::RecordBatch,
+    },
+    common::{
+        tree_node::{Transformed, TreeNode},
+        Column, OwnedTableReference,
+    },
+    datasource::{provider_as_source, source_as_provider, TableProvider},
+    logical_expr::{cast, Expr, LogicalPlan, TableScan},
+    prelude::DataFrame,
+    sql::parser::Statement as DFStatement,
+};
+use ensemble::{EnsembleTable, LOCATION_COLUMN};
+use sqlparser::ast::{
+    Assignment, Ident, MergeClause, ObjectName, Statement, TableFactor, TableWithJoins,
+};
+
+use crate::{parser, Error, SqlSession};
+
+/// Marks rows coming from the source relation of a MERGE.
+const SOURCE_ROW_COLUMN: &str = "__conductor_source_row";
+/// Index of the WHEN MATCHED clause applied to a target row, 0 for none.
+const MERGE_ACTION_COLUMN: &str = "__conductor_merge_action";
+
+impl SqlSession {
+    pub(crate) async fn execute_update(&self, stmt: Statement) -> Result<RecordBatch, Error> {
+        let Statement::Update {
+            table,
+            assignments,
+            from,
+            selection,
+            returning,
+        } = &stmt
+        else {
+            unreachable!("not an UPDATE statement");
+        };
+
+        if from.is_some() || returning.is_some() || !table.joins.is_empty() {
+            return Err(Error::Error(
+                "UPDATE with FROM, RETURNING or joins is not supported".to_string(),
+            ));
+        }
+
+        let (x_table, reference, target) = self.target_table(table)?;
+        self.check_target_reads(&stmt, &x_table, &reference, &relation_alias(&target)?)?;
+        let snapshot = x_table.snapshot().await?;
+        let schema = snapshot.schema();
+
+        let assignments = assignment_map(assignments, &schema)?;
+        let mut predicate = selection
+            .as_ref()
+            .map(|e| format!("({})", e))
+            .unwrap_or_else(|| "TRUE".to_string());
+        if let Some(filter) = self.row_filter_sql(&reference, &schema) {
+            predicate = format!("{} AND ({})", predicate, filter);
+        }
+
+        let num_updated_rows = self
+            .query_on_snapshot(
+                &format!("SELECT count(*) FROM {} WHERE {}", target, predicate),
+                &x_table,
+                &snapshot,
+            )
+            .await?;
+        let num_updated_rows = count_result(num_updated_rows.collect().await?)?;
+
+        if num_updated_rows > 0 {
+            let target_alias = relation_alias(&target)?;
+            let part = self
+                .changed_part(&x_table, &target, &snapshot, |located| {
+                    format!(
+                        "SELECT DISTINCT {}.{} FROM {} WHERE {}",
+                        target_alias,
+                        identifier(LOCATION_COLUMN),
+                        located,
+                        predicate
+                    )
+                })
+                .await?;
+
+            let projection = schema
+                .fields()
+                .iter()
+                .map(|f| {
+                    let column = identifier(f.name());
+                    match assignments.get(f.name()) {
+                        Some(value) => format!(
+                            "CASE WHEN {} THEN {} ELSE {} END AS {}",
+                            predicate, value, column, column
+                        ),
+                        None => column.to_string(),
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join(", ");
+
+            let updated = async {
+                let rows = self
+                    .query_on_snapshot(
+                        &format!("SELECT {} FROM {}", projection, part.relation),
+                        &x_table,
+                        &snapshot,
+                    )
+                    .await?;
+                x_table
+                    .overwrite(
+                        part.snapshot.clone(),
+                        cast_to_schema(rows, &schema)?.execute_stream().await?,
+                        "UPDATE",
+                    )
+                    .await?;
+
+                Ok::<_, Error>(())
+            }
+            .await;
+            self.deregister_tables(&part.registered)?;
+            updated?;
+        }
+
+        affected_rows_batch(&[("num_updated_rows", num_updated_rows)])
+    }
+
+    pub(crate) async fn execute_merge(&self, stmt: Statement) -> Result<RecordBatch, Error> {
+        let Statement::Merge {
+            table,
+            source,
+            on,
+            clauses,
+            ..
+        } = &stmt
+        else {
+            unreachable!("not a MERGE statement");
+        };
+
+        let (x_table, reference, target) = self.target_table(&TableWithJoins {
+            relation: table.clone(),
+            joins: vec![],
+        })?;
+        if self.row_filter_sql(&reference, &x_table.schema()).is_some() {
+            return Err(Error::Error(
+                "MERGE into a table with row policies is not supported".to_string(),
+            ));
+        }
+        let target_alias = relation_alias(&target)?;
+        let source_alias = relation_alias(source)?;
+        self.check_target_reads(&stmt, &x_table, &reference, &target_alias)?;
+        let snapshot = x_table.snapshot().await?;
+        let schema = snapshot.schema();
+
+        // Split the clauses into WHEN MATCHED (applied to the target rows) and
+        // WHEN NOT MATCHED (producing new rows from the source).
+        let matched = format!(
+            "{}.{} IS NOT NULL",
+            source_alias,
+            identifier(SOURCE_ROW_COLUMN)
+        );
+        let mut action_cases = vec![];
+        let mut column_cases = HashMap::<String, Vec<String>>::new();
+        let mut update_actions = vec![];
+        let mut delete_actions = vec![];
+        let mut not_matched = vec![];
+
+        for clause in clauses {
+            match clause {
+                MergeClause::MatchedUpdate {
+                    predicate,
+                    assignments,
+                } => {
+                    let action = action_cases.len() + 1;
+                    let condition = clause_condition(&matched, predicate.as_ref());
+                    for (name, value) in assignment_map(assignments, &schema)? {
+                        column_cases
+                            .entry(name)
+                            .or_default()
+                            .push(format!("WHEN {} THEN {}", condition, value));
+                    }
+                    action_cases.push(format!("WHEN {} THEN {}", condition, action));
+                    update_actions.push(action);
+                }
+                MergeClause::MatchedDelete(predicate) => {
+                    let action = action_cases.len() + 1;
+                    let condition = clause_condition(&matched, predicate.as_ref());
+                    action_cases.push(format!("WHEN {} THEN {}", condition, action));
+                    delete_actions.push(action);
+                }
+                MergeClause::NotMatched {
+                    predicate,
+                    columns,
+                    values,
+                } => not_matched.push((predicate, columns, values)),
+            }
+        }
+
+        // Target rows joined with the matching source rows. Every target row
+        // must match at most one source row.
+        let action_column = identifier(MERGE_ACTION_COLUMN);
+        let mut projection = schema
+            .fields()
+            .iter()
+            .map(|f| {
+                let column = format!("{}.{}", target_alias, identifier(f.name()));
+                match column_cases.get(f.name()) {
+                    Some(cases) => format!(
+                        "CASE {} ELSE {} END AS {}",
+                        cases.join(" "),
+                        column,
+                        identifier(f.name())
+                    ),
+                    None => format!("{} AS {}", column, identifier(f.name())),
+                }
+            })
+            .collect::<Vec<_>>();
+        projection.push(match action_cases.is_empty() {
+            true => format!("0 AS {}", action_column),
+            false => format!(
+                "CASE {} ELSE 0 END AS {}",
+                action_cases.join(" "),
+                action_column
+            ),
+        });
+        let target_rows = |relation: &TableFactor, projection: &[String]| {
+            format!(
+                "SELECT {} FROM {} LEFT JOIN (SELECT *, TRUE AS {} FROM {}) AS {} ON {}",
+                projection.join(", "),
+                relation,
+                identifier(SOURCE_ROW_COLUMN),
+                source,
+                source_alias,
+                on
+            )
+        };
+
+        let mut action_counts = HashMap::new();
+        let actions = self
+            .query_on_snapshot(
+                &format!(
+                    "SELECT {}, count(*) FROM ({}) GROUP BY {}",
+                    action_column,
+                    target_rows(&target, &projection),
+                    action_column
+                ),
+                &x_table,
+                &snapshot,
+            )
+            .await?
+            .collect()
+            .await?;
+        for batch in actions {
+            let (Some(action), Some(count)) = (
+                batch.column(0).as_any().downcast_ref::<Int64Array>(),
+                batch.column(1).as_any().downcast_ref::<Int64Array>(),
+            ) else {
+                return Err(Error::Error("unexpected MERGE action counts".to_string()));
+            };
+            for (action, count) in action.iter().zip(count.iter()) {
+                action_counts.insert(
+                    action.unwrap_or_default() as usize,
+                    count.unwrap_or_default() as u64,
+                );
+            }
+        }
+
+        let num_target_rows = self
+            .query_on_snapshot(
+                &format!("SELECT count(*) FROM {}", target),
+                &x_table,
+                &snapshot,
+            )
+            .await?;
+        let num_target_rows = count_result(num_target_rows.collect().await?)?;
+        if action_counts.values().sum::<u64>() > num_target_rows {
+            return Err(Error::Error(
+                "MERGE matched a target row with more than one source row".to_string(),
+            ));
+        }
+
+        // Source rows without a matching target row, inserted by the first
+        // WHEN NOT MATCHED clause whose condition holds.
+        let mut inserted_rows = vec![];
+        let mut previous_conditions = vec![];
+        for (predicate, columns, values) in not_matched {
+            let [row] = values.rows.as_slice() else {
+                return Err(Error::Error(
+                    "MERGE INSERT must have exactly one row of VALUES".to_string(),
+                ));
+            };
+            let columns = match columns.is_empty() {
+                true => schema
+                    .fields()
+                    .iter()
+                    .map(|f| Ident::new(f.name()))
+                    .collect(),
+                false => columns.clone(),
+            };
+            if columns.len() != row.len() {
+                return Err(Error::Error(
+                    "MERGE INSERT columns and VALUES differ in length".to_string(),
+                ));
+            }
+
+            let values = columns
+                .iter()
+                .map(|c| c.value.clone())
+                .zip(row.iter())
+                .collect::<HashMap<_, _>>();
+            let projection = schema
+                .fields()
+                .iter()
+                .map(|f| match values.get(f.name()) {
+                    Some(value) => format!("{} AS {}", value, identifier(f.name())),
+                    None => format!("NULL AS {}", identifier(f.name())),
+                })
+                .collect::<Vec<_>>();
+
+            let condition = predicate
+                .as_ref()
+                .map(|p| format!("({})", p))
+                .unwrap_or_else(|| "TRUE".to_string());
+            let mut conditions = vec![condition.clone()];
+            conditions.extend(
+                previous_conditions
+                    .iter()
+                    .map(|c| format!("{} IS NOT TRUE", c)),
+            );
+            previous_conditions.push(condition);
+
+            inserted_rows.push(format!(
+                "SELECT {} FROM {} LEFT ANTI JOIN {} ON {} WHERE {}",
+                projection.join(", "),
+                source,
+                target,
+                on,
+                conditions.join(" AND ")
+            ));
+        }
+
+        let num_inserted_rows = match inserted_rows.is_empty() {
+            true => 0,
+            false => {
+                let count = self
+                    .query_on_snapshot(
+                        &format!(
+                            "SELECT count(*) FROM ({}) AS inserted",
+                            inserted_rows.join(" UNION ALL ")
+                        ),
+                        &x_table,
+                        &snapshot,
+                    )
+                    .await?;
+                count_result(count.collect().await?)?
+            }
+        };
+        let num_updated_rows = update_actions
+            .iter()
+            .filter_map(|a| action_counts.get(a))
+            .sum::<u64>();
+        let num_deleted_rows = delete_actions
+            .iter()
+            .filter_map(|a| action_counts.get(a))
+            .sum::<u64>();
+        let num_affected_rows = num_updated_rows + num_deleted_rows + num_inserted_rows;
+
+        if num_affected_rows > 0 {
+            // Only target rows a WHEN MATCHED clause applies to change.
+            let location = identifier(LOCATION_COLUMN);
+            let part = self
+                .changed_part(&x_table, &target, &snapshot, |located| {
+                    let mut projection = projection.clone();
+                    projection.push(format!("{}.{} AS {}", target_alias, location, location));
+                    format!(
+                        "SELECT DISTINCT {} FROM ({}) AS merged WHERE {} <> 0",
+                        location,
+                        target_rows(located, &projection),
+                        action_column
+                    )
+                })
+                .await?;
+
+            let columns = schema
+                .fields()
+                .iter()
+                .map(|f| identifier(f.name()).to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+            let mut remaining_rows = format!(
+                "SELECT {} FROM ({}) AS merged",
+                columns,
+                target_rows(&part.relation, &projection)
+            );
+            if !delete_actions.is_empty() {
+                remaining_rows = format!(
+                    "{} WHERE {} NOT IN ({})",
+                    remaining_rows,
+                    action_column,
+                    delete_actions
+                        .iter()
+                        .map(|a| a.to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+            }
+
+            let merged = async {
+                let rows = self
+                    .query_on_snapshot(
+                        &std::iter::once(remaining_rows)
+                            .chain(inserted_rows)
+                            .collect::<Vec<_>>()
+                            .join(" UNION ALL "),
+                        &x_table,
+                        &snapshot,
+                    )
+                    .await?;
+                x_table
+                    .overwrite(
+                        part.snapshot.clone(),
+                        cast_to_schema(rows, &schema)?.execute_stream().await?,
+                        "MERGE",
+                    )
+                    .await?;
+
+                Ok::<_, Error>(())
+            }
+            .await;
+            self.deregister_tables(&part.registered)?;
+            merged?;
+        }
+
+        affected_rows_batch(&[
+            ("num_affected_rows", num_affected_rows),
+            ("num_updated_rows", num_updated_rows),
+            ("num_deleted_rows", num_deleted_rows),
+            ("num_inserted_rows", num_inserted_rows),
+        ])
+    }
+
+    /// Resolve the target of an UPDATE or MERGE. The returned relation always
+    /// has an alias, so its columns can be qualified by a single identifier.
+    fn target_table(
+        &self,
+        table: &TableWithJoins,
+    ) -> Result<(Arc<dyn EnsembleTable>, OwnedTableReference, TableFactor), Error> {
+        let TableFactor::Table { name, alias, .. } = &table.relation else {
+            return Err(Error::Error(format!("not a table: {}", table.relation)));
+        };
+
+        let reference = OwnedTableReference::from(name.to_string());
+        let x_table = self.ensemble_table(&reference)?;
+
+        let mut relation = table.relation.clone();
+        if alias.is_none() {
+            if let TableFactor::Table { alias, .. } = &mut relation {
+                *alias = Some(sqlparser::ast::TableAlias {
+                    name: Ident::new(table_name(name)),
+                    columns: vec![],
+                });
+            }
+        }
+
+        Ok((x_table, reference, relation))
+    }
+
+    /// The part of `snapshot` a statement changes, at the locations
+    /// `changed_locations` selects given the target relation with the
+    /// location of each row, and the target relation reading from that part.
+    /// The whole snapshot if the table doesn't tell where its rows are.
+    async fn changed_part(
+        &self,
+        x_table: &Arc<dyn EnsembleTable>,
+        target: &TableFactor,
+        snapshot: &Arc<dyn TableProvider>,
+        changed_locations: impl FnOnce(&TableFactor) -> String,
+    ) -> Result<ChangedPart, Error> {
+        let Some(located) = x_table.located_snapshot(snapshot.clone()).await? else {
+            return Ok(ChangedPart {
+                snapshot: snapshot.clone(),
+                relation: target.clone(),
+                registered: vec![],
+            });
+        };
+
+        let (relation, reference) = self.register_relation(target, "located", located)?;
+        let locations = match self
+            .query_on_snapshot(&changed_locations(&relation), x_table, snapshot)
+            .await
+        {
+            Ok(locations) => locations.collect().await.map_err(Error::from),
+            Err(e) => Err(e),
+        };
+        self.deregister_tables(&[reference])?;
+        let mut changed = vec![];
+        for batch in locations? {
+            let Some(locations) = batch.column(0).as_any().downcast_ref::<StringArray>() else {
+                return Err(Error::Error("unexpected row locations".to_string()));
+            };
+            changed.extend(locations.iter().flatten().map(str::to_string));
+        }
+
+        let part = x_table.snapshot_part(snapshot.clone(), &changed).await?;
+        let (relation, reference) = self.register_relation(target, "part", part.clone())?;
+
+        Ok(ChangedPart {
+            snapshot: part,
+            relation,
+            registered: vec![reference],
+        })
+    }
+
+    /// Register `provider` as `<table>@<suffix>` next to the table of
+    /// `relation`, and return the relation reading from it instead.
+    fn register_relation(
+        &self,
+        relation: &TableFactor,
+        suffix: &str,
+        provider: Arc<dyn TableProvider>,
+    ) -> Result<(TableFactor, OwnedTableReference), Error> {
+        let mut relation = relation.clone();
+        let TableFactor::Table { name, .. } = &mut relation else {
+            return Err(Error::Error(format!("not a table: {}", relation)));
+        };
+        let table = name.0.pop().unwrap_or_else(|| Ident::new(""));
+        // The registered name is quoted, so normalize it like the planner
+        // does unquoted identifiers.
+        let table = match table.quote_style {
+            Some(_) => table.value,
+            None => table.value.to_lowercase(),
+        };
+        name.0.push(identifier(&format!("{}@{}", table, suffix)));
+
+        let reference = OwnedTableReference::from(name.to_string());
+        self.schema(&reference)?
+            .register_table(reference.table().to_string(), provider)?;
+
+        Ok((relation, reference))
+    }
+
+    /// Plan `sql` with scans of `x_table` reading from `snapshot` instead, so
+    /// all queries of a statement see the same version of the table.
+    async fn query_on_snapshot(
+        &self,
+        sql: &str,
+        x_table: &Arc<dyn EnsembleTable>,
+        snapshot: &Arc<dyn TableProvider>,
+    ) -> Result<DataFrame, Error> {
+        let stmt = {
+            let mut parser = parser::SqlParser::new(sql)?;
+            match parser.parse_sql()?.pop_front() {
+                Some(parser::Statement::Statement(stmt)) => stmt,
+                _ => return Err(Error::Error(format!("expected a query: {sql}"))),
+            }
+        };
+
+        let plan = self
+            .state
+            .statement_to_plan(DFStatement::Statement(Box::new(stmt)))
+            .await?;
+        let plan = self.apply_table_policies(plan, Some(x_table))?;
+        let plan = plan.transform_up(&|plan| match plan {
+            LogicalPlan::TableScan(scan) if is_scan_of(&scan, x_table) => {
+                Ok(Transformed::Yes(LogicalPlan::TableScan(TableScan {
+                    source: provider_as_source(snapshot.clone()),
+                    ..scan
+                })))
+            }
+            plan => Ok(Transformed::No(plan)),
+        })?;
+
+        Ok(DataFrame::new(self.state.clone(), plan))
+    }
+}
+
+/// The part of the target of an UPDATE or MERGE it changes.
+struct ChangedPart {
+    snapshot: Arc<dyn TableProvider>,
+    /// The target relation, reading from `snapshot`.
+    relation: TableFactor,
+    /// Tables registered for the statement, to deregister once it is done.
+    registered: Vec<OwnedTableReference>,
+}
+
+pub(crate) fn is_scan_of(scan: &TableScan, x_table: &Arc<dyn EnsembleTable>) -> bool {
+    source_as_provider(&scan.source)
+        .map(|provider| {
+            std::ptr::eq(
+                Arc::as_ptr(&provider) as *const u8,
+                Arc::as_ptr(x_table) as *const u8,
+            )
+        })
+        .unwrap_or(false)
+}
+
+fn table_name(name: &ObjectName) -> &str {
+    name.0.last().map(|i| i.value.as_str()).unwrap_or_default()
+}
+
+fn relation_alias(relation: &TableFactor) -> Result<Ident, Error> {
+    match relation {
+        TableFactor::Table {
+            alias: Some(alias), ..
+        }
+        | TableFactor::Derived {
+            alias: Some(alias), ..
+        } => Ok(alias.name.clone()),
+        TableFactor::Table { name, .. } => Ok(Ident::new(table_name(name))),
+        relation => Err(Error::Error(format!(
+            "relation must have an alias: {}",
+            relation
+        ))),
+    }
+}
+
+fn assignment_map(
+    assignments: &[Assignment],
+    schema: &Schema,
+) -> Result<HashMap<String, sqlparser::ast::Expr>, Error> {
+    assignments
+        .iter()
+        .map(|assignment| {
+            let column = assignment
+                .id
+                .last()
+                .ok_or_else(|| Error::Error("empty assignment target".to_string()))?;
+            schema.field_with_name(&column.value)?;
+
+            Ok((column.value.clone(), assignment.value.clone()))
+        })
+        .collect()
+}
+
+fn clause_condition(matched: &str, predicate: Option<&sqlparser::ast::Expr>) -> String {
+    match predicate {
+        Some(predicate) => format!("{} AND ({})", matched, predicate),
+        None => matched.to_string(),
+    }
+}
+
+fn identifier(name: &str) -> Ident {
+    Ident::with_quote('"', name)
+}
+
+/// Cast the columns of `df` to the table schema, the written batches must
+/// match it exactly.
+fn cast_to_schema(df: DataFrame, schema: &Schema) -> Result<DataFrame, Error> {
+    Ok(df.select(
+        schema
+            .fields()
+            .iter()
+            .map(|f| {
+                cast(
+                    Expr::Column(Column::from_name(f.name())),
+                    f.data_type().clone(),
+                )
+                .alias(f.name())
+            })
+            .collect(),
+    )?)
+}
+
+fn count_result(batches: Vec<RecordBatch>) -> Result<u64, Error> {
+    batches
+        .first()
+        .and_then(|batch| batch.column(0).as_any().downcast_ref::<Int64Array>())
+        .and_then(|array| array.iter().next().flatten())
+        .map(|count| count as u64)
+        .ok_or_else(|| Error::Error("unexpected count result".to_string()))
+}
+
+pub(crate) fn affected_rows_batch(counts: &[(&str, u64)]) -> Result<RecordBatch, Error> {
+    let schema = Schema::new(
+        counts
+            .iter()
+            .map(|(name, _)| Field::new(*name, DataType::UInt64, false))
+            .collect::<Vec<_>>(),
+    );
+
+    Ok(RecordBatch::try_new(
+        Arc::new(schema),
+        counts
+            .iter()
+            .map(|(_, count)| Arc::new(UInt64Array::from(vec![*count])) as _)
+            .collect(),
+    )?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use datafusion::{
+        arrow::array::{Int32Array, Int64Array, StringArray},
+        prelude::SessionContext,
+    };
+    use ensemble::{Ensemble, LOCATION_COLUMN};
+    use ensemble_x::EnsembleX;
+    use sqlparser::ast::DataType;
+
+    use crate::{tests::test_ensemble, SqlSession};
+
+    async fn session() -> SqlSession {
+        let ensemble = test_ensemble(
+            &[
+                (
+                    "t",
+                    &[("id", DataType::Integer(None)), ("name", DataType::Text)],
+                ),
+                (
+                    "s",
+                    &[("id", DataType::Integer(None)), ("name", DataType::Text)],
+                ),
+            ],
+            vec![],
+        )
+        .await;
+        let mut session = SqlSession::new(&ensemble).await.unwrap();
+        session
+            .execute(
+                "INSERT INTO ns.t VALUES (1, 'a'), (2, 'b'); \
+                 INSERT INTO ns.s VALUES (2, 'y'), (3, 'z')",
+            )
+            .await
+            .unwrap();
+
+        session
+    }
+
+    /// The number of rows of `ns.t` `predicate` holds for.
+    async fn count(session: &mut SqlSession, predicate: &str) -> i64 {
+        let batches = session
+            .execute(&format!("SELECT count(*) AS n FROM ns.t WHERE {predicate}"))
+            .await
+            .unwrap();
+        batches[0]
+            .column(0)
+            .as_any()
+            .downcast_ref::<Int64Array>()
+            .unwrap()
+            .value(0)
+    }
+
+    #[tokio::test]
+    async fn test_update() {
+        let mut session = session().await;
+
+        session
+            .execute("UPDATE ns.t SET name = 'x' WHERE id = 1")
+            .await
+            .unwrap();
+        assert_eq!(count(&mut session, "id = 1 AND name = 'x'").await, 1);
+        assert_eq!(count(&mut session, "id = 2 AND name = 'b'").await, 1);
+
+        session
+            .execute("UPDATE ns.t SET id = id + 10")
+            .await
+            .unwrap();
+        assert_eq!(count(&mut session, "id = 11 AND name = 'x'").await, 1);
+        assert_eq!(count(&mut session, "id = 12 AND name = 'b'").await, 1);
+        assert_eq!(count(&mut session, "TRUE").await, 2);
+    }
+
+    #[tokio::test]
+    async fn test_merge() {
+        let mut session = session().await;
+
+        session
+            .execute(
+                "MERGE INTO ns.t USING ns.s ON t.id = s.id \
+                 WHEN MATCHED THEN UPDATE SET name = s.name \
+                 WHEN NOT MATCHED THEN INSERT (id, name) VALUES (s.id, s.name)",
+            )
+            .await
+            .unwrap();
+        assert_eq!(count(&mut session, "id = 1 AND name = 'a'").await, 1);
+        assert_eq!(count(&mut session, "id = 2 AND name = 'y'").await, 1);
+        assert_eq!(count(&mut session, "id = 3 AND name = 'z'").await, 1);
+        assert_eq!(count(&mut session, "TRUE").await, 3);
+
+        session
+            .execute(
+                "MERGE INTO ns.t USING ns.s ON t.id = s.id \
+                 WHEN MATCHED AND s.id = 3 THEN DELETE",
+            )
+            .await
+            .unwrap();
+        assert_eq!(count(&mut session, "id = 3").await, 0);
+        assert_eq!(count(&mut session, "TRUE").await, 2);
+    }
+
+    #[tokio::test]
+    async fn test_delete() {
+        let mut session = session().await;
+
+        session
+            .execute("DELETE FROM ns.t WHERE name = 'a'")
+            .await
+            .unwrap();
+        assert_eq!(count(&mut session, "TRUE").await, 1);
+
+        session.execute("DELETE FROM ns.t").await.unwrap();
+        assert_eq!(count(&mut session, "TRUE").await, 0);
+    }
+
+    /// Where the row of `ns.t` with each id is stored.
+    async fn locations(ensemble: &EnsembleX) -> HashMap<i32, String> {
+        let table = Ensemble::table(ensemble, "ns", "t").await.unwrap();
+        let snapshot = table.snapshot().await.unwrap();
+        let located = table.located_snapshot(snapshot).await.unwrap().unwrap();
+        let batches = SessionContext::new()
+            .read_table(located)
+            .unwrap()
+            .select_columns(&["id", LOCATION_COLUMN])
+            .unwrap()
+            .collect()
+            .await
+            .unwrap();
+
+        let mut locations = HashMap::new();
+        for batch in batches {
+            let ids = batch.column(0).as_any().downcast_ref::<Int32Array>();
+            let paths = batch.column(1).as_any().downcast_ref::<StringArray>();
+            for (id, path) in ids.unwrap().iter().zip(paths.unwrap()) {
+                locations.insert(id.unwrap(), path.unwrap().to_string());
+            }
+        }
+
+        locations
+    }
+
+    #[tokio::test]
+    async fn test_rewrite_changed_files() {
+        let columns = [("id", DataType::Integer(None)), ("name", DataType::Text)];
+        let ensemble = test_ensemble(&[("t", &columns), ("s", &columns)], vec![]).await;
+        let mut session = SqlSession::new(&ensemble).await.unwrap();
+        // A data file per INSERT.
+        session
+            .execute("INSERT INTO ns.t VALUES (1, 'a'), (2, 'b')")
+            .await
+            .unwrap();
+        session
+            .execute("INSERT INTO ns.s VALUES (1, 'a')")
+            .await
+            .unwrap();
+        session
+            .execute("INSERT INTO ns.t VALUES (3, 'c')")
+            .await
+            .unwrap();
+        let inserted = locations(&ensemble).await;
+
+        session
+            .execute("UPDATE ns.t SET name = 'x' WHERE id = 3")
+            .await
+            .unwrap();
+        let updated = locations(&ensemble).await;
+        assert_eq!(updated[&1], inserted[&1]);
+        assert_eq!(updated[&2], inserted[&2]);
+        assert_ne!(updated[&3], inserted[&3]);
+        assert_eq!(count(&mut session, "name = 'x'").await, 1);
+
+        session
+            .execute("MERGE INTO ns.t USING ns.s ON t.id = s.id WHEN MATCHED THEN DELETE")
+            .await
+            .unwrap();
+        let merged = locations(&ensemble).await;
+        assert!(!merged.contains_key(&1));
+        assert_ne!(merged[&2], updated[&2]);
+        assert_eq!(merged[&3], updated[&3]);
+        assert_eq!(count(&mut session, "TRUE").await, 2);
+    }
+}
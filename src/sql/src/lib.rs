@@ -1,27 +1,38 @@
 use std::{collections::HashMap, sync::Arc};
 
+use catalog::{auth::AuthorizationRequest, AuthorizationPolicy, MaskingPolicy};
+
 use datafusion::{
-    arrow::record_batch::RecordBatch,
+    arrow::{datatypes::SchemaRef, record_batch::RecordBatch},
     catalog::schema::{MemorySchemaProvider, SchemaProvider},
+    common::OwnedTableReference,
     execution::{context::SessionState, runtime_env::RuntimeEnv},
-    logical_expr::LogicalPlan,
-    prelude::{DataFrame, SessionConfig},
+    logical_expr::{expr_rewriter::unnormalize_col, LogicalPlan},
+    optimizer::analyzer::Analyzer,
+    prelude::{DataFrame, SessionConfig, SessionContext},
     sql::parser::Statement as DFStatement,
 };
-use ensemble_x::{EnsembleX, TableX};
+use ensemble::{Ensemble, EnsembleTable};
 
+use sqlparser::ast::Statement as SqlStatement;
 use thiserror::Error;
 
+mod dml;
+mod maintenance;
+mod masking;
 pub mod parser;
+mod row_policy;
+mod time_travel;
+mod transaction;
 
 #[derive(Error, Debug)]
 pub enum Error {
-    #[error("deltalake error: {0}")]
-    DeltaLakeError(#[from] deltalake::DeltaTableError),
     #[error("ensemble error: {0}")]
-    EnsembleError(#[from] ensemble_x::Error),
+    EnsembleError(#[from] ensemble::Error),
     #[error("datafusion error: {0}")]
     DFError(#[from] datafusion::error::DataFusionError),
+    #[error("arrow error: {0}")]
+    ArrowError(#[from] datafusion::arrow::error::ArrowError),
     #[error("sql parser error: {0}")]
     ParserError(#[from] sqlparser::parser::ParserError),
     #[error("sql tokenizer error: {0}")]
@@ -32,28 +43,64 @@ pub enum Error {
 
 pub struct SqlSession {
     pub state: SessionState,
-    tables: HashMap<String, Arc<TableX>>,
+    /// Tables of the ensemble by namespace and name.
+    tables: HashMap<(String, String), Arc<dyn EnsembleTable>>,
+    /// The open transaction, if any.
+    transaction: Option<transaction::Transaction>,
+    /// Row policies of tables by namespace and name of the table.
+    row_policies: HashMap<(String, String), Vec<AuthorizationPolicy>>,
+    /// Masking policies of tables by namespace and name of the table.
+    masking_policies: HashMap<(String, String), Vec<MaskingPolicy>>,
+    /// The request row policies are applied for, if any.
+    request: Option<AuthorizationRequest>,
 }
 
 impl SqlSession {
     #[allow(clippy::new_without_default)]
-    pub async fn new(ensemble: EnsembleX) -> Result<Self, Error> {
+    pub async fn new(ensemble: &dyn Ensemble) -> Result<Self, Error> {
         let config = SessionConfig::new()
             .with_information_schema(true)
             .with_default_catalog_and_schema("conductor", "public")
             .with_create_default_catalog_and_schema(true);
         let state = SessionState::with_config_rt(config, Arc::new(RuntimeEnv::default()));
+        // Row and masking policies are applied with the functions of authorization
+        // expressions.
+        let context = SessionContext::with_state(state);
+        for function in catalog::auth::functions() {
+            context.register_udf(function);
+        }
+        let state = context.state();
 
         let mut tables = HashMap::new();
+        let mut row_policies = HashMap::<_, Vec<_>>::new();
+        let mut masking_policies = HashMap::<_, Vec<_>>::new();
         let catalog = ensemble.catalog()?;
 
         for ns in catalog.namespaces.values() {
             let schema_provider = Arc::new(MemorySchemaProvider::new());
-            for table in ns.tables.values() {
-                let x_table = ensemble.table(&table.namespace, &table.name).await?;
+            // Keys tables hold credentials, so sessions don't see them.
+            for table in ns.tables.values().filter(|t| !ns.is_keys_table(&t.name)) {
+                let ensemble_table = ensemble.table(&table.namespace, &table.name).await?;
 
-                tables.insert(table.name.clone(), x_table.clone());
-                schema_provider.register_table(table.name.clone(), x_table)?;
+                tables.insert(
+                    (table.namespace.clone(), table.name.clone()),
+                    ensemble_table.clone(),
+                );
+                schema_provider.register_table(table.name.clone(), ensemble_table)?;
+            }
+            for policy in ns.authorization_policies.values() {
+                if let Some(table) = &policy.table {
+                    row_policies
+                        .entry((ns.name.clone(), table.clone()))
+                        .or_default()
+                        .push(policy.clone());
+                }
+            }
+            for policy in ns.masking_policies.values() {
+                masking_policies
+                    .entry((ns.name.clone(), policy.table.clone()))
+                    .or_default()
+                    .push(policy.clone());
             }
 
             state
@@ -63,7 +110,14 @@ impl SqlSession {
                 .register_schema(&ns.name, schema_provider)?;
         }
 
-        Ok(SqlSession { state, tables })
+        Ok(SqlSession {
+            state,
+            tables,
+            transaction: None,
+            row_policies,
+            masking_policies,
+            request: None,
+        })
     }
 
     pub fn register_schema(
@@ -80,15 +134,162 @@ impl SqlSession {
         Ok(())
     }
 
+    /// Run the statements of `sql` and return the rows of the last one.
     pub async fn execute(&mut self, sql: &str) -> Result<Vec<RecordBatch>, Error> {
-        let stmt;
+        Ok(self.execute_all(sql).await?.pop().unwrap_or_default())
+    }
+
+    /// Run the statements of `sql` in order and return the rows of each.
+    ///
+    /// Several statements outside of a transaction run in one of their own,
+    /// so their changes are committed together or not at all. An error ends
+    /// the transaction, dropping its changes.
+    ///
+    /// Changes to tables of an ensemble that can't commit them in a single
+    /// transaction, like Delta Lake tables, are committed one table at a time
+    /// and reverted if a later one fails, so readers may briefly see some of
+    /// them. DELETE, UPDATE and MERGE in a transaction are limited to small
+    /// tables.
+    pub async fn execute_all(&mut self, sql: &str) -> Result<Vec<Vec<RecordBatch>>, Error> {
+        let statements = parser::SqlParser::new(sql)?.parse_sql()?;
+        let implicit_transaction = statements.len() > 1
+            && self.transaction.is_none()
+            && !statements.iter().any(is_transaction_control);
 
-        {
-            let mut parser = parser::SqlParser::new(sql)?;
-            let mut statements = parser.parse_sql()?;
-            assert_eq!(statements.len(), 1, "multiple statements not supported yet");
+        let mut results = vec![];
+        let result = async {
+            if implicit_transaction {
+                self.begin()?;
+            }
+            for stmt in statements {
+                results.push(self.execute_parsed(stmt).await?);
+            }
+            if implicit_transaction {
+                self.commit().await?;
+            }
+            Ok(())
+        }
+        .await;
 
-            parser::Statement::Statement(stmt) = statements.pop_front().unwrap();
+        if let Err(e) = result {
+            if self.transaction.is_some() {
+                self.rollback()?;
+            }
+            return Err(e);
+        }
+
+        Ok(results)
+    }
+
+    async fn execute_parsed(&mut self, stmt: parser::Statement) -> Result<Vec<RecordBatch>, Error> {
+        match stmt {
+            parser::Statement::Statement(SqlStatement::StartTransaction { .. }) => {
+                self.begin()?;
+                Ok(vec![])
+            }
+            parser::Statement::Statement(SqlStatement::Commit { .. }) => {
+                self.commit().await?;
+                Ok(vec![])
+            }
+            parser::Statement::Statement(SqlStatement::Rollback { .. }) => {
+                self.rollback()?;
+                Ok(vec![])
+            }
+            parser::Statement::Statement(stmt) => {
+                let time_travel_tables = self.register_time_travel_tables(&stmt).await?;
+                let result = self.execute_statement(stmt).await;
+                self.deregister_tables(&time_travel_tables)?;
+
+                result
+            }
+            parser::Statement::DescribeHistory(name) => {
+                Ok(vec![self.describe_history(&name).await?])
+            }
+            parser::Statement::Optimize(_) | parser::Statement::Vacuum { .. }
+                if self.transaction.is_some() =>
+            {
+                Err(Error::Error(
+                    "OPTIMIZE and VACUUM can't run in a transaction".to_string(),
+                ))
+            }
+            parser::Statement::Optimize(name) => Ok(vec![self.optimize(&name).await?]),
+            parser::Statement::Vacuum {
+                table,
+                retain_hours,
+                dry_run,
+            } => Ok(vec![self.vacuum(&table, retain_hours, dry_run).await?]),
+        }
+    }
+
+    /// Compute the rows the INSERT statement `sql` would insert, without
+    /// writing them. Returns the namespace and name of the target table with
+    /// the rows, which have the schema of the table.
+    pub async fn rows_to_insert(
+        &self,
+        sql: &str,
+    ) -> Result<((String, String), Vec<RecordBatch>), Error> {
+        let mut statements = parser::SqlParser::new(sql)?.parse_sql()?;
+        let stmt = match (statements.pop_front(), statements.is_empty()) {
+            (Some(parser::Statement::Statement(stmt @ SqlStatement::Insert { .. })), true) => stmt,
+            _ => {
+                return Err(Error::Error(format!(
+                    "not a single INSERT statement: {sql}"
+                )))
+            }
+        };
+
+        let plan = self
+            .state
+            .statement_to_plan(DFStatement::Statement(Box::new(stmt)))
+            .await?;
+        let LogicalPlan::Dml(dml_stmt) = plan else {
+            return Err(Error::Error(format!(
+                "unsupported logical plan: {:?}",
+                plan
+            )));
+        };
+
+        // Make sure the target is a table of the ensemble.
+        self.ensemble_table(&dml_stmt.table_name)?;
+
+        let options = &self.state.config_options().catalog;
+        let table = dml_stmt
+            .table_name
+            .resolve(&options.default_catalog, &options.default_schema);
+        let input = self.apply_table_policies((*dml_stmt.input).clone(), None)?;
+        let rows = DataFrame::new(self.state.clone(), input).collect().await?;
+
+        Ok(((table.schema.to_string(), table.table.to_string()), rows))
+    }
+
+    /// Run the query `sql` and return the schema of its rows with the rows.
+    pub async fn query(&self, sql: &str) -> Result<(SchemaRef, Vec<RecordBatch>), Error> {
+        let mut statements = parser::SqlParser::new(sql)?.parse_sql()?;
+        let stmt = match (statements.pop_front(), statements.is_empty()) {
+            (Some(parser::Statement::Statement(stmt @ SqlStatement::Query(_))), true) => stmt,
+            _ => return Err(Error::Error(format!("not a single query: {sql}"))),
+        };
+
+        let plan = self
+            .state
+            .statement_to_plan(DFStatement::Statement(Box::new(stmt)))
+            .await?;
+        let plan = self.apply_table_policies(plan, None)?;
+        let df = DataFrame::new(self.state.clone(), plan);
+        let schema = Arc::new(df.schema().into());
+
+        Ok((schema, df.collect().await?))
+    }
+
+    async fn execute_statement(&mut self, stmt: SqlStatement) -> Result<Vec<RecordBatch>, Error> {
+        // DataFusion doesn't plan MERGE, and plans UPDATE only as the updated
+        // rows, so both are carried out from the statement itself.
+        match stmt {
+            stmt @ SqlStatement::Update { .. } => {
+                return Ok(vec![self.execute_update(stmt).await?])
+            }
+            stmt @ SqlStatement::Merge { .. } => return Ok(vec![self.execute_merge(stmt).await?]),
+            _ => {}
         }
 
         let plan = self
@@ -98,6 +299,7 @@ impl SqlSession {
 
         match plan {
             plan @ LogicalPlan::Projection(_) => {
+                let plan = self.apply_table_policies(plan, None)?;
                 Ok(DataFrame::new(self.state.clone(), plan).collect().await?)
             }
             plan @ LogicalPlan::Dml(_) => {
@@ -107,21 +309,56 @@ impl SqlSession {
                             datafusion::logical_expr::WriteOp::Insert => {
                                 // Collect the input plan.
                                 let input =
-                                    DataFrame::new(self.state.clone(), (*dml_stmt.input).clone());
+                                    self.apply_table_policies((*dml_stmt.input).clone(), None)?;
+                                let input = DataFrame::new(self.state.clone(), input);
+
+                                let table = self.ensemble_table(&dml_stmt.table_name)?;
 
-                                // Get the delta table handle.
-                                // TODO: Qualify table names correctly.
-                                let table = self
-                                    .tables
-                                    .get_mut(dml_stmt.table_name.table())
-                                    .ok_or(Error::Error(format!(
-                                        "table not found: {}",
-                                        dml_stmt.table_name
-                                    )))?;
+                                let metrics = table.write(input.execute_stream().await?).await?;
 
-                                table.write(input.execute_stream().await?).await?;
+                                return Ok(vec![dml::affected_rows_batch(&[
+                                    ("num_inserted_rows", metrics.num_rows),
+                                    ("num_files_added", metrics.num_files),
+                                    ("num_bytes_added", metrics.num_bytes),
+                                ])?]);
+                            }
+                            datafusion::logical_expr::WriteOp::Delete => {
+                                let table = self.ensemble_table(&dml_stmt.table_name)?;
+
+                                // The input is a scan of the table, filtered by
+                                // the WHERE clause if there is one, and by the
+                                // row policies of the table. The analyzer
+                                // coerces the predicate to the column types.
+                                let input = self.restrict_delete(
+                                    &dml_stmt.table_name,
+                                    &table.schema(),
+                                    &dml_stmt.input,
+                                )?;
+                                let input = Analyzer::new().execute_and_check(
+                                    &input,
+                                    self.state.config_options(),
+                                    |_, _| {},
+                                )?;
+                                let predicate = match input {
+                                    LogicalPlan::Filter(filter)
+                                        if matches!(*filter.input, LogicalPlan::TableScan(_)) =>
+                                    {
+                                        Some(unnormalize_col(filter.predicate))
+                                    }
+                                    LogicalPlan::TableScan(_) => None,
+                                    input => {
+                                        return Err(Error::Error(format!(
+                                            "unsupported DELETE input: {:?}",
+                                            input
+                                        )))
+                                    }
+                                };
+                                let num_deleted_rows = table.delete(predicate).await?;
 
-                                return Ok(vec![]);
+                                return Ok(vec![dml::affected_rows_batch(&[(
+                                    "num_deleted_rows",
+                                    num_deleted_rows as u64,
+                                )])?]);
                             }
                             _ => Err(Error::Error(format!(
                                 "unsupported logical plan: {:?}",
@@ -143,4 +380,422 @@ impl SqlSession {
             ))),
         }
     }
+
+    /// The ensemble tables the statements of `sql` use, by namespace and
+    /// name.
+    pub fn tables_used(&self, sql: &str) -> Result<Vec<(String, String)>, Error> {
+        let options = &self.state.config_options().catalog;
+        let mut references = vec![];
+        for stmt in parser::SqlParser::new(sql)?.parse_sql()? {
+            match stmt {
+                parser::Statement::Statement(stmt) => references.extend(
+                    self.state
+                        .resolve_table_references(&DFStatement::Statement(Box::new(stmt)))?,
+                ),
+                parser::Statement::DescribeHistory(name)
+                | parser::Statement::Optimize(name)
+                | parser::Statement::Vacuum { table: name, .. } => {
+                    references.push(OwnedTableReference::from(name.to_string()))
+                }
+            }
+        }
+
+        let mut tables = references
+            .into_iter()
+            .map(|reference| {
+                let resolved = reference.resolve(&options.default_catalog, &options.default_schema);
+                (resolved.schema.to_string(), resolved.table.to_string())
+            })
+            .filter(|key| self.tables.contains_key(key))
+            .collect::<Vec<_>>();
+        tables.sort();
+        tables.dedup();
+
+        Ok(tables)
+    }
+
+    /// The ensemble table `reference` resolves to.
+    pub(crate) fn ensemble_table(
+        &self,
+        reference: &OwnedTableReference,
+    ) -> Result<Arc<dyn EnsembleTable>, Error> {
+        let options = &self.state.config_options().catalog;
+        let resolved = reference
+            .clone()
+            .resolve(&options.default_catalog, &options.default_schema);
+
+        let key = (resolved.schema.to_string(), resolved.table.to_string());
+        if let Some(staged) = self.transaction.as_ref().and_then(|t| t.get(&key)) {
+            return Ok(staged.clone());
+        }
+
+        self.tables
+            .get(&key)
+            .cloned()
+            .ok_or_else(|| Error::Error(format!("table not found: {reference}")))
+    }
+
+    pub(crate) fn schema(
+        &self,
+        reference: &OwnedTableReference,
+    ) -> Result<Arc<dyn SchemaProvider>, Error> {
+        let options = &self.state.config_options().catalog;
+        let reference = reference
+            .clone()
+            .resolve(&options.default_catalog, &options.default_schema);
+
+        self.state
+            .catalog_list()
+            .catalog(&reference.catalog)
+            .and_then(|catalog| catalog.schema(&reference.schema))
+            .ok_or_else(|| Error::Error(format!("schema not found: {}", reference.schema)))
+    }
+}
+
+fn is_transaction_control(stmt: &parser::Statement) -> bool {
+    matches!(
+        stmt,
+        parser::Statement::Statement(
+            SqlStatement::StartTransaction { .. }
+                | SqlStatement::Commit { .. }
+                | SqlStatement::Rollback { .. }
+        )
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use catalog::{
+        auth::AuthorizationRequest, edit::Edit, ApiKeySource, AuthenticationPolicy,
+        AuthenticationPolicyType, AuthorizationPolicy, Column, Mask, MaskingPolicy, PolicyKind,
+        Table,
+    };
+    use datafusion::arrow::util::pretty::pretty_format_batches;
+    use ensemble::Ensemble;
+    use ensemble_x::{storage::ObjectStore, EnsembleX};
+    use sqlparser::{ast::DataType, dialect::GenericDialect, parser::Parser};
+
+    use crate::SqlSession;
+
+    /// An in-memory ensemble with namespace `ns`, its `tables` with their
+    /// columns, and `edits` applied after them.
+    pub(crate) async fn test_ensemble(
+        tables: &[(&str, &[(&str, DataType)])],
+        edits: Vec<Edit>,
+    ) -> EnsembleX {
+        let mut ensemble = EnsembleX::new(ObjectStore::in_memory()).await.unwrap();
+        let tables = tables.iter().map(|(name, columns)| {
+            Edit::CreateTable(Table {
+                namespace: "ns".to_string(),
+                uuid: uuid::Uuid::new_v4(),
+                name: name.to_string(),
+                columns: columns
+                    .iter()
+                    .zip(1..)
+                    .map(|((name, data_type), uid)| Column {
+                        uid,
+                        name: name.to_string(),
+                        data_type: data_type.clone(),
+                    })
+                    .collect(),
+                partition_by: vec![],
+                retention_hours: None,
+                policies: vec![],
+            })
+        });
+        let namespace = Edit::CreateNamespace {
+            name: "ns".to_string(),
+        };
+        for edit in [namespace].into_iter().chain(tables).chain(edits) {
+            Ensemble::apply(&mut ensemble, &edit).await.unwrap();
+        }
+        Ensemble::commit(&mut ensemble).await.unwrap();
+
+        ensemble
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_ensemble() {
+        let ensemble = test_ensemble(
+            &[(
+                "t",
+                &[("id", DataType::Integer(None)), ("name", DataType::Text)],
+            )],
+            vec![],
+        )
+        .await;
+
+        let mut session = SqlSession::new(&ensemble).await.unwrap();
+        assert_eq!(
+            session
+                .tables_used("WITH u AS (SELECT * FROM ns.t) SELECT * FROM u, ns.t, other.t")
+                .unwrap(),
+            vec![("ns".to_string(), "t".to_string())]
+        );
+        session
+            .execute("INSERT INTO ns.t VALUES (1, 'a'), (2, 'b')")
+            .await
+            .unwrap();
+        session
+            .execute("DELETE FROM ns.t WHERE id = 1")
+            .await
+            .unwrap();
+
+        let batches = session.execute("SELECT * FROM ns.t").await.unwrap();
+        assert_eq!(
+            pretty_format_batches(&batches).unwrap().to_string(),
+            [
+                "+----+------+",
+                "| id | name |",
+                "+----+------+",
+                "| 2  | b    |",
+                "+----+------+",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_keys_tables() {
+        let ensemble = test_ensemble(
+            &[("api_keys", &[("key_id", DataType::Text)])],
+            vec![Edit::ReplaceAuthenticationPolicy(AuthenticationPolicy {
+                namespace: "ns".to_string(),
+                name: "keys".to_string(),
+                typ: AuthenticationPolicyType::ApiKey(ApiKeySource::Table("api_keys".to_string())),
+            })],
+        )
+        .await;
+
+        // Sessions don't see the credentials in keys tables.
+        let mut session = SqlSession::new(&ensemble).await.unwrap();
+        session
+            .execute("SELECT * FROM ns.api_keys")
+            .await
+            .unwrap_err();
+        session
+            .execute("INSERT INTO ns.api_keys VALUES ('k')")
+            .await
+            .unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn test_transaction() {
+        let ensemble = test_ensemble(
+            &[
+                ("t", &[("id", DataType::Integer(None))]),
+                ("u", &[("id", DataType::Integer(None))]),
+            ],
+            vec![],
+        )
+        .await;
+
+        let mut session = SqlSession::new(&ensemble).await.unwrap();
+        // Later statements see the changes of earlier ones.
+        session
+            .execute("INSERT INTO ns.t VALUES (1), (2); INSERT INTO ns.u SELECT count(*) FROM ns.t")
+            .await
+            .unwrap();
+
+        // A failing statement drops the changes of the others.
+        session
+            .execute("INSERT INTO ns.t VALUES (3); DELETE FROM ns.u; INSERT INTO ns.u VALUES ('x')")
+            .await
+            .unwrap_err();
+
+        session
+            .execute("BEGIN; INSERT INTO ns.t VALUES (4)")
+            .await
+            .unwrap();
+        session.execute("ROLLBACK").await.unwrap();
+
+        let t = session
+            .execute("SELECT count(*) AS n FROM ns.t")
+            .await
+            .unwrap();
+        let u = session.execute("SELECT id FROM ns.u").await.unwrap();
+        assert_eq!(
+            pretty_format_batches(&t).unwrap().to_string(),
+            ["+---+", "| n |", "+---+", "| 2 |", "+---+"].join("\n")
+        );
+        assert_eq!(
+            pretty_format_batches(&u).unwrap().to_string(),
+            ["+----+", "| id |", "+----+", "| 2  |", "+----+"].join("\n")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_row_policies() {
+        let policy = |name: &str, kind, expr: &str| {
+            Edit::ReplaceAuthorizationPolicy(AuthorizationPolicy {
+                namespace: "ns".to_string(),
+                name: name.to_string(),
+                kind,
+                expr: Parser::new(&GenericDialect {})
+                    .try_with_sql(expr)
+                    .unwrap()
+                    .parse_expr()
+                    .unwrap(),
+                table: Some("t".to_string()),
+            })
+        };
+        let ensemble = test_ensemble(
+            &[(
+                "t",
+                &[("id", DataType::Integer(None)), ("owner", DataType::Text)],
+            )],
+            vec![
+                policy("own_rows", PolicyKind::Permissive, "owner = principal"),
+                policy(
+                    "tenant_rows",
+                    PolicyKind::Permissive,
+                    "owner = claims.tenant",
+                ),
+                policy("not_zero", PolicyKind::Restrictive, "id <> 0"),
+            ],
+        )
+        .await;
+
+        let mut session = SqlSession::new(&ensemble).await.unwrap();
+        session
+            .execute("INSERT INTO ns.t VALUES (0, 'bob'), (1, 'bob'), (2, 'acme'), (3, 'eve')")
+            .await
+            .unwrap();
+
+        session.authorize_as(AuthorizationRequest {
+            principal: Some("bob".to_string()),
+            claims: serde_json::from_str(r#"{"tenant": "acme"}"#).unwrap(),
+            ..Default::default()
+        });
+        let select = "SELECT count(*) AS n, sum(id) AS s FROM ns.t";
+        let batches = session.execute(select).await.unwrap();
+        assert_eq!(
+            pretty_format_batches(&batches).unwrap().to_string(),
+            [
+                "+---+---+",
+                "| n | s |",
+                "+---+---+",
+                "| 2 | 3 |",
+                "+---+---+"
+            ]
+            .join("\n")
+        );
+
+        // Also at earlier versions of the table.
+        let batches = session
+            .execute("SELECT count(*) AS n, sum(id) AS s FROM ns.t VERSION AS OF 1")
+            .await
+            .unwrap();
+        assert_eq!(
+            pretty_format_batches(&batches).unwrap().to_string(),
+            [
+                "+---+---+",
+                "| n | s |",
+                "+---+---+",
+                "| 2 | 3 |",
+                "+---+---+"
+            ]
+            .join("\n")
+        );
+
+        // Only the rows the policies allow are changed.
+        session
+            .execute("DELETE FROM ns.t WHERE id < 2")
+            .await
+            .unwrap();
+        session.execute("UPDATE ns.t SET id = 4").await.unwrap();
+
+        let mut session = SqlSession::new(&ensemble).await.unwrap();
+        let batches = session.execute(select).await.unwrap();
+        assert_eq!(
+            pretty_format_batches(&batches).unwrap().to_string(),
+            [
+                "+---+---+",
+                "| n | s |",
+                "+---+---+",
+                "| 3 | 7 |",
+                "+---+---+"
+            ]
+            .join("\n")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_masking_policies() {
+        let policy = |name: &str, column: &str, mask| {
+            Edit::ReplaceMaskingPolicy(MaskingPolicy {
+                namespace: "ns".to_string(),
+                name: name.to_string(),
+                table: "t".to_string(),
+                column: column.to_string(),
+                mask,
+                unless: Some(
+                    Parser::new(&GenericDialect {})
+                        .try_with_sql("claims.role = 'admin'")
+                        .unwrap()
+                        .parse_expr()
+                        .unwrap(),
+                ),
+            })
+        };
+        let ensemble = test_ensemble(
+            &[(
+                "t",
+                &[("id", DataType::Integer(None)), ("email", DataType::Text)],
+            )],
+            vec![
+                policy("hide_id", "id", Mask::Redact),
+                policy("short_email", "email", Mask::Truncate(3)),
+            ],
+        )
+        .await;
+
+        let mut session = SqlSession::new(&ensemble).await.unwrap();
+        session
+            .execute("INSERT INTO ns.t VALUES (1, 'bob@example.com')")
+            .await
+            .unwrap();
+
+        // Queries only see the masked values, also in their predicates.
+        let select = "SELECT x.id, x.email, count(*) AS n FROM ns.t AS x \
+                      WHERE email = 'bob' GROUP BY x.id, x.email";
+        let request = |role: &str| AuthorizationRequest {
+            claims: serde_json::from_str(&format!(r#"{{"role": "{role}"}}"#)).unwrap(),
+            ..Default::default()
+        };
+        session.authorize_as(request("analyst"));
+        let batches = session.execute(select).await.unwrap();
+        assert_eq!(
+            pretty_format_batches(&batches).unwrap().to_string(),
+            [
+                "+----+-------+---+",
+                "| id | email | n |",
+                "+----+-------+---+",
+                "|    | bob   | 1 |",
+                "+----+-------+---+",
+            ]
+            .join("\n")
+        );
+
+        // Also at earlier versions of the table.
+        let batches = session
+            .execute("SELECT id, email FROM ns.t VERSION AS OF 1")
+            .await
+            .unwrap();
+        assert_eq!(
+            pretty_format_batches(&batches).unwrap().to_string(),
+            [
+                "+----+-------+",
+                "| id | email |",
+                "+----+-------+",
+                "|    | bob   |",
+                "+----+-------+",
+            ]
+            .join("\n")
+        );
+
+        session.authorize_as(request("admin"));
+        let batches = session.execute(select).await.unwrap();
+        assert_eq!(batches.iter().map(|b| b.num_rows()).sum::<usize>(), 0);
+    }
 }
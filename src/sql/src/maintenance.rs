@@ -0,0 +1,125 @@
+//! Compaction and cleanup of table files.
+
+use std::{sync::Arc, time::Duration};
+
+use datafusion::{
+    arrow::{
+        array::{ArrayRef, StringArray},
+        record_batch::RecordBatch,
+    },
+    common::OwnedTableReference,
+};
+use sqlparser::ast::ObjectName;
+
+use crate::{dml, Error, SqlSession};
+
+impl SqlSession {
+    /// Rewrite the small files of a table into larger ones.
+    pub(crate) async fn optimize(&self, name: &ObjectName) -> Result<RecordBatch, Error> {
+        let reference = OwnedTableReference::from(name.to_string());
+        let metrics = self.ensemble_table(&reference)?.optimize().await?;
+
+        dml::affected_rows_batch(&[
+            ("num_files_added", metrics.num_files_added),
+            ("num_files_removed", metrics.num_files_removed),
+        ])
+    }
+
+    /// Delete files no longer part of a table. Lists the deleted files.
+    pub(crate) async fn vacuum(
+        &self,
+        name: &ObjectName,
+        retain_hours: Option<u64>,
+        dry_run: bool,
+    ) -> Result<RecordBatch, Error> {
+        let reference = OwnedTableReference::from(name.to_string());
+        let retention = retain_hours
+            .map(|hours| {
+                hours
+                    .checked_mul(60 * 60)
+                    .map(Duration::from_secs)
+                    .ok_or_else(|| Error::Error(format!("retention of {hours} hours is too long")))
+            })
+            .transpose()?;
+        let files = self
+            .ensemble_table(&reference)?
+            .vacuum(retention, dry_run)
+            .await?;
+
+        Ok(RecordBatch::try_from_iter([(
+            "path",
+            Arc::new(StringArray::from(files)) as ArrayRef,
+        )])?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use catalog::edit::Edit;
+    use datafusion::arrow::{record_batch::RecordBatch, util::pretty::pretty_format_batches};
+    use ensemble::Ensemble;
+    use sqlparser::ast::DataType;
+
+    use crate::{tests::test_ensemble, SqlSession};
+
+    #[tokio::test]
+    async fn test_optimize_and_vacuum() {
+        let mut ensemble =
+            test_ensemble(&[("t", &[("id", DataType::Integer(None))])], vec![]).await;
+        let mut table = Ensemble::catalog(&ensemble).unwrap().namespaces["ns"].tables["t"].clone();
+        table.retention_hours = Some(0);
+        Ensemble::apply(&mut ensemble, &Edit::SetTableRetention(table))
+            .await
+            .unwrap();
+        Ensemble::commit(&mut ensemble).await.unwrap();
+
+        let mut session = SqlSession::new(&ensemble).await.unwrap();
+        for id in 1..=3 {
+            session
+                .execute(&format!("INSERT INTO ns.t VALUES ({id})"))
+                .await
+                .unwrap();
+        }
+
+        let batches = session.execute("OPTIMIZE ns.t").await.unwrap();
+        assert_eq!(
+            pretty_format_batches(&batches).unwrap().to_string(),
+            [
+                "+-----------------+-------------------+",
+                "| num_files_added | num_files_removed |",
+                "+-----------------+-------------------+",
+                "| 1               | 3                 |",
+                "+-----------------+-------------------+",
+            ]
+            .join("\n")
+        );
+
+        // The files OPTIMIZE removed are only deleted without DRY RUN.
+        let num_files =
+            |batches: Vec<RecordBatch>| batches.iter().map(|b| b.num_rows()).sum::<usize>();
+        let batches = session
+            .execute("VACUUM ns.t RETAIN 0 HOURS DRY RUN")
+            .await
+            .unwrap();
+        assert_eq!(num_files(batches), 3);
+        let batches = session.execute("VACUUM ns.t").await.unwrap();
+        assert_eq!(num_files(batches), 3);
+        let batches = session.execute("VACUUM ns.t").await.unwrap();
+        assert_eq!(num_files(batches), 0);
+
+        let batches = session
+            .execute("SELECT count(*) AS n FROM ns.t")
+            .await
+            .unwrap();
+        assert_eq!(
+            pretty_format_batches(&batches).unwrap().to_string(),
+            ["+---+", "| n |", "+---+", "| 3 |", "+---+"].join("\n")
+        );
+
+        session
+            .execute("VACUUM ns.t RETAIN 18446744073709551615 HOURS")
+            .await
+            .unwrap_err();
+        session.execute("BEGIN; VACUUM ns.t").await.unwrap_err();
+    }
+}
@@ -0,0 +1,225 @@
+//! Masking policies of columns, for sessions of a principal.
+//!
+//! Scans of a table with masking policies, also at an earlier version of the
+//! table, are wrapped in a projection that replaces each masked column by its
+//! masking expression for the request of the session, under the name of the
+//! table, so queries never see the values of the column itself. Row policies
+//! still see them, being applied below.
+//!
+//! UPDATE and MERGE read their target unmasked, to copy the columns they
+//! don't set, so they can't read its masked columns. Nor can they read a
+//! target with row or masking policies anywhere else in the statement.
+
+use catalog::auth;
+use datafusion::{
+    arrow::datatypes::Schema,
+    common::{Column, OwnedTableReference},
+    error::Result as DFResult,
+    logical_expr::{Expr, LogicalPlan, Projection, SubqueryAlias},
+};
+use ensemble::EnsembleTable;
+use sqlparser::ast::{visit_expressions, visit_relations, Expr as SqlExpr, Ident, Statement};
+use std::{ops::ControlFlow, sync::Arc};
+
+use crate::{
+    row_policy::{column_names, plan_expr},
+    Error, SqlSession,
+};
+
+impl SqlSession {
+    /// `input`, a scan of table `reference` with `schema`, with its masked
+    /// columns masked, None if none of them are.
+    pub(crate) fn mask_columns(
+        &self,
+        reference: &OwnedTableReference,
+        schema: &Schema,
+        input: &LogicalPlan,
+    ) -> DFResult<Option<LogicalPlan>> {
+        let Some(request) = &self.request else {
+            return Ok(None);
+        };
+        let Some(policies) = self.masking_policies.get(&self.table_key(reference)) else {
+            return Ok(None);
+        };
+
+        let columns = column_names(schema);
+        let exprs = schema
+            .fields()
+            .iter()
+            .map(
+                |field| match policies.iter().find(|p| p.column == *field.name()) {
+                    Some(policy) => {
+                        let masked =
+                            auth::masked_column(policy, field.data_type(), &columns, request);
+                        Ok(plan_expr(masked, schema, Some(reference))?.alias(field.name()))
+                    }
+                    None => Ok(Expr::Column(Column::new(
+                        Some(reference.clone()),
+                        field.name(),
+                    ))),
+                },
+            )
+            .collect::<DFResult<Vec<_>>>()?;
+
+        let projection = Projection::try_new(exprs, Arc::new(input.clone()))?;
+        Ok(Some(LogicalPlan::SubqueryAlias(SubqueryAlias::try_new(
+            LogicalPlan::Projection(projection),
+            reference.clone(),
+        )?)))
+    }
+
+    /// Check that UPDATE or MERGE `stmt` of `x_table`, table `reference`
+    /// under `alias`, doesn't read what the policies of the table hide.
+    pub(crate) fn check_target_reads(
+        &self,
+        stmt: &Statement,
+        x_table: &Arc<dyn EnsembleTable>,
+        reference: &OwnedTableReference,
+        alias: &Ident,
+    ) -> Result<(), Error> {
+        if self.request.is_none() {
+            return Ok(());
+        }
+        let key = self.table_key(reference);
+        let masked = self
+            .masking_policies
+            .get(&key)
+            .map(|policies| {
+                policies
+                    .iter()
+                    .map(|p| p.column.as_str())
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        if masked.is_empty() && !self.row_policies.contains_key(&key) {
+            return Ok(());
+        }
+
+        let mut reads = 0;
+        let _ = visit_relations(stmt, |name| {
+            let table = self.ensemble_table(&OwnedTableReference::from(name.to_string()));
+            if table.is_ok_and(|table| Arc::ptr_eq(&table, x_table)) {
+                reads += 1;
+            }
+            ControlFlow::<()>::Continue(())
+        });
+        // The target itself is one of them.
+        if reads > 1 {
+            return Err(Error::Error(format!(
+                "{} has policies, so it can only be read as the target",
+                reference
+            )));
+        }
+
+        let normalize = |ident: &Ident| match ident.quote_style {
+            Some(_) => ident.value.clone(),
+            None => ident.value.to_lowercase(),
+        };
+        let read = visit_expressions(stmt, |e| {
+            let column = match e {
+                SqlExpr::Identifier(column) => Some(column),
+                SqlExpr::CompoundIdentifier(ids) => match ids.as_slice() {
+                    [.., qualifier, column] if normalize(qualifier) == normalize(alias) => {
+                        Some(column)
+                    }
+                    _ => None,
+                },
+                _ => None,
+            };
+            match column.map(normalize) {
+                Some(column) if masked.contains(&column.as_str()) => ControlFlow::Break(column),
+                _ => ControlFlow::Continue(()),
+            }
+        });
+        match read {
+            ControlFlow::Break(column) => Err(Error::Error(format!(
+                "column {} of {} is masked, so it can't be read",
+                column, reference
+            ))),
+            ControlFlow::Continue(()) => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use catalog::{auth::AuthorizationRequest, edit::Edit, Mask, MaskingPolicy};
+    use datafusion::arrow::util::pretty::pretty_format_batches;
+    use sqlparser::ast::DataType;
+
+    use crate::{tests::test_ensemble, SqlSession};
+
+    #[tokio::test]
+    async fn test_update_and_merge_masked_target() {
+        let columns = [
+            ("id", DataType::Integer(None)),
+            ("email", DataType::Text),
+            ("name", DataType::Text),
+        ];
+        let ensemble = test_ensemble(
+            &[("t", &columns), ("s", &columns)],
+            vec![Edit::ReplaceMaskingPolicy(MaskingPolicy {
+                namespace: "ns".to_string(),
+                name: "short_email".to_string(),
+                table: "t".to_string(),
+                column: "email".to_string(),
+                mask: Mask::Truncate(3),
+                unless: None,
+            })],
+        )
+        .await;
+
+        let mut session = SqlSession::new(&ensemble).await.unwrap();
+        session
+            .execute(
+                "INSERT INTO ns.t VALUES (1, 'bob@example.com', 'bob'); \
+                 INSERT INTO ns.s VALUES (1, 'bob@example.com', 'robert')",
+            )
+            .await
+            .unwrap();
+
+        // Statements that would read the unmasked values of the target.
+        session.authorize_as(AuthorizationRequest::default());
+        for sql in [
+            "UPDATE ns.t SET name = email",
+            "UPDATE ns.t SET name = 'x' WHERE t.email = 'bob@example.com'",
+            "UPDATE ns.t SET name = 'x' WHERE id IN (SELECT id FROM ns.t)",
+            "MERGE INTO ns.t USING ns.s ON t.email = s.email \
+             WHEN MATCHED THEN UPDATE SET name = s.name",
+            "MERGE INTO ns.t USING ns.t AS s ON t.id = s.id \
+             WHEN MATCHED THEN UPDATE SET name = s.email",
+        ] {
+            assert!(session.execute(sql).await.is_err(), "{sql}");
+        }
+
+        // Setting masked columns doesn't read them.
+        session
+            .execute("UPDATE ns.t SET email = 'b@example.com' WHERE id = 1")
+            .await
+            .unwrap();
+        session
+            .execute(
+                "MERGE INTO ns.t USING ns.s ON t.id = s.id \
+                 WHEN MATCHED THEN UPDATE SET name = s.name, email = s.email",
+            )
+            .await
+            .unwrap();
+
+        let mut session = SqlSession::new(&ensemble).await.unwrap();
+        let batches = session
+            .execute("SELECT email, name FROM ns.t")
+            .await
+            .unwrap();
+        assert_eq!(
+            pretty_format_batches(&batches).unwrap().to_string(),
+            [
+                "+-----------------+--------+",
+                "| email           | name   |",
+                "+-----------------+--------+",
+                "| bob@example.com | robert |",
+                "+-----------------+--------+",
+            ]
+            .join("\n")
+        );
+    }
+}
@@ -1,18 +1,30 @@
 use std::collections::VecDeque;
 
-use crate::Error;
+use crate::{time_travel, Error};
 use sqlparser::{
-    ast::Statement as SqlStatement,
+    ast::{ObjectName, Statement as SqlStatement},
     dialect::GenericDialect,
+    keywords::Keyword,
     parser::Parser,
-    tokenizer::{TokenWithLocation, Tokenizer},
+    tokenizer::{Token, TokenWithLocation, Tokenizer, Word},
 };
 
 type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
 pub enum Statement {
     Statement(SqlStatement),
+    /// `DESCRIBE HISTORY <table>`
+    DescribeHistory(ObjectName),
+    /// `OPTIMIZE <table>`
+    Optimize(ObjectName),
+    /// `VACUUM <table> [RETAIN <n> HOURS] [DRY RUN]`
+    Vacuum {
+        table: ObjectName,
+        retain_hours: Option<u64>,
+        dry_run: bool,
+    },
 }
 
 pub struct SqlParser<'a> {
@@ -23,7 +35,7 @@ impl<'a> SqlParser<'a> {
     pub fn new(sql: &'a str) -> Result<Self> {
         let dialect = &GenericDialect {};
         let mut tokenizer = Tokenizer::new(dialect, sql);
-        let tokens = tokenizer.tokenize()?;
+        let tokens = time_travel::rewrite_tokens(tokenizer.tokenize()?)?;
 
         Ok(SqlParser {
             inner: Parser::new(dialect).with_tokens(tokens),
@@ -31,18 +43,98 @@ impl<'a> SqlParser<'a> {
     }
 
     pub fn parse_sql(&mut self) -> Result<VecDeque<Statement>> {
-        Ok(self
-            .inner
-            .parse_statements()?
-            .into_iter()
-            .map(Statement::Statement)
-            .collect())
+        let mut stmts = VecDeque::new();
+        let mut expecting_statement_delimiter = false;
+        loop {
+            // ignore empty statements (between successive statement delimiters)
+            while self.inner.consume_token(&Token::SemiColon) {
+                expecting_statement_delimiter = false;
+            }
+
+            if self.inner.peek_token() == Token::EOF {
+                break;
+            }
+            if expecting_statement_delimiter {
+                return self.expected("end of statement", self.inner.peek_token());
+            }
+
+            stmts.push_back(self.parse_statement()?);
+            expecting_statement_delimiter = true;
+        }
+
+        Ok(stmts)
     }
 
-    fn _expected<T>(&self, expected: &str, found: TokenWithLocation) -> Result<T> {
+    fn parse_statement(&mut self) -> Result<Statement> {
+        match (
+            self.inner.peek_token().token,
+            self.inner.peek_nth_token(1).token,
+        ) {
+            (Token::Word(describe), Token::Word(history))
+                if describe.keyword == Keyword::DESCRIBE && is_word(&history, "HISTORY") =>
+            {
+                self.inner.next_token();
+                self.inner.next_token();
+
+                Ok(Statement::DescribeHistory(self.inner.parse_object_name()?))
+            }
+            (Token::Word(w), _) if is_word(&w, "OPTIMIZE") => {
+                self.inner.next_token();
+
+                Ok(Statement::Optimize(self.inner.parse_object_name()?))
+            }
+            (Token::Word(w), _) if is_word(&w, "VACUUM") => {
+                self.inner.next_token();
+                self.parse_vacuum()
+            }
+            _ => Ok(Statement::Statement(self.inner.parse_statement()?)),
+        }
+    }
+
+    fn parse_vacuum(&mut self) -> Result<Statement> {
+        let table = self.inner.parse_object_name()?;
+
+        let retain_hours = if self.parse_word("RETAIN") {
+            let hours = self.inner.parse_literal_uint()?;
+            if !self.parse_word("HOURS") {
+                return self.expected("HOURS", self.inner.peek_token());
+            }
+            Some(hours)
+        } else {
+            None
+        };
+
+        let dry_run = self.parse_word("DRY");
+        if dry_run && !self.parse_word("RUN") {
+            return self.expected("RUN", self.inner.peek_token());
+        }
+
+        Ok(Statement::Vacuum {
+            table,
+            retain_hours,
+            dry_run,
+        })
+    }
+
+    /// Consume the next token if it is the unquoted word `expected`.
+    fn parse_word(&mut self, expected: &str) -> bool {
+        match self.inner.peek_token().token {
+            Token::Word(w) if is_word(&w, expected) => {
+                self.inner.next_token();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn expected<T>(&self, expected: &str, found: TokenWithLocation) -> Result<T> {
         Err(Error::Error(format!(
             "Expected {expected}, found: {found} at Line: {}, Column {}",
             found.location.line, found.location.column,
         )))
     }
 }
+
+fn is_word(word: &Word, expected: &str) -> bool {
+    word.quote_style.is_none() && word.value.eq_ignore_ascii_case(expected)
+}
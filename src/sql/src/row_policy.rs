@@ -0,0 +1,164 @@
+//! Row policies of tables, for sessions of a principal.
+//!
+//! Scans of a table with row policies are filtered by the predicate of its
+//! policies for the request of the session, so queries only see the rows it
+//! allows. DELETE and UPDATE only change those rows, and MERGE into such a
+//! table isn't supported. UPDATE can't read the table other than as its
+//! target, e.g. in a subquery. Rows inserted aren't checked.
+//!
+//! Columns with masking policies are masked above the filter, see
+//! [`crate::masking`].
+
+use std::sync::Arc;
+
+use catalog::auth::{self, AuthorizationExprContext, AuthorizationRequest};
+use datafusion::{
+    arrow::datatypes::Schema,
+    common::{
+        tree_node::{Transformed, TreeNode},
+        Column, DFSchema, OwnedTableReference,
+    },
+    error::Result as DFResult,
+    logical_expr::{Expr, Filter, LogicalPlan},
+    sql::planner::{PlannerContext, SqlToRel},
+};
+use ensemble::EnsembleTable;
+
+use crate::{dml::is_scan_of, time_travel::base_table_name, Error, SqlSession};
+
+impl SqlSession {
+    /// Apply the row policies of tables to the statements run from now on,
+    /// for `request`. Until then, the session sees and changes every row.
+    pub fn authorize_as(&mut self, request: AuthorizationRequest) {
+        self.request = Some(request);
+    }
+
+    /// The namespace and name of table `reference`, also when it is queried
+    /// at an earlier version.
+    pub(crate) fn table_key(&self, reference: &OwnedTableReference) -> (String, String) {
+        let options = &self.state.config_options().catalog;
+        let resolved = reference
+            .clone()
+            .resolve(&options.default_catalog, &options.default_schema);
+
+        (
+            resolved.schema.to_string(),
+            base_table_name(&resolved.table).to_string(),
+        )
+    }
+
+    /// The predicate the rows of table `reference` with `schema` are
+    /// filtered by, in SQL.
+    pub(crate) fn row_filter_sql(
+        &self,
+        reference: &OwnedTableReference,
+        schema: &Schema,
+    ) -> Option<sqlparser::ast::Expr> {
+        let request = self.request.as_ref()?;
+        let policies = self.row_policies.get(&self.table_key(reference))?;
+
+        auth::row_filter(policies, &column_names(schema), request)
+    }
+
+    /// The predicate the rows of table `reference` with `schema` are
+    /// filtered by, with columns qualified by `qualifier` if given.
+    pub(crate) fn row_filter(
+        &self,
+        reference: &OwnedTableReference,
+        schema: &Schema,
+        qualifier: Option<&OwnedTableReference>,
+    ) -> DFResult<Option<Expr>> {
+        self.row_filter_sql(reference, schema)
+            .map(|filter| plan_expr(filter, schema, qualifier))
+            .transpose()
+    }
+
+    /// Filter the scans of tables with row policies in `plan` and mask the
+    /// columns with masking policies, except for the scans of `except`.
+    pub(crate) fn apply_table_policies(
+        &self,
+        plan: LogicalPlan,
+        except: Option<&Arc<dyn EnsembleTable>>,
+    ) -> Result<LogicalPlan, Error> {
+        if self.request.is_none() {
+            return Ok(plan);
+        }
+
+        Ok(plan.transform_up(&|plan| {
+            let LogicalPlan::TableScan(scan) = &plan else {
+                return Ok(Transformed::No(plan));
+            };
+            if except.is_some_and(|t| is_scan_of(scan, t)) {
+                return Ok(Transformed::No(plan));
+            }
+            let table_name = scan.table_name.clone();
+            let schema = scan.source.schema();
+
+            let filter = self.row_filter(&table_name, &schema, Some(&table_name))?;
+            let filtered = match filter {
+                Some(filter) => LogicalPlan::Filter(Filter::try_new(filter, Arc::new(plan))?),
+                None => plan,
+            };
+
+            Ok(match self.mask_columns(&table_name, &schema, &filtered)? {
+                Some(masked) => Transformed::Yes(masked),
+                None if matches!(filtered, LogicalPlan::Filter(_)) => Transformed::Yes(filtered),
+                None => Transformed::No(filtered),
+            })
+        })?)
+    }
+
+    /// Restrict the rows `input` of a DELETE from `table_name` selects to
+    /// those its row policies allow.
+    pub(crate) fn restrict_delete(
+        &self,
+        table_name: &OwnedTableReference,
+        schema: &Schema,
+        input: &LogicalPlan,
+    ) -> Result<LogicalPlan, Error> {
+        let Some(filter) = self.row_filter(table_name, schema, Some(table_name))? else {
+            return Ok(input.clone());
+        };
+
+        Ok(LogicalPlan::Filter(match input {
+            LogicalPlan::Filter(selection) => Filter::try_new(
+                selection.predicate.clone().and(filter),
+                selection.input.clone(),
+            )?,
+            input => Filter::try_new(filter, Arc::new(input.clone()))?,
+        }))
+    }
+}
+
+/// `expr` planned against `schema`, with columns qualified by `qualifier` if
+/// given.
+pub(crate) fn plan_expr(
+    expr: sqlparser::ast::Expr,
+    schema: &Schema,
+    qualifier: Option<&OwnedTableReference>,
+) -> DFResult<Expr> {
+    let df_schema = match qualifier {
+        Some(qualifier) => DFSchema::try_from_qualified_schema(qualifier.clone(), schema)?,
+        None => DFSchema::try_from(schema.clone())?,
+    };
+
+    let context = AuthorizationExprContext::default();
+    let expr = SqlToRel::new(&context).sql_to_expr(expr, &df_schema, &mut PlannerContext::new())?;
+
+    // The planner leaves the columns of identifiers unqualified.
+    let Some(qualifier) = qualifier else {
+        return Ok(expr);
+    };
+    expr.transform_up(&|expr| {
+        Ok(match expr {
+            Expr::Column(column) if column.relation.is_none() => Transformed::Yes(Expr::Column(
+                Column::new(Some(qualifier.clone()), column.name),
+            )),
+            expr => Transformed::No(expr),
+        })
+    })
+}
+
+pub(crate) fn column_names(schema: &Schema) -> Vec<String> {
+    schema.fields().iter().map(|f| f.name().clone()).collect()
+}
@@ -0,0 +1,292 @@
+//! Queries on earlier versions of tables.
+//!
+//! `<table> VERSION AS OF <version>` and `<table> TIMESTAMP AS OF '<timestamp>'`
+//! are rewritten by the parser into references to `"<table>@v<version>"` and
+//! `"<table>@<yyyyMMddHHmmssSSS>"`, so that statements keep their meaning when
+//! printed and parsed again. Before a statement is planned, the tables it
+//! references this way are loaded at that version and registered under the
+//! versioned name for the duration of the statement.
+
+use std::sync::Arc;
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use datafusion::{
+    arrow::{
+        array::{ArrayRef, Int64Array, StringArray, TimestampMillisecondArray},
+        record_batch::RecordBatch,
+    },
+    common::OwnedTableReference,
+    sql::parser::Statement as DFStatement,
+};
+use sqlparser::{
+    ast::{ObjectName, Statement as SqlStatement},
+    keywords::Keyword,
+    tokenizer::{Token, Word},
+};
+
+use crate::{Error, SqlSession};
+
+const TIMESTAMP_FORMAT: &str = "%Y%m%d%H%M%S%3f";
+
+enum TimeTravel {
+    Version(i64),
+    Timestamp(DateTime<Utc>),
+}
+
+impl TimeTravel {
+    fn table_name(&self, table: &str) -> String {
+        match self {
+            TimeTravel::Version(version) => format!("{table}@v{version}"),
+            TimeTravel::Timestamp(timestamp) => {
+                format!("{table}@{}", timestamp.format(TIMESTAMP_FORMAT))
+            }
+        }
+    }
+
+    fn parse_table_name(name: &str) -> Option<(&str, TimeTravel)> {
+        let (table, suffix) = name.rsplit_once('@')?;
+
+        let time_travel = match suffix.strip_prefix('v') {
+            Some(version) => TimeTravel::Version(version.parse().ok()?),
+            None if suffix.len() == 17 && suffix.bytes().all(|b| b.is_ascii_digit()) => {
+                let timestamp = NaiveDateTime::parse_from_str(suffix, TIMESTAMP_FORMAT).ok()?;
+                TimeTravel::Timestamp(DateTime::from_naive_utc_and_offset(timestamp, Utc))
+            }
+            None => return None,
+        };
+
+        Some((table, time_travel))
+    }
+}
+
+/// The name of the table `name` refers to, without the version it may be
+/// queried at.
+pub(crate) fn base_table_name(name: &str) -> &str {
+    TimeTravel::parse_table_name(name).map_or(name, |(table, _)| table)
+}
+
+/// Accepts RFC 3339 timestamps, and `YYYY-MM-DD[ HH:MM:SS[.fff]]` in UTC.
+fn parse_timestamp(s: &str) -> Option<DateTime<Utc>> {
+    if let Ok(timestamp) = DateTime::parse_from_rfc3339(s) {
+        return Some(timestamp.with_timezone(&Utc));
+    }
+
+    let timestamp = NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S%.f")
+        .or_else(|_| NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S%.f"))
+        .ok()
+        .or_else(|| {
+            NaiveDate::parse_from_str(s, "%Y-%m-%d")
+                .ok()?
+                .and_hms_opt(0, 0, 0)
+        })?;
+
+    Some(DateTime::from_naive_utc_and_offset(timestamp, Utc))
+}
+
+/// Replace `<table> VERSION AS OF <version>` and
+/// `<table> TIMESTAMP AS OF '<timestamp>'` with a versioned table name.
+pub(crate) fn rewrite_tokens(tokens: Vec<Token>) -> Result<Vec<Token>, Error> {
+    let mut rewritten: Vec<Token> = Vec::with_capacity(tokens.len());
+    let mut i = 0;
+
+    while i < tokens.len() {
+        let Some((time_travel, len)) = time_travel_clause(&tokens[i..])? else {
+            rewritten.push(tokens[i].clone());
+            i += 1;
+            continue;
+        };
+
+        let table = rewritten
+            .iter_mut()
+            .rev()
+            .find(|token| !matches!(token, Token::Whitespace(_)));
+        match table {
+            Some(Token::Word(word)) => {
+                // Unquoted identifiers are normalized to lowercase by the
+                // planner, the versioned name is quoted so do it here.
+                let table = match word.quote_style {
+                    Some(_) => word.value.clone(),
+                    None => word.value.to_lowercase(),
+                };
+                *word = Word {
+                    value: time_travel.table_name(&table),
+                    quote_style: Some('"'),
+                    keyword: Keyword::NoKeyword,
+                };
+            }
+            _ => {
+                return Err(Error::Error(
+                    "expected a table name before VERSION AS OF or TIMESTAMP AS OF".to_string(),
+                ))
+            }
+        }
+
+        i += len;
+    }
+
+    Ok(rewritten)
+}
+
+/// Match a time travel clause at the start of `tokens`. Returns the clause and
+/// the number of tokens it spans.
+fn time_travel_clause(tokens: &[Token]) -> Result<Option<(TimeTravel, usize)>, Error> {
+    let mut words = tokens
+        .iter()
+        .enumerate()
+        .filter(|(_, token)| !matches!(token, Token::Whitespace(_)));
+
+    let kind = match words.next() {
+        Some((_, Token::Word(w))) if w.quote_style.is_none() => w.value.to_uppercase(),
+        _ => return Ok(None),
+    };
+    if kind != "VERSION" && kind != "TIMESTAMP" {
+        return Ok(None);
+    }
+    for keyword in [Keyword::AS, Keyword::OF] {
+        match words.next() {
+            Some((_, Token::Word(w))) if w.keyword == keyword => {}
+            _ => return Ok(None),
+        }
+    }
+
+    let time_travel = match (kind.as_str(), words.next()) {
+        ("VERSION", Some((i, Token::Number(version, _)))) => {
+            let version = version
+                .parse()
+                .map_err(|_| Error::Error(format!("invalid table version: {version}")))?;
+            (TimeTravel::Version(version), i + 1)
+        }
+        ("TIMESTAMP", Some((i, Token::SingleQuotedString(timestamp)))) => {
+            let timestamp = parse_timestamp(timestamp)
+                .ok_or_else(|| Error::Error(format!("invalid timestamp: {timestamp}")))?;
+            (TimeTravel::Timestamp(timestamp), i + 1)
+        }
+        (_, found) => {
+            return Err(Error::Error(format!(
+                "Expected a version number or a timestamp string after {kind} AS OF, found: {}",
+                found.map_or(Token::EOF, |(_, token)| token.clone())
+            )))
+        }
+    };
+
+    Ok(Some(time_travel))
+}
+
+impl SqlSession {
+    /// Load the tables `stmt` references as of a version or a time, and
+    /// register them under their versioned names. Returns the registered
+    /// tables, to be deregistered once the statement is done.
+    pub(crate) async fn register_time_travel_tables(
+        &self,
+        stmt: &SqlStatement,
+    ) -> Result<Vec<OwnedTableReference>, Error> {
+        let references = self
+            .state
+            .resolve_table_references(&DFStatement::Statement(Box::new(stmt.clone())))?;
+
+        let mut registered = vec![];
+        for reference in references {
+            let Some((table, time_travel)) = TimeTravel::parse_table_name(reference.table()) else {
+                continue;
+            };
+
+            let ensemble_table = self.ensemble_table(&with_table(&reference, table))?;
+            let provider = match time_travel {
+                TimeTravel::Version(version) => ensemble_table.load_version(version).await?,
+                TimeTravel::Timestamp(timestamp) => {
+                    ensemble_table.load_with_datetime(timestamp).await?
+                }
+            };
+
+            self.schema(&reference)?
+                .register_table(reference.table().to_string(), provider)?;
+            registered.push(reference);
+        }
+
+        Ok(registered)
+    }
+
+    pub(crate) fn deregister_tables(&self, tables: &[OwnedTableReference]) -> Result<(), Error> {
+        for table in tables {
+            self.schema(table)?.deregister_table(table.table())?;
+        }
+
+        Ok(())
+    }
+
+    /// One row per commit of the table, newest first.
+    pub(crate) async fn describe_history(&self, name: &ObjectName) -> Result<RecordBatch, Error> {
+        let reference = OwnedTableReference::from(name.to_string());
+        let commits = self.ensemble_table(&reference)?.history(None).await?;
+
+        let version = Int64Array::from_iter_values(commits.iter().map(|c| c.version));
+        let timestamp = TimestampMillisecondArray::from(
+            commits.iter().map(|c| c.timestamp).collect::<Vec<_>>(),
+        )
+        .with_timezone("UTC");
+        let operation = StringArray::from_iter(commits.iter().map(|c| c.operation.as_ref()));
+        let operation_parameters =
+            StringArray::from_iter(commits.iter().map(|c| c.operation_parameters.as_ref()));
+        let conductor_operation =
+            StringArray::from_iter(commits.iter().map(|c| c.conductor_operation.as_ref()));
+
+        Ok(RecordBatch::try_from_iter([
+            ("version", Arc::new(version) as ArrayRef),
+            ("timestamp", Arc::new(timestamp)),
+            ("operation", Arc::new(operation)),
+            ("operation_parameters", Arc::new(operation_parameters)),
+            ("conductor_operation", Arc::new(conductor_operation)),
+        ])?)
+    }
+}
+
+fn with_table(reference: &OwnedTableReference, table: &str) -> OwnedTableReference {
+    let table = table.to_string().into();
+    match reference.clone() {
+        OwnedTableReference::Bare { .. } => OwnedTableReference::Bare { table },
+        OwnedTableReference::Partial { schema, .. } => {
+            OwnedTableReference::Partial { schema, table }
+        }
+        OwnedTableReference::Full {
+            catalog, schema, ..
+        } => OwnedTableReference::Full {
+            catalog,
+            schema,
+            table,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::{SqlParser, Statement};
+
+    fn parse(sql: &str) -> String {
+        match SqlParser::new(sql)
+            .unwrap()
+            .parse_sql()
+            .unwrap()
+            .pop_front()
+        {
+            Some(Statement::Statement(stmt)) => stmt.to_string(),
+            stmt => panic!("unexpected statement: {stmt:?}"),
+        }
+    }
+
+    #[test]
+    fn test_time_travel_rewrite() {
+        assert_eq!(
+            parse("SELECT * FROM ns.Foo VERSION AS OF 12 AS f"),
+            r#"SELECT * FROM ns."foo@v12" AS f"#
+        );
+        assert_eq!(
+            parse("SELECT * FROM ns.foo TIMESTAMP AS OF '2023-06-01 12:30:00'"),
+            r#"SELECT * FROM ns."foo@20230601123000000""#
+        );
+        assert_eq!(
+            parse(r#"SELECT * FROM ns."foo@v12""#),
+            r#"SELECT * FROM ns."foo@v12""#
+        );
+        assert!(SqlParser::new("SELECT * FROM foo VERSION AS OF 'x'").is_err());
+    }
+}
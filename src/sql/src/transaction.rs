@@ -0,0 +1,403 @@
+//! Transactions spanning several statements.
+//!
+//! Within a transaction the tables of the session are replaced by
+//! [`StagedTable`]s, which keep the changes of the statements in memory on
+//! top of a snapshot of the table taken when it is first used, so later
+//! statements see the changes of earlier ones. On COMMIT the changes to all
+//! tables are committed together, on ROLLBACK or an error they are dropped.
+//!
+//! Statements that do more than append, DELETE, UPDATE and MERGE, load all
+//! rows of the table and replace them on COMMIT, so they are limited to
+//! tables of up to [`MAX_STAGED_BYTES`].
+
+use std::{any::Any, collections::HashMap, sync::Arc};
+
+use async_trait::async_trait;
+use datafusion::{
+    arrow::{compute::cast, datatypes::SchemaRef, record_batch::RecordBatch},
+    common::OwnedTableReference,
+    datasource::{MemTable, TableProvider},
+    error::DataFusionError,
+    execution::context::{SessionContext, SessionState},
+    logical_expr::TableType,
+    physical_plan::{
+        memory::MemoryExec, union::UnionExec, ExecutionPlan, SendableRecordBatchStream,
+    },
+    prelude::Expr,
+};
+use ensemble::{EnsembleTable, TableChange, TableCommit, WriteMetrics};
+use futures::TryStreamExt;
+use tokio::sync::OnceCell;
+
+use crate::{Error, SqlSession};
+
+/// Size of the rows of a table a transaction loads at most.
+const MAX_STAGED_BYTES: usize = 64 * 1024 * 1024;
+
+/// The staged tables of an open transaction, by namespace and name.
+pub(crate) type Transaction = HashMap<(String, String), Arc<StagedTable>>;
+
+#[derive(Default)]
+enum Staged {
+    #[default]
+    Unchanged,
+    /// Rows appended to the table.
+    Append(Vec<RecordBatch>),
+    /// All rows of the table, after statements that did more than append.
+    Overwrite {
+        rows: Vec<RecordBatch>,
+        operations: Vec<String>,
+    },
+}
+
+/// A table of a transaction, with the changes made to it so far.
+pub(crate) struct StagedTable {
+    inner: Arc<dyn EnsembleTable>,
+    /// The table as it was when the transaction first used it.
+    base: OnceCell<Arc<dyn TableProvider>>,
+    staged: std::sync::Mutex<Staged>,
+    max_bytes: usize,
+}
+
+impl StagedTable {
+    fn new(inner: Arc<dyn EnsembleTable>) -> Self {
+        Self {
+            inner,
+            base: OnceCell::new(),
+            staged: Default::default(),
+            max_bytes: MAX_STAGED_BYTES,
+        }
+    }
+
+    async fn base(&self) -> Result<Arc<dyn TableProvider>, ensemble::Error> {
+        self.base
+            .get_or_try_init(|| self.inner.snapshot())
+            .await
+            .cloned()
+    }
+
+    /// All rows of the table with the changes so far, if they fit in
+    /// `max_bytes`.
+    async fn rows(&self) -> Result<Vec<RecordBatch>, ensemble::Error> {
+        let appended = match &*self.staged.lock().unwrap() {
+            Staged::Unchanged => vec![],
+            Staged::Append(appended) => appended.clone(),
+            Staged::Overwrite { rows, .. } => return Ok(rows.clone()),
+        };
+
+        let ctx = SessionContext::new();
+        let mut base = ctx.read_table(self.base().await?)?.execute_stream().await?;
+        let mut size = appended
+            .iter()
+            .map(|b| b.get_array_memory_size())
+            .sum::<usize>();
+        let mut rows = vec![];
+        while let Some(batch) = base.try_next().await? {
+            size += batch.get_array_memory_size();
+            if size > self.max_bytes {
+                return Err(DataFusionError::ResourcesExhausted(format!(
+                    "only tables of up to {} MiB can be changed by more than INSERT \
+                     in a transaction",
+                    self.max_bytes / (1024 * 1024)
+                ))
+                .into());
+            }
+            rows.push(batch);
+        }
+        rows.extend(appended);
+
+        Ok(rows)
+    }
+
+    fn overwrite_with(&self, rows: Vec<RecordBatch>, operation: &str) {
+        let mut staged = self.staged.lock().unwrap();
+        let mut operations = match std::mem::take(&mut *staged) {
+            Staged::Overwrite { operations, .. } => operations,
+            _ => vec![],
+        };
+        operations.push(operation.to_string());
+
+        *staged = Staged::Overwrite { rows, operations };
+    }
+
+    /// The change to commit, if there is one.
+    async fn change(&self) -> Result<Option<TableChange>, ensemble::Error> {
+        let staged = std::mem::take(&mut *self.staged.lock().unwrap());
+
+        Ok(match staged {
+            Staged::Unchanged => None,
+            Staged::Append(rows) => Some(TableChange::Append(rows)),
+            Staged::Overwrite { rows, operations } => Some(TableChange::Overwrite {
+                snapshot: self.base().await?,
+                rows,
+                operation: operations.join(", "),
+            }),
+        })
+    }
+
+    /// Collect `input`, with the columns cast to the table schema.
+    async fn collect(
+        &self,
+        input: SendableRecordBatchStream,
+    ) -> Result<Vec<RecordBatch>, ensemble::Error> {
+        let schema = self.inner.schema();
+        let batches = input.try_collect::<Vec<_>>().await?;
+
+        batches
+            .into_iter()
+            .map(|batch| {
+                let columns = batch
+                    .columns()
+                    .iter()
+                    .zip(schema.fields())
+                    .map(|(column, field)| cast(column, field.data_type()))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(RecordBatch::try_new(schema.clone(), columns)?)
+            })
+            .collect::<Result<_, DataFusionError>>()
+            .map_err(ensemble::Error::from)
+    }
+}
+
+#[async_trait]
+impl TableProvider for StagedTable {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.inner.schema()
+    }
+
+    fn table_type(&self) -> TableType {
+        TableType::Base
+    }
+
+    async fn scan(
+        &self,
+        state: &SessionState,
+        projection: Option<&Vec<usize>>,
+        filters: &[Expr],
+        limit: Option<usize>,
+    ) -> datafusion::error::Result<Arc<dyn ExecutionPlan>> {
+        let staged = match &*self.staged.lock().unwrap() {
+            Staged::Unchanged => None,
+            Staged::Append(rows) => Some((false, rows.clone())),
+            Staged::Overwrite { rows, .. } => Some((true, rows.clone())),
+        };
+        let memory = |rows: Vec<RecordBatch>| -> datafusion::error::Result<_> {
+            Ok(Arc::new(MemoryExec::try_new(
+                &[rows],
+                self.schema(),
+                projection.cloned(),
+            )?))
+        };
+
+        match staged {
+            Some((true, rows)) => Ok(memory(rows)?),
+            staged => {
+                let base = self
+                    .base()
+                    .await
+                    .map_err(|e| DataFusionError::External(Box::new(e)))?
+                    .scan(state, projection, filters, limit)
+                    .await?;
+                match staged {
+                    Some((_, appended)) => {
+                        Ok(Arc::new(UnionExec::new(vec![base, memory(appended)?])))
+                    }
+                    None => Ok(base),
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl EnsembleTable for StagedTable {
+    async fn write(
+        &self,
+        input: SendableRecordBatchStream,
+    ) -> Result<WriteMetrics, ensemble::Error> {
+        let batches = self.collect(input).await?;
+        let num_rows = batches.iter().map(|b| b.num_rows() as u64).sum();
+
+        let mut staged = self.staged.lock().unwrap();
+        match &mut *staged {
+            Staged::Unchanged => *staged = Staged::Append(batches),
+            Staged::Append(rows) | Staged::Overwrite { rows, .. } => rows.extend(batches),
+        }
+
+        Ok(WriteMetrics {
+            num_rows,
+            ..Default::default()
+        })
+    }
+
+    async fn delete(&self, predicate: Option<Expr>) -> Result<usize, ensemble::Error> {
+        let ctx = SessionContext::new();
+        let rows = ctx.read_table(Arc::new(MemTable::try_new(
+            self.schema(),
+            vec![self.rows().await?],
+        )?))?;
+
+        let (num_deleted_rows, kept_rows) = match predicate {
+            Some(predicate) => (
+                rows.clone().filter(predicate.clone())?.count().await?,
+                rows.filter(predicate.is_not_true())?.collect().await?,
+            ),
+            None => (rows.count().await?, vec![]),
+        };
+        if num_deleted_rows > 0 {
+            self.overwrite_with(kept_rows, "DELETE");
+        }
+
+        Ok(num_deleted_rows)
+    }
+
+    async fn snapshot(&self) -> Result<Arc<dyn TableProvider>, ensemble::Error> {
+        if matches!(*self.staged.lock().unwrap(), Staged::Unchanged) {
+            return self.base().await;
+        }
+
+        Ok(Arc::new(MemTable::try_new(
+            self.schema(),
+            vec![self.rows().await?],
+        )?))
+    }
+
+    /// Statements of a session run one at a time, so nothing can have
+    /// changed since `snapshot` was taken.
+    async fn overwrite(
+        &self,
+        _snapshot: Arc<dyn TableProvider>,
+        input: SendableRecordBatchStream,
+        operation: &str,
+    ) -> Result<(), ensemble::Error> {
+        let rows = self.collect(input).await?;
+        self.overwrite_with(rows, operation);
+
+        Ok(())
+    }
+
+    async fn load_version(&self, version: i64) -> Result<Arc<dyn TableProvider>, ensemble::Error> {
+        self.inner.load_version(version).await
+    }
+
+    async fn load_with_datetime(
+        &self,
+        datetime: chrono::DateTime<chrono::Utc>,
+    ) -> Result<Arc<dyn TableProvider>, ensemble::Error> {
+        self.inner.load_with_datetime(datetime).await
+    }
+
+    async fn history(&self, limit: Option<usize>) -> Result<Vec<TableCommit>, ensemble::Error> {
+        self.inner.history(limit).await
+    }
+}
+
+impl SqlSession {
+    /// Start a transaction, replacing the tables of the session by staged
+    /// ones.
+    pub(crate) fn begin(&mut self) -> Result<(), Error> {
+        if self.transaction.is_some() {
+            return Err(Error::Error(
+                "a transaction is already in progress".to_string(),
+            ));
+        }
+
+        let mut transaction = Transaction::new();
+        for ((namespace, name), table) in &self.tables {
+            let staged = Arc::new(StagedTable::new(table.clone()));
+            self.replace_table(namespace, name, staged.clone())?;
+            transaction.insert((namespace.clone(), name.clone()), staged);
+        }
+        self.transaction = Some(transaction);
+
+        Ok(())
+    }
+
+    /// Commit the changes of the transaction to all tables, or to none if
+    /// one of them fails.
+    pub(crate) async fn commit(&mut self) -> Result<(), Error> {
+        let transaction = self.end_transaction()?;
+
+        let mut prepared = vec![];
+        for staged in transaction.values() {
+            if let Some(change) = staged.change().await? {
+                prepared.push(staged.inner.clone().prepare(change).await?);
+            }
+        }
+        ensemble::commit_all(prepared).await?;
+
+        Ok(())
+    }
+
+    /// Drop the changes of the transaction.
+    pub(crate) fn rollback(&mut self) -> Result<(), Error> {
+        self.end_transaction()?;
+
+        Ok(())
+    }
+
+    /// Put the tables of the session back in place of the staged ones.
+    fn end_transaction(&mut self) -> Result<Transaction, Error> {
+        let transaction = self
+            .transaction
+            .take()
+            .ok_or_else(|| Error::Error("no transaction is in progress".to_string()))?;
+
+        for ((namespace, name), table) in &self.tables {
+            self.replace_table(namespace, name, table.clone())?;
+        }
+
+        Ok(transaction)
+    }
+
+    fn replace_table(
+        &self,
+        namespace: &str,
+        name: &str,
+        provider: Arc<dyn TableProvider>,
+    ) -> Result<(), Error> {
+        let schema = self.schema(&OwnedTableReference::partial(
+            namespace.to_string(),
+            name.to_string(),
+        ))?;
+        schema.deregister_table(name)?;
+        schema.register_table(name.to_string(), provider)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ensemble::{Ensemble, EnsembleTable};
+    use sqlparser::ast::DataType;
+
+    use super::StagedTable;
+    use crate::{tests::test_ensemble, SqlSession};
+
+    #[tokio::test]
+    async fn test_max_bytes() {
+        let ensemble = test_ensemble(&[("t", &[("id", DataType::Integer(None))])], vec![]).await;
+        let mut session = SqlSession::new(&ensemble).await.unwrap();
+        session
+            .execute("INSERT INTO ns.t VALUES (1), (2)")
+            .await
+            .unwrap();
+
+        let table = Ensemble::table(&ensemble, "ns", "t").await.unwrap();
+        let staged = StagedTable {
+            max_bytes: 1,
+            ..StagedTable::new(table.clone())
+        };
+        assert!(staged.delete(None).await.is_err());
+        // Reading the table is still possible, without loading its rows.
+        assert!(staged.snapshot().await.is_ok());
+
+        let staged = StagedTable::new(table);
+        assert_eq!(staged.delete(None).await.unwrap(), 2);
+    }
+}